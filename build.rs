@@ -1,3 +1,19 @@
+use std::{env, fs, path::Path};
+
 fn main() {
     println!("cargo:rerun-if-changed=kernel8.ld");
+    // Baked into the logger's default filter
+    println!("cargo:rerun-if-env-changed=KERNEL_LOG");
+
+    // Symbol table for backtraces, made by `gen_symbols.sh` from a previous build.
+    // Without one we still build, just without function names in backtraces.
+    println!("cargo:rerun-if-env-changed=KERNEL_SYMBOLS");
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("symbols.txt");
+    match env::var("KERNEL_SYMBOLS").ok().filter(|path| !path.is_empty()) {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            fs::copy(&path, &out).expect("Could not read KERNEL_SYMBOLS");
+        }
+        None => fs::write(&out, "").unwrap(),
+    }
 }
@@ -0,0 +1,11 @@
+#!/bin/sh
+# Turns `nm` output into the symbol table the kernel embeds for backtraces.
+# One function per line, sorted by address: `<hex address> <demangled name>`
+#
+# rust-nm --defined-only --numeric-sort --demangle kernel.elf | ./gen_symbols.sh > symbols.txt
+awk '$2 == "T" || $2 == "t" {
+    addr = $1
+    sub(/^[0-9a-f]+ [tT] /, "")
+    sub(/::h[0-9a-f]+$/, "")
+    print addr, $0
+}'
@@ -0,0 +1,12 @@
+#!/bin/sh
+# Boots a kernel ELF in QEMU. Cargo runs this for `cargo run` and `cargo test`, see
+# .cargo/config.toml. For tests, anything after `cargo test --` becomes `test=` on the command
+# line, which picks the tests to run. Semihosting lets the tests exit QEMU with their result.
+QEMU=${QEMU:-qemu-system-aarch64}
+KERNEL=$1
+shift
+if [ -n "$1" ]; then
+    set -- -append "test=$1"
+fi
+exec "$QEMU" -M raspi3b -kernel "$KERNEL" -serial null -serial mon:stdio -display none \
+    -semihosting "$@"
@@ -0,0 +1,104 @@
+//! The ARM-local peripherals of the BCM2836/7: per-core interrupt routing, mailboxes and timers.
+//!
+//! Unlike the rest of the peripherals these aren't behind the VideoCore's bus, so their address
+//! is a plain physical one.
+//! https://datasheets.raspberrypi.com/bcm2836/bcm2836-peripherals.pdf
+use crate::MMIODerefWrapper;
+use tock_registers::{
+    interfaces::{ReadWriteable, Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::{LocalRegisterCopy, ReadOnly, ReadWrite, WriteOnly},
+};
+
+pub const BASE_ADDR: usize = 0x4000_0000;
+pub const MAILBOXES_PER_CPU: usize = 4;
+
+type Local = MMIODerefWrapper<Registers>;
+
+register_structs! {
+    Registers {
+        (0x00 => _reserved_control),
+        (0x40 => timer_int_control: [ReadWrite<u32, TIMER_INT_CONTROL::Register>; 4]),
+        (0x50 => mailbox_int_control: [ReadWrite<u32, MAILBOX_INT_CONTROL::Register>; 4]),
+        (0x60 => irq_source: [ReadOnly<u32, IRQ_SOURCE::Register>; 4]),
+        (0x70 => _reserved_fiq_source),
+        // Four per core. Writing sets bits.
+        (0x80 => mailbox_set: [WriteOnly<u32>; 16]),
+        // Four per core. Writing clears bits.
+        (0xC0 => mailbox_clear: [ReadWrite<u32>; 16]),
+        (0x100 => @END),
+    }
+}
+
+register_bitfields! {
+    // 32 bit registers
+    u32,
+
+    pub TIMER_INT_CONTROL [
+        CNTPSIRQ    OFFSET(0)   NUMBITS(1) [],
+        CNTPNSIRQ   OFFSET(1)   NUMBITS(1) [],
+        CNTHPIRQ    OFFSET(2)   NUMBITS(1) [],
+        CNTVIRQ     OFFSET(3)   NUMBITS(1) [],
+    ],
+    pub MAILBOX_INT_CONTROL [
+        // One bit per mailbox
+        IRQ     OFFSET(0)   NUMBITS(4) [],
+        FIQ     OFFSET(4)   NUMBITS(4) [],
+    ],
+    pub IRQ_SOURCE [
+        CNTPSIRQ    OFFSET(0)   NUMBITS(1) [],
+        CNTPNSIRQ   OFFSET(1)   NUMBITS(1) [],
+        CNTHPIRQ    OFFSET(2)   NUMBITS(1) [],
+        CNTVIRQ     OFFSET(3)   NUMBITS(1) [],
+        // One bit per mailbox
+        MAILBOX     OFFSET(4)   NUMBITS(4) [],
+        GPU         OFFSET(8)   NUMBITS(1) [],
+        PMU         OFFSET(9)   NUMBITS(1) [],
+        AXI         OFFSET(10)  NUMBITS(1) [],
+        LOCAL_TIMER OFFSET(11)  NUMBITS(1) [],
+    ],
+}
+
+fn regs() -> Local {
+    // Safety: Always mapped, and every access is a single register read or write
+    unsafe { Local::new(BASE_ADDR) }
+}
+
+/// Which interrupts are waiting for a core
+pub fn irq_source(cpu: usize) -> LocalRegisterCopy<u32, IRQ_SOURCE::Register> {
+    regs().irq_source[cpu].extract()
+}
+
+/// Route a mailbox's interrupt to its core
+pub fn enable_mailbox_irq(cpu: usize, mailbox: usize) {
+    let regs = regs();
+    let enabled = regs.mailbox_int_control[cpu].read(MAILBOX_INT_CONTROL::IRQ);
+    regs.mailbox_int_control[cpu].modify(MAILBOX_INT_CONTROL::IRQ.val(enabled | 1 << mailbox));
+}
+
+/// Route the non-secure physical timer's interrupt to its core
+pub fn enable_physical_timer_irq(cpu: usize) {
+    regs().timer_int_control[cpu].modify(TIMER_INT_CONTROL::CNTPNSIRQ::SET);
+}
+
+/// Set `bits` in one of a core's mailboxes
+pub fn mailbox_set(cpu: usize, mailbox: usize, bits: u32) {
+    regs().mailbox_set[cpu * MAILBOXES_PER_CPU + mailbox].set(bits);
+}
+
+/// Read one of a core's mailboxes and clear the bits that were set
+pub fn mailbox_take(cpu: usize, mailbox: usize) -> u32 {
+    mailbox_take_bits(cpu, mailbox, u32::MAX)
+}
+
+/// Like `mailbox_take`, but only for the bits in `mask`. The rest stay pending.
+pub fn mailbox_take_bits(cpu: usize, mailbox: usize, mask: u32) -> u32 {
+    let regs = regs();
+    let reg = &regs.mailbox_clear[cpu * MAILBOXES_PER_CPU + mailbox];
+    let bits = reg.get() & mask;
+    // Only clears what we read, so anything that arrived in between stays pending
+    if bits != 0 {
+        reg.set(bits);
+    }
+    bits
+}
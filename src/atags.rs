@@ -0,0 +1,100 @@
+//! ATAGs, the tagged list older firmware (and QEMU, when booting without a device tree) uses to
+//! describe memory, the initrd and the command line.
+//! http://www.simtec.co.uk/products/SWLINUX/files/booting_article.html#appendix_tag_reference
+use arrayvec::ArrayVec;
+use core::ops::Range;
+use spin::Once;
+
+const ATAG_NONE: u32 = 0;
+const ATAG_CORE: u32 = 0x5441_0001;
+const ATAG_MEM: u32 = 0x5441_0002;
+const ATAG_INITRD2: u32 = 0x5442_0005;
+const ATAG_CMDLINE: u32 = 0x5441_0009;
+
+/// Where QEMU puts them if nobody says otherwise
+pub const DEFAULT_ADDR: usize = 0x100;
+/// More tags than this means we're reading garbage
+const MAX_TAGS: usize = 64;
+
+static MEMORY: Once<ArrayVec<Range<usize>, 4>> = Once::new();
+
+#[derive(Clone, Copy)]
+pub struct Atags {
+    addr: usize,
+}
+
+impl Atags {
+    /// The list at `addr`, if it starts with an `ATAG_CORE`
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be readable, and the list there must stay put
+    pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+        if addr % 4 != 0 {
+            return None;
+        }
+        let header = addr as *const u32;
+        (header.add(1).read_volatile() == ATAG_CORE).then_some(Atags { addr })
+    }
+
+    /// Every tag, as its type and its data (everything after the two word header)
+    fn tags(&self) -> impl Iterator<Item = (u32, &'static [u32])> {
+        let mut addr = self.addr;
+        core::iter::from_fn(move || {
+            let header = addr as *const u32;
+            // Safety: `from_addr` checked there's a list here, and it ends with `ATAG_NONE`
+            let (size, tag) = unsafe { (header.read_volatile() as usize, header.add(1).read_volatile()) };
+            if tag == ATAG_NONE || size < 2 {
+                return None;
+            }
+            // Safety: As above. The size counts the header, in words.
+            let data = unsafe { core::slice::from_raw_parts(header.add(2), size - 2) };
+            addr += size * 4;
+            Some((tag, data))
+        })
+        .take(MAX_TAGS)
+    }
+
+    pub fn initrd(&self) -> Option<Range<usize>> {
+        let (_, data) = self.tags().find(|&(tag, data)| tag == ATAG_INITRD2 && data.len() >= 2)?;
+        let start = data[0] as usize;
+        Some(start..start + data[1] as usize)
+    }
+
+    /// Each bank of RAM
+    pub fn memory(&self) -> impl Iterator<Item = Range<usize>> {
+        self.tags()
+            .filter(|&(tag, data)| tag == ATAG_MEM && data.len() >= 2)
+            .map(|(_, data)| data[1] as usize..data[1] as usize + data[0] as usize)
+    }
+
+    pub fn cmdline(&self) -> Option<&'static str> {
+        let (_, data) = self.tags().find(|&(tag, _)| tag == ATAG_CMDLINE)?;
+        // Safety: Reinterpreting words we can already read as bytes
+        let bytes = unsafe { core::slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * 4) };
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+/// Find out where RAM is from the ATAGs `boot_arg` points at, when there's no device tree to say.
+/// Needs `fdt::init`, and must run before anything allocates pages.
+pub fn init(boot_arg: usize) {
+    if crate::fdt::get().is_some() {
+        return;
+    }
+    let addr = if boot_arg == 0 { DEFAULT_ADDR } else { boot_arg };
+    // Safety: Whatever the firmware points x0 at is in RAM, and nothing has been put over it
+    let Some(atags) = (unsafe { Atags::from_addr(addr) }) else {
+        return;
+    };
+    let memory: ArrayVec<_, 4> = atags.memory().take(4).collect();
+    if !memory.is_empty() {
+        MEMORY.call_once(|| memory);
+    }
+}
+
+/// The banks of RAM the ATAGs list, if we booted with them
+pub fn memory() -> Option<&'static [Range<usize>]> {
+    MEMORY.get().map(|memory| memory.as_slice())
+}
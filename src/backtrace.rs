@@ -0,0 +1,149 @@
+//! Stack backtraces, by walking the chain of frame records.
+//!
+//! We build with `-C force-frame-pointers`, so every function starts by pushing a frame record
+//! (the caller's x29 followed by x30) and pointing x29 at it. Following x29 gets us every return
+//! address on the stack.
+//!
+//! Return addresses are named using a table of function addresses that the build embeds in the
+//! `.ksyms` section. See `gen_symbols.sh` and the Makefile.
+use crate::mmu::frames::RAM_END;
+use core::fmt;
+
+/// Stop walking after this many frames, in case the chain loops
+const MAX_FRAMES: usize = 32;
+
+// The symbol table is only ever referenced through the linker symbols, so code doesn't change
+// when it does and function addresses are the same with or without it
+#[used]
+#[link_section = ".ksyms"]
+static SYMBOLS: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/symbols.txt")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/symbols.txt"));
+
+extern "C" {
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+fn symbol_table() -> &'static str {
+    // Safety: The linker puts the symbol table between these two
+    let table = unsafe {
+        let start = core::ptr::addr_of!(__ksyms_start);
+        let len = core::ptr::addr_of!(__ksyms_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    core::str::from_utf8(table).unwrap_or("")
+}
+
+fn is_code(addr: usize) -> bool {
+    // Safety: Only the addresses are used
+    let text = unsafe {
+        core::ptr::addr_of!(__text_start) as usize..core::ptr::addr_of!(__text_end) as usize
+    };
+    text.contains(&addr)
+}
+
+/// Name of the function holding `addr` and how far into it `addr` is
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    if !is_code(addr) {
+        return None;
+    }
+
+    // Table is sorted by address, so the last function starting at or before `addr` holds it
+    let mut found = None;
+    for line in symbol_table().lines() {
+        let Some((start, name)) = line.split_once(' ') else {
+            continue;
+        };
+        let Ok(start) = usize::from_str_radix(start, 16) else {
+            continue;
+        };
+        if start > addr {
+            break;
+        }
+        found = Some((name, addr - start));
+    }
+    found
+}
+
+/// Iterator over the return addresses on the stack, newest first
+pub struct Backtrace {
+    fp: usize,
+    frames: usize,
+}
+
+impl Backtrace {
+    /// Walk the stack of whoever called this
+    #[inline(always)]
+    pub fn here() -> Self {
+        let fp: usize;
+        // Safety: Only reads a register
+        unsafe { core::arch::asm!("mov {}, x29", out(reg) fp) };
+        Backtrace::from_fp(fp)
+    }
+
+    /// Walk a stack starting from the frame record `fp` points to
+    pub fn from_fp(fp: usize) -> Self {
+        Backtrace { fp, frames: 0 }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        // A frame pointer of 0 marks the outermost frame. Anything misaligned or outside of the
+        // RAM we hand out stacks from means the chain is corrupt, and following it would fault.
+        if self.fp == 0 || self.fp % 8 != 0 || self.fp + 16 > RAM_END {
+            return None;
+        }
+        if self.frames >= MAX_FRAMES {
+            return None;
+        }
+
+        // Safety: Checked it is an aligned address in RAM above
+        let (next_fp, ret_addr) = unsafe {
+            let record = self.fp as *const usize;
+            (record.read(), record.add(1).read())
+        };
+        // The stack grows down, so callers' frames are always further up
+        if next_fp != 0 && next_fp <= self.fp {
+            self.fp = 0;
+        } else {
+            self.fp = next_fp;
+        }
+        self.frames += 1;
+
+        (ret_addr != 0).then_some(ret_addr)
+    }
+}
+
+/// Formats an address as `function+offset`
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match symbolize(self.0) {
+            Some((name, offset)) => write!(f, "{:#010x} {}+{:#x}", self.0, name, offset),
+            None => write!(f, "{:#010x} <unknown>", self.0),
+        }
+    }
+}
+
+/// Print a backtrace one line at a time through `emit`.
+/// `pc` is where execution was, if it is known, and goes first.
+pub fn print(pc: Option<usize>, trace: Backtrace, mut emit: impl FnMut(fmt::Arguments)) {
+    emit(format_args!("Backtrace:"));
+    let mut idx = 0;
+    if let Some(pc) = pc {
+        emit(format_args!("  #{:<2} {}", idx, Symbolized(pc)));
+        idx += 1;
+    }
+    for ret_addr in trace {
+        // Return addresses point after the call. Name the call itself instead, in case it
+        // was the last instruction of the function.
+        emit(format_args!("  #{:<2} {}", idx, Symbolized(ret_addr.wrapping_sub(4))));
+        idx += 1;
+    }
+}
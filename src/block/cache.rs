@@ -0,0 +1,476 @@
+//! A write-back cache of blocks, in front of a disk that's slow to talk to.
+//!
+//! Filesystems read and write a sector at a time, and mostly the same few sectors: the FAT,
+//! directories, the boot sector. The cache keeps the most recently used blocks in pages from the
+//! frame allocator, and throws out the least recently used one when it needs room.
+//!
+//! Writes only change the cached copy and mark it dirty. Dirty blocks go to the disk when they're
+//! evicted, or when someone calls `sync` (or `flush`), which writes them all in order, joining
+//! neighbours into one command.
+//!
+//! A read that misses right where the last read ended looks like someone reading a file from
+//! start to end, so the blocks after it are fetched in the same command, before they're asked
+//! for.
+use super::{check_range, BlockDevice, BLOCK_SIZE};
+use crate::mmu::frames;
+use crate::sync::Mutex;
+use arrayvec::ArrayVec;
+use log::info;
+
+const PAGE_SIZE: usize = 4096;
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+/// How many blocks are kept. 128KiB.
+pub const CACHE_BLOCKS: usize = 256;
+const CACHE_PAGES: usize = CACHE_BLOCKS / BLOCKS_PER_PAGE;
+/// Most blocks moved in one command, which is what fits in the scratch page
+const MAX_RUN: usize = BLOCKS_PER_PAGE;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Blocks read that were already here
+    pub hits: u64,
+    /// Blocks read that had to come from the disk
+    pub misses: u64,
+    /// Blocks fetched before anyone asked for them
+    pub read_ahead: u64,
+    /// Blocks written to the cache
+    pub writes: u64,
+    /// Dirty blocks written back to the disk
+    pub write_backs: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    /// The block it holds, if any
+    block: Option<u64>,
+    dirty: bool,
+    /// When it was last used, in accesses. The lowest is evicted first.
+    last_used: u64,
+}
+
+struct Inner {
+    slots: [Slot; CACHE_BLOCKS],
+    /// Where each slot's data is. `CACHE_BLOCKS / BLOCKS_PER_PAGE` pages, a slot after another.
+    pages: [usize; CACHE_PAGES],
+    /// A page to gather runs of blocks in, for commands that move more than one
+    scratch: usize,
+    clock: u64,
+    /// Block after the last one read, to spot sequential reads
+    next_read: u64,
+    stats: Stats,
+}
+
+/// `len` bytes of one of the cache's pages
+///
+/// # Safety
+///
+/// They have to be in one of the pages `Cache::new` took, and nothing else may be using them
+unsafe fn bytes<'a>(addr: usize, len: usize) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut(addr as *mut u8, len)
+}
+
+impl Inner {
+    fn slot_addr(&self, slot: usize) -> usize {
+        self.pages[slot / BLOCKS_PER_PAGE] + slot % BLOCKS_PER_PAGE * BLOCK_SIZE
+    }
+
+    fn data(&mut self, slot: usize) -> &mut [u8] {
+        // Safety: Each slot has its own part of a page, and the lock we're borrowed through keeps
+        // everyone else out of it
+        unsafe { bytes(self.slot_addr(slot), BLOCK_SIZE) }
+    }
+
+    /// The first `blocks` blocks of the scratch page
+    fn scratch(&mut self, blocks: usize) -> &mut [u8] {
+        // Safety: As for `data`
+        unsafe { bytes(self.scratch, blocks * BLOCK_SIZE) }
+    }
+
+    fn find(&self, block: u64) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.block == Some(block))
+    }
+
+    fn touch(&mut self, slot: usize) {
+        self.clock += 1;
+        self.slots[slot].last_used = self.clock;
+    }
+
+    /// A slot to put `block` in, writing back whatever was in it if it's dirty
+    fn claim(&mut self, device: &dyn BlockDevice, block: u64) -> Result<usize, &'static str> {
+        let slot = match self.slots.iter().position(|slot| slot.block.is_none()) {
+            Some(free) => free,
+            None => (0..CACHE_BLOCKS).min_by_key(|&idx| self.slots[idx].last_used).unwrap(),
+        };
+        if let Slot { block: Some(old), dirty: true, .. } = self.slots[slot] {
+            device.write_blocks(old, self.data(slot))?;
+            self.stats.write_backs += 1;
+        }
+        self.slots[slot] = Slot { block: Some(block), dirty: false, last_used: 0 };
+        self.touch(slot);
+        Ok(slot)
+    }
+
+    /// Read blocks `start..start + count` from the disk into the cache, which has none of them,
+    /// and copy the ones before `wanted` into `buf`
+    fn fill(
+        &mut self,
+        device: &dyn BlockDevice,
+        start: u64,
+        count: usize,
+        wanted: usize,
+        buf: &mut [u8],
+    ) -> Result<(), &'static str> {
+        device.read_blocks(start, self.scratch(count))?;
+        for idx in 0..count {
+            let slot = self.claim(device, start + idx as u64)?;
+            // Safety: As for `data`. The scratch page is never a slot's.
+            let src = unsafe { bytes(self.scratch + idx * BLOCK_SIZE, BLOCK_SIZE) };
+            self.data(slot).copy_from_slice(src);
+            if idx < wanted {
+                buf[idx * BLOCK_SIZE..][..BLOCK_SIZE].copy_from_slice(src);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write every dirty block back, in order, joining neighbours
+    fn sync(&mut self, device: &dyn BlockDevice) -> Result<(), &'static str> {
+        let mut dirty: ArrayVec<(u64, usize), CACHE_BLOCKS> = (0..CACHE_BLOCKS)
+            .filter_map(|idx| match self.slots[idx] {
+                Slot { block: Some(block), dirty: true, .. } => Some((block, idx)),
+                _ => None,
+            })
+            .collect();
+        dirty.sort_unstable();
+        for run in dirty.chunk_by(|a, b| a.0 + 1 == b.0) {
+            for part in run.chunks(MAX_RUN) {
+                for (n, &(_, slot)) in part.iter().enumerate() {
+                    // Safety: As for `data`. The scratch page is never a slot's.
+                    let dst = unsafe { bytes(self.scratch + n * BLOCK_SIZE, BLOCK_SIZE) };
+                    dst.copy_from_slice(self.data(slot));
+                }
+                let len = part.len();
+                device.write_blocks(part[0].0, self.scratch(len))?;
+                for &(_, slot) in part {
+                    self.slots[slot].dirty = false;
+                }
+                self.stats.write_backs += len as u64;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Cache {
+    device: &'static dyn BlockDevice,
+    inner: Mutex<Inner>,
+}
+
+impl Cache {
+    /// A cache in front of `device`. Takes `CACHE_PAGES + 1` pages, which it gives back when
+    /// dropped. Dirty blocks are lost then, so `sync` first.
+    pub fn new(device: &'static dyn BlockDevice) -> Result<Self, &'static str> {
+        let mut pages = ArrayVec::<usize, { CACHE_PAGES + 1 }>::new();
+        while !pages.is_full() {
+            let Some(page) = frames::alloc() else {
+                pages.iter().for_each(|&page| frames::free(page));
+                return Err("Out of memory for the block cache");
+            };
+            pages.push(page);
+        }
+        let empty = Slot { block: None, dirty: false, last_used: 0 };
+        let inner = Inner {
+            slots: [empty; CACHE_BLOCKS],
+            pages: pages[..CACHE_PAGES].try_into().unwrap(),
+            scratch: pages[CACHE_PAGES],
+            clock: 0,
+            next_read: u64::MAX,
+            stats: Stats::default(),
+        };
+        Ok(Cache { device, inner: Mutex::new(inner) })
+    }
+
+    /// Write every dirty block to the disk
+    pub fn sync(&self) -> Result<(), &'static str> {
+        self.inner.lock().sync(self.device)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.inner.lock().stats
+    }
+
+    /// Log how well it's been doing
+    pub fn report(&self) {
+        let (stats, dirty) = {
+            let inner = self.inner.lock();
+            (inner.stats, inner.slots.iter().filter(|slot| slot.dirty).count())
+        };
+        let reads = stats.hits + stats.misses;
+        let hit_rate = if reads == 0 { 0 } else { stats.hits * 100 / reads };
+        info!(
+            "Block cache: {} hits, {} misses ({}% hit), {} read ahead, {} writes, {} written back, \
+             {} dirty",
+            stats.hits,
+            stats.misses,
+            hit_rate,
+            stats.read_ahead,
+            stats.writes,
+            stats.write_backs,
+            dirty
+        );
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        let inner = self.inner.lock();
+        inner.pages.iter().chain([&inner.scratch]).for_each(|&page| frames::free(page));
+    }
+}
+
+impl BlockDevice for Cache {
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, start, buf.len())?;
+        let count = buf.len() / BLOCK_SIZE;
+        let mut inner = self.inner.lock();
+        let sequential = start == inner.next_read;
+        inner.next_read = start + count as u64;
+
+        let mut idx = 0;
+        while idx < count {
+            let block = start + idx as u64;
+            if let Some(slot) = inner.find(block) {
+                inner.touch(slot);
+                buf[idx * BLOCK_SIZE..][..BLOCK_SIZE].copy_from_slice(inner.data(slot));
+                inner.stats.hits += 1;
+                idx += 1;
+                continue;
+            }
+            // Everything missing from here on, in one command
+            let mut wanted = 1;
+            while wanted < MAX_RUN
+                && idx + wanted < count
+                && inner.find(block + wanted as u64).is_none()
+            {
+                wanted += 1;
+            }
+            let mut run = wanted;
+            if sequential && idx + wanted == count {
+                while run < MAX_RUN
+                    && block + (run as u64) < self.block_count()
+                    && inner.find(block + run as u64).is_none()
+                {
+                    run += 1;
+                }
+            }
+            let dst = &mut buf[idx * BLOCK_SIZE..(idx + wanted) * BLOCK_SIZE];
+            inner.fill(self.device, block, run, wanted, dst)?;
+            inner.stats.misses += wanted as u64;
+            inner.stats.read_ahead += (run - wanted) as u64;
+            idx += wanted;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, start, buf.len())?;
+        let mut inner = self.inner.lock();
+        for (idx, data) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            let block = start + idx as u64;
+            let slot = match inner.find(block) {
+                Some(slot) => slot,
+                None => inner.claim(self.device, block)?,
+            };
+            inner.touch(slot);
+            inner.data(slot).copy_from_slice(data);
+            inner.slots[slot].dirty = true;
+            inner.stats.writes += 1;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.sync()?;
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::MemDisk;
+
+    const BLOCKS: usize = CACHE_BLOCKS + 64;
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    enum Command {
+        Read { start: u64, count: usize },
+        Write { start: u64, count: usize },
+    }
+
+    /// A disk that remembers the last few commands it was given
+    struct Recorder {
+        disk: MemDisk<BLOCKS>,
+        commands: Mutex<ArrayVec<Command, 16>>,
+    }
+
+    impl Recorder {
+        fn record(&self, command: Command) {
+            let mut commands = self.commands.lock();
+            if commands.is_full() {
+                commands.remove(0);
+            }
+            commands.push(command);
+        }
+    }
+
+    impl BlockDevice for Recorder {
+        fn block_count(&self) -> u64 {
+            self.disk.block_count()
+        }
+
+        fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+            let count = buf.len() / BLOCK_SIZE;
+            self.record(Command::Read { start, count });
+            self.disk.read_blocks(start, buf)
+        }
+
+        fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+            let count = buf.len() / BLOCK_SIZE;
+            self.record(Command::Write { start, count });
+            self.disk.write_blocks(start, buf)
+        }
+    }
+
+    static DISK: Recorder = Recorder {
+        disk: MemDisk::new(),
+        commands: Mutex::new(ArrayVec::new_const()),
+    };
+
+    /// A cache in front of a disk whose blocks are each filled with their number
+    fn cache() -> Cache {
+        DISK.disk.load(|blocks| {
+            for (idx, block) in blocks.iter_mut().enumerate() {
+                block.fill(idx as u8);
+            }
+        });
+        DISK.commands.lock().clear();
+        Cache::new(&DISK).unwrap()
+    }
+
+    /// What the disk was asked to do since last time
+    fn commands() -> ArrayVec<Command, 16> {
+        core::mem::take(&mut *DISK.commands.lock())
+    }
+
+    fn read(cache: &Cache, block: u64) -> u8 {
+        let mut buf = [0; BLOCK_SIZE];
+        cache.read_blocks(block, &mut buf).unwrap();
+        buf[0]
+    }
+
+    /// Fill every slot, oldest first, without the reads looking sequential
+    fn fill_backwards(cache: &Cache, blocks: core::ops::Range<u64>) {
+        for block in blocks.rev() {
+            assert_eq!(read(cache, block), block as u8);
+        }
+    }
+
+    #[test_case]
+    fn evicts_least_recently_used() {
+        let cache = cache();
+        fill_backwards(&cache, 0..CACHE_BLOCKS as u64);
+        // The last block is the oldest, until it's used again
+        let last = CACHE_BLOCKS as u64 - 1;
+        read(&cache, last);
+        commands();
+
+        read(&cache, BLOCKS as u64 - 1);
+        assert_eq!(commands().as_slice(), [Command::Read { start: BLOCKS as u64 - 1, count: 1 }]);
+        read(&cache, last);
+        read(&cache, last - 2);
+        assert!(commands().is_empty());
+        // The oldest once the last block was used again, so it was the one to go
+        read(&cache, last - 1);
+        assert_eq!(commands().as_slice(), [Command::Read { start: last - 1, count: 1 }]);
+    }
+
+    #[test_case]
+    fn writes_back_dirty_blocks_on_eviction() {
+        let cache = cache();
+        cache.write_blocks(0, &[0xAA; BLOCK_SIZE]).unwrap();
+        assert!(commands().is_empty());
+        DISK.disk.inspect(|blocks| assert_eq!(blocks[0][0], 0));
+        // Block 0 is the oldest when the last free slot is taken
+        fill_backwards(&cache, 1..CACHE_BLOCKS as u64 + 1);
+        assert!(commands().contains(&Command::Write { start: 0, count: 1 }));
+        DISK.disk.inspect(|blocks| assert_eq!(blocks[0], [0xAA; BLOCK_SIZE]));
+        assert_eq!(cache.stats().write_backs, 1);
+        // It was clean after that, so there's nothing left to write
+        cache.sync().unwrap();
+        assert!(commands().is_empty());
+    }
+
+    #[test_case]
+    fn sync_joins_neighbours() {
+        let cache = cache();
+        for block in [7, 5, 9, 6] {
+            cache.write_blocks(block, &[0xEE; BLOCK_SIZE]).unwrap();
+        }
+        cache.sync().unwrap();
+        assert_eq!(
+            commands().as_slice(),
+            [Command::Write { start: 5, count: 3 }, Command::Write { start: 9, count: 1 }]
+        );
+        DISK.disk.inspect(|blocks| {
+            assert!([5, 6, 7, 9].iter().all(|&idx| blocks[idx] == [0xEE; BLOCK_SIZE]));
+            assert_eq!(blocks[8][0], 8);
+        });
+        cache.sync().unwrap();
+        assert!(commands().is_empty());
+    }
+
+    #[test_case]
+    fn reads_ahead_only_when_sequential() {
+        let cache = cache();
+        read(&cache, 10);
+        read(&cache, 11);
+        read(&cache, 40);
+        assert_eq!(
+            commands().as_slice(),
+            [
+                Command::Read { start: 10, count: 1 },
+                Command::Read { start: 11, count: MAX_RUN },
+                Command::Read { start: 40, count: 1 },
+            ]
+        );
+        // Already fetched
+        assert_eq!(read(&cache, 12), 12);
+        assert!(commands().is_empty());
+        assert_eq!(cache.stats().read_ahead, MAX_RUN as u64 - 1);
+
+        // Never past the end of the disk
+        read(&cache, BLOCKS as u64 - 3);
+        read(&cache, BLOCKS as u64 - 2);
+        assert_eq!(commands().last(), Some(&Command::Read { start: BLOCKS as u64 - 2, count: 2 }));
+    }
+
+    #[test_case]
+    fn counts() {
+        let cache = cache();
+        let mut buf = [0; 3 * BLOCK_SIZE];
+        cache.read_blocks(20, &mut buf).unwrap();
+        assert_eq!(buf[2 * BLOCK_SIZE], 22);
+        cache.read_blocks(21, &mut buf[..BLOCK_SIZE]).unwrap();
+        cache.write_blocks(21, &buf[..2 * BLOCK_SIZE]).unwrap();
+        cache.sync().unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.read_ahead), (1, 3, 0));
+        assert_eq!((stats.writes, stats.write_backs), (2, 2));
+    }
+}
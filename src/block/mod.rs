@@ -0,0 +1,98 @@
+//! Storage that's read and written in fixed-size blocks, like an SD card.
+//!
+//! Drivers implement `BlockDevice`, and filesystems sit on top of one without caring which.
+//! `partition` splits a disk into the partitions in its table, each a `BlockDevice` too. The SD
+//! card is used through a `cache::Cache`, so filesystems can read a sector at a time without each
+//! one being a command to the card.
+use log::{info, warn};
+use spin::Once;
+
+pub mod cache;
+pub mod partition;
+pub mod ramdisk;
+
+pub use partition::Partition;
+
+/// Size of a block, in bytes. Every SD card uses 512 byte blocks, and so do partition tables.
+pub const BLOCK_SIZE: usize = 512;
+
+pub trait BlockDevice: Sync {
+    /// How many blocks there are
+    fn block_count(&self) -> u64;
+
+    /// Read the blocks starting at `start` into `buf`, which must be a whole number of blocks
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Write `buf`, which must be a whole number of blocks, to the blocks starting at `start`
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), &'static str>;
+
+    /// Make sure everything written so far is stored
+    fn flush(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// Check that `len` bytes starting at block `start` are whole blocks on `device`
+pub fn check_range(device: &dyn BlockDevice, start: u64, len: usize) -> Result<(), &'static str> {
+    if len % BLOCK_SIZE != 0 {
+        return Err("Not a whole number of blocks");
+    }
+    let end = start.checked_add((len / BLOCK_SIZE) as u64).ok_or("Block is out of range")?;
+    if end > device.block_count() {
+        return Err("Block is out of range");
+    }
+    Ok(())
+}
+
+static CACHE: Once<cache::Cache> = Once::new();
+static PARTITIONS: Once<arrayvec::ArrayVec<Partition, { partition::MAX_PARTITIONS }>> = Once::new();
+
+/// The cache in front of the SD card. Made the first time it's asked for.
+pub fn cache() -> Option<&'static cache::Cache> {
+    let card = crate::emmc::card()?;
+    match CACHE.try_call_once(|| cache::Cache::new(card)) {
+        Ok(cache) => Some(cache),
+        Err(err) => {
+            warn!("No block cache: {}", err);
+            None
+        }
+    }
+}
+
+/// The disk filesystems live on: the SD card, or failing that the image from `ramdisk=`
+pub fn disk() -> Option<&'static dyn BlockDevice> {
+    if let Some(cache) = cache() {
+        return Some(cache);
+    }
+    match crate::emmc::card() {
+        Some(card) => Some(card),
+        None => Some(ramdisk::get()?),
+    }
+}
+
+/// The partitions on `disk()`. Read the first time it's called.
+pub fn partitions() -> &'static [Partition] {
+    PARTITIONS.call_once(|| {
+        let Some(disk) = disk() else {
+            return arrayvec::ArrayVec::new();
+        };
+        match partition::read(disk) {
+            Ok(partitions) => {
+                for (idx, part) in partitions.iter().enumerate() {
+                    info!(
+                        "Partition {}: {} blocks at {}, {:?}",
+                        idx,
+                        part.block_count(),
+                        part.start(),
+                        part.kind()
+                    );
+                }
+                partitions
+            }
+            Err(err) => {
+                warn!("Could not read the partition table: {}", err);
+                arrayvec::ArrayVec::new()
+            }
+        }
+    })
+}
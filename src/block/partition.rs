@@ -0,0 +1,366 @@
+//! Partition tables: MBR, and GPT behind a protective MBR.
+//!
+//! A disk with a FAT boot sector where the MBR would be has no table, and is treated as one
+//! partition covering all of it. Extended MBR partitions and the GPT checksums aren't looked at.
+use super::{check_range, BlockDevice, BLOCK_SIZE};
+use arrayvec::ArrayVec;
+
+/// Most partitions we keep per disk
+pub const MAX_PARTITIONS: usize = 16;
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// Marks a disk that really uses GPT
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_FAT32_CHS: u8 = 0x0B;
+const MBR_FAT32_LBA: u8 = 0x0C;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Type GUIDs as they're stored, with the first three fields little endian
+const GPT_BASIC_DATA: [u8; 16] = [
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+];
+const GPT_EFI_SYSTEM: [u8; 16] = [
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PartitionType {
+    /// The system ID byte
+    Mbr(u8),
+    /// The type GUID, as stored
+    Gpt([u8; 16]),
+    /// There's no partition table
+    Whole,
+}
+
+impl PartitionType {
+    /// Whether it's meant to hold a FAT filesystem
+    pub fn is_fat(&self) -> bool {
+        match *self {
+            PartitionType::Mbr(id) => id == MBR_FAT32_CHS || id == MBR_FAT32_LBA,
+            PartitionType::Gpt(guid) => guid == GPT_BASIC_DATA || guid == GPT_EFI_SYSTEM,
+            PartitionType::Whole => true,
+        }
+    }
+}
+
+/// Part of a disk, as a block device of its own
+#[derive(Clone, Copy)]
+pub struct Partition {
+    device: &'static dyn BlockDevice,
+    /// First block on the disk
+    start: u64,
+    blocks: u64,
+    kind: PartitionType,
+}
+
+impl Partition {
+    pub fn kind(&self) -> PartitionType {
+        self.kind
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, start, buf.len())?;
+        self.device.read_blocks(self.start + start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, start, buf.len())?;
+        self.device.write_blocks(self.start + start, buf)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.device.flush()
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Whether `sector` is a FAT boot sector, rather than an MBR
+fn is_fat_boot_sector(sector: &[u8; BLOCK_SIZE]) -> bool {
+    // The jump over the BPB, and a sector size that makes sense
+    matches!(sector[0], 0xEB | 0xE9)
+        && u16::from_le_bytes([sector[11], sector[12]]) as usize == BLOCK_SIZE
+        && (&sector[82..90] == b"FAT32   " || sector[54..57] == *b"FAT")
+}
+
+fn add(
+    partitions: &mut ArrayVec<Partition, MAX_PARTITIONS>,
+    partition: Partition,
+) -> Result<(), &'static str> {
+    let end = partition.start.checked_add(partition.blocks).ok_or("Partition is out of range")?;
+    if partition.blocks == 0 || end > partition.device.block_count() {
+        return Err("Partition is out of range");
+    }
+    partitions.try_push(partition).map_err(|_| "Too many partitions")
+}
+
+fn read_gpt(
+    device: &'static dyn BlockDevice,
+    partitions: &mut ArrayVec<Partition, MAX_PARTITIONS>,
+) -> Result<(), &'static str> {
+    let mut header = [0; BLOCK_SIZE];
+    device.read_blocks(1, &mut header)?;
+    if !header.starts_with(GPT_SIGNATURE) {
+        return Err("Protective MBR without a GPT header");
+    }
+    let entries_start = u64_at(&header, 72);
+    let count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if entry_size < 128 || entry_size > BLOCK_SIZE || BLOCK_SIZE % entry_size != 0 {
+        return Err("GPT entries are an odd size");
+    }
+    let per_block = BLOCK_SIZE / entry_size;
+    let mut block = [0; BLOCK_SIZE];
+    for idx in 0..count {
+        if idx % per_block == 0 {
+            let lba = entries_start.checked_add((idx / per_block) as u64);
+            device.read_blocks(lba.ok_or("GPT entries are out of range")?, &mut block)?;
+        }
+        let entry = &block[idx % per_block * entry_size..][..entry_size];
+        let guid: [u8; 16] = entry[..16].try_into().unwrap();
+        if guid == [0; 16] {
+            continue;
+        }
+        let first = u64_at(entry, 32);
+        let last = u64_at(entry, 40);
+        let blocks = last.checked_sub(first).ok_or("GPT partition ends before it starts")?;
+        let blocks = blocks.checked_add(1).ok_or("Partition is out of range")?;
+        let kind = PartitionType::Gpt(guid);
+        add(partitions, Partition { device, start: first, blocks, kind })?;
+    }
+    Ok(())
+}
+
+/// Read the partition table on `device`
+pub fn read(
+    device: &'static dyn BlockDevice,
+) -> Result<ArrayVec<Partition, MAX_PARTITIONS>, &'static str> {
+    let mut partitions = ArrayVec::new();
+    let mut mbr = [0; BLOCK_SIZE];
+    device.read_blocks(0, &mut mbr)?;
+    if mbr[510..] != BOOT_SIGNATURE {
+        return Err("No partition table");
+    }
+    if is_fat_boot_sector(&mbr) {
+        let blocks = device.block_count();
+        add(&mut partitions, Partition { device, start: 0, blocks, kind: PartitionType::Whole })?;
+        return Ok(partitions);
+    }
+
+    for entry in mbr[MBR_TABLE..MBR_TABLE + 4 * MBR_ENTRY_SIZE].chunks_exact(MBR_ENTRY_SIZE) {
+        let id = entry[4];
+        if id == 0 {
+            continue;
+        }
+        if id == MBR_PROTECTIVE {
+            read_gpt(device, &mut partitions)?;
+            return Ok(partitions);
+        }
+        let start = u32_at(entry, 8) as u64;
+        let blocks = u32_at(entry, 12) as u64;
+        add(&mut partitions, Partition { device, start, blocks, kind: PartitionType::Mbr(id) })?;
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::MemDisk;
+
+    const BLOCKS: usize = 64;
+    static DISK: MemDisk<BLOCKS> = MemDisk::new();
+
+    fn mbr_entry(mbr: &mut [u8; BLOCK_SIZE], idx: usize, id: u8, start: u32, blocks: u32) {
+        let entry = &mut mbr[MBR_TABLE + idx * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        entry[4] = id;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    }
+
+    /// A protective MBR, and a GPT header saying its entries start at block 2
+    fn gpt(blocks: &mut [[u8; BLOCK_SIZE]; BLOCKS], count: u32, entry_size: u32) {
+        blocks[0][510..].copy_from_slice(&BOOT_SIGNATURE);
+        mbr_entry(&mut blocks[0], 0, MBR_PROTECTIVE, 1, BLOCKS as u32 - 1);
+        let header = &mut blocks[1];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&count.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+    }
+
+    /// Fill in 128 byte GPT entry `idx`
+    fn gpt_entry(
+        blocks: &mut [[u8; BLOCK_SIZE]; BLOCKS],
+        idx: usize,
+        guid: [u8; 16],
+        first: u64,
+        last: u64,
+    ) {
+        let entry = &mut blocks[2 + idx / 4][idx % 4 * 128..][..128];
+        entry[..16].copy_from_slice(&guid);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+    }
+
+    #[test_case]
+    fn reads_mbr() {
+        DISK.load(|blocks| {
+            blocks[0][510..].copy_from_slice(&BOOT_SIGNATURE);
+            mbr_entry(&mut blocks[0], 0, MBR_FAT32_LBA, 4, 20);
+            // Linux, and an empty entry between
+            mbr_entry(&mut blocks[0], 2, 0x83, 24, 40);
+        });
+        let partitions = read(&DISK).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!((partitions[0].start(), partitions[0].block_count()), (4, 20));
+        assert!(partitions[0].kind().is_fat());
+        assert_eq!(partitions[1].kind(), PartitionType::Mbr(0x83));
+        assert!(!partitions[1].kind().is_fat());
+    }
+
+    #[test_case]
+    fn partitions_are_offset() {
+        DISK.load(|blocks| {
+            blocks[0][510..].copy_from_slice(&BOOT_SIGNATURE);
+            mbr_entry(&mut blocks[0], 0, MBR_FAT32_LBA, 4, 20);
+            blocks[5][0] = 0xAB;
+        });
+        let partitions = read(&DISK).unwrap();
+        let mut block = [0; BLOCK_SIZE];
+        partitions[0].read_blocks(1, &mut block).unwrap();
+        assert_eq!(block[0], 0xAB);
+        assert!(partitions[0].read_blocks(20, &mut block).is_err());
+    }
+
+    #[test_case]
+    fn whole_disk_fat() {
+        DISK.load(|blocks| {
+            let boot = &mut blocks[0];
+            boot[0] = 0xEB;
+            boot[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+            boot[82..90].copy_from_slice(b"FAT32   ");
+            boot[510..].copy_from_slice(&BOOT_SIGNATURE);
+        });
+        let partitions = read(&DISK).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].kind(), PartitionType::Whole);
+        assert_eq!(partitions[0].block_count(), BLOCKS as u64);
+    }
+
+    #[test_case]
+    fn rejects_bad_mbr() {
+        DISK.load(|_| {});
+        assert_eq!(read(&DISK).err(), Some("No partition table"));
+
+        DISK.load(|blocks| {
+            blocks[0][510..].copy_from_slice(&BOOT_SIGNATURE);
+            mbr_entry(&mut blocks[0], 0, MBR_FAT32_LBA, 60, 8);
+        });
+        assert_eq!(read(&DISK).err(), Some("Partition is out of range"));
+
+        DISK.load(|blocks| {
+            blocks[0][510..].copy_from_slice(&BOOT_SIGNATURE);
+            mbr_entry(&mut blocks[0], 0, MBR_PROTECTIVE, 1, 63);
+        });
+        assert_eq!(read(&DISK).err(), Some("Protective MBR without a GPT header"));
+    }
+
+    #[test_case]
+    fn reads_gpt() {
+        DISK.load(|blocks| {
+            gpt(blocks, 8, 128);
+            gpt_entry(blocks, 0, GPT_EFI_SYSTEM, 10, 29);
+            // Unused entries are skipped
+            gpt_entry(blocks, 5, [7; 16], 30, 63);
+        });
+        let partitions = read(&DISK).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!((partitions[0].start(), partitions[0].block_count()), (10, 20));
+        assert!(partitions[0].kind().is_fat());
+        assert_eq!(partitions[1].kind(), PartitionType::Gpt([7; 16]));
+        assert_eq!(partitions[1].block_count(), 34);
+    }
+
+    #[test_case]
+    fn gpt_entry_sizes() {
+        for size in [0, 96, 127, 384, 1024, u32::MAX] {
+            DISK.load(|blocks| gpt(blocks, 1, size));
+            assert_eq!(read(&DISK).err(), Some("GPT entries are an odd size"));
+        }
+        // One entry per block is fine
+        DISK.load(|blocks| {
+            gpt(blocks, 2, 512);
+            blocks[3][..16].copy_from_slice(&GPT_BASIC_DATA);
+            blocks[3][32..40].copy_from_slice(&40u64.to_le_bytes());
+            blocks[3][40..48].copy_from_slice(&41u64.to_le_bytes());
+        });
+        let partitions = read(&DISK).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].start(), 40);
+    }
+
+    #[test_case]
+    fn gpt_counts() {
+        DISK.load(|blocks| gpt(blocks, 0, 128));
+        assert_eq!(read(&DISK).map(|partitions| partitions.len()), Ok(0));
+
+        // Runs off the end of the disk looking for entries, rather than forever
+        DISK.load(|blocks| gpt(blocks, u32::MAX, 128));
+        assert_eq!(read(&DISK).err(), Some("Block is out of range"));
+
+        DISK.load(|blocks| {
+            gpt(blocks, MAX_PARTITIONS as u32 + 1, 128);
+            for idx in 0..=MAX_PARTITIONS {
+                gpt_entry(blocks, idx, [1; 16], 30 + idx as u64, 30 + idx as u64);
+            }
+        });
+        assert_eq!(read(&DISK).err(), Some("Too many partitions"));
+    }
+
+    #[test_case]
+    fn rejects_bad_gpt_entries() {
+        DISK.load(|blocks| {
+            gpt(blocks, 1, 128);
+            gpt_entry(blocks, 0, [1; 16], 20, 19);
+        });
+        assert_eq!(read(&DISK).err(), Some("GPT partition ends before it starts"));
+
+        DISK.load(|blocks| {
+            gpt(blocks, 1, 128);
+            gpt_entry(blocks, 0, [1; 16], 20, u64::MAX);
+        });
+        assert_eq!(read(&DISK).err(), Some("Partition is out of range"));
+
+        DISK.load(|blocks| {
+            gpt(blocks, 8, 128);
+            blocks[1][72..80].copy_from_slice(&u64::MAX.to_le_bytes());
+        });
+        assert_eq!(read(&DISK).err(), Some("Block is out of range"));
+
+        // One more block than a u64 can count
+        DISK.load(|blocks| {
+            gpt(blocks, 1, 128);
+            gpt_entry(blocks, 0, [1; 16], 0, u64::MAX);
+        });
+        assert_eq!(read(&DISK).err(), Some("Partition is out of range"));
+    }
+}
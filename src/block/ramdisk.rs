@@ -0,0 +1,138 @@
+//! A disk image in RAM, put there by whatever loaded the kernel.
+//!
+//! For QEMU, `-device loader,file=disk.img,addr=0x20000000,force-raw=on` with
+//! `-append ramdisk=0x20000000,<size of disk.img>` makes the image a block device, so
+//! filesystems can be tried without an SD card. Writes change the copy in RAM only.
+use super::{check_range, BlockDevice, BLOCK_SIZE};
+use core::ops::Range;
+use log::info;
+use spin::Once;
+
+pub struct RamDisk {
+    range: Range<usize>,
+}
+
+impl BlockDevice for RamDisk {
+    fn block_count(&self) -> u64 {
+        (self.range.len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, start, buf.len())?;
+        let addr = self.range.start + start as usize * BLOCK_SIZE;
+        // Safety: In range, and the memory was reserved for us in `init`
+        unsafe { core::ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, start, buf.len())?;
+        let addr = self.range.start + start as usize * BLOCK_SIZE;
+        // Safety: In range, and the memory was reserved for us in `init`
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), addr as *mut u8, buf.len()) };
+        Ok(())
+    }
+}
+
+/// From the command line, until `init` checks it
+static REQUESTED: Once<Range<usize>> = Once::new();
+static RAMDISK: Once<RamDisk> = Once::new();
+
+crate::cmdline::early_param!("ramdisk", set_ramdisk);
+
+fn set_ramdisk(value: &'static str) -> Result<(), &'static str> {
+    let (addr, size) = value.split_once(',').ok_or("Expected an address and a size")?;
+    let addr = crate::cmdline::parse_number(addr)?;
+    let size = crate::cmdline::parse_number(size)?;
+    if addr % BLOCK_SIZE != 0 || size < BLOCK_SIZE {
+        return Err("Must start on a block boundary, and hold at least one block");
+    }
+    let end = addr.checked_add(size).ok_or("Ends past the end of memory")?;
+    REQUESTED.call_once(|| addr..end);
+    Ok(())
+}
+
+/// Keep the image from `ramdisk=` out of the page allocator. Must run before anything allocates
+/// pages.
+pub fn init() -> Result<(), &'static str> {
+    let Some(range) = REQUESTED.get().cloned() else {
+        return Ok(());
+    };
+    if range.end > crate::mmu::frames::RAM_END {
+        return Err("ramdisk is outside of RAM");
+    }
+    crate::mmu::frames::reserve(range.clone());
+    info!("ramdisk: {} bytes at {:#x}", range.len(), range.start);
+    RAMDISK.call_once(|| RamDisk { range });
+    Ok(())
+}
+
+/// The image from `ramdisk=`, if there is one
+pub fn get() -> Option<&'static RamDisk> {
+    RAMDISK.get()
+}
+
+/// A disk in a buffer of its own, for tests. `load` gives it a fresh image.
+#[cfg(test)]
+pub struct MemDisk<const BLOCKS: usize> {
+    blocks: crate::sync::Mutex<[[u8; BLOCK_SIZE]; BLOCKS]>,
+}
+
+#[cfg(test)]
+impl<const BLOCKS: usize> MemDisk<BLOCKS> {
+    pub const fn new() -> Self {
+        MemDisk { blocks: crate::sync::Mutex::new([[0; BLOCK_SIZE]; BLOCKS]) }
+    }
+
+    /// Zero the disk, then let `f` write an image onto it
+    pub fn load(&self, f: impl FnOnce(&mut [[u8; BLOCK_SIZE]; BLOCKS])) {
+        let mut blocks = self.blocks.lock();
+        blocks.iter_mut().for_each(|block| block.fill(0));
+        f(&mut blocks);
+    }
+
+    /// Look at what's on the disk
+    pub fn inspect<R>(&self, f: impl FnOnce(&[[u8; BLOCK_SIZE]; BLOCKS]) -> R) -> R {
+        f(&self.blocks.lock())
+    }
+}
+
+#[cfg(test)]
+impl<const BLOCKS: usize> BlockDevice for MemDisk<BLOCKS> {
+    fn block_count(&self) -> u64 {
+        BLOCKS as u64
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, start, buf.len())?;
+        let blocks = self.blocks.lock();
+        for (idx, chunk) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            chunk.copy_from_slice(&blocks[start as usize + idx]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, start, buf.len())?;
+        let mut blocks = self.blocks.lock();
+        for (idx, chunk) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            blocks[start as usize + idx].copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Only ones that fail, since a good one would be kept for `init`
+    #[test_case]
+    fn rejects_bad_options() {
+        assert_eq!(set_ramdisk("0x20000000"), Err("Expected an address and a size"));
+        assert_eq!(set_ramdisk("0x20000000,lots"), Err("Expected a number"));
+        assert!(set_ramdisk("0x20000100,0x1000").is_err());
+        assert!(set_ramdisk("0x20000000,0x100").is_err());
+        assert_eq!(set_ramdisk("0xfffffffffffffe00,0x400"), Err("Ends past the end of memory"));
+    }
+}
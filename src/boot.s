@@ -28,6 +28,11 @@ bss_clear_loop:
   b bss_clear_loop
 bss_clear_done:
 
+  // The firmware leaves the address of the device tree (or ATAGs) in x0. Keep it, now that
+  // clearing the BSS can't wipe it out.
+  ldr x6, =BOOT_ARG
+  str x0, [x6]
+
   // Set up the stack below our code (it grows downwards).
   // This should be plenty big enough: only the first 4KB of memory are used.
   ldr x6, =_start
@@ -38,3 +43,15 @@ bss_clear_done:
 .globl _start
 .type _start, function
 .size _start, . - _start
+
+// Cores 1-3 start here once `smp::start_secondary_cores` writes this address into their
+// spin table slot. Their stack comes from `SECONDARY_STACK_TOP`.
+_start_secondary:
+  ldr x6, =SECONDARY_STACK_TOP
+  ldr x6, [x6]
+  mov sp, x6
+  b __start_secondary_kernel
+
+.globl _start_secondary
+.type _start_secondary, function
+.size _start_secondary, . - _start_secondary
@@ -0,0 +1,249 @@
+//! The kernel command line.
+//!
+//! It comes from the device tree's `/chosen/bootargs` (QEMU's `-append`, or `cmdline.txt` on a
+//! real Pi), an `ATAG_CMDLINE`, or failing both, the firmware's mailbox. It's a list of
+//! `name=value` options and bare `name` flags separated by spaces. Values with spaces in them
+//! can be quoted: `init="/bin/sh -l"`.
+//!
+//! Modules that care about an option register a handler for it with `early_param!`, which puts
+//! it in the `.early_params` section. `parse_early` runs each handler once, for the last time its
+//! option appears. Nothing is printed until `report`, since options like `console=` decide where
+//! printing goes.
+//!
+//! | Option              | Effect                                             | Handled by |
+//! |---------------------|----------------------------------------------------|------------|
+//! | `console=uart,fb`   | Only show output on these sinks                    | `console`  |
+//! | `loglevel=info,...` | Replace the log filter. Same format as `KERNEL_LOG` | `logger`   |
+//! | `init=/bin/init`    | Program to run first                               | `process`  |
+//! | `test=name`         | Only run tests whose names contain `name`          | here       |
+//! | `mmu=off`           | Leave the MMU off, for debugging                   | `mmu`      |
+//! | `ramdisk=addr,size` | Use a disk image loaded into RAM as a block device | `block`    |
+//! | `font=8x13`         | Console font: a built in size, or a PSF2 file      | `framebuffer` |
+use arrayvec::{ArrayString, ArrayVec};
+use log::{info, warn};
+use spin::Once;
+
+/// Longest command line we keep
+pub const MAX_LEN: usize = 1024;
+const MAX_PROBLEMS: usize = 8;
+
+/// An option and what to do with its value. Made with `early_param!`.
+#[repr(C)]
+pub struct EarlyParam {
+    pub name: &'static str,
+    /// Gets the value, or `""` for a bare flag
+    pub handler: fn(&'static str) -> Result<(), &'static str>,
+}
+
+/// Handle option `$name` with `$handler`, a `fn(&'static str) -> Result<(), &'static str>`
+/// that gets its value
+macro_rules! early_param {
+    ($name:literal, $handler:expr) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".early_params"]
+            static PARAM: $crate::cmdline::EarlyParam = $crate::cmdline::EarlyParam {
+                name: $name,
+                handler: $handler,
+            };
+        };
+    };
+}
+pub(crate) use early_param;
+
+extern "C" {
+    static __early_params_start: EarlyParam;
+    static __early_params_end: EarlyParam;
+}
+
+fn early_params() -> &'static [EarlyParam] {
+    // Safety: The linker script puts every `EarlyParam` between the two symbols, and nothing else
+    unsafe {
+        let start = core::ptr::addr_of!(__early_params_start);
+        let len = (core::ptr::addr_of!(__early_params_end) as usize - start as usize)
+            / core::mem::size_of::<EarlyParam>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+static CMDLINE: Once<&'static str> = Once::new();
+/// Where a command line from the mailbox is kept
+static MAILBOX_CMDLINE: Once<ArrayString<MAX_LEN>> = Once::new();
+/// Options that were rejected, and why. Kept for `report`.
+static PROBLEMS: Once<ArrayVec<(&'static str, &'static str), MAX_PROBLEMS>> = Once::new();
+static TEST_FILTER: Once<&'static str> = Once::new();
+
+early_param!("test", set_test_filter);
+
+fn set_test_filter(value: &'static str) -> Result<(), &'static str> {
+    TEST_FILTER.call_once(|| value);
+    Ok(())
+}
+
+/// Only tests whose names contain this should run
+pub fn test_filter() -> Option<&'static str> {
+    TEST_FILTER.get().copied()
+}
+
+/// Ask the firmware. Needs `mailbox::init`.
+fn from_mailbox() -> Option<&'static str> {
+    let cmdline = crate::mailbox::command_line()?;
+    Some(MAILBOX_CMDLINE.call_once(|| cmdline).as_str())
+}
+
+/// Find the command line. `boot_arg` is what the firmware left in x0. Needs `fdt::init` and
+/// `mailbox::init`.
+pub fn init(boot_arg: usize) {
+    let addr = if boot_arg == 0 { crate::atags::DEFAULT_ADDR } else { boot_arg };
+    let cmdline = crate::fdt::bootargs()
+        // Safety: Whatever the firmware points x0 at is in RAM, and nothing has been put over it
+        .or_else(|| unsafe { crate::atags::Atags::from_addr(addr) }?.cmdline())
+        .or_else(from_mailbox)
+        .unwrap_or("");
+    CMDLINE.call_once(|| cmdline);
+}
+
+/// The whole command line
+pub fn get() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// Every option, as its name and value. Flags have an empty value.
+pub fn options() -> impl Iterator<Item = (&'static str, &'static str)> {
+    split(get())
+}
+
+/// The options in `cmdline`
+fn split(cmdline: &'static str) -> impl Iterator<Item = (&'static str, &'static str)> {
+    let mut rest = cmdline;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        // Up to the first space that isn't in quotes
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map_or(rest.len(), |(idx, _)| idx);
+        let option = &rest[..end];
+        rest = &rest[end..];
+        Some(match option.split_once('=') {
+            Some((name, value)) => (name, value.trim_matches('"')),
+            None => (option, ""),
+        })
+    })
+}
+
+/// The value of the last `name` option
+pub fn value(name: &str) -> Option<&'static str> {
+    last_value(get(), name)
+}
+
+/// The value of the last `name` option in `cmdline`
+fn last_value(cmdline: &'static str, name: &str) -> Option<&'static str> {
+    split(cmdline).filter(|&(option, _)| option == name).last().map(|(_, value)| value)
+}
+
+/// Whether there's a `name` option, with any value
+pub fn has(name: &str) -> bool {
+    options().any(|(option, _)| option == name)
+}
+
+/// Read a flag's value as on or off. A bare flag is on.
+pub fn parse_bool(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "" | "1" | "on" | "yes" | "true" | "y" => Ok(true),
+        "0" | "off" | "no" | "false" | "n" => Ok(false),
+        _ => Err("Expected on or off"),
+    }
+}
+
+/// Read a number, in hex if it starts with `0x`
+pub fn parse_number(value: &str) -> Result<usize, &'static str> {
+    let res = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    res.map_err(|_| "Expected a number")
+}
+
+/// Run every registered handler whose option is on the command line
+pub fn parse_early() {
+    let mut problems = ArrayVec::new();
+    for param in early_params() {
+        let Some(value) = value(param.name) else {
+            continue;
+        };
+        if let Err(err) = (param.handler)(value) {
+            let _ = problems.try_push((param.name, err));
+        }
+    }
+    PROBLEMS.call_once(|| problems);
+}
+
+/// Print the command line and anything wrong with it
+pub fn report() {
+    info!("Command line: {}", get());
+    for (name, err) in PROBLEMS.get().into_iter().flatten() {
+        warn!("Bad option {}: {}", name, err);
+    }
+    for (name, _) in options() {
+        if !early_params().iter().any(|param| param.name == name) {
+            warn!("Unknown option {}", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options_in(cmdline: &'static str) -> ArrayVec<(&'static str, &'static str), 8> {
+        split(cmdline).collect()
+    }
+
+    #[test_case]
+    fn splits_options() {
+        assert_eq!(
+            options_in("  console=uart,fb quiet   ramdisk=0x1000,512 ").as_slice(),
+            [("console", "uart,fb"), ("quiet", ""), ("ramdisk", "0x1000,512")]
+        );
+        // Spaces in quotes don't end the option, and the quotes come off
+        assert_eq!(
+            options_in(r#"init="/bin/sh -l" test=fat32"#).as_slice(),
+            [("init", "/bin/sh -l"), ("test", "fat32")]
+        );
+        // Only the first `=` splits
+        assert_eq!(options_in("a=b=c").as_slice(), [("a", "b=c")]);
+        assert!(options_in("   ").is_empty());
+    }
+
+    #[test_case]
+    fn last_value_wins() {
+        let cmdline = "console=uart quiet console=fb";
+        assert_eq!(last_value(cmdline, "console"), Some("fb"));
+        assert_eq!(last_value(cmdline, "quiet"), Some(""));
+        assert_eq!(last_value(cmdline, "cons"), None);
+    }
+
+    #[test_case]
+    fn parses_values() {
+        assert_eq!(parse_number("42"), Ok(42));
+        assert_eq!(parse_number("0x1F"), Ok(31));
+        assert_eq!(parse_number("0X10"), Ok(16));
+        for bad in ["", "0x", "12k", "-1", "0xg"] {
+            assert_eq!(parse_number(bad), Err("Expected a number"));
+        }
+
+        assert_eq!(parse_bool(""), Ok(true));
+        assert_eq!(parse_bool("off"), Ok(false));
+        assert!(parse_bool("maybe").is_err());
+    }
+}
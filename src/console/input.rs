@@ -0,0 +1,44 @@
+//! Keys typed at the serial console, on their way to whoever reads `/dev/console`.
+//!
+//! The kernel's main loop is the only thing that reads the UART. Keys it doesn't use itself
+//! (debug commands, scrolling) are queued here, so a process reading the console never races it
+//! for them.
+use crate::sync::{Channel, Mutex, Receiver};
+use spin::Once;
+
+const INPUT_SIZE: usize = 256;
+
+static INPUT: Channel<char, INPUT_SIZE> = Channel::new();
+/// Readers take turns, so a line isn't split between them
+static RECEIVER: Once<Mutex<Receiver<'static, char, INPUT_SIZE>>> = Once::new();
+
+fn receiver() -> &'static Mutex<Receiver<'static, char, INPUT_SIZE>> {
+    RECEIVER.call_once(|| Mutex::new(INPUT.receiver().expect("Console input receiver taken")))
+}
+
+/// Queue `c` for readers. Dropped if nobody has read the last `INPUT_SIZE` keys.
+pub fn push(c: char) {
+    let _ = INPUT.sender().try_send(c);
+}
+
+/// Wait for at least one key, then read up to the end of the line or of `buf`, whichever comes
+/// first. Enter comes out as `\n`. Returns how many bytes were read.
+pub fn read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    let mut receiver = receiver().lock();
+    let mut next = Some(receiver.recv());
+    let mut len = 0;
+    while let Some(c) = next {
+        // Terminals send a carriage return for enter
+        buf[len] = if c == '\r' { b'\n' } else { c as u8 };
+        len += 1;
+        if buf[len - 1] == b'\n' || len == buf.len() {
+            break;
+        }
+        // Return what's there rather than waiting for a full buffer
+        next = receiver.try_recv();
+    }
+    len
+}
@@ -0,0 +1,208 @@
+//! Fans kernel output out to every device that can show it.
+//!
+//! Sinks are registered once and never removed. Writing never waits on a sink for long: one
+//! that isn't initialized yet, or stays locked (say, by the code we interrupted), is skipped so
+//! printing from an exception handler can't deadlock.
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{Level, LevelFilter};
+use spin::Once;
+
+pub mod input;
+pub mod ring;
+
+const MAX_SINKS: usize = 8;
+/// How many times a busy sink is retried before its output is dropped
+const LOCK_ATTEMPTS: usize = 1000;
+
+/// Something console output can be written to
+pub trait Sink: Sync {
+    fn name(&self) -> &'static str;
+    fn try_write(&self, args: fmt::Arguments) -> Result<(), SinkError>;
+
+    /// Break whatever lock guards the device, for when its holder will never let go.
+    ///
+    /// # Safety
+    ///
+    /// The holder must never touch the device again
+    unsafe fn force_unlock(&self) {}
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SinkError {
+    /// The device hasn't been set up yet
+    Uninitialized,
+    /// Someone else is using the device
+    Locked,
+    /// The device rejected the write
+    Failed,
+}
+
+struct Registration {
+    sink: &'static dyn Sink,
+    /// Most verbose `LevelFilter` this sink shows, as a `usize`
+    max_level: AtomicUsize,
+}
+
+// Filled in order. A slot is only visible once `call_once` finishes, so readers never need a lock.
+static SINKS: [Once<Registration>; MAX_SINKS] = [const { Once::new() }; MAX_SINKS];
+static NUM_SINKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Start sending output to `sink`
+pub fn register(sink: &'static dyn Sink, max_level: LevelFilter) -> Result<(), &'static str> {
+    let idx = NUM_SINKS.fetch_add(1, Ordering::AcqRel);
+    let slot = SINKS.get(idx).ok_or("Too many console sinks")?;
+    slot.call_once(|| Registration {
+        sink,
+        max_level: AtomicUsize::new(max_level as usize),
+    });
+    Ok(())
+}
+
+fn registrations() -> impl Iterator<Item = &'static Registration> {
+    let count = NUM_SINKS.load(Ordering::Acquire).min(MAX_SINKS);
+    SINKS[..count].iter().filter_map(|slot| slot.get())
+}
+
+/// Change how verbose a sink is. Returns `false` if there is no sink called `name`.
+pub fn set_level(name: &str, max_level: LevelFilter) -> bool {
+    let mut found = false;
+    for reg in registrations().filter(|reg| reg.sink.name() == name) {
+        reg.max_level.store(max_level as usize, Ordering::Relaxed);
+        found = true;
+    }
+    found
+}
+
+/// Change how verbose every sink is
+pub fn set_all_levels(max_level: LevelFilter) {
+    for reg in registrations() {
+        reg.max_level.store(max_level as usize, Ordering::Relaxed);
+    }
+}
+
+/// Write to every sink that wants messages of `level`.
+/// Sinks that can't be written to right now miss out.
+pub fn write(level: Level, args: fmt::Arguments) {
+    for reg in registrations() {
+        if level as usize > reg.max_level.load(Ordering::Relaxed) {
+            continue;
+        }
+        for _ in 0..LOCK_ATTEMPTS {
+            match reg.sink.try_write(args) {
+                Err(SinkError::Locked) => core::hint::spin_loop(),
+                _ => break,
+            }
+        }
+    }
+}
+
+crate::cmdline::early_param!("console", select);
+
+/// Only keep the sinks named in `names`, a comma separated list like `uart,fb`. The rest are
+/// turned off.
+fn select(names: &'static str) -> Result<(), &'static str> {
+    let names = || names.split(',').map(str::trim).filter(|name| !name.is_empty());
+    if names().any(|name| !registrations().any(|reg| reg.sink.name() == name)) {
+        return Err("No console with that name");
+    }
+    if names().next().is_none() {
+        return Err("No consoles given");
+    }
+    for reg in registrations().filter(|reg| !names().any(|name| name == reg.sink.name())) {
+        reg.max_level.store(LevelFilter::Off as usize, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Break every sink's lock so the panic handler's output can't be blocked.
+///
+/// # Safety
+///
+/// Nothing else may run afterwards. Whoever held the locks would find the devices in use.
+pub unsafe fn force_unlock_all() {
+    for reg in registrations() {
+        reg.sink.force_unlock();
+    }
+}
+
+/// Writes `args` to the device `try_get` hands back, if there is one.
+/// `try_get` can't tell locked from uninitialized, so `is_init` is asked which it was.
+fn write_with<W: Write, G: core::ops::DerefMut<Target = W>>(
+    try_get: impl FnOnce() -> Option<G>,
+    is_init: impl FnOnce() -> bool,
+    args: fmt::Arguments,
+) -> Result<(), SinkError> {
+    match try_get() {
+        Some(mut dev) => dev.write_fmt(args).map_err(|_| SinkError::Failed),
+        None if is_init() => Err(SinkError::Locked),
+        None => Err(SinkError::Uninitialized),
+    }
+}
+
+/// The mini UART
+pub struct MiniUart;
+/// The PL011 UART
+pub struct Pl011;
+/// The framebuffer's text log
+pub struct FrameBuffer;
+/// The in-memory ring. See `ring`.
+pub struct Ring;
+
+impl Sink for MiniUart {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+    fn try_write(&self, args: fmt::Arguments) -> Result<(), SinkError> {
+        write_with(crate::uart::try_get, crate::uart::is_init, args)
+    }
+    unsafe fn force_unlock(&self) {
+        crate::uart::force_unlock();
+    }
+}
+
+impl Sink for Pl011 {
+    fn name(&self) -> &'static str {
+        "pl011"
+    }
+    fn try_write(&self, args: fmt::Arguments) -> Result<(), SinkError> {
+        write_with(crate::pl011::try_get, crate::pl011::is_init, args)
+    }
+    unsafe fn force_unlock(&self) {
+        crate::pl011::force_unlock();
+    }
+}
+
+impl Sink for FrameBuffer {
+    fn name(&self) -> &'static str {
+        "fb"
+    }
+    fn try_write(&self, args: fmt::Arguments) -> Result<(), SinkError> {
+        write_with(crate::framebuffer::try_get, crate::framebuffer::is_init, args)
+    }
+    unsafe fn force_unlock(&self) {
+        crate::framebuffer::force_unlock();
+    }
+}
+
+impl Sink for Ring {
+    fn name(&self) -> &'static str {
+        "ring"
+    }
+    fn try_write(&self, args: fmt::Arguments) -> Result<(), SinkError> {
+        write_with(|| ring::RING.try_lock(), || true, args)
+    }
+    unsafe fn force_unlock(&self) {
+        ring::RING.force_unlock();
+    }
+}
+
+/// Registers every sink we have a driver for. They start printing as their devices come up.
+pub fn init() -> Result<(), &'static str> {
+    register(&MiniUart, LevelFilter::Trace)?;
+    register(&Pl011, LevelFilter::Trace)?;
+    // The screen is small, so keep the chatter off of it
+    register(&FrameBuffer, LevelFilter::Info)?;
+    register(&Ring, LevelFilter::Trace)?;
+    Ok(())
+}
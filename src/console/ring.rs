@@ -0,0 +1,76 @@
+//! In-memory copy of the most recent console output.
+//!
+//! Always available, even before any device is set up, so early output can still be looked at
+//! from a debugger or dumped later.
+use core::fmt;
+use crate::sync::{rank, IrqSafeMutex};
+
+const RING_SIZE: usize = 16 * 1024;
+
+pub struct Ring {
+    buf: [u8; RING_SIZE],
+    /// Where the next byte goes
+    head: usize,
+    /// Number of bytes holding output
+    len: usize,
+}
+
+pub(super) static RING: IrqSafeMutex<Ring> = IrqSafeMutex::ranked(rank::RING, Ring::new());
+
+impl Ring {
+    const fn new() -> Self {
+        Ring {
+            buf: [0; RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[self.head] = byte;
+            self.head = (self.head + 1) % RING_SIZE;
+        }
+        self.len = (self.len + bytes.len()).min(RING_SIZE);
+    }
+
+    /// Output in the order it was written, as the two halves of the ring
+    fn contents(&self) -> (&[u8], &[u8]) {
+        let start = (self.head + RING_SIZE - self.len) % RING_SIZE;
+        if start + self.len <= RING_SIZE {
+            (&self.buf[start..(start + self.len)], &[])
+        } else {
+            (&self.buf[start..], &self.buf[..self.head])
+        }
+    }
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Copies the oldest output still in the ring into `buf`. Returns how many bytes were copied.
+pub fn read(buf: &mut [u8]) -> usize {
+    let ring = RING.lock();
+    let (first, second) = ring.contents();
+    let mut copied = 0;
+    for half in [first, second] {
+        let count = half.len().min(buf.len() - copied);
+        buf[copied..(copied + count)].copy_from_slice(&half[..count]);
+        copied += count;
+    }
+    copied
+}
+
+/// Number of bytes of output in the ring
+pub fn len() -> usize {
+    RING.lock().len
+}
+
+/// Forget everything that has been written so far
+pub fn clear() {
+    RING.lock().len = 0;
+}
@@ -0,0 +1,34 @@
+//! Debug commands, run by typing a control key at the serial console.
+//!
+//! | Key      | Command                                       |
+//! |----------|-----------------------------------------------|
+//! | `Ctrl-B` | Print the block cache's hits, misses and more |
+//! | `Ctrl-W` | Sync every filesystem, writing dirty blocks   |
+use log::{info, warn};
+
+const COMMANDS: [(char, fn()); 2] = [('\x02', block_stats), ('\x17', sync)];
+
+/// Run the command for `c`, if there is one. Returns whether there was.
+pub fn handle_key(c: char) -> bool {
+    match COMMANDS.iter().find(|&&(key, _)| key == c) {
+        Some((_, command)) => {
+            command();
+            true
+        }
+        None => false,
+    }
+}
+
+fn block_stats() {
+    match crate::block::cache() {
+        Some(cache) => cache.report(),
+        None => info!("No block cache"),
+    }
+}
+
+fn sync() {
+    match crate::fs::sync() {
+        Ok(()) => info!("Synced"),
+        Err(err) => warn!("Sync failed: {:?}", err),
+    }
+}
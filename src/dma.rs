@@ -0,0 +1,466 @@
+//! The DMA controller, which moves memory around, and to and from peripherals, without the CPU.
+//!
+//! A transfer is a chain of `ControlBlock`s in RAM, each saying what to move where and pointing
+//! at the next. `alloc` hands out a channel to run chains on. The firmware says which channels it
+//! leaves to us (the mailbox's DMA channel tag), and only those are ever handed out. Of them, only
+//! the full channels 0-6 are used: the lite ones move at most 64KiB per control block, at half
+//! the bandwidth.
+//!
+//! The controller reads and writes RAM behind the CPU's caches. `Channel::run` cleans what a chain
+//! is about to read out to RAM, and throws away cached copies of what it wrote. While threads are
+//! running it sleeps until the channel's interrupt says the chain is done; before that, or with
+//! IRQs masked, the channel is polled.
+//!
+//! The controller works in bus addresses: RAM is seen through the uncached alias at
+//! 0xC000_0000, and peripherals at 0x7E00_0000.
+//! https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf section 4
+use crate::sync::WaitQueue;
+use crate::{bus_to_phys, MMIODerefWrapper};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use log::{info, warn};
+use tock_registers::{
+    interfaces::{ReadWriteable, Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite},
+};
+
+type ChannelRegs = MMIODerefWrapper<ChannelRegisters>;
+type GlobalRegs = MMIODerefWrapper<GlobalRegisters>;
+
+register_structs! {
+    ChannelRegisters {
+        (0x00 => cs: ReadWrite<u32, CS::Register>),
+        // Bus address of the control block to load, or that's loaded
+        (0x04 => conblk_ad: ReadWrite<u32>),
+        // The rest are copied from the loaded control block
+        (0x08 => ti: ReadOnly<u32, TI::Register>),
+        (0x0C => source_ad: ReadOnly<u32>),
+        (0x10 => dest_ad: ReadOnly<u32>),
+        (0x14 => txfr_len: ReadOnly<u32>),
+        (0x18 => stride: ReadOnly<u32>),
+        (0x1C => nextconbk: ReadWrite<u32>),
+        // Writing 1s clears errors
+        (0x20 => debug: ReadWrite<u32, DEBUG::Register>),
+        (0x24 => @END),
+    }
+}
+
+register_structs! {
+    GlobalRegisters {
+        (0x00 => int_status: ReadOnly<u32>),
+        (0x04 => _reserved),
+        (0x10 => enable: ReadWrite<u32>),
+        (0x14 => @END),
+    }
+}
+
+register_bitfields! {
+    u32,
+
+    CS [
+        ACTIVE          OFFSET(0)   NUMBITS(1) [],
+        /// Set when a chain finishes. Writing 1 clears it.
+        END             OFFSET(1)   NUMBITS(1) [],
+        /// Set when a control block with `INTEN` finishes. Writing 1 clears it.
+        INT             OFFSET(2)   NUMBITS(1) [],
+        ERROR           OFFSET(8)   NUMBITS(1) [],
+        PRIORITY        OFFSET(16)  NUMBITS(4) [],
+        PANIC_PRIORITY  OFFSET(20)  NUMBITS(4) [],
+        /// Don't count a write as done until the bus says so
+        WAIT_FOR_OUTSTANDING_WRITES OFFSET(28) NUMBITS(1) [],
+        ABORT           OFFSET(30)  NUMBITS(1) [],
+        RESET           OFFSET(31)  NUMBITS(1) [],
+    ],
+    TI [
+        /// Raise the channel's interrupt when this control block is done
+        INTEN       OFFSET(0)   NUMBITS(1) [],
+        /// Wait for each write to be acknowledged
+        WAIT_RESP   OFFSET(3)   NUMBITS(1) [],
+        DEST_INC    OFFSET(4)   NUMBITS(1) [],
+        /// 128 bit writes, instead of 32
+        DEST_WIDTH  OFFSET(5)   NUMBITS(1) [],
+        /// Only write when the peripheral in `PERMAP` asks
+        DEST_DREQ   OFFSET(6)   NUMBITS(1) [],
+        SRC_INC     OFFSET(8)   NUMBITS(1) [],
+        SRC_WIDTH   OFFSET(9)   NUMBITS(1) [],
+        SRC_DREQ    OFFSET(10)  NUMBITS(1) [],
+        /// Words per burst, less one
+        BURST_LENGTH OFFSET(12) NUMBITS(4) [],
+        PERMAP      OFFSET(16)  NUMBITS(5) [],
+    ],
+    DEBUG [
+        READ_LAST_NOT_SET_ERROR OFFSET(0) NUMBITS(1) [],
+        FIFO_ERROR  OFFSET(1)   NUMBITS(1) [],
+        READ_ERROR  OFFSET(2)   NUMBITS(1) [],
+    ],
+}
+
+const BUS_ADDR: usize = 0x7E00_7000;
+/// Each channel's registers are this far after the last one's
+const CHANNEL_STRIDE: usize = 0x100;
+/// Where `int_status` and `enable` are
+const GLOBAL_OFFSET: usize = 0xFE0;
+/// Channels 0-6, which have the full feature set
+const FULL_CHANNELS: u32 = 0x7F;
+const NUM_CHANNELS: usize = 7;
+/// Longest transfer a full channel's control block can do
+const MAX_LEN: usize = 1 << 30;
+/// How long a chain is given before it's aborted
+const TIMEOUT_US: u64 = 1_000_000;
+
+/// Where peripherals start, for the controller
+const PERIPHERAL_BUS: u32 = 0x7E00_0000;
+/// RAM as the controller sees it, bypassing the GPU's L2 cache like the CPU does
+const RAM_BUS: u32 = 0xC000_0000;
+/// Everything under the bus alias bits
+const BUS_OFFSET_MASK: u32 = 0x3FFF_FFFF;
+
+/// Peripherals that can pace a transfer
+#[derive(Clone, Copy, Debug)]
+#[repr(u32)]
+pub enum Peripheral {
+    Emmc = 11,
+}
+
+/// One step of a transfer, as the controller reads it. Has to be 32 byte aligned.
+#[repr(C, align(32))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ControlBlock {
+    ti: u32,
+    source: u32,
+    dest: u32,
+    len: u32,
+    stride: u32,
+    /// Bus address of the next control block, or 0 for the last
+    next: u32,
+    _reserved: [u32; 2],
+}
+
+/// The bus address of `addr` in RAM
+fn ram_bus_addr(addr: usize) -> u32 {
+    (addr as u32 & BUS_OFFSET_MASK) | RAM_BUS
+}
+
+/// The bus address of a peripheral register at physical address `addr`
+pub fn peripheral_bus_addr(addr: usize) -> u32 {
+    (addr - crate::peripheral_range().start) as u32 + PERIPHERAL_BUS
+}
+
+fn is_peripheral(bus: u32) -> bool {
+    bus & 0xFF00_0000 == PERIPHERAL_BUS
+}
+
+fn check(addrs: &[usize], len: usize) -> Result<(), &'static str> {
+    if len == 0 || len >= MAX_LEN {
+        return Err("DMA transfer is empty or too long");
+    }
+    if addrs.iter().any(|addr| addr % 4 != 0) || len % 4 != 0 {
+        return Err("DMA addresses and lengths have to be multiples of 4");
+    }
+    Ok(())
+}
+
+impl ControlBlock {
+    /// Copy `len` bytes from `src` to `dst`, both in RAM. They can overlap if `dst` is lower.
+    pub fn copy(src: usize, dst: usize, len: usize) -> Result<Self, &'static str> {
+        check(&[src, dst], len)?;
+        // Wide reads and writes when everything lines up for them
+        let wide = if (src | dst | len) % 16 == 0 {
+            TI::SRC_WIDTH::SET + TI::DEST_WIDTH::SET
+        } else {
+            TI::SRC_WIDTH::CLEAR + TI::DEST_WIDTH::CLEAR
+        };
+        let ti = TI::SRC_INC::SET + TI::DEST_INC::SET + TI::BURST_LENGTH.val(3) + wide;
+        Ok(ControlBlock {
+            ti: ti.value,
+            source: ram_bus_addr(src),
+            dest: ram_bus_addr(dst),
+            len: len as u32,
+            ..ControlBlock::default()
+        })
+    }
+
+    /// Fill `len` bytes at `dst` with copies of the word at `pattern`, which has to stay put
+    /// until the chain has run
+    pub fn fill(pattern: &u32, dst: usize, len: usize) -> Result<Self, &'static str> {
+        let src = pattern as *const u32 as usize;
+        check(&[src, dst], len)?;
+        let ti = TI::DEST_INC::SET + TI::BURST_LENGTH.val(3);
+        Ok(ControlBlock {
+            ti: ti.value,
+            source: ram_bus_addr(src),
+            dest: ram_bus_addr(dst),
+            len: len as u32,
+            ..ControlBlock::default()
+        })
+    }
+
+    /// Read `len` bytes from the register at bus address `reg` into `dst`, a word whenever
+    /// `peripheral` asks
+    pub fn from_peripheral(
+        reg: u32,
+        peripheral: Peripheral,
+        dst: usize,
+        len: usize,
+    ) -> Result<Self, &'static str> {
+        check(&[dst], len)?;
+        let ti = TI::SRC_DREQ::SET
+            + TI::DEST_INC::SET
+            + TI::WAIT_RESP::SET
+            + TI::PERMAP.val(peripheral as u32);
+        Ok(ControlBlock {
+            ti: ti.value,
+            source: reg,
+            dest: ram_bus_addr(dst),
+            len: len as u32,
+            ..ControlBlock::default()
+        })
+    }
+
+    /// Write `len` bytes from `src` to the register at bus address `reg`, a word whenever
+    /// `peripheral` asks
+    pub fn to_peripheral(
+        src: usize,
+        reg: u32,
+        peripheral: Peripheral,
+        len: usize,
+    ) -> Result<Self, &'static str> {
+        check(&[src], len)?;
+        let ti = TI::DEST_DREQ::SET
+            + TI::SRC_INC::SET
+            + TI::WAIT_RESP::SET
+            + TI::PERMAP.val(peripheral as u32);
+        Ok(ControlBlock {
+            ti: ti.value,
+            source: ram_bus_addr(src),
+            dest: reg,
+            len: len as u32,
+            ..ControlBlock::default()
+        })
+    }
+
+    /// The RAM `bus` is in, as the CPU sees it. `inc` says whether the whole of `len` is covered,
+    /// or only one word.
+    fn ram_range(&self, bus: u32, inc: bool) -> Option<Range<usize>> {
+        if is_peripheral(bus) {
+            return None;
+        }
+        let start = (bus & BUS_OFFSET_MASK) as usize;
+        let len = if inc { self.len as usize } else { 16 };
+        Some(start..start + len)
+    }
+
+    fn source_range(&self) -> Option<Range<usize>> {
+        self.ram_range(self.source, TI::SRC_INC.read(self.ti) != 0)
+    }
+
+    fn dest_range(&self) -> Option<Range<usize>> {
+        self.ram_range(self.dest, TI::DEST_INC.read(self.ti) != 0)
+    }
+}
+
+/// Smallest data cache line, in bytes
+fn cache_line() -> usize {
+    let ctr: u64;
+    // Safety: Reading CTR_EL0 has no side effects
+    unsafe { core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr) };
+    4 << ((ctr >> 16) & 0xF)
+}
+
+/// Write the cache lines covering `range` out to RAM, and drop them from the cache
+pub fn clean_invalidate(range: Range<usize>) {
+    let line = cache_line();
+    for addr in (range.start & !(line - 1)..range.end).step_by(line) {
+        // Safety: Cleaning the cache doesn't change memory
+        unsafe { core::arch::asm!("dc civac, {}", in(reg) addr) };
+    }
+    aarch64_cpu::asm::barrier::dsb(aarch64_cpu::asm::barrier::SY);
+}
+
+/// Where the controller is, once `init` has found it
+static ADDR: AtomicUsize = AtomicUsize::new(0);
+/// Channels nobody has, one bit each
+static FREE: AtomicU32 = AtomicU32::new(0);
+/// Channels whose interrupt is set up
+static IRQS: AtomicU32 = AtomicU32::new(0);
+/// Channels whose interrupt has come in since their chain started
+static DONE: AtomicU32 = AtomicU32::new(0);
+static WAITERS: WaitQueue = WaitQueue::new();
+
+fn channel_regs(index: usize) -> ChannelRegs {
+    // Safety: `init` found the controller there, and channels below `NUM_CHANNELS` have their
+    // registers in it
+    unsafe { ChannelRegs::new(ADDR.load(Ordering::Acquire) + index * CHANNEL_STRIDE) }
+}
+
+fn handle_irq<const CHANNEL: usize>() {
+    // Clears `INT` and `END`, while leaving `ACTIVE` alone
+    channel_regs(CHANNEL).cs.modify(CS::INT::SET);
+    DONE.fetch_or(1 << CHANNEL, Ordering::AcqRel);
+    WAITERS.wake_all();
+}
+
+const HANDLERS: [fn(); NUM_CHANNELS] = [
+    handle_irq::<0>,
+    handle_irq::<1>,
+    handle_irq::<2>,
+    handle_irq::<3>,
+    handle_irq::<4>,
+    handle_irq::<5>,
+    handle_irq::<6>,
+];
+
+fn describe(debug: u32) -> &'static str {
+    if DEBUG::READ_ERROR.read(debug) != 0 {
+        "DMA read error"
+    } else if DEBUG::FIFO_ERROR.read(debug) != 0 {
+        "DMA FIFO error"
+    } else if DEBUG::READ_LAST_NOT_SET_ERROR.read(debug) != 0 {
+        "DMA peripheral did not signal the last word"
+    } else {
+        "DMA error"
+    }
+}
+
+/// A DMA channel of our own. Goes back to the pool when dropped.
+#[derive(Debug)]
+pub struct Channel {
+    index: usize,
+}
+
+impl Channel {
+    fn regs(&self) -> ChannelRegs {
+        channel_regs(self.index)
+    }
+
+    fn reset(&self) {
+        self.regs().cs.write(CS::RESET::SET);
+    }
+
+    /// Run `chain` from start to end, and wait for it.
+    ///
+    /// What it writes to in RAM should be whole cache lines. The CPU dirtying the rest of a line
+    /// while the chain runs would have its copy written back over what the controller wrote.
+    pub fn run(&mut self, chain: &mut [ControlBlock]) -> Result<(), &'static str> {
+        if chain.is_empty() {
+            return Err("Empty DMA chain");
+        }
+        let bit = 1 << self.index;
+        let irq = IRQS.load(Ordering::Relaxed) & bit != 0;
+        let sleep = irq
+            && crate::thread::try_current().is_some()
+            && crate::exceptions::interrupts_enabled();
+
+        // Only the last block interrupts, and only if someone's going to sleep on it
+        let chain_start = chain.as_ptr() as usize;
+        let last = chain.len() - 1;
+        for (idx, block) in chain.iter_mut().enumerate() {
+            let next = chain_start + (idx + 1) * core::mem::size_of::<ControlBlock>();
+            block.next = if idx == last { 0 } else { ram_bus_addr(next) };
+            let inten = TI::INTEN::SET.value;
+            block.ti = if idx == last && sleep { block.ti | inten } else { block.ti & !inten };
+        }
+
+        // What the controller reads has to be in RAM, and what it writes can't be sitting dirty
+        // in the cache, where it'd be written back over the new data
+        clean_invalidate(chain_start..chain_start + core::mem::size_of_val(chain));
+        for block in chain.iter() {
+            block.source_range().into_iter().chain(block.dest_range()).for_each(clean_invalidate);
+        }
+
+        let regs = self.regs();
+        DONE.fetch_and(!bit, Ordering::AcqRel);
+        regs.debug.set(u32::MAX);
+        regs.cs.write(CS::END::SET + CS::INT::SET);
+        regs.conblk_ad.set(ram_bus_addr(chain_start));
+        regs.cs.write(
+            CS::ACTIVE::SET
+                + CS::PRIORITY.val(8)
+                + CS::PANIC_PRIORITY.val(15)
+                + CS::WAIT_FOR_OUTSTANDING_WRITES::SET,
+        );
+
+        let done = || !regs.cs.is_set(CS::ACTIVE) || regs.cs.is_set(CS::ERROR);
+        let finished = if sleep {
+            WAITERS.wait_until_timeout(
+                || DONE.load(Ordering::Acquire) & bit != 0 || done(),
+                Duration::from_micros(TIMEOUT_US),
+            )
+        } else {
+            let start = crate::time::uptime_microsec();
+            loop {
+                if done() {
+                    break true;
+                }
+                if crate::time::uptime_microsec() - start > TIMEOUT_US {
+                    break false;
+                }
+            }
+        };
+        if !finished {
+            regs.cs.write(CS::ABORT::SET);
+            self.reset();
+            return Err("DMA transfer timed out");
+        }
+
+        let failed = regs.cs.is_set(CS::ERROR);
+        let debug = regs.debug.get();
+        regs.cs.write(CS::END::SET + CS::INT::SET);
+        // Anything the CPU read ahead while the controller was writing is stale
+        for range in chain.iter().filter_map(ControlBlock::dest_range) {
+            clean_invalidate(range);
+        }
+        if failed {
+            self.reset();
+            return Err(describe(debug));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.reset();
+        FREE.fetch_or(1 << self.index, Ordering::AcqRel);
+    }
+}
+
+/// A channel to run chains on, if there's one left
+pub fn alloc() -> Option<Channel> {
+    // Take the lowest free one
+    FREE.fetch_update(Ordering::AcqRel, Ordering::Acquire, |free| {
+        (free != 0).then(|| free & (free - 1))
+    })
+    .ok()
+    .map(|free| Channel { index: free.trailing_zeros() as usize })
+}
+
+/// Find the controller, and take the channels the firmware doesn't use. Needs `mailbox::init`
+/// and `irq::init`.
+pub unsafe fn init() -> Result<(), &'static str> {
+    let addr = crate::fdt::device("brcm,bcm2835-dma")
+        .map_or(bus_to_phys(BUS_ADDR), |dev| dev.regs.start);
+    let channels = crate::mailbox::dma_channels().ok_or("Firmware did not say which to use")?;
+    let usable = channels & FULL_CHANNELS;
+    if usable == 0 {
+        return Err("Firmware left no full channels");
+    }
+    ADDR.store(addr, Ordering::Release);
+
+    let global = GlobalRegs::new(addr + GLOBAL_OFFSET);
+    global.enable.set(global.enable.get() | usable);
+    let mut irqs = 0;
+    for index in (0..NUM_CHANNELS).filter(|&index| usable & (1 << index) != 0) {
+        channel_regs(index).cs.write(CS::RESET::SET);
+        match crate::irq::register(crate::irq::DMA_BASE + index, HANDLERS[index]) {
+            Ok(()) => irqs |= 1 << index,
+            Err(err) => warn!("DMA channel {} interrupt: {}. Polling instead.", index, err),
+        }
+    }
+    IRQS.store(irqs, Ordering::Release);
+    FREE.store(usable, Ordering::Release);
+    info!("DMA: channels {:#x} are ours", usable);
+    Ok(())
+}
@@ -0,0 +1,628 @@
+//! The Arasan SD host controller (called EMMC in the BCM2837 docs), and the SD card in it.
+//!
+//! `init` takes the card through identification at 400kHz (CMD0, CMD8, ACMD41, CMD2, CMD3),
+//! selects it (CMD7), then switches to a 4 bit bus at 25MHz. After that blocks are moved through
+//! the data register by a DMA channel, paced by the controller's DREQ. Without a channel, or for
+//! buffers the DMA controller can't safely write to, it's PIO, 512 bytes at a time.
+//!
+//! Commands and transfers finish by raising flags in the `interrupt` register. Once threads are
+//! running the controller's IRQ collects them and wakes whoever is waiting, so a thread reading a
+//! file sleeps instead of spinning. Before that, or if the IRQ couldn't be set up, the flags are
+//! polled.
+//!
+//! QEMU puts the card from `-drive if=sd` here. A real Pi 3 uses this controller for WiFi, and
+//! boots from its SD card through the other one (`sdhost`), which there's no driver for.
+//! https://www.sdcard.org/downloads/pls/ "SD Host Controller Simplified Specification"
+use crate::block::{self, BlockDevice, BLOCK_SIZE};
+use crate::dma::{self, ControlBlock, Peripheral};
+use crate::mailbox::ClockId;
+use crate::sync::{Mutex, WaitQueue};
+use crate::{bus_to_phys, MMIODerefWrapper};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use log::{info, warn};
+use spin::Once;
+use tock_registers::{
+    fields::FieldValue,
+    interfaces::{ReadWriteable, Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite},
+};
+
+type Emmc = MMIODerefWrapper<Registers>;
+
+register_structs! {
+    Registers {
+        (0x00 => arg2: ReadWrite<u32>),
+        (0x04 => blksizecnt: ReadWrite<u32, BLKSIZECNT::Register>),
+        (0x08 => arg1: ReadWrite<u32>),
+        (0x0C => cmdtm: ReadWrite<u32, CMDTM::Register>),
+        (0x10 => resp: [ReadOnly<u32>; 4]),
+        (0x20 => data: ReadWrite<u32>),
+        (0x24 => status: ReadOnly<u32, STATUS::Register>),
+        (0x28 => control0: ReadWrite<u32, CONTROL0::Register>),
+        (0x2C => control1: ReadWrite<u32, CONTROL1::Register>),
+        // Writing 1s clears flags
+        (0x30 => interrupt: ReadWrite<u32, INTERRUPT::Register>),
+        // Which flags get set in `interrupt`
+        (0x34 => irpt_mask: ReadWrite<u32, INTERRUPT::Register>),
+        // Which flags raise the IRQ
+        (0x38 => irpt_en: ReadWrite<u32, INTERRUPT::Register>),
+        (0x3C => control2: ReadWrite<u32>),
+        (0x40 => _reserved),
+        (0xFC => slotisr_ver: ReadOnly<u32, SLOTISR_VER::Register>),
+        (0x100 => @END),
+    }
+}
+
+register_bitfields! {
+    // 32 bit registers
+    u32,
+
+    BLKSIZECNT [
+        BLKSIZE     OFFSET(0)   NUMBITS(10) [],
+        BLKCNT      OFFSET(16)  NUMBITS(16) [],
+    ],
+    CMDTM [
+        BLKCNT_EN   OFFSET(1)   NUMBITS(1) [],
+        AUTO_CMD    OFFSET(2)   NUMBITS(2) [
+            None = 0,
+            /// Send STOP_TRANSMISSION after the last block
+            Cmd12 = 1,
+        ],
+        DAT_DIR     OFFSET(4)   NUMBITS(1) [
+            HostToCard = 0,
+            CardToHost = 1,
+        ],
+        MULTI_BLOCK OFFSET(5)   NUMBITS(1) [],
+        RSPNS_TYPE  OFFSET(16)  NUMBITS(2) [
+            None = 0,
+            Bits136 = 1,
+            Bits48 = 2,
+            /// 48 bits, then the card holds DAT0 low while it's busy
+            Bits48Busy = 3,
+        ],
+        CRCCHK_EN   OFFSET(19)  NUMBITS(1) [],
+        IXCHK_EN    OFFSET(20)  NUMBITS(1) [],
+        ISDATA      OFFSET(21)  NUMBITS(1) [],
+        INDEX       OFFSET(24)  NUMBITS(6) [],
+    ],
+    STATUS [
+        CMD_INHIBIT     OFFSET(0)   NUMBITS(1) [],
+        DAT_INHIBIT     OFFSET(1)   NUMBITS(1) [],
+        CARD_INSERTED   OFFSET(16)  NUMBITS(1) [],
+    ],
+    CONTROL0 [
+        DWIDTH4     OFFSET(1)   NUMBITS(1) [],
+        HS_EN       OFFSET(2)   NUMBITS(1) [],
+        BUS_POWER   OFFSET(8)   NUMBITS(1) [],
+        BUS_VOLTAGE OFFSET(9)   NUMBITS(3) [
+            V3_3 = 0b111,
+        ],
+    ],
+    CONTROL1 [
+        CLK_INTLEN  OFFSET(0)   NUMBITS(1) [],
+        CLK_STABLE  OFFSET(1)   NUMBITS(1) [],
+        CLK_EN      OFFSET(2)   NUMBITS(1) [],
+        /// Top two bits of the 10 bit divisor
+        CLK_FREQ_MS2 OFFSET(6)  NUMBITS(2) [],
+        CLK_FREQ8   OFFSET(8)   NUMBITS(8) [],
+        /// Data timeout is TMCLK * 2^(this + 13)
+        DATA_TOUNIT OFFSET(16)  NUMBITS(4) [],
+        SRST_HC     OFFSET(24)  NUMBITS(1) [],
+        SRST_CMD    OFFSET(25)  NUMBITS(1) [],
+        SRST_DATA   OFFSET(26)  NUMBITS(1) [],
+    ],
+    INTERRUPT [
+        CMD_DONE    OFFSET(0)   NUMBITS(1) [],
+        DATA_DONE   OFFSET(1)   NUMBITS(1) [],
+        WRITE_RDY   OFFSET(4)   NUMBITS(1) [],
+        READ_RDY    OFFSET(5)   NUMBITS(1) [],
+        ERR         OFFSET(15)  NUMBITS(1) [],
+        CTO_ERR     OFFSET(16)  NUMBITS(1) [],
+        CCRC_ERR    OFFSET(17)  NUMBITS(1) [],
+        CEND_ERR    OFFSET(18)  NUMBITS(1) [],
+        CBAD_ERR    OFFSET(19)  NUMBITS(1) [],
+        DTO_ERR     OFFSET(20)  NUMBITS(1) [],
+        DCRC_ERR    OFFSET(21)  NUMBITS(1) [],
+        DEND_ERR    OFFSET(22)  NUMBITS(1) [],
+        ACMD_ERR    OFFSET(24)  NUMBITS(1) [],
+    ],
+    SLOTISR_VER [
+        /// 0 for version 1.0 of the host controller spec, up to 2 for 3.0
+        SDVERSION   OFFSET(16)  NUMBITS(8) [],
+    ],
+}
+
+const BUS_ADDR: usize = 0x7E30_0000;
+/// What cards have to be identified at
+const IDENTIFY_CLOCK: u32 = 400_000;
+/// Default speed, which every card supports
+const TRANSFER_CLOCK: u32 = 25_000_000;
+/// The firmware's default, for when it won't say
+const DEFAULT_BASE_CLOCK: u32 = 200_000_000;
+/// Version 3.0 of the spec lets the clock divisor be any 10 bit number, rather than a power of 2
+const HOST_SPEC_V3: u32 = 2;
+/// How long anything is given before we give up on it
+const TIMEOUT_US: u64 = 1_000_000;
+/// Most blocks moved by one command. Bounds how long the controller is held.
+const MAX_TRANSFER_BLOCKS: usize = 128;
+
+/// DMA writes whole cache lines of RAM, so reads only go by DMA into buffers made of them
+const DMA_READ_ALIGN: usize = 64;
+/// The data register is read and written a word at a time
+const DMA_WRITE_ALIGN: usize = 4;
+
+/// Every error flag, including the summary bit
+const ERRORS: u32 = 0xFFFF_8000;
+/// What raises the IRQ, when we have it
+const IRQ_FLAGS: u32 = 0b11_0011 | ERRORS;
+
+/// `SEND_IF_COND` argument: 2.7-3.6V, and a pattern for the card to echo
+const IF_COND: u32 = 0x1AA;
+/// `SD_SEND_OP_COND` argument: the card may be anywhere from 3.2V to 3.4V
+const OCR_VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+/// We can handle high capacity cards
+const OCR_HCS: u32 = 1 << 30;
+/// Set in the response once the card has powered up
+const OCR_POWERED_UP: u32 = 1 << 31;
+/// Set in the response for high capacity cards, which are addressed in blocks instead of bytes
+const OCR_CCS: u32 = 1 << 30;
+/// `SET_BUS_WIDTH` argument for 4 bits
+const BUS_WIDTH_4: u32 = 2;
+
+#[derive(Clone, Copy, Debug)]
+enum Response {
+    None,
+    /// Card status
+    R1,
+    /// Card status, then busy
+    R1b,
+    /// CID or CSD
+    R2,
+    /// OCR, which has no CRC
+    R3,
+    /// New RCA
+    R6,
+    /// Echoed interface conditions
+    R7,
+}
+
+impl Response {
+    fn flags(self) -> FieldValue<u32, CMDTM::Register> {
+        let checked = CMDTM::CRCCHK_EN::SET + CMDTM::IXCHK_EN::SET;
+        match self {
+            Response::None => CMDTM::RSPNS_TYPE::None,
+            Response::R1 | Response::R6 | Response::R7 => CMDTM::RSPNS_TYPE::Bits48 + checked,
+            Response::R1b => CMDTM::RSPNS_TYPE::Bits48Busy + checked,
+            Response::R2 => CMDTM::RSPNS_TYPE::Bits136 + CMDTM::CRCCHK_EN::SET,
+            Response::R3 => CMDTM::RSPNS_TYPE::Bits48,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Command {
+    index: u32,
+    response: Response,
+}
+
+const fn command(index: u32, response: Response) -> Command {
+    Command { index, response }
+}
+
+const GO_IDLE_STATE: Command = command(0, Response::None);
+const ALL_SEND_CID: Command = command(2, Response::R2);
+const SEND_RELATIVE_ADDR: Command = command(3, Response::R6);
+const SELECT_CARD: Command = command(7, Response::R1b);
+const SEND_IF_COND: Command = command(8, Response::R7);
+const SEND_CSD: Command = command(9, Response::R2);
+const SET_BLOCKLEN: Command = command(16, Response::R1);
+const READ_SINGLE_BLOCK: Command = command(17, Response::R1);
+const READ_MULTIPLE_BLOCK: Command = command(18, Response::R1);
+const WRITE_BLOCK: Command = command(24, Response::R1);
+const WRITE_MULTIPLE_BLOCK: Command = command(25, Response::R1);
+const APP_CMD: Command = command(55, Response::R1);
+// Application commands, which have to follow `APP_CMD`
+const SET_BUS_WIDTH: Command = command(6, Response::R1);
+const SD_SEND_OP_COND: Command = command(41, Response::R3);
+
+/// Where the registers are, for the IRQ handler
+static ADDR: AtomicUsize = AtomicUsize::new(0);
+/// Flags taken from `interrupt` that nobody has waited for yet
+static FLAGS: AtomicU32 = AtomicU32::new(0);
+static WAITERS: WaitQueue = WaitQueue::new();
+
+/// Move the controller's flags into `FLAGS`, clearing them
+fn collect(regs: &Emmc) {
+    let flags = regs.interrupt.get();
+    if flags != 0 {
+        regs.interrupt.set(flags);
+        FLAGS.fetch_or(flags, Ordering::AcqRel);
+    }
+}
+
+fn handle_irq() {
+    // Safety: Only registered once `init` has stored the address
+    let regs = unsafe { Emmc::new(ADDR.load(Ordering::Relaxed)) };
+    collect(&regs);
+    WAITERS.wake_all();
+}
+
+/// Spin until `cond` holds. Fails with `err` if it doesn't in time.
+fn spin_until(mut cond: impl FnMut() -> bool, err: &'static str) -> Result<(), &'static str> {
+    let start = crate::time::uptime_microsec();
+    while !cond() {
+        if crate::time::uptime_microsec() - start > TIMEOUT_US {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn describe(flags: u32) -> &'static str {
+    // Most specific first, so the first that's set says what went wrong
+    let errors = [
+        (INTERRUPT::CTO_ERR, "Command timed out"),
+        (INTERRUPT::CCRC_ERR, "Command response CRC error"),
+        (INTERRUPT::CEND_ERR, "Command response end bit error"),
+        (INTERRUPT::CBAD_ERR, "Command response index error"),
+        (INTERRUPT::DTO_ERR, "Data timed out"),
+        (INTERRUPT::DCRC_ERR, "Data CRC error"),
+        (INTERRUPT::DEND_ERR, "Data end bit error"),
+        (INTERRUPT::ACMD_ERR, "Auto CMD12 error"),
+    ];
+    errors
+        .iter()
+        .find(|(field, _)| field.read(flags) != 0)
+        .map_or("SD host controller error", |&(_, err)| err)
+}
+
+pub struct Controller {
+    regs: Emmc,
+    /// The card's relative address, which selects it in addressed commands
+    rca: u32,
+    /// Addressed in blocks rather than bytes
+    high_capacity: bool,
+    base_clock: u32,
+    version: u32,
+    /// The IRQ is set up, so waits can sleep
+    irq: bool,
+    /// Moves the data, if we got one
+    dma: Option<dma::Channel>,
+    /// Bus address of the data register, for the DMA controller
+    data_bus: u32,
+}
+
+impl Controller {
+    /// Wait for every flag in `mask`, or an error. The flags are consumed.
+    fn wait(&mut self, mask: u32) -> Result<(), &'static str> {
+        let regs = &self.regs;
+        let done = || {
+            collect(regs);
+            let flags = FLAGS.load(Ordering::Acquire);
+            flags & mask == mask || flags & ERRORS != 0
+        };
+        let timed_out = "Timed out waiting for the SD card";
+        let waited = if self.irq && crate::thread::try_current().is_some() {
+            let timeout = Duration::from_micros(TIMEOUT_US);
+            WAITERS.wait_until_timeout(done, timeout).then_some(()).ok_or(timed_out)
+        } else {
+            spin_until(done, timed_out)
+        };
+        let flags = FLAGS.fetch_and(!(mask | ERRORS), Ordering::AcqRel);
+        if let Err(err) = waited {
+            self.reset_lines()?;
+            return Err(err);
+        }
+        if flags & ERRORS != 0 {
+            self.reset_lines()?;
+            return Err(describe(flags));
+        }
+        Ok(())
+    }
+
+    /// Get the command and data lines working again for whatever comes next
+    fn reset_lines(&mut self) -> Result<(), &'static str> {
+        self.regs.control1.modify(CONTROL1::SRST_CMD::SET + CONTROL1::SRST_DATA::SET);
+        let regs = &self.regs;
+        spin_until(
+            || !regs.control1.matches_any(CONTROL1::SRST_CMD::SET + CONTROL1::SRST_DATA::SET),
+            "SD host controller did not reset",
+        )
+    }
+
+    /// Send `cmd`, with `data` describing the transfer that follows, if any
+    fn issue(
+        &mut self,
+        cmd: Command,
+        arg: u32,
+        data: FieldValue<u32, CMDTM::Register>,
+    ) -> Result<(), &'static str> {
+        let regs = &self.regs;
+        spin_until(|| !regs.status.is_set(STATUS::CMD_INHIBIT), "SD command line is busy")?;
+        // Anything left over is from an earlier command
+        collect(regs);
+        FLAGS.store(0, Ordering::Release);
+
+        self.regs.arg1.set(arg);
+        self.regs
+            .cmdtm
+            .write(CMDTM::INDEX.val(cmd.index) + cmd.response.flags() + data);
+        self.wait(INTERRUPT::CMD_DONE::SET.value)?;
+        if let Response::R1b = cmd.response {
+            self.wait(INTERRUPT::DATA_DONE::SET.value)?;
+        }
+        Ok(())
+    }
+
+    /// Send `cmd`, and return the first 32 bits of its response
+    fn command(&mut self, cmd: Command, arg: u32) -> Result<u32, &'static str> {
+        self.issue(cmd, arg, CMDTM::ISDATA::CLEAR)?;
+        Ok(self.regs.resp[0].get())
+    }
+
+    /// Send `cmd`, which has a 136 bit response, and return it
+    fn command_long(&mut self, cmd: Command, arg: u32) -> Result<[u32; 4], &'static str> {
+        self.issue(cmd, arg, CMDTM::ISDATA::CLEAR)?;
+        Ok(core::array::from_fn(|idx| self.regs.resp[idx].get()))
+    }
+
+    fn app_command(&mut self, cmd: Command, arg: u32) -> Result<u32, &'static str> {
+        self.command(APP_CMD, self.rca << 16)?;
+        self.command(cmd, arg)
+    }
+
+    /// Run the card's clock as close to `freq` as we can without going over
+    fn set_clock(&mut self, freq: u32) -> Result<(), &'static str> {
+        let regs = &self.regs;
+        spin_until(
+            || !regs.status.matches_any(STATUS::CMD_INHIBIT::SET + STATUS::DAT_INHIBIT::SET),
+            "SD card is busy",
+        )?;
+        self.regs.control1.modify(CONTROL1::CLK_EN::CLEAR);
+
+        // The card gets base / (2 * divisor), or base itself for 0
+        let divisor = if freq >= self.base_clock {
+            0
+        } else if self.version >= HOST_SPEC_V3 {
+            self.base_clock.div_ceil(2 * freq).min(0x3FF)
+        } else {
+            self.base_clock.div_ceil(2 * freq).next_power_of_two().min(0x80)
+        };
+        self.regs.control1.modify(
+            CONTROL1::CLK_FREQ8.val(divisor & 0xFF)
+                + CONTROL1::CLK_FREQ_MS2.val(divisor >> 8)
+                + CONTROL1::DATA_TOUNIT.val(0xE)
+                + CONTROL1::CLK_INTLEN::SET,
+        );
+        let regs = &self.regs;
+        spin_until(|| regs.control1.is_set(CONTROL1::CLK_STABLE), "SD clock did not settle")?;
+        self.regs.control1.modify(CONTROL1::CLK_EN::SET);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), &'static str> {
+        self.regs.control0.set(0);
+        self.regs.control1.write(CONTROL1::SRST_HC::SET);
+        let regs = &self.regs;
+        spin_until(
+            || !regs.control1.is_set(CONTROL1::SRST_HC),
+            "SD host controller did not reset",
+        )?;
+
+        self.regs.control0.write(CONTROL0::BUS_POWER::SET + CONTROL0::BUS_VOLTAGE::V3_3);
+        self.regs.control2.set(0);
+        self.regs.irpt_mask.set(u32::MAX);
+        self.regs.interrupt.set(u32::MAX);
+        self.regs.irpt_en.set(if self.irq { IRQ_FLAGS } else { 0 });
+        self.set_clock(IDENTIFY_CLOCK)
+    }
+
+    /// Take the card from idle to ready for transfers. Returns how many blocks it has.
+    fn identify(&mut self) -> Result<u64, &'static str> {
+        self.command(GO_IDLE_STATE, 0)?;
+        // Only version 2 cards know this command, and they echo the pattern back
+        let v2 = match self.command(SEND_IF_COND, IF_COND) {
+            Ok(resp) if resp & 0xFFF == IF_COND => true,
+            Ok(_) => return Err("SD card does not support 3.3V"),
+            Err(_) => false,
+        };
+
+        let arg = OCR_VOLTAGE_WINDOW | if v2 { OCR_HCS } else { 0 };
+        let start = crate::time::uptime_microsec();
+        let ocr = loop {
+            let ocr = self.app_command(SD_SEND_OP_COND, arg)?;
+            if ocr & OCR_POWERED_UP != 0 {
+                break ocr;
+            }
+            if crate::time::uptime_microsec() - start > TIMEOUT_US {
+                return Err("SD card did not power up");
+            }
+            crate::time::wait_microsec(10_000);
+        };
+        self.high_capacity = ocr & OCR_CCS != 0;
+
+        self.command(ALL_SEND_CID, 0)?;
+        self.rca = self.command(SEND_RELATIVE_ADDR, 0)? >> 16;
+        let blocks = capacity(self.command_long(SEND_CSD, self.rca << 16)?);
+        self.command(SELECT_CARD, self.rca << 16)?;
+        if !self.high_capacity {
+            self.command(SET_BLOCKLEN, BLOCK_SIZE as u32)?;
+        }
+        self.app_command(SET_BUS_WIDTH, BUS_WIDTH_4)?;
+        self.regs.control0.modify(CONTROL0::DWIDTH4::SET);
+        self.set_clock(TRANSFER_CLOCK)?;
+        Ok(blocks)
+    }
+
+    /// Set up a transfer of `len` bytes starting at `block`, and send `single` or `multiple` for it
+    fn start_transfer(
+        &mut self,
+        block: u64,
+        len: usize,
+        single: Command,
+        multiple: Command,
+        dir: FieldValue<u32, CMDTM::Register>,
+    ) -> Result<(), &'static str> {
+        let count = len / BLOCK_SIZE;
+        let addr = if self.high_capacity { block } else { block * BLOCK_SIZE as u64 };
+        let addr = u32::try_from(addr).map_err(|_| "Block is out of range")?;
+        let regs = &self.regs;
+        spin_until(|| !regs.status.is_set(STATUS::DAT_INHIBIT), "SD data lines are busy")?;
+        self.regs.blksizecnt.write(
+            BLKSIZECNT::BLKSIZE.val(BLOCK_SIZE as u32) + BLKSIZECNT::BLKCNT.val(count as u32),
+        );
+        if count == 1 {
+            self.issue(single, addr, CMDTM::ISDATA::SET + dir)
+        } else {
+            let flags = CMDTM::BLKCNT_EN::SET + CMDTM::MULTI_BLOCK::SET + CMDTM::AUTO_CMD::Cmd12;
+            self.issue(multiple, addr, CMDTM::ISDATA::SET + dir + flags)
+        }
+    }
+
+    /// Read whole blocks starting at `block` into `buf`
+    pub fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let dir = CMDTM::DAT_DIR::CardToHost;
+        self.start_transfer(block, buf.len(), READ_SINGLE_BLOCK, READ_MULTIPLE_BLOCK, dir)?;
+        let addr = buf.as_ptr() as usize;
+        if let Some(channel) = self.dma.as_mut() {
+            if addr % DMA_READ_ALIGN == 0 && buf.len() % DMA_READ_ALIGN == 0 {
+                let dst = buf.as_mut_ptr() as usize;
+                let len = buf.len();
+                let transfer =
+                    ControlBlock::from_peripheral(self.data_bus, Peripheral::Emmc, dst, len)?;
+                channel.run(&mut [transfer])?;
+                return self.wait(INTERRUPT::DATA_DONE::SET.value);
+            }
+        }
+        for chunk in buf.chunks_exact_mut(BLOCK_SIZE) {
+            self.wait(INTERRUPT::READ_RDY::SET.value)?;
+            for word in chunk.chunks_exact_mut(4) {
+                word.copy_from_slice(&self.regs.data.get().to_le_bytes());
+            }
+        }
+        self.wait(INTERRUPT::DATA_DONE::SET.value)
+    }
+
+    /// Write whole blocks from `buf`, starting at `block`
+    pub fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), &'static str> {
+        let dir = CMDTM::DAT_DIR::HostToCard;
+        self.start_transfer(block, buf.len(), WRITE_BLOCK, WRITE_MULTIPLE_BLOCK, dir)?;
+        let src = buf.as_ptr() as usize;
+        if let Some(channel) = self.dma.as_mut() {
+            if src % DMA_WRITE_ALIGN == 0 {
+                let len = buf.len();
+                let transfer =
+                    ControlBlock::to_peripheral(src, self.data_bus, Peripheral::Emmc, len)?;
+                channel.run(&mut [transfer])?;
+                return self.wait(INTERRUPT::DATA_DONE::SET.value);
+            }
+        }
+        for chunk in buf.chunks_exact(BLOCK_SIZE) {
+            self.wait(INTERRUPT::WRITE_RDY::SET.value)?;
+            for word in chunk.chunks_exact(4) {
+                self.regs.data.set(u32::from_le_bytes(word.try_into().unwrap()));
+            }
+        }
+        self.wait(INTERRUPT::DATA_DONE::SET.value)
+    }
+}
+
+/// How many blocks a card has, going by its CSD. The controller leaves off the CRC byte, so bit
+/// `n` of the CSD is bit `n - 8` of the response.
+fn capacity(resp: [u32; 4]) -> u64 {
+    let csd = resp.iter().rev().fold(0u128, |csd, &word| csd << 32 | word as u128);
+    let bits = |start: u32, len: u32| (csd >> (start - 8)) as u64 & ((1 << len) - 1);
+    match bits(126, 2) {
+        // Version 1: C_SIZE, C_SIZE_MULT and READ_BL_LEN
+        0 => (bits(62, 12) + 1) << (bits(47, 3) + 2 + bits(80, 4)) >> 9,
+        // Version 2 and up: C_SIZE in 512KiB units
+        _ => (bits(48, 22) + 1) * 1024,
+    }
+}
+
+/// The SD card, as a block device
+pub struct Card {
+    controller: Mutex<Controller>,
+    blocks: u64,
+}
+
+static CARD: Once<Card> = Once::new();
+
+/// The SD card, if `init` found one
+pub fn card() -> Option<&'static Card> {
+    CARD.get()
+}
+
+impl BlockDevice for Card {
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(self, start, buf.len())?;
+        let mut controller = self.controller.lock();
+        for (idx, chunk) in buf.chunks_mut(MAX_TRANSFER_BLOCKS * BLOCK_SIZE).enumerate() {
+            controller.read(start + (idx * MAX_TRANSFER_BLOCKS) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(self, start, buf.len())?;
+        let mut controller = self.controller.lock();
+        for (idx, chunk) in buf.chunks(MAX_TRANSFER_BLOCKS * BLOCK_SIZE).enumerate() {
+            controller.write(start + (idx * MAX_TRANSFER_BLOCKS) as u64, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the SD card and get it ready. Needs `mailbox::init` and `irq::init`. Without a card this
+/// fails, and there's no `card()`.
+pub unsafe fn init() -> Result<(), &'static str> {
+    let addr = crate::fdt::device("brcm,bcm2835-sdhci")
+        .map_or(bus_to_phys(BUS_ADDR), |dev| dev.regs.start);
+    ADDR.store(addr, Ordering::Relaxed);
+    let regs = Emmc::new(addr);
+    let version = regs.slotisr_ver.read(SLOTISR_VER::SDVERSION);
+    let base_clock = crate::mailbox::clock_rate(ClockId::Emmc).unwrap_or(DEFAULT_BASE_CLOCK);
+    let irq = match crate::irq::register(crate::irq::EMMC, handle_irq) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("SD card interrupt: {}. Polling instead.", err);
+            false
+        }
+    };
+    let mut controller = Controller {
+        regs,
+        rca: 0,
+        high_capacity: false,
+        base_clock,
+        version,
+        irq,
+        dma: dma::alloc(),
+        data_bus: dma::peripheral_bus_addr(addr + core::mem::offset_of!(Registers, data)),
+    };
+
+    let blocks = controller.reset().and_then(|_| controller.identify());
+    let blocks = match blocks {
+        Ok(blocks) => blocks,
+        Err(err) => {
+            if irq {
+                crate::irq::unregister(crate::irq::EMMC);
+            }
+            return Err(err);
+        }
+    };
+    info!(
+        "SD card: {} blocks ({} MiB), {} capacity, host controller version {}",
+        blocks,
+        blocks * BLOCK_SIZE as u64 / (1024 * 1024),
+        if controller.high_capacity { "high" } else { "standard" },
+        version + 1,
+    );
+    CARD.call_once(|| Card { controller: Mutex::new(controller), blocks });
+    Ok(())
+}
@@ -2,7 +2,7 @@ use bitflags::bitflags;
 use bit_field::BitField;
 use num_enum::TryFromPrimitive;
 use tock_registers::{
-    interfaces::{Readable, Writeable},
+    interfaces::{ReadWriteable, Readable, Writeable},
     registers::InMemoryRegister,
 };
 use aarch64_cpu::{
@@ -10,7 +10,11 @@ use aarch64_cpu::{
     registers::*,
 };
 use arrayvec::ArrayString;
+use log::{debug, error, trace};
 use core::arch::global_asm;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use crate::smp::MAX_CPUS;
+use crate::sync::PerCpu;
 use crate::units::KB;
 global_asm!(include_str!("setup_handler.s"));
 
@@ -19,6 +23,24 @@ extern "Rust" {
 }
 
 static mut output: ArrayString::<{1 * KB as usize}> = ArrayString::new_const();
+/// Frame of the exception each core is handling, so a panic partway through can report it
+static CURRENT_FRAME: PerCpu<AtomicPtr<InterruptFrame>> =
+    PerCpu::new([const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS]);
+
+/// The state that was saved when the exception currently being handled was taken
+pub fn current_frame() -> Option<InterruptFrame> {
+    let frame = CURRENT_FRAME.get().load(Ordering::Acquire);
+    // Safety: Only set while the handler that owns the frame is running, and we are inside it
+    unsafe { frame.as_ref().copied() }
+}
+
+/// Publishes `frame` as the current one while `f` runs
+fn with_current_frame<R>(frame: &mut InterruptFrame, f: impl FnOnce(&mut InterruptFrame) -> R) -> R {
+    let outer = CURRENT_FRAME.get().swap(frame, Ordering::AcqRel);
+    let res = f(frame);
+    CURRENT_FRAME.get().store(outer, Ordering::Release);
+    res
+}
 struct ExceptionCause(InMemoryRegister<u64, ESR_EL1::Register>);
 impl ExceptionCause {
     pub fn new() -> Self {
@@ -35,7 +57,7 @@ impl ExceptionCause {
 
         let val = self.0.get();
         let iss2 = val.get_bits(32..=55);
-        println!("iss = {:b}", iss2);
+        trace!("iss = {:b}", iss2);
         iss2.try_into().ok()
     }
 }
@@ -53,21 +75,45 @@ enum DataAbortCause {
 // https://krinkinmu.github.io/2021/01/10/aarch64-interrupt-handling.html
 #[no_mangle]
 pub extern "C" fn __handle_exception(frame: &mut InterruptFrame) {
+    if frame.from_user() {
+        handle_user_exception(frame);
+        crate::process::return_to_user();
+        return;
+    }
+    with_current_frame(frame, handle_exception)
+}
 
+/// An exception from a process. Nothing it does should take down the kernel, so anything we
+/// don't expect kills it.
+fn handle_user_exception(frame: &mut InterruptFrame) {
+    // Raw, since a process can cause exceptions the enum doesn't know about
+    let cause = ESR_EL1.read(ESR_EL1::EC);
+    if cause == ESR_EL1::EC::Value::SVC64 as u64 {
+        crate::process::syscall::dispatch(frame);
+        return;
+    }
+    error!(
+        "Process {} faulted at {:#x}: cause {:#x}, address {:#x}",
+        crate::process::current_pid().unwrap_or(0),
+        frame.elr(),
+        cause,
+        frame.far(),
+    );
+    crate::process::kill_current();
+}
+
+fn handle_exception(frame: &mut InterruptFrame) {
     use ESR_EL1::EC::Value as Cause;
     let syndrome_reg = ExceptionCause::new();
     let cause = syndrome_reg.get_cause();
     match cause {
         Cause::DataAbortCurrentEL => {
             use DataAbortCause::*;
-            println!("Kernel experienced a page fault.");
+            error!("Kernel experienced a page fault.");
             let abort_cause = syndrome_reg.get_data_abort_cause();
-            println!("Fault cause: {:?}", abort_cause);
-        },
-        _ => {
-            println!("Unknown/unhandled exception type. 0x{:x}", cause as  u64);
-            loop {}
+            error!("Fault cause: {:?}", abort_cause);
         },
+        _ => panic!("Unknown/unhandled exception type. 0x{:x}", cause as  u64),
 
     }
     crate::mmu::without_mmu! {
@@ -77,17 +123,46 @@ pub extern "C" fn __handle_exception(frame: &mut InterruptFrame) {
 
 #[no_mangle]
 pub unsafe extern "C" fn __handle_interrupt(frame: &mut InterruptFrame) {
-    crate::mmu::without_mmu! {
-        do_exc(InterruptType::Interrupt, &mut *frame);
+    with_current_frame(frame, |frame| {
+        if !handle_irq() {
+            crate::mmu::without_mmu! {
+                do_exc(InterruptType::Interrupt, &mut *frame);
+            }
+        }
+    });
+    // Last, since we may not come back here for a while. The frame stays on this thread's stack.
+    crate::thread::preempt();
+    if frame.from_user() {
+        crate::process::return_to_user();
+    }
+}
+
+/// Handle whatever the ARM-local interrupt controller says is pending for this core.
+/// Returns `false` if there was something we don't know how to handle.
+fn handle_irq() -> bool {
+    use crate::arm_local::IRQ_SOURCE;
+    let source = crate::arm_local::irq_source(crate::smp::cpu_id());
+    let mut handled = false;
+    if source.read(IRQ_SOURCE::MAILBOX) & (1 << crate::ipi::MAILBOX) != 0 {
+        crate::ipi::handle_pending();
+        handled = true;
+    }
+    if source.is_set(IRQ_SOURCE::CNTPNSIRQ) {
+        crate::time::rearm_tick();
+        crate::thread::request_resched();
+        handled = true;
     }
+    if source.is_set(IRQ_SOURCE::GPU) {
+        handled |= crate::irq::handle_pending();
+    }
+    handled
 }
 
 fn do_exc(int_type: InterruptType, frame: &mut InterruptFrame) {
     let el = CurrentEL.read(CurrentEL::EL);
     // println!("Exception occured");
     use core::fmt::Write;
-    println!("Handling interrupt. Type = {:?}", int_type);
-    println!("{:x?}", frame);
+    debug!("Handling interrupt. Type = {:?}", int_type);
     // let mut u = crate::uart::get();
     // unsafe {
     //     write!(output, "IN EXC {}", el).ok();
@@ -95,8 +170,7 @@ fn do_exc(int_type: InterruptType, frame: &mut InterruptFrame) {
     //
     // }
 
-    loop {}
-    // panic!("Exceptio");
+    panic!("Unhandled {:?}", int_type);
 }
 
 pub unsafe fn init_el2() {
@@ -106,6 +180,8 @@ pub unsafe fn init_el2() {
 }
 pub unsafe fn init() {
     VBAR_EL1.set(__exception_vector_table.get() as u64);
+    // Processes use FP/SIMD (musl's string functions do, for one). `__switch_context` saves it.
+    CPACR_EL1.modify(CPACR_EL1::FPEN::TrapNothing);
     barrier::isb(barrier::SY);
     enable_interrupts();
 }
@@ -124,6 +200,31 @@ pub fn enable_interrupts() {
                + DAIF::F::Unmasked);
 }
 
+/// Whether IRQs can come in right now
+pub fn interrupts_enabled() -> bool {
+    !DAIF.is_set(DAIF::I)
+}
+
+/// Mask IRQs. Returns the old mask to hand to `restore_interrupts`.
+pub fn mask_interrupts() -> u64 {
+    let saved = DAIF.get();
+    DAIF.modify(DAIF::I::Masked);
+    saved
+}
+
+/// Put back the mask `mask_interrupts` returned
+pub fn restore_interrupts(saved: u64) {
+    DAIF.set(saved);
+}
+
+/// Run `f` with IRQs masked, then put the mask back how it was
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let saved = mask_interrupts();
+    let res = f();
+    restore_interrupts(saved);
+    res
+}
+
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub enum InterruptType {
     Interrupt,
@@ -154,7 +255,50 @@ pub struct InterruptFrame {
     x18: u64,
     fp: u64,
     lr: u64,
-    xzr: u64,
+    sp_el0: u64,
     esr: u64,
     far: u64,
+    /// Where the exception was taken from, and where we return to
+    elr: u64,
+    spsr: u64,
+}
+
+impl InterruptFrame {
+    /// Frame pointer of the code that was interrupted
+    pub fn fp(&self) -> usize {
+        self.fp as usize
+    }
+
+    pub fn lr(&self) -> usize {
+        self.lr as usize
+    }
+
+    /// Address of the instruction the exception was taken at
+    pub fn elr(&self) -> usize {
+        self.elr as usize
+    }
+
+    /// Whether the exception was taken from EL0
+    pub fn from_user(&self) -> bool {
+        self.spsr & 0b1111 == 0
+    }
+
+    pub fn far(&self) -> usize {
+        self.far as usize
+    }
+
+    /// Which system call an `svc` is making, per the Linux aarch64 ABI: the number is in x8
+    pub fn syscall_number(&self) -> u64 {
+        self.x8
+    }
+
+    /// System call arguments, from x0-x5
+    pub fn syscall_args(&self) -> [u64; 6] {
+        [self.x0, self.x1, self.x2, self.x3, self.x4, self.x5]
+    }
+
+    /// Results go back in x0
+    pub fn set_syscall_return(&mut self, value: u64) {
+        self.x0 = value;
+    }
 }
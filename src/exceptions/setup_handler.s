@@ -27,15 +27,16 @@ __exception_vector_table:
 .balign 0x80
   b exception_entry
 
-// From lower EL in aarch64
+// From lower EL in aarch64. SP_EL1 is still wherever the thread's kernel stack was when it
+// dropped to EL0, so the same entries work.
 .balign 0x80
-  b .
+  b exception_entry
 .balign 0x80
-  b .
+  b interrupt_entry
 .balign 0x80
-  b .
+  b interrupt_entry
 .balign 0x80
-  b .
+  b exception_entry
 
 // From lower EL in aarch32
 .balign 0x80
@@ -48,7 +49,7 @@ __exception_vector_table:
   b .
 
 exception_entry:
-    sub sp, sp, #192
+    sub sp, sp, #208
     stp x0, x1, [sp, #0]
     stp x2, x3, [sp, #16]
     stp x4, x5, [sp, #32]
@@ -59,15 +60,27 @@ exception_entry:
     stp x14, x15, [sp, #112]
     stp x16, x17, [sp, #128]
     stp x18, x29, [sp, #144]
-    stp x30, xzr, [sp, #160]
+    // User stack pointer. Another thread may drop to EL0 before we get back.
+    mrs x0, SP_EL0
+    stp x30, x0, [sp, #160]
 
     mrs x0, ESR_EL1
     mrs x1, FAR_EL1
     stp x0, x1, [sp, #176]
+    mrs x0, ELR_EL1
+    mrs x1, SPSR_EL1
+    stp x0, x1, [sp, #192]
 
     mov x0, sp
     bl __handle_exception
 
+    // The handler may have changed where we return to
+    ldp x0, x1, [sp, #192]
+    msr ELR_EL1, x0
+    msr SPSR_EL1, x1
+    ldr x0, [sp, #168]
+    msr SP_EL0, x0
+
     ldp x0, x1, [sp, #0]
     ldp x2, x3, [sp, #16]
     ldp x4, x5, [sp, #32]
@@ -78,12 +91,12 @@ exception_entry:
     ldp x14, x15, [sp, #112]
     ldp x16, x17, [sp, #128]
     ldp x18, x29, [sp, #144]
-    ldp x30, xzr, [sp, #160]
-    add sp, sp, #192
+    ldr x30, [sp, #160]
+    add sp, sp, #208
     eret
 
 interrupt_entry:
-    sub sp, sp, #192
+    sub sp, sp, #208
     stp x0, x1, [sp, #0]
     stp x2, x3, [sp, #16]
     stp x4, x5, [sp, #32]
@@ -94,15 +107,27 @@ interrupt_entry:
     stp x14, x15, [sp, #112]
     stp x16, x17, [sp, #128]
     stp x18, x29, [sp, #144]
-    stp x30, xzr, [sp, #160]
+    // User stack pointer. Another thread may drop to EL0 before we get back.
+    mrs x0, SP_EL0
+    stp x30, x0, [sp, #160]
 
     mrs x0, ESR_EL1
     mrs x1, FAR_EL1
     stp x0, x1, [sp, #176]
+    mrs x0, ELR_EL1
+    mrs x1, SPSR_EL1
+    stp x0, x1, [sp, #192]
 
     mov x0, sp
     bl __handle_interrupt
 
+    // The handler may have changed where we return to
+    ldp x0, x1, [sp, #192]
+    msr ELR_EL1, x0
+    msr SPSR_EL1, x1
+    ldr x0, [sp, #168]
+    msr SP_EL0, x0
+
     ldp x0, x1, [sp, #0]
     ldp x2, x3, [sp, #16]
     ldp x4, x5, [sp, #32]
@@ -113,8 +138,8 @@ interrupt_entry:
     ldp x14, x15, [sp, #112]
     ldp x16, x17, [sp, #128]
     ldp x18, x29, [sp, #144]
-    ldp x30, xzr, [sp, #160]
-    add sp, sp, #192
+    ldr x30, [sp, #160]
+    add sp, sp, #208
     eret
 
 /*
@@ -0,0 +1,640 @@
+//! Reading the flattened device tree the firmware (or QEMU's `-dtb`) hands us in x0.
+//!
+//! The blob is never copied: nodes and properties are slices of it, and lookups walk the
+//! structure block from the start. That's slow, but the tree is small and only read at boot.
+//! Everything in it is big endian.
+//! https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+use arrayvec::ArrayVec;
+use core::ops::Range;
+use log::{info, warn};
+use spin::Once;
+
+const MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+/// Deepest node we can look at
+const MAX_DEPTH: usize = 8;
+/// Most cells in one `interrupts` property we keep
+pub const MAX_INTERRUPT_CELLS: usize = 8;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// What a node's children get when it doesn't say
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+/// A property holding one 32 or 64 bit number, depending on its length
+pub fn read_number(value: &[u8]) -> Option<u64> {
+    match value.len() {
+        4 => Some(u32::from_be_bytes(value.try_into().unwrap()) as u64),
+        8 => Some(u64::from_be_bytes(value.try_into().unwrap())),
+        _ => None,
+    }
+}
+
+/// A number `cells` 32 bit cells long from the front of `value`, and what's left after it
+fn take_cells(value: &[u8], cells: u32) -> Option<(u64, &[u8])> {
+    let len = cells as usize * 4;
+    let bytes = value.get(..len)?;
+    // Only the low 64 bits fit. Nothing on a Pi needs more.
+    let number = bytes
+        .chunks_exact(4)
+        .fold(0u64, |acc, cell| acc << 32 | u32::from_be_bytes(cell.try_into().unwrap()) as u64);
+    Some((number, &value[len..]))
+}
+
+/// A NUL terminated string at the front of `bytes`, and where the bytes after its NUL start
+fn c_str(bytes: &[u8]) -> Option<(&str, usize)> {
+    let len = bytes.iter().position(|&b| b == 0)?;
+    Some((core::str::from_utf8(&bytes[..len]).ok()?, len + 1))
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    reservations: &'a [u8],
+}
+
+impl Fdt<'static> {
+    /// The device tree at `addr`, if there is one there
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be readable, and whatever is there must stay put for as long as the kernel runs
+    pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+        if addr == 0 || addr % 4 != 0 {
+            return None;
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE);
+        if be_u32(header, 0)? != MAGIC {
+            return None;
+        }
+        let size = be_u32(header, 4)? as usize;
+        Fdt::new(core::slice::from_raw_parts(addr as *const u8, size)).ok()
+    }
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(blob: &'a [u8]) -> Result<Self, &'static str> {
+        let field = |offset| be_u32(blob, offset).ok_or("Device tree is truncated");
+        if field(0)? != MAGIC {
+            return Err("Not a device tree");
+        }
+        let section = |offset_field, size_field| -> Result<&'a [u8], &'static str> {
+            let start = field(offset_field)? as usize;
+            let end = start.checked_add(field(size_field)? as usize);
+            end.and_then(|end| blob.get(start..end)).ok_or("Device tree section is out of bounds")
+        };
+        let reservations_start = field(16)? as usize;
+        Ok(Fdt {
+            blob,
+            structs: section(8, 36)?,
+            strings: section(12, 32)?,
+            reservations: blob
+                .get(reservations_start..)
+                .ok_or("Device tree section is out of bounds")?,
+        })
+    }
+
+    /// The whole blob
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.blob
+    }
+
+    fn string(&self, offset: usize) -> Option<&'a str> {
+        Some(c_str(self.strings.get(offset..)?)?.0)
+    }
+
+    /// Memory the tree says nobody else may use, besides the tree itself
+    pub fn reservations(&self) -> impl Iterator<Item = Range<usize>> + 'a {
+        let reservations = self.reservations;
+        (0..)
+            .map_while(move |idx| {
+                let entry = reservations.get(idx * 16..idx * 16 + 16)?;
+                let (addr, rest) = take_cells(entry, 2)?;
+                let (size, _) = take_cells(rest, 2)?;
+                // An all zero entry ends the list
+                (size != 0 || addr != 0).then_some(addr as usize..addr.checked_add(size)? as usize)
+            })
+    }
+
+    /// Every node, parents before their children
+    pub fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            fdt: *self,
+            offset: 0,
+            levels: ArrayVec::new(),
+            done: false,
+        }
+    }
+
+    /// The node at `path`, like `/soc/serial@7e215040`. Leaving a unit address off matches any.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let mut components = ArrayVec::<&str, MAX_DEPTH>::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            components.try_push(component).ok()?;
+        }
+        // Walk down one component at a time, skipping subtrees that can't match
+        let mut matched = 0;
+        for node in self.nodes() {
+            if node.depth == 0 {
+                if components.is_empty() {
+                    return Some(node);
+                }
+                continue;
+            }
+            if node.depth > matched + 1 {
+                continue;
+            }
+            if node.depth <= matched {
+                // Left the subtree that matched so far. Paths are unique, so it isn't here.
+                return None;
+            }
+            if node.name_matches(components[matched]) {
+                matched += 1;
+                if matched == components.len() {
+                    return Some(node);
+                }
+            }
+        }
+        None
+    }
+
+    /// The value of property `name` on the node at `path`
+    pub fn property(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        self.find_node(path)?.property(name)
+    }
+
+    /// The first node whose `compatible` list has `compatible` in it
+    pub fn find_compatible(&self, compatible: &str) -> Option<Node<'a>> {
+        self.nodes().find(|node| node.is_compatible(compatible))
+    }
+
+    /// The node with `phandle`, which is how properties like `clocks` point at other nodes
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.nodes().find(|node| {
+            let value = node.property("phandle").or_else(|| node.property("linux,phandle"));
+            value.and_then(read_number) == Some(phandle as u64)
+        })
+    }
+
+    /// The banks of RAM the `/memory` nodes list
+    pub fn memory(&self) -> ArrayVec<Range<usize>, 4> {
+        self.nodes()
+            .filter(|node| node.depth == 1 && node.name_matches("memory"))
+            .flat_map(|node| node.reg_phys().take(4).collect::<ArrayVec<_, 4>>())
+            .take(4)
+            .collect()
+    }
+
+    /// Where the main peripherals (`/soc`) are, as their first bus address and the physical
+    /// range it maps to
+    pub fn peripherals(&self) -> Option<(usize, Range<usize>)> {
+        let soc = self.find_node("/soc")?;
+        let root = soc.ancestors.last().copied().unwrap_or_default();
+        let cells = |name, default| soc.property_number(name).map_or(default, |cells| cells as u32);
+        let ranges = soc.property("ranges")?;
+        let (bus, rest) = take_cells(ranges, cells("#address-cells", DEFAULT_ADDRESS_CELLS))?;
+        let (phys, rest) = take_cells(rest, root.address_cells)?;
+        let (size, _) = take_cells(rest, cells("#size-cells", DEFAULT_SIZE_CELLS))?;
+        Some((bus as usize, phys as usize..phys.checked_add(size)? as usize))
+    }
+}
+
+/// What a node says about its children's addresses
+#[derive(Clone, Copy, Debug)]
+struct Level<'a> {
+    address_cells: u32,
+    size_cells: u32,
+    /// How the children's addresses map to ours. `None` means they don't.
+    ranges: Option<&'a [u8]>,
+}
+
+impl Default for Level<'_> {
+    fn default() -> Self {
+        Level {
+            address_cells: DEFAULT_ADDRESS_CELLS,
+            size_cells: DEFAULT_SIZE_CELLS,
+            ranges: None,
+        }
+    }
+}
+
+pub struct Nodes<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    /// One for each node we're inside of, filled in as its properties go by
+    levels: ArrayVec<Level<'a>, MAX_DEPTH>,
+    done: bool,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        while !self.done {
+            let Some(token) = be_u32(self.fdt.structs, self.offset) else {
+                break;
+            };
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let (name, len) = c_str(self.fdt.structs.get(self.offset..)?)?;
+                    self.offset = (self.offset + len).next_multiple_of(4);
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth: self.levels.len(),
+                        props: self.offset,
+                        ancestors: self.levels.clone(),
+                    };
+                    if self.levels.try_push(Level::default()).is_err() {
+                        warn!("Device tree is nested too deeply");
+                        break;
+                    }
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.levels.pop();
+                }
+                FDT_PROP => {
+                    let (name, value, next) = self.fdt.read_prop(self.offset)?;
+                    self.offset = next;
+                    let level = self.levels.last_mut()?;
+                    match name {
+                        "#address-cells" => level.address_cells = read_number(value)? as u32,
+                        "#size-cells" => level.size_cells = read_number(value)? as u32,
+                        "ranges" => level.ranges = Some(value),
+                        _ => {}
+                    }
+                }
+                FDT_NOP => {}
+                _ => break,
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+impl<'a> Fdt<'a> {
+    /// The property whose length field is at `offset`, and where the next token starts
+    fn read_prop(&self, offset: usize) -> Option<(&'a str, &'a [u8], usize)> {
+        let len = be_u32(self.structs, offset)? as usize;
+        let name = self.string(be_u32(self.structs, offset + 4)? as usize)?;
+        let value = self.structs.get(offset + 8..offset + 8 + len)?;
+        Some((name, value, (offset + 8 + len).next_multiple_of(4)))
+    }
+}
+
+#[derive(Clone)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// The root is 0
+    depth: usize,
+    /// Where its properties start in the structure block
+    props: usize,
+    /// The nodes above it, root first
+    ancestors: ArrayVec<Level<'a>, MAX_DEPTH>,
+}
+
+impl<'a> Node<'a> {
+    /// Including the unit address, like `serial@7e215040`
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Whether it's the node `component` names. Leaving the unit address out matches any.
+    fn name_matches(&self, component: &str) -> bool {
+        self.name == component
+            || (!component.contains('@') && self.name.split('@').next() == Some(component))
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> + 'a {
+        let fdt = self.fdt;
+        let mut offset = self.props;
+        core::iter::from_fn(move || loop {
+            match be_u32(fdt.structs, offset)? {
+                FDT_PROP => {
+                    let (name, value, next) = fdt.read_prop(offset + 4)?;
+                    offset = next;
+                    return Some((name, value));
+                }
+                FDT_NOP => offset += 4,
+                // Its children, or its end
+                _ => return None,
+            }
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties().find(|&(prop, _)| prop == name).map(|(_, value)| value)
+    }
+
+    /// A property that is a single string
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        Some(c_str(self.property(name)?)?.0)
+    }
+
+    /// A property that is one 32 or 64 bit number
+    pub fn property_number(&self, name: &str) -> Option<u64> {
+        read_number(self.property(name)?)
+    }
+
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> + 'a {
+        let list = self.property("compatible").unwrap_or(&[]);
+        list.split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    /// Whether it's turned on. Nodes without a `status` are.
+    pub fn is_enabled(&self) -> bool {
+        matches!(self.property_str("status"), None | Some("okay") | Some("ok"))
+    }
+
+    /// The `reg` entries, as addresses on the parent's bus
+    pub fn reg(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let parent = self.ancestors.last().copied().unwrap_or_default();
+        let mut value = self.property("reg").unwrap_or(&[]);
+        core::iter::from_fn(move || {
+            let (addr, rest) = take_cells(value, parent.address_cells)?;
+            let (size, rest) = take_cells(rest, parent.size_cells)?;
+            value = rest;
+            Some((addr, size))
+        })
+    }
+
+    /// The `reg` entries as CPU physical addresses, going through every parent's `ranges`.
+    /// Entries that don't reach the CPU's bus are left out.
+    pub fn reg_phys(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.reg().filter_map(|(addr, size)| {
+            let addr = self.translate(addr)?;
+            Some(addr as usize..addr.checked_add(size)? as usize)
+        })
+    }
+
+    /// Map an address on our parent's bus up to the root's
+    fn translate(&self, mut addr: u64) -> Option<u64> {
+        // Each level past the root maps its children's addresses into its own parent's
+        for idx in (1..self.ancestors.len()).rev() {
+            let level = self.ancestors[idx];
+            let parent_cells = self.ancestors[idx - 1].address_cells;
+            let mut ranges = level.ranges?;
+            if ranges.is_empty() {
+                // Empty `ranges` means the same addresses on both sides
+                continue;
+            }
+            let mut found = None;
+            while !ranges.is_empty() {
+                let (child, rest) = take_cells(ranges, level.address_cells)?;
+                let (parent, rest) = take_cells(rest, parent_cells)?;
+                let (size, rest) = take_cells(rest, level.size_cells)?;
+                ranges = rest;
+                if (child..child.saturating_add(size)).contains(&addr) {
+                    found = (addr - child).checked_add(parent);
+                    break;
+                }
+            }
+            addr = found?;
+        }
+        Some(addr)
+    }
+
+    /// The raw `interrupts` cells. What they mean is up to the interrupt controller: for the
+    /// BCM2835's it's a bank and a number, for the ARM-local one a number and flags.
+    pub fn interrupts(&self) -> ArrayVec<u32, MAX_INTERRUPT_CELLS> {
+        self.property("interrupts")
+            .unwrap_or(&[])
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+            .take(MAX_INTERRUPT_CELLS)
+            .collect()
+    }
+
+    /// How fast its clock runs, from `clock-frequency` or the first clock in `clocks` that says
+    pub fn clock_frequency(&self) -> Option<u64> {
+        if let Some(freq) = self.property_number("clock-frequency") {
+            return Some(freq);
+        }
+        // Each entry is a phandle and however many cells that clock wants. We only follow the
+        // first, since we can't tell how long it is without asking its provider.
+        let clocks = self.property("clocks")?;
+        let phandle = u32::from_be_bytes(clocks.get(..4)?.try_into().unwrap());
+        self.fdt.find_phandle(phandle)?.property_number("clock-frequency")
+    }
+}
+
+/// A device the tree tells us about
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// Its registers, as physical addresses
+    pub regs: Range<usize>,
+    pub interrupts: ArrayVec<u32, MAX_INTERRUPT_CELLS>,
+    pub clock_frequency: Option<u64>,
+}
+
+static FDT: Once<Fdt<'static>> = Once::new();
+/// Where RAM is. `frames` only uses the part of it below `frames::RAM_END`.
+static MEMORY: Once<ArrayVec<Range<usize>, 4>> = Once::new();
+
+/// Find the device tree the firmware left at `boot_arg` and keep it from being reused.
+/// Booting without one is fine, everything falls back to what a Pi 3 has.
+/// Must run before anything allocates pages.
+pub fn init(boot_arg: usize) {
+    // Safety: The firmware put it in RAM, and nothing has been put over it yet
+    let Some(fdt) = (unsafe { Fdt::from_addr(boot_arg) }) else {
+        return;
+    };
+    let fdt = FDT.call_once(|| fdt);
+    let blob = fdt.as_bytes();
+    crate::mmu::frames::reserve(blob.as_ptr() as usize..blob.as_ptr() as usize + blob.len());
+    for range in fdt.reservations() {
+        crate::mmu::frames::reserve(range);
+    }
+
+    let memory = fdt.memory();
+    if !memory.is_empty() {
+        MEMORY.call_once(|| memory);
+    }
+}
+
+/// Say what `init` found. Separate, since `init` runs before there's anywhere to print to.
+pub fn report() {
+    let Some(fdt) = get() else {
+        info!("No device tree");
+        return;
+    };
+    let model = fdt.find_node("/").and_then(|root| root.property_str("model"));
+    info!(
+        "Device tree at {:#x}: {}, memory {:x?}",
+        fdt.as_bytes().as_ptr() as usize,
+        model.unwrap_or("unknown model"),
+        MEMORY.get()
+    );
+}
+
+pub fn get() -> Option<&'static Fdt<'static>> {
+    FDT.get()
+}
+
+/// The banks of RAM the device tree lists, if there was one
+pub fn memory() -> Option<&'static [Range<usize>]> {
+    MEMORY.get().map(|memory| memory.as_slice())
+}
+
+/// The kernel command line from `/chosen/bootargs`
+pub fn bootargs() -> Option<&'static str> {
+    get()?.find_node("/chosen")?.property_str("bootargs")
+}
+
+/// Where the boot loader put the initrd, from `/chosen`
+pub fn initrd() -> Option<Range<usize>> {
+    let chosen = get()?.find_node("/chosen")?;
+    let start = chosen.property_number("linux,initrd-start")? as usize;
+    let end = chosen.property_number("linux,initrd-end")? as usize;
+    Some(start..end)
+}
+
+/// The first enabled device compatible with `compatible`
+pub fn device(compatible: &str) -> Option<Device> {
+    let node = get()?.nodes().find(|node| node.is_compatible(compatible) && node.is_enabled())?;
+    Some(Device {
+        regs: node.reg_phys().next()?,
+        interrupts: node.interrupts(),
+        clock_frequency: node.clock_frequency(),
+    })
+}
+
+/// Where the main peripherals are, going by the device tree we booted with. See
+/// `Fdt::peripherals`.
+pub fn peripherals() -> Option<(usize, Range<usize>)> {
+    get()?.peripherals()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// See `make_dtb.py` for what's in it
+    const TREE: &[u8] = include_bytes!("process/testdata/tree.dtb");
+
+    fn tree() -> Fdt<'static> {
+        Fdt::new(TREE).unwrap()
+    }
+
+    /// Where `bytes` first shows up in the tree
+    fn offset_of(bytes: &[u8]) -> usize {
+        TREE.windows(bytes.len()).position(|window| window == bytes).unwrap()
+    }
+
+    #[test_case]
+    fn finds_nodes() {
+        let fdt = tree();
+        assert_eq!(fdt.find_node("/").unwrap().property_str("model"), Some("Test Pi"));
+        assert_eq!(fdt.find_node("/soc/serial@7e201000").unwrap().name(), "serial@7e201000");
+        // Without a unit address, the first one
+        assert_eq!(fdt.find_node("/soc/serial").unwrap().name(), "serial@7e215040");
+        assert_eq!(fdt.find_node("soc/clock").unwrap().property_number("phandle"), Some(1));
+        assert!(fdt.find_node("/soc/serial@1234").is_none());
+        assert!(fdt.find_node("/serial@7e215040").is_none());
+        assert!(fdt.find_node("/gpu/dev@10/nope").is_none());
+
+        assert_eq!(
+            fdt.property("/chosen", "bootargs"),
+            Some(&b"console=serial0 init=/bin/sh\0"[..])
+        );
+        let names: ArrayVec<&str, 16> = fdt.nodes().map(|node| node.name()).collect();
+        assert_eq!(names.len(), 11);
+        assert_eq!(names[..3], ["", "memory@0", "memory@40000000"]);
+    }
+
+    #[test_case]
+    fn reads_devices() {
+        let fdt = tree();
+        let uart = fdt.find_compatible("brcm,bcm2835-aux-uart").unwrap();
+        assert!(uart.is_enabled());
+        assert_eq!(uart.interrupts().as_slice(), [1, 29]);
+        // Through `clocks`
+        assert_eq!(uart.clock_frequency(), Some(250_000_000));
+
+        let pl011 = fdt.find_compatible("arm,primecell").unwrap();
+        assert!(pl011.is_compatible("arm,pl011"));
+        assert!(!pl011.is_enabled());
+        assert!(pl011.interrupts().is_empty());
+    }
+
+    #[test_case]
+    fn translates_reg_through_ranges() {
+        let fdt = tree();
+        let uart = fdt.find_node("/soc/serial@7e215040").unwrap();
+        assert_eq!(uart.reg().collect::<ArrayVec<_, 2>>().as_slice(), [(0x7E21_5040, 0x40)]);
+        let regs: ArrayVec<_, 2> = uart.reg_phys().collect();
+        assert_eq!(regs.as_slice(), [0x3F21_5040..0x3F21_5080]);
+        // Outside of the soc's `ranges`, and under a node without any
+        assert_eq!(fdt.find_node("/soc/unmapped").unwrap().reg_phys().count(), 0);
+        assert_eq!(fdt.find_node("/gpu/dev").unwrap().reg_phys().count(), 0);
+
+        assert_eq!(fdt.peripherals(), Some((0x7E00_0000, 0x3F00_0000..0x4000_0000)));
+        assert_eq!(fdt.memory().as_slice(), [0..0x3C00_0000, 0x4000_0000..0x5000_0000]);
+        assert_eq!(fdt.reservations().collect::<ArrayVec<_, 2>>().as_slice(), [0x1000..0x3000]);
+    }
+
+    #[test_case]
+    fn rejects_bad_headers() {
+        assert_eq!(Fdt::new(&TREE[..20]).err(), Some("Device tree is truncated"));
+        assert_eq!(Fdt::new(&TREE[4..]).err(), Some("Not a device tree"));
+        let err = Some("Device tree section is out of bounds");
+        assert_eq!(Fdt::new(&TREE[..TREE.len() / 2]).err(), err);
+
+        let mut blob = [0; TREE.len()];
+        blob.copy_from_slice(TREE);
+        // Structure block offset and size that wrap around
+        blob[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        blob[36..40].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(Fdt::new(&blob).err(), err);
+    }
+
+    #[test_case]
+    fn stops_at_corrupt_structure() {
+        let mut blob = [0; TREE.len()];
+
+        // A bad token where `/chosen` starts ends the walk there
+        blob.copy_from_slice(TREE);
+        let chosen = offset_of(b"chosen\0") - 4;
+        blob[chosen..chosen + 4].copy_from_slice(&0x77u32.to_be_bytes());
+        let fdt = Fdt::new(&blob).unwrap();
+        assert!(fdt.find_node("/memory@0").is_some());
+        assert!(fdt.find_node("/chosen").is_none());
+        assert!(fdt.find_node("/soc").is_none());
+        assert_eq!(fdt.nodes().count(), 3);
+
+        // A property running off the end of the block
+        blob.copy_from_slice(TREE);
+        let len = offset_of(b"console=serial0") - 8;
+        blob[len..len + 4].copy_from_slice(&0xFFFF_FF00u32.to_be_bytes());
+        let fdt = Fdt::new(&blob).unwrap();
+        let chosen = fdt.find_node("/chosen").unwrap();
+        assert!(chosen.property("bootargs").is_none());
+        assert!(fdt.find_node("/soc").is_none());
+
+        // Reservations that wrap around end the list
+        blob.copy_from_slice(TREE);
+        blob[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(Fdt::new(&blob).unwrap().reservations().count(), 0);
+    }
+}
@@ -0,0 +1,378 @@
+//! Parser for the subset of ANSI/VT100 escape sequences the text console understands.
+//!
+//! https://vt100.net/emu/dec_ansi_parser
+//! https://en.wikipedia.org/wiki/ANSI_escape_code
+use arrayvec::ArrayVec;
+use embedded_graphics::pixelcolor::Rgb888;
+
+/// Parameters past this are dropped
+const MAX_PARAMS: usize = 16;
+
+/// Something the console should do in response to the input stream
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Print(char),
+    CarriageReturn,
+    LineFeed,
+    Tab,
+    Backspace,
+    Bell,
+    CursorUp(u32),
+    CursorDown(u32),
+    CursorForward(u32),
+    CursorBack(u32),
+    /// Zero-based row and column
+    CursorTo { row: u32, col: u32 },
+    /// Zero-based column
+    CursorColumn(u32),
+    EraseDisplay(Erase),
+    EraseLine(Erase),
+    Sgr(Sgr),
+}
+
+/// Which part of the line/screen an erase applies to
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Erase {
+    /// From the cursor to the end, inclusive
+    ToEnd,
+    /// From the start to the cursor, inclusive
+    ToStart,
+    All,
+}
+
+/// Select Graphic Rendition attributes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Sgr {
+    Reset,
+    Bold,
+    NormalIntensity,
+    Inverse,
+    NotInverse,
+    Foreground(Color),
+    Background(Color),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Color {
+    Default,
+    /// Index into the xterm 256 color palette
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    pub fn to_rgb(self, default: Rgb888) -> Rgb888 {
+        match self {
+            Color::Default => default,
+            Color::Indexed(idx) => palette(idx),
+            Color::Rgb(r, g, b) => Rgb888::new(r, g, b),
+        }
+    }
+}
+
+/// The xterm 256 color palette
+/// https://en.wikipedia.org/wiki/ANSI_escape_code#8-bit
+fn palette(idx: u8) -> Rgb888 {
+    const BASE: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    match idx {
+        0..=15 => {
+            let (r, g, b) = BASE[idx as usize];
+            Rgb888::new(r, g, b)
+        }
+        // 6x6x6 color cube
+        16..=231 => {
+            let idx = (idx - 16) as usize;
+            Rgb888::new(CUBE_LEVELS[idx / 36], CUBE_LEVELS[(idx / 6) % 6], CUBE_LEVELS[idx % 6])
+        }
+        // Grayscale ramp
+        232..=255 => {
+            let level = 8 + 10 * (idx - 232);
+            Rgb888::new(level, level, level)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+pub struct Parser {
+    state: State,
+    params: ArrayVec<u32, MAX_PARAMS>,
+    /// Parameter currently being read
+    current: Option<u32>,
+    /// Set for DEC private sequences (`ESC [ ?`). We don't support any, so they are ignored.
+    private: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            params: ArrayVec::new_const(),
+            current: None,
+            private: false,
+        }
+    }
+
+    /// Feed a character to the parser.
+    ///
+    /// Returns what the console should do with it. Most characters produce at most one action, but
+    /// a single SGR sequence can set several attributes at once.
+    pub fn advance(&mut self, c: char) -> ArrayVec<Action, MAX_PARAMS> {
+        let mut actions = ArrayVec::new();
+        match self.state {
+            State::Ground => match c {
+                '\x1b' => self.state = State::Escape,
+                '\r' => actions.push(Action::CarriageReturn),
+                // Vertical tab and form feed are treated like line feed, same as xterm
+                '\n' | '\x0b' | '\x0c' => actions.push(Action::LineFeed),
+                '\t' => actions.push(Action::Tab),
+                '\x08' => actions.push(Action::Backspace),
+                '\x07' => actions.push(Action::Bell),
+                // Every other control character is ignored
+                c if c.is_control() => {}
+                c => actions.push(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '[' => {
+                    self.params.clear();
+                    self.current = None;
+                    self.private = false;
+                    self.state = State::Csi;
+                }
+                // Start over if we get another escape
+                '\x1b' => {}
+                // Anything else is an escape sequence we don't support. Drop it.
+                _ => self.state = State::Ground,
+            },
+            State::Csi => match c {
+                '0'..='9' => {
+                    let digit = c as u32 - '0' as u32;
+                    let val = self.current.unwrap_or(0).saturating_mul(10).saturating_add(digit);
+                    self.current = Some(val);
+                }
+                ';' => self.push_param(),
+                '?' | '<' | '=' | '>' => self.private = true,
+                // Final byte
+                '\x40'..='\x7e' => {
+                    self.push_param();
+                    self.state = State::Ground;
+                    if !self.private {
+                        self.dispatch_csi(c, &mut actions);
+                    }
+                }
+                // CAN and SUB abort the sequence
+                '\x18' | '\x1a' => self.state = State::Ground,
+                '\x1b' => self.state = State::Escape,
+                // Intermediate bytes. None of the sequences we support use them.
+                _ => {}
+            },
+        }
+        actions
+    }
+
+    fn push_param(&mut self) {
+        // A missing parameter is the same as 0
+        let _ = self.params.try_push(self.current.take().unwrap_or(0));
+    }
+
+    /// Get a parameter, with missing and 0 values replaced by `default`
+    fn param_or(&self, idx: usize, default: u32) -> u32 {
+        match self.params.get(idx) {
+            Some(0) | None => default,
+            Some(&val) => val,
+        }
+    }
+
+    fn dispatch_csi(&self, final_byte: char, actions: &mut ArrayVec<Action, MAX_PARAMS>) {
+        let count = self.param_or(0, 1);
+        let erase = || match self.params.first() {
+            Some(0) | None => Some(Erase::ToEnd),
+            Some(1) => Some(Erase::ToStart),
+            Some(2) | Some(3) => Some(Erase::All),
+            _ => None,
+        };
+
+        match final_byte {
+            'A' => actions.push(Action::CursorUp(count)),
+            'B' => actions.push(Action::CursorDown(count)),
+            'C' => actions.push(Action::CursorForward(count)),
+            'D' => actions.push(Action::CursorBack(count)),
+            // Next/previous line
+            'E' => {
+                actions.push(Action::CursorDown(count));
+                actions.push(Action::CarriageReturn);
+            }
+            'F' => {
+                actions.push(Action::CursorUp(count));
+                actions.push(Action::CarriageReturn);
+            }
+            'G' => actions.push(Action::CursorColumn(count - 1)),
+            'H' | 'f' => actions.push(Action::CursorTo {
+                row: self.param_or(0, 1) - 1,
+                col: self.param_or(1, 1) - 1,
+            }),
+            'J' => {
+                if let Some(erase) = erase() {
+                    actions.push(Action::EraseDisplay(erase));
+                }
+            }
+            'K' => {
+                if let Some(erase) = erase() {
+                    actions.push(Action::EraseLine(erase));
+                }
+            }
+            'm' => self.dispatch_sgr(actions),
+            _ => {}
+        }
+    }
+
+    fn dispatch_sgr(&self, actions: &mut ArrayVec<Action, MAX_PARAMS>) {
+        let mut params = self.params.iter().copied();
+        while let Some(param) = params.next() {
+            let sgr = match param {
+                0 => Sgr::Reset,
+                1 => Sgr::Bold,
+                22 => Sgr::NormalIntensity,
+                7 => Sgr::Inverse,
+                27 => Sgr::NotInverse,
+                30..=37 => Sgr::Foreground(Color::Indexed((param - 30) as u8)),
+                38 => match extended_color(&mut params) {
+                    Some(color) => Sgr::Foreground(color),
+                    None => break,
+                },
+                39 => Sgr::Foreground(Color::Default),
+                40..=47 => Sgr::Background(Color::Indexed((param - 40) as u8)),
+                48 => match extended_color(&mut params) {
+                    Some(color) => Sgr::Background(color),
+                    None => break,
+                },
+                49 => Sgr::Background(Color::Default),
+                90..=97 => Sgr::Foreground(Color::Indexed((param - 90 + 8) as u8)),
+                100..=107 => Sgr::Background(Color::Indexed((param - 100 + 8) as u8)),
+                _ => continue,
+            };
+            actions.push(Action::Sgr(sgr));
+        }
+    }
+}
+
+/// Parse the arguments of a `38`/`48` SGR parameter.
+/// Either `5;<index>` or `2;<r>;<g>;<b>`
+fn extended_color(params: &mut impl Iterator<Item = u32>) -> Option<Color> {
+    let channel = |val: u32| val.min(u8::MAX as u32) as u8;
+    match params.next()? {
+        5 => Some(Color::Indexed(channel(params.next()?))),
+        2 => {
+            let r = channel(params.next()?);
+            let g = channel(params.next()?);
+            let b = channel(params.next()?);
+            Some(Color::Rgb(r, g, b))
+        }
+        _ => None,
+    }
+}
+
+/// Keys a terminal can send us that the console acts on
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Key {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    End,
+}
+
+/// Picks out special keys from a terminal's input stream.
+///
+/// Only looks at the first parameter of a sequence, so modifiers (`ESC [ 5 ; 2 ~` for
+/// Shift+PageUp) are accepted but ignored.
+pub struct KeyDecoder {
+    state: State,
+    param: u32,
+    /// Set once we are past the first parameter
+    past_first_param: bool,
+}
+
+impl KeyDecoder {
+    pub const fn new() -> Self {
+        KeyDecoder {
+            state: State::Ground,
+            param: 0,
+            past_first_param: false,
+        }
+    }
+
+    pub fn advance(&mut self, c: char) -> Option<Key> {
+        match self.state {
+            State::Ground => {
+                if c == '\x1b' {
+                    self.state = State::Escape;
+                }
+                None
+            }
+            State::Escape => {
+                // Some terminals send `ESC O` instead of `ESC [` for cursor keys
+                if c == '[' || c == 'O' {
+                    self.param = 0;
+                    self.past_first_param = false;
+                    self.state = State::Csi;
+                } else if c != '\x1b' {
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::Csi => match c {
+                '0'..='9' if !self.past_first_param => {
+                    let digit = c as u32 - '0' as u32;
+                    self.param = self.param.saturating_mul(10).saturating_add(digit);
+                    None
+                }
+                '0'..='9' => None,
+                ';' => {
+                    self.past_first_param = true;
+                    None
+                }
+                '\x40'..='\x7e' => {
+                    self.state = State::Ground;
+                    match (c, self.param) {
+                        ('A', _) => Some(Key::Up),
+                        ('B', _) => Some(Key::Down),
+                        ('F', _) => Some(Key::End),
+                        ('~', 5) => Some(Key::PageUp),
+                        ('~', 6) => Some(Key::PageDown),
+                        ('~', 4) | ('~', 8) => Some(Key::End),
+                        _ => None,
+                    }
+                }
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Fonts the text console can draw with.
+//!
+//! Either one of the `embedded_graphics` mono fonts, or a PSF2 font (the format Linux uses for
+//! console fonts). Box drawing and block characters are drawn by us when the font doesn't have
+//! them, so tables and banners still show up with the built in fonts.
+use super::glyph_cache::{Glyph, MAX_GLYPH_HEIGHT, MAX_GLYPH_WIDTH};
+use super::{MIN_GLYPH_HEIGHT, MIN_GLYPH_WIDTH};
+use embedded_graphics::{
+    mono_font::{iso_8859_1, MonoFont, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+
+#[derive(Clone, Copy)]
+pub enum Font {
+    /// One of the `embedded_graphics` ISO 8859-1 mono fonts
+    Mono(&'static MonoFont<'static>),
+    Psf2(Psf2Font),
+}
+
+pub const DEFAULT_FONT: Font = Font::Mono(&iso_8859_1::FONT_6X10);
+
+impl Font {
+    /// Look up one of the built in fonts by its size. e.g. `"8x13"`
+    pub fn builtin(name: &str) -> Option<Font> {
+        let font = match name {
+            "6x10" => &iso_8859_1::FONT_6X10,
+            "6x12" => &iso_8859_1::FONT_6X12,
+            "6x13" => &iso_8859_1::FONT_6X13,
+            "7x13" => &iso_8859_1::FONT_7X13,
+            "7x14" => &iso_8859_1::FONT_7X14,
+            "8x13" => &iso_8859_1::FONT_8X13,
+            "9x15" => &iso_8859_1::FONT_9X15,
+            "9x18" => &iso_8859_1::FONT_9X18,
+            "10x20" => &iso_8859_1::FONT_10X20,
+            _ => return None,
+        };
+        Some(Font::Mono(font))
+    }
+
+    pub fn glyph_size(&self) -> Size {
+        match self {
+            Font::Mono(font) => font.character_size,
+            Font::Psf2(font) => Size::new(font.width, font.height),
+        }
+    }
+
+    /// Draws `c` into `glyph`, falling back to our own box drawing characters.
+    /// Returns `false` if there is no way to draw it.
+    pub fn render(&self, c: char, glyph: &mut Glyph) -> bool {
+        let found = match self {
+            Font::Mono(font) => render_mono(font, c, glyph),
+            Font::Psf2(font) => font.render(c, glyph),
+        };
+        found || render_builtin(c, self.glyph_size(), glyph)
+    }
+}
+
+fn render_mono(font: &MonoFont<'static>, c: char, glyph: &mut Glyph) -> bool {
+    // Everything past Latin-1 would get drawn as the font's replacement character
+    if c > '\u{FF}' {
+        return false;
+    }
+
+    let mut str_buf = [0u8; 4];
+    let s = c.encode_utf8(&mut str_buf);
+    let style = MonoTextStyle::new(font, BinaryColor::On);
+    let mut canvas = GlyphCanvas {
+        glyph,
+        size: font.character_size,
+    };
+    Text::with_baseline(s, Point::zero(), style, Baseline::Top)
+        .draw(&mut canvas)
+        .unwrap();
+    true
+}
+
+const PSF2_MAGIC: u32 = 0x864a_b572;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+/// A PC Screen Font, version 2
+/// https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
+#[derive(Clone, Copy)]
+pub struct Psf2Font {
+    data: &'static [u8],
+    header_size: usize,
+    num_glyphs: usize,
+    bytes_per_glyph: usize,
+    width: u32,
+    height: u32,
+    /// Offset of the table mapping glyphs to unicode characters, if the font has one
+    unicode_table: Option<usize>,
+}
+
+impl Psf2Font {
+    pub fn parse(data: &'static [u8]) -> Result<Self, &'static str> {
+        // Header is a list of little endian u32s
+        let field = |idx: usize| -> Result<u32, &'static str> {
+            data.get((idx * 4)..(idx * 4 + 4))
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .ok_or("PSF2 font header is truncated")
+        };
+
+        if field(0)? != PSF2_MAGIC {
+            return Err("Not a PSF2 font");
+        }
+        let header_size = field(2)? as usize;
+        let flags = field(3)?;
+        let num_glyphs = field(4)? as usize;
+        let bytes_per_glyph = field(5)? as usize;
+        let height = field(6)?;
+        let width = field(7)?;
+
+        if width > MAX_GLYPH_WIDTH || height > MAX_GLYPH_HEIGHT {
+            return Err("PSF2 font glyphs are too big");
+        }
+        if width < MIN_GLYPH_WIDTH || height < MIN_GLYPH_HEIGHT {
+            return Err("PSF2 font glyphs are too small");
+        }
+        if bytes_per_glyph < height as usize * row_bytes(width) {
+            return Err("PSF2 font glyph size does not match its dimensions");
+        }
+        let glyphs_end = num_glyphs
+            .checked_mul(bytes_per_glyph)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or("PSF2 font is too big")?;
+        if glyphs_end > data.len() {
+            return Err("PSF2 font is truncated");
+        }
+
+        Ok(Psf2Font {
+            data,
+            header_size,
+            num_glyphs,
+            bytes_per_glyph,
+            width,
+            height,
+            unicode_table: (flags & PSF2_HAS_UNICODE_TABLE != 0).then_some(glyphs_end),
+        })
+    }
+
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        let Some(table_start) = self.unicode_table else {
+            // Without a table glyphs are indexed by code point
+            return Some(c as usize).filter(|&idx| idx < self.num_glyphs);
+        };
+
+        // Each glyph's entry is a list of UTF-8 characters, then optionally some sequences of
+        // combining characters that each start with 0xFE. Entries end with 0xFF.
+        let mut str_buf = [0u8; 4];
+        let needle = c.encode_utf8(&mut str_buf).as_bytes();
+        let table = &self.data[table_start..];
+        let mut glyph = 0;
+        let mut in_sequence = false;
+        for (idx, &byte) in table.iter().enumerate() {
+            if glyph >= self.num_glyphs {
+                break;
+            }
+            match byte {
+                PSF2_SEPARATOR => {
+                    glyph += 1;
+                    in_sequence = false;
+                }
+                PSF2_START_SEQUENCE => in_sequence = true,
+                // Continuation bytes never look like the start of a character, so matching on
+                // any byte boundary is safe
+                _ if !in_sequence && table[idx..].starts_with(needle) => return Some(glyph),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn render(&self, c: char, glyph: &mut Glyph) -> bool {
+        let Some(idx) = self.glyph_index(c) else {
+            return false;
+        };
+
+        let row_bytes = row_bytes(self.width);
+        let start = self.header_size + idx * self.bytes_per_glyph;
+        let bitmap = &self.data[start..(start + self.bytes_per_glyph)];
+        for y in 0..self.height {
+            let row = &bitmap[(y as usize * row_bytes)..][..row_bytes];
+            for x in 0..self.width {
+                // Leftmost pixel is the most significant bit
+                if row[x as usize / 8] & (0x80 >> (x % 8)) != 0 {
+                    glyph.set(x, y);
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Bytes used by a row of a PSF2 glyph
+fn row_bytes(width: u32) -> usize {
+    (width as usize + 7) / 8
+}
+
+/// Thickness of one arm of a box drawing character
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Weight {
+    Empty,
+    Light,
+    Heavy,
+    Double,
+}
+
+impl Weight {
+    /// Offsets from the center line that are part of a line of this weight
+    fn offsets(self) -> &'static [i32] {
+        match self {
+            Weight::Empty => &[],
+            Weight::Light => &[0],
+            Weight::Heavy => &[-1, 0, 1],
+            Weight::Double => &[-1, 1],
+        }
+    }
+}
+
+/// Arms of a box drawing character: up, right, down, left
+fn box_arms(c: char) -> Option<[Weight; 4]> {
+    use Weight::{Double as D, Empty as E, Heavy as H, Light as L};
+    let arms = match c {
+        // Dashed lines are drawn solid
+        '─' | '┄' | '┈' | '╌' => [E, L, E, L],
+        '━' | '┅' | '┉' | '╍' => [E, H, E, H],
+        '│' | '┆' | '┊' | '╎' => [L, E, L, E],
+        '┃' | '┇' | '┋' | '╏' => [H, E, H, E],
+        // Rounded corners are drawn square
+        '┌' | '╭' => [E, L, L, E],
+        '┏' => [E, H, H, E],
+        '┐' | '╮' => [E, E, L, L],
+        '┓' => [E, E, H, H],
+        '└' | '╰' => [L, L, E, E],
+        '┗' => [H, H, E, E],
+        '┘' | '╯' => [L, E, E, L],
+        '┛' => [H, E, E, H],
+        '├' => [L, L, L, E],
+        '┣' => [H, H, H, E],
+        '┤' => [L, E, L, L],
+        '┫' => [H, E, H, H],
+        '┬' => [E, L, L, L],
+        '┳' => [E, H, H, H],
+        '┴' => [L, L, E, L],
+        '┻' => [H, H, E, H],
+        '┼' => [L, L, L, L],
+        '╋' => [H, H, H, H],
+        '═' => [E, D, E, D],
+        '║' => [D, E, D, E],
+        '╔' => [E, D, D, E],
+        '╗' => [E, E, D, D],
+        '╚' => [D, D, E, E],
+        '╝' => [D, E, E, D],
+        '╠' => [D, D, D, E],
+        '╣' => [D, E, D, D],
+        '╦' => [E, D, D, D],
+        '╩' => [D, D, E, D],
+        '╬' => [D, D, D, D],
+        '╴' => [E, E, E, L],
+        '╵' => [L, E, E, E],
+        '╶' => [E, L, E, E],
+        '╷' => [E, E, L, E],
+        '╸' => [E, E, E, H],
+        '╹' => [H, E, E, E],
+        '╺' => [E, H, E, E],
+        '╻' => [E, E, H, E],
+        _ => return None,
+    };
+    Some(arms)
+}
+
+/// Draws the box drawing and block element characters fonts are often missing.
+/// Returns `false` for anything else.
+fn render_builtin(c: char, size: Size, glyph: &mut Glyph) -> bool {
+    let (width, height) = (size.width as i32, size.height as i32);
+    let mut set = |x: i32, y: i32| {
+        if (0..width).contains(&x) && (0..height).contains(&y) {
+            glyph.set(x as u32, y as u32);
+        }
+    };
+
+    if let Some([up, right, down, left]) = box_arms(c) {
+        let (cx, cy) = (width / 2, height / 2);
+        // Each arm reaches from the edge to just past the center, so arms of different
+        // weights still meet
+        for &off in up.offsets() {
+            (0..=(cy + 1)).for_each(|y| set(cx + off, y));
+        }
+        for &off in down.offsets() {
+            ((cy - 1)..height).for_each(|y| set(cx + off, y));
+        }
+        for &off in left.offsets() {
+            (0..=(cx + 1)).for_each(|x| set(x, cy + off));
+        }
+        for &off in right.offsets() {
+            ((cx - 1)..width).for_each(|x| set(x, cy + off));
+        }
+        return true;
+    }
+
+    if block_pixel(c, 0, 0, size).is_none() {
+        return false;
+    }
+    for y in 0..height {
+        for x in 0..width {
+            if block_pixel(c, x, y, size) == Some(true) {
+                set(x, y);
+            }
+        }
+    }
+    true
+}
+
+/// Whether a pixel is part of a block element character. `None` if `c` isn't one.
+/// Shades are dither patterns.
+fn block_pixel(c: char, x: i32, y: i32, size: Size) -> Option<bool> {
+    let (width, height) = (size.width as i32, size.height as i32);
+    let filled = match c {
+        '▀' => y < height / 2,
+        // Lower one eighth through lower seven eighths, then full block
+        '▁'..='█' => {
+            let eighths = c as i32 - '▀' as i32;
+            y >= height - height * eighths / 8
+        }
+        // Left seven eighths through left one eighth
+        '▉'..='▏' => {
+            let eighths = '█' as i32 + 8 - c as i32;
+            x < width * eighths / 8
+        }
+        '▐' => x >= width / 2,
+        '░' => x % 2 == 0 && y % 2 == 0,
+        '▒' => (x + y) % 2 == 0,
+        '▓' => !(x % 2 == 1 && y % 2 == 1),
+        '▔' => y < height / 8,
+        '▕' => x >= width - width / 8,
+        _ => return None,
+    };
+    Some(filled)
+}
+
+/// Draw target used to capture a glyph while rendering a mono font
+struct GlyphCanvas<'a> {
+    glyph: &'a mut Glyph,
+    size: Size,
+}
+
+impl OriginDimensions for GlyphCanvas<'_> {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for GlyphCanvas<'_> {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(coord, color) in pixels.into_iter() {
+            if let Ok((x, y)) = coord.try_into() {
+                if x < self.size.width && y < self.size.height && color.is_on() {
+                    self.glyph.set(x, y);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! Glyphs rendered ahead of time, so painting a character is a plain copy into the framebuffer
+//! instead of a trip through the font for every cell.
+use super::font::Font;
+use core::ops::RangeInclusive;
+use embedded_graphics::prelude::*;
+
+pub const MAX_GLYPH_WIDTH: u32 = u16::BITS;
+pub const MAX_GLYPH_HEIGHT: u32 = 32;
+
+/// Characters we keep glyphs for: printable Latin-1, box drawing and block elements
+const CACHED_CHARS: [RangeInclusive<char>; 3] = [' '..='~', '\u{A0}'..='\u{FF}', '\u{2500}'..='\u{259F}'];
+const NUM_GLYPHS: usize = 95 + 96 + 160;
+
+/// Bitmap of a single character.
+/// Bit `x` of row `y` is set when the pixel at (x, y) is part of the character.
+#[derive(Clone, Copy, Debug)]
+pub struct Glyph([u16; MAX_GLYPH_HEIGHT as usize]);
+
+impl Glyph {
+    pub const EMPTY: Glyph = Glyph([0; MAX_GLYPH_HEIGHT as usize]);
+
+    pub fn is_set(&self, x: u32, y: u32) -> bool {
+        self.0[y as usize] & (1 << x) != 0
+    }
+
+    pub fn set(&mut self, x: u32, y: u32) {
+        self.0[y as usize] |= 1 << x;
+    }
+}
+
+pub struct GlyphCache {
+    glyphs: [Glyph; NUM_GLYPHS],
+    size: Size,
+}
+
+/// Where a character's glyph is kept in the cache
+fn slot(c: char) -> Option<usize> {
+    let mut base = 0;
+    for range in CACHED_CHARS.iter() {
+        if range.contains(&c) {
+            return Some(base + (c as usize - *range.start() as usize));
+        }
+        base += *range.end() as usize - *range.start() as usize + 1;
+    }
+    None
+}
+
+impl GlyphCache {
+    pub const fn new() -> Self {
+        GlyphCache {
+            glyphs: [Glyph::EMPTY; NUM_GLYPHS],
+            size: Size::zero(),
+        }
+    }
+
+    /// Render every glyph from `font`
+    pub fn load(&mut self, font: &Font) {
+        self.size = font.glyph_size();
+        for range in CACHED_CHARS.iter() {
+            for c in range.clone() {
+                let glyph = &mut self.glyphs[slot(c).unwrap()];
+                *glyph = Glyph::EMPTY;
+                // Characters the font doesn't have are left blank
+                font.render(c, glyph);
+            }
+        }
+    }
+
+    /// Size of every glyph in the cache
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Get the glyph for a character. Characters we have no glyph for are drawn as blanks.
+    pub fn get(&self, c: char) -> &Glyph {
+        match slot(c) {
+            Some(slot) => &self.glyphs[slot],
+            None => &Glyph::EMPTY,
+        }
+    }
+}
@@ -0,0 +1,1007 @@
+use crate::dma;
+use crate::mailbox;
+use crate::mailbox::tags::{
+    FBAllocateBufferRequest, FBGetPitchRequest, FBSetBitsPerPixelRequest,
+    FBSetPhysicalSizeRequest, FBSetPixelOrderRequest, FBSetVirtualSizeRequest,
+    TagInterfaceRequest,
+};
+use core::{fmt, marker::PhantomData};
+use embedded_graphics::{
+    pixelcolor::{Rgb565, Rgb888},
+    prelude::*,
+};
+use num_enum::TryFromPrimitive;
+use crate::sync::{rank, IrqSafeMutex, IrqSafeMutexGuard};
+use spin::Once;
+use static_assertions::assert_eq_size;
+
+mod ansi;
+mod font;
+mod glyph_cache;
+
+pub use font::{Font, Psf2Font};
+use glyph_cache::{Glyph, GlyphCache};
+
+const PREFERRED_WIDTH: usize = 640;
+const PREFERRED_HEIGHT: usize = 480;
+// We ask for 32 bpp since it keeps every pixel aligned, but will take whatever we are given
+const PREFERRED_BPP: u32 = 32;
+/// Smallest font we accept. Text buffers are sized for this, so smaller glyphs would overrun them.
+const MIN_GLYPH_WIDTH: u32 = 6;
+const MIN_GLYPH_HEIGHT: u32 = 10;
+const TAB_WIDTH: u32 = 8;
+/// Longest line of text we can show
+const MAX_LINE_LEN: usize = PREFERRED_WIDTH / MIN_GLYPH_WIDTH as usize;
+/// Most lines of text that fit on screen at once
+const MAX_SCREEN_LINES: usize = PREFERRED_HEIGHT / MIN_GLYPH_HEIGHT as usize;
+/// How many lines that scrolled off the top of the screen are kept around to page back through
+const SCROLLBACK_LINES: usize = 500;
+
+pub struct FrameBuffer(DisplayMode);
+
+/// How a pixel is laid out in memory. Picked based on the depth the firmware grants us.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PixelFormat {
+    /// 16 bits per pixel
+    Rgb565,
+    /// 24 bits per pixel, tightly packed
+    Rgb888,
+    /// 32 bits per pixel. The top byte is unused.
+    Xrgb8888,
+}
+
+impl PixelFormat {
+    fn from_bpp(bpp: u32) -> Option<Self> {
+        match bpp {
+            16 => Some(PixelFormat::Rgb565),
+            24 => Some(PixelFormat::Rgb888),
+            32 => Some(PixelFormat::Xrgb8888),
+            _ => None,
+        }
+    }
+}
+
+/// Order of the color channels in a pixel.
+///
+/// `Rgb` means red is stored in the lowest bits (and so the lowest address) of the pixel.
+/// https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface#get-pixel-order
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, TryFromPrimitive)]
+pub enum PixelOrder {
+    Bgr = 0,
+    Rgb = 1,
+}
+
+/// A single pixel as stored in the framebuffer
+pub trait FBPixel: Copy {
+    /// The color type drawn with `embedded_graphics`
+    type Color: PixelColor + From<Rgb888>;
+    const FORMAT: PixelFormat;
+
+    fn from_color(color: Self::Color, order: PixelOrder) -> Self;
+
+    /// A word of this pixel over and over, if whole pixels fit in one
+    fn pattern(self) -> Option<u32>;
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug)]
+pub struct Rgb565Pixel(u16);
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug)]
+pub struct Rgb888Pixel([u8; 3]);
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug)]
+pub struct Xrgb8888Pixel(u32);
+assert_eq_size!(Rgb565Pixel, [u8; 2]);
+assert_eq_size!(Rgb888Pixel, [u8; 3]);
+assert_eq_size!(Xrgb8888Pixel, [u8; 4]);
+
+impl FBPixel for Rgb565Pixel {
+    type Color = Rgb565;
+    const FORMAT: PixelFormat = PixelFormat::Rgb565;
+
+    fn from_color(color: Rgb565, order: PixelOrder) -> Self {
+        let (low, high) = match order {
+            PixelOrder::Rgb => (color.r(), color.b()),
+            PixelOrder::Bgr => (color.b(), color.r()),
+        };
+        Rgb565Pixel(low as u16 | (color.g() as u16) << 5 | (high as u16) << 11)
+    }
+
+    fn pattern(self) -> Option<u32> {
+        Some(self.0 as u32 * 0x1_0001)
+    }
+}
+
+impl FBPixel for Rgb888Pixel {
+    type Color = Rgb888;
+    const FORMAT: PixelFormat = PixelFormat::Rgb888;
+
+    fn from_color(color: Rgb888, order: PixelOrder) -> Self {
+        match order {
+            PixelOrder::Rgb => Rgb888Pixel([color.r(), color.g(), color.b()]),
+            PixelOrder::Bgr => Rgb888Pixel([color.b(), color.g(), color.r()]),
+        }
+    }
+
+    fn pattern(self) -> Option<u32> {
+        None
+    }
+}
+
+impl FBPixel for Xrgb8888Pixel {
+    type Color = Rgb888;
+    const FORMAT: PixelFormat = PixelFormat::Xrgb8888;
+
+    fn from_color(color: Rgb888, order: PixelOrder) -> Self {
+        let [b0, b1, b2] = Rgb888Pixel::from_color(color, order).0;
+        Xrgb8888Pixel(u32::from_le_bytes([b0, b1, b2, u8::MAX]))
+    }
+
+    fn pattern(self) -> Option<u32> {
+        Some(self.0)
+    }
+}
+
+pub struct BufferData<P: FBPixel> {
+    buffer: BufferPtr,
+    buff_size: usize,
+    /// Number of bytes in a row. Rows may be padded, so this can be more than `width * bpp`.
+    pitch: usize,
+    order: PixelOrder,
+    dims: Size,
+    /// Fills and scrolls are handed to the DMA controller if we got a channel
+    dma: Option<dma::Channel>,
+    _pixel: PhantomData<P>,
+}
+struct BufferPtr(*mut u8);
+unsafe impl Send for BufferPtr {}
+/// Describes some position on the display
+#[derive(Clone, Copy, Debug)]
+struct ScreenPos(u32, u32);
+
+impl<P: FBPixel> BufferData<P> {
+    /// Safety: `buffer` must point to a framebuffer of `buff_size` bytes holding `dims.height`
+    /// rows of `pitch` bytes each.
+    unsafe fn new(buffer: *mut u8, buff_size: usize, pitch: usize, order: PixelOrder, dims: Size) -> Self {
+        BufferData {
+            buffer: BufferPtr(buffer),
+            buff_size,
+            pitch,
+            order,
+            dims,
+            dma: dma::alloc(),
+            _pixel: PhantomData,
+        }
+    }
+
+    /// Converts screen position to a byte offset into the buffer.
+    fn pos_to_offset(&self, ScreenPos(x, y): ScreenPos) -> usize {
+        y as usize * self.pitch + x as usize * core::mem::size_of::<P>()
+    }
+
+    /// Converts a color to how it is stored in the buffer
+    fn pixel(&self, color: Rgb888) -> P {
+        P::from_color(color.into(), self.order)
+    }
+
+    /// Pointer to the first pixel of a row
+    fn row_ptr(&mut self, y: u32) -> *mut P {
+        debug_assert!(y < self.dims.height);
+        unsafe { self.buffer.0.add(self.pos_to_offset(ScreenPos(0, y))) as *mut P }
+    }
+
+    fn fill_row(&mut self, y: u32, fill: P) {
+        let row = self.row_ptr(y);
+        for x in 0..(self.dims.width as usize) {
+            unsafe { row.add(x).write(fill) };
+        }
+    }
+
+    /// Fills every row in `rows` with `fill`
+    fn fill_rows(&mut self, rows: core::ops::Range<u32>, fill: P) {
+        if let (Some(channel), Some(pattern)) = (self.dma.as_mut(), fill.pattern()) {
+            // The padding at the end of each row gets filled too, which nobody sees
+            let start = self.buffer.0 as usize + rows.start as usize * self.pitch;
+            let len = rows.len() * self.pitch;
+            let res = dma::ControlBlock::fill(&pattern, start, len)
+                .and_then(|block| channel.run(&mut [block]));
+            if res.is_ok() {
+                return;
+            }
+        }
+        for y in rows {
+            self.fill_row(y, fill);
+        }
+    }
+
+    /// `scroll_up` on the DMA controller: the copy, then the fill, in one chain
+    fn dma_scroll_up(&mut self, kept_rows: u32, rows: u32, fill: P) -> Result<(), &'static str> {
+        let channel = self.dma.as_mut().ok_or("No DMA channel")?;
+        let pattern = fill.pattern().ok_or("Pixels don't fit in a word")?;
+        let base = self.buffer.0 as usize;
+        let kept = kept_rows as usize * self.pitch;
+        let moved = rows as usize * self.pitch;
+        // The copy goes downwards in memory, so it never reads what it already overwrote
+        let mut chain = [
+            dma::ControlBlock::copy(base + moved, base, kept)?,
+            dma::ControlBlock::fill(&pattern, base + kept, moved)?,
+        ];
+        channel.run(&mut chain)
+    }
+
+    /// Moves the top `height` rows of pixels up by `rows`.
+    /// The rows that get uncovered at the bottom are filled with `fill`.
+    fn scroll_up(&mut self, height: u32, rows: u32, fill: P) {
+        let height = height.min(self.dims.height);
+        let rows = rows.min(height);
+        let kept_rows = height - rows;
+        if self.dma_scroll_up(kept_rows, rows, fill).is_ok() {
+            return;
+        }
+
+        // Rows are contiguous, so this is a single memmove
+        unsafe {
+            core::ptr::copy(
+                self.buffer.0.add(rows as usize * self.pitch),
+                self.buffer.0,
+                kept_rows as usize * self.pitch,
+            );
+        }
+        self.fill_rows(kept_rows..height, fill);
+    }
+
+    /// Paints `glyph` with its top-left corner at `pos`.
+    /// Glyphs that don't fully fit on screen are skipped.
+    fn blit_glyph(&mut self, pos: ScreenPos, glyph: &Glyph, size: Size, fg: P, bg: P) {
+        if pos.0 + size.width > self.dims.width || pos.1 + size.height > self.dims.height {
+            return;
+        }
+
+        for y in 0..size.height {
+            let row = self.row_ptr(pos.1 + y);
+            for x in 0..size.width {
+                let pixel = if glyph.is_set(x, y) { fg } else { bg };
+                unsafe { row.add((pos.0 + x) as usize).write(pixel) };
+            }
+        }
+    }
+}
+
+enum DisplayMode {
+    // Append text to show on screen.
+    // If too big the oldest text is removed.
+    // Writing to screen is done immediately; nothing is deferred.
+    TextLog(AnyTextLog),
+    Graphical,
+}
+
+/// A text log drawing to whichever pixel format the firmware gave us
+enum AnyTextLog {
+    Rgb565(TextLogData<Rgb565Pixel>),
+    Rgb888(TextLogData<Rgb888Pixel>),
+    Xrgb8888(TextLogData<Xrgb8888Pixel>),
+}
+
+/// Runs `$body` with `$log` bound to the `TextLogData` inside of an `AnyTextLog`
+macro_rules! with_text_log {
+    ($any:expr, |$log:ident| $body:expr) => {
+        match $any {
+            AnyTextLog::Rgb565($log) => $body,
+            AnyTextLog::Rgb888($log) => $body,
+            AnyTextLog::Xrgb8888($log) => $body,
+        }
+    };
+}
+
+impl FrameBuffer {
+    /// How the pixels in `bytes` are laid out. `None` in a mode without a buffer we know about.
+    pub fn pixel_format(&self) -> Option<PixelFormat> {
+        match &self.0 {
+            DisplayMode::TextLog(log) => Some(with_text_log!(log, |log| log.data.format())),
+            DisplayMode::Graphical => None,
+        }
+    }
+
+    /// Page through the text log's scrollback
+    pub fn scroll(&mut self, cmd: ScrollCommand) {
+        if let DisplayMode::TextLog(ref mut text_log) = self.0 {
+            with_text_log!(text_log, |text_log| text_log.scroll_view(cmd));
+        }
+    }
+
+    /// The raw pixels, laid out as `pixel_format` says. Anything drawn here is overwritten the
+    /// next time the text log redraws that part of the screen. `None` in a mode without a buffer
+    /// we know about.
+    pub fn bytes(&mut self) -> Option<&mut [u8]> {
+        match &mut self.0 {
+            DisplayMode::TextLog(log) => Some(with_text_log!(log, |log| log.data.bytes())),
+            DisplayMode::Graphical => None,
+        }
+    }
+
+    /// Switch the text log to a different font.
+    /// Text is laid out again from scratch, so this clears the console.
+    pub fn set_font(&mut self, font: Font) {
+        if let DisplayMode::TextLog(ref mut text_log) = self.0 {
+            with_text_log!(text_log, |text_log| text_log.set_font(&font));
+        }
+    }
+}
+
+impl<P: FBPixel> BufferData<P> {
+    fn format(&self) -> PixelFormat {
+        P::FORMAT
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        // Safety: `new`'s caller promised `buff_size` bytes, and `&mut self` keeps them ours
+        unsafe { core::slice::from_raw_parts_mut(self.buffer.0, self.buff_size) }
+    }
+}
+
+pub struct TextLogData<P: FBPixel> {
+    data: BufferData<P>,
+    history: &'static mut History,
+    // NOTE: Cursor is allowed to be past the end-of-line
+    // Contract: 0 <= cursor.0 <= data.dims.width
+    // Contract: 0 <= cursor.1 < data.dims.height
+    cursor: TextPos,
+    parser: ansi::Parser,
+    attrs: TextAttrs,
+    glyphs: &'static mut GlyphCache,
+    /// How many lines up from the newest text the screen is showing. 0 follows new output.
+    view_offset: usize,
+    /// Cells that changed since they were last painted
+    dirty: Option<DirtyRect>,
+    /// Set when everything on screen has to be painted again
+    redraw_required: bool,
+}
+
+/// Describes some position in the text log
+#[derive(Clone, Copy, Debug)]
+struct TextPos(u32, u32);
+
+/// Bounding box of a group of cells. Both corners are inclusive.
+#[derive(Clone, Copy, Debug)]
+struct DirtyRect {
+    top_left: TextPos,
+    bottom_right: TextPos,
+}
+
+impl DirtyRect {
+    fn new(pos: TextPos) -> Self {
+        DirtyRect {
+            top_left: pos,
+            bottom_right: pos,
+        }
+    }
+
+    /// Grow to cover `pos`
+    fn add(&mut self, pos: TextPos) {
+        self.top_left = TextPos(self.top_left.0.min(pos.0), self.top_left.1.min(pos.1));
+        self.bottom_right = TextPos(self.bottom_right.0.max(pos.0), self.bottom_right.1.max(pos.1));
+    }
+}
+
+type Line = [Cell; MAX_LINE_LEN];
+const HISTORY_LEN: usize = MAX_SCREEN_LINES + SCROLLBACK_LINES;
+
+/// Ring of the most recent lines of text, including the ones on screen.
+/// Lines are addressed by how far back from the newest line they are.
+pub struct History {
+    lines: [Line; HISTORY_LEN],
+    /// Ring index of the newest line
+    newest: usize,
+    /// Number of lines holding text
+    len: usize,
+}
+
+// Big enough that it must not go on the stack
+static mut HISTORY: History = History::new();
+static mut GLYPHS: GlyphCache = GlyphCache::new();
+
+impl History {
+    const fn new() -> Self {
+        History {
+            lines: [[Cell::blank(DEFAULT_BG); MAX_LINE_LEN]; HISTORY_LEN],
+            newest: 0,
+            len: 0,
+        }
+    }
+
+    /// Empty out the history, leaving `len` blank lines.
+    fn reset(&mut self, len: usize) {
+        for line in self.lines.iter_mut() {
+            line.fill(Cell::blank(DEFAULT_BG));
+        }
+        self.newest = len - 1;
+        self.len = len;
+    }
+
+    fn ring_idx(&self, lines_back: usize) -> usize {
+        debug_assert!(lines_back < self.len);
+        (self.newest + HISTORY_LEN - lines_back) % HISTORY_LEN
+    }
+
+    fn line(&self, lines_back: usize) -> &Line {
+        &self.lines[self.ring_idx(lines_back)]
+    }
+
+    fn line_mut(&mut self, lines_back: usize) -> &mut Line {
+        &mut self.lines[self.ring_idx(lines_back)]
+    }
+
+    /// Adds a blank line, dropping the oldest one if the ring is full
+    fn push_line(&mut self, bg: Rgb888) {
+        self.newest = (self.newest + 1) % HISTORY_LEN;
+        self.lines[self.newest].fill(Cell::blank(bg));
+        self.len = (self.len + 1).min(HISTORY_LEN);
+    }
+}
+
+/// Commands for paging through the scrollback
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScrollCommand {
+    LineUp,
+    LineDown,
+    PageUp,
+    PageDown,
+    /// Go back to following new output
+    Bottom,
+}
+
+/// A character on screen along with the colors it is drawn in
+#[derive(Clone, Copy, Debug)]
+struct Cell {
+    /// `'\0'` for a cell nothing was written to
+    c: char,
+    fg: Rgb888,
+    bg: Rgb888,
+}
+
+impl Cell {
+    const fn blank(bg: Rgb888) -> Self {
+        Cell {
+            c: '\0',
+            // Never drawn, but this keeps a blank cell all zeroes so `HISTORY` can live in .bss
+            fg: bg,
+            bg,
+        }
+    }
+}
+
+const DEFAULT_FG: Rgb888 = Rgb888::new(229, 229, 229);
+const DEFAULT_BG: Rgb888 = Rgb888::BLACK;
+
+/// Graphics rendition set through SGR escape sequences
+#[derive(Clone, Copy, Debug)]
+struct TextAttrs {
+    fg: ansi::Color,
+    bg: ansi::Color,
+    bold: bool,
+    inverse: bool,
+}
+
+impl TextAttrs {
+    const DEFAULT: TextAttrs = TextAttrs {
+        fg: ansi::Color::Default,
+        bg: ansi::Color::Default,
+        bold: false,
+        inverse: false,
+    };
+
+    fn apply(&mut self, sgr: ansi::Sgr) {
+        use ansi::Sgr;
+        match sgr {
+            Sgr::Reset => *self = Self::DEFAULT,
+            Sgr::Bold => self.bold = true,
+            Sgr::NormalIntensity => self.bold = false,
+            Sgr::Inverse => self.inverse = true,
+            Sgr::NotInverse => self.inverse = false,
+            Sgr::Foreground(color) => self.fg = color,
+            Sgr::Background(color) => self.bg = color,
+        }
+    }
+
+    /// Foreground and background color to draw with
+    fn colors(&self) -> (Rgb888, Rgb888) {
+        // There is no bold font, so do what old terminals did and use the bright color instead
+        let fg = match self.fg {
+            ansi::Color::Indexed(idx) if self.bold && idx < 8 => ansi::Color::Indexed(idx + 8),
+            ansi::Color::Default if self.bold => ansi::Color::Indexed(15),
+            color => color,
+        };
+        let fg = fg.to_rgb(DEFAULT_FG);
+        let bg = self.bg.to_rgb(DEFAULT_BG);
+
+        if self.inverse {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+}
+
+impl<P: FBPixel> TextLogData<P> {
+    fn new(data: BufferData<P>, history: &'static mut History, glyphs: &'static mut GlyphCache) -> Self {
+        glyphs.load(&font::DEFAULT_FONT);
+        let mut log = TextLogData {
+            data,
+            history,
+            cursor: TextPos(0, 0),
+            parser: ansi::Parser::new(),
+            attrs: TextAttrs::DEFAULT,
+            glyphs,
+            view_offset: 0,
+            dirty: None,
+            redraw_required: false,
+        };
+        let chars_height = log.chars_height() as usize;
+        log.history.reset(chars_height);
+        log
+    }
+
+    /// Render text with `font` from now on. The screen is cleared, since the number of
+    /// characters that fit changes along with the glyph size.
+    fn set_font(&mut self, font: &Font) {
+        self.glyphs.load(font);
+        self.history.reset(self.chars_height() as usize);
+        self.cursor = TextPos(0, 0);
+        self.view_offset = 0;
+
+        // The old glyphs may have covered pixels the new ones don't reach
+        let fill = self.data.pixel(DEFAULT_BG);
+        self.data.fill_rows(0..self.data.dims.height, fill);
+        self.redraw_required = true;
+        self.flush();
+    }
+
+    /// Shifts all text up a line, leaving an empty line at the bottom.
+    /// The pixels are moved along with the text, so only the new line needs to be drawn.
+    fn shift_text(&mut self) {
+        // Anything not painted yet is about to move
+        self.flush();
+
+        let chars_height = self.chars_height();
+        let (_, bg) = self.attrs.colors();
+        self.history.push_line(bg);
+
+        let fill = self.data.pixel(bg);
+        let glyph_height = self.glyphs.size().height;
+        self.data.scroll_up(chars_height * glyph_height, glyph_height, fill);
+    }
+
+    /// Paints everything that changed since the last flush
+    fn flush(&mut self) {
+        if self.redraw_required {
+            self.redraw_text();
+            return;
+        }
+
+        if let Some(DirtyRect { top_left, bottom_right }) = self.dirty.take() {
+            for y in top_left.1..=bottom_right.1 {
+                for x in top_left.0..=bottom_right.0 {
+                    let pos = TextPos(x, y);
+                    self.paint_char(self.cell(pos), self.text_pos_to_screen_pos(pos));
+                }
+            }
+        }
+    }
+
+    /// Redraws entire screen
+    fn redraw_text(&mut self) {
+        let chars_height = self.chars_height();
+        for y in 0..chars_height {
+            let lines_back = (chars_height - 1 - y) as usize + self.view_offset;
+            for x in 0..(self.chars_width()) {
+                let cell = self.history.line(lines_back)[x as usize];
+                self.paint_char(cell, self.text_pos_to_screen_pos(TextPos(x, y)));
+            }
+        }
+        self.dirty = None;
+        self.redraw_required = false;
+    }
+
+    /// Move the screen through the scrollback. Only redraws if the view actually moved.
+    fn scroll_view(&mut self, cmd: ScrollCommand) {
+        let page = self.chars_height() as usize;
+        let max_offset = self.history.len - page;
+        let offset = match cmd {
+            ScrollCommand::LineUp => self.view_offset + 1,
+            ScrollCommand::LineDown => self.view_offset.saturating_sub(1),
+            ScrollCommand::PageUp => self.view_offset + page,
+            ScrollCommand::PageDown => self.view_offset.saturating_sub(page),
+            ScrollCommand::Bottom => 0,
+        }
+        .min(max_offset);
+
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.redraw_required = true;
+            self.flush();
+        }
+    }
+
+    /// The cell at a position on the newest screen of text
+    fn cell(&self, TextPos(x, y): TextPos) -> Cell {
+        let lines_back = (self.chars_height() - 1 - y) as usize;
+        self.history.line(lines_back)[x as usize]
+    }
+
+    /// Moves the cursor to the start of the next line, scrolling if we are at the bottom
+    fn newline(&mut self) {
+        self.cursor.0 = 0;
+        if self.cursor.1 + 1 < self.chars_height() {
+            self.cursor.1 += 1;
+        } else {
+            self.shift_text();
+        }
+    }
+
+    /// How many characters can be rendered per line
+    ///
+    /// Capped to what fits in the text buffer, in case we got a bigger screen than we asked for.
+    fn chars_width(&self) -> u32 {
+        self.data.dims.width.min(PREFERRED_WIDTH as u32) / self.glyphs.size().width
+    }
+    /// How many lines of text can fit
+    fn chars_height(&self) -> u32 {
+        self.data.dims.height.min(PREFERRED_HEIGHT as u32) / self.glyphs.size().height
+    }
+
+    /// Converts text position to index in the text buffer.
+    fn pos_to_idx(&self, TextPos(x, y): TextPos) -> usize {
+        (x + y * self.chars_width()) as usize
+    }
+    /// Converts index in the text buffer to text position.
+    fn idx_to_pos(&self, idx: usize) -> TextPos {
+        let chars_width = self.chars_width() as usize;
+        TextPos((idx % chars_width) as u32, (idx / chars_width) as u32)
+    }
+    /// Convert text-space to screen-space
+    fn text_pos_to_screen_pos(&self, pos: TextPos) -> ScreenPos {
+        let size = self.glyphs.size();
+        let x = pos.0 * size.width;
+        let y = pos.1 * size.height;
+        ScreenPos(x, y)
+    }
+
+    fn write_char(&mut self, c: char) {
+        // New output brings us back to the bottom
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.redraw_required = true;
+        }
+
+        for action in self.parser.advance(c) {
+            self.handle_action(action);
+        }
+    }
+
+    fn handle_action(&mut self, action: ansi::Action) {
+        use ansi::{Action, Erase};
+        let last_col = self.chars_width() - 1;
+        let last_row = self.chars_height() - 1;
+
+        match action {
+            Action::Print(c) => self.put_char(c),
+            Action::CarriageReturn => self.cursor.0 = 0,
+            Action::LineFeed => self.newline(),
+            Action::Tab => {
+                let next_stop = (self.cursor.0 / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.cursor.0 = self.cursor.0.max(next_stop.min(last_col));
+            }
+            Action::Backspace => self.cursor.0 = self.cursor.0.min(last_col).saturating_sub(1),
+            // No speaker to beep with
+            Action::Bell => {}
+            Action::CursorUp(n) => self.cursor.1 = self.cursor.1.saturating_sub(n),
+            Action::CursorDown(n) => self.cursor.1 = self.cursor.1.saturating_add(n).min(last_row),
+            Action::CursorForward(n) => self.cursor.0 = self.cursor.0.saturating_add(n).min(last_col),
+            Action::CursorBack(n) => self.cursor.0 = self.cursor.0.min(last_col).saturating_sub(n),
+            Action::CursorTo { row, col } => self.cursor = TextPos(col.min(last_col), row.min(last_row)),
+            Action::CursorColumn(col) => self.cursor.0 = col.min(last_col),
+            Action::EraseDisplay(erase) => {
+                let cursor_idx = self.pos_to_idx(TextPos(self.cursor.0.min(last_col), self.cursor.1));
+                let end_idx = self.pos_to_idx(TextPos(0, last_row + 1));
+                match erase {
+                    Erase::ToEnd => self.erase(cursor_idx..end_idx),
+                    Erase::ToStart => self.erase(0..(cursor_idx + 1)),
+                    Erase::All => self.erase(0..end_idx),
+                }
+            }
+            Action::EraseLine(erase) => {
+                let cursor_idx = self.pos_to_idx(TextPos(self.cursor.0.min(last_col), self.cursor.1));
+                let start_idx = self.pos_to_idx(TextPos(0, self.cursor.1));
+                let end_idx = self.pos_to_idx(TextPos(0, self.cursor.1 + 1));
+                match erase {
+                    Erase::ToEnd => self.erase(cursor_idx..end_idx),
+                    Erase::ToStart => self.erase(start_idx..(cursor_idx + 1)),
+                    Erase::All => self.erase(start_idx..end_idx),
+                }
+            }
+            Action::Sgr(sgr) => self.attrs.apply(sgr),
+        }
+    }
+
+    /// Writes a printable character at the cursor and moves it forward
+    fn put_char(&mut self, c: char) {
+        // Wrapping is deferred until there is something to put on the next line
+        if self.cursor.0 >= self.chars_width() {
+            self.newline();
+        }
+
+        let (fg, bg) = self.attrs.colors();
+        self.write_char_to_pos(Cell { c, fg, bg }, self.cursor);
+        self.cursor.0 += 1;
+    }
+
+    /// Clears a range of the text buffer with the current background color
+    fn erase(&mut self, range: core::ops::Range<usize>) {
+        let (_, bg) = self.attrs.colors();
+        for idx in range {
+            self.write_char_to_pos(Cell::blank(bg), self.idx_to_pos(idx));
+        }
+    }
+
+    fn paint_char(&mut self, cell: Cell, screen_pos: ScreenPos) {
+        let glyph = self.glyphs.get(cell.c);
+        let fg = self.data.pixel(cell.fg);
+        let bg = self.data.pixel(cell.bg);
+        self.data.blit_glyph(screen_pos, glyph, self.glyphs.size(), fg, bg);
+    }
+
+    /// Changes a cell. It gets painted on the next flush.
+    fn write_char_to_pos(&mut self, cell: Cell, TextPos(x, y): TextPos) {
+        let lines_back = (self.chars_height() - 1 - y) as usize;
+        self.history.line_mut(lines_back)[x as usize] = cell;
+
+        match self.dirty {
+            Some(ref mut dirty) => dirty.add(TextPos(x, y)),
+            None => self.dirty = Some(DirtyRect::new(TextPos(x, y))),
+        }
+    }
+}
+
+impl fmt::Write for FrameBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let DisplayMode::TextLog(ref mut text_log) = self.0 {
+            with_text_log!(text_log, |text_log| {
+                for c in s.chars() {
+                    text_log.write_char(c);
+                }
+                text_log.flush();
+            });
+            return Ok(());
+        }
+
+        return Err(fmt::Error);
+    }
+
+    fn write_char(&mut self, c: char) -> fmt::Result {
+        if let DisplayMode::TextLog(ref mut text_log) = self.0 {
+            with_text_log!(text_log, |text_log| {
+                text_log.write_char(c);
+                text_log.flush();
+            });
+            return Ok(());
+        }
+
+        return Err(fmt::Error);
+    }
+}
+
+impl<P: FBPixel> core::ops::Index<ScreenPos> for BufferData<P> {
+    type Output = P;
+
+    // Safety: Checks that coordinates are inside the buffer
+    fn index(&self, pos: ScreenPos) -> &Self::Output {
+        if (0..self.dims.width).contains(&pos.0) && (0..self.dims.height).contains(&pos.1) {
+            let offset = self.pos_to_offset(pos);
+            unsafe { &*(self.buffer.0.add(offset) as *const P) }
+        } else {
+            panic!(
+                "FrameBuffer::Index out of bounds. {:?} is outside of {:?}",
+                pos,
+                self.dims
+            )
+        }
+    }
+}
+impl<P: FBPixel> core::ops::IndexMut<ScreenPos> for BufferData<P> {
+    // Safety: Checks that coordinates are inside the buffer
+    fn index_mut(&mut self, pos: ScreenPos) -> &mut Self::Output {
+        if (0..self.dims.width).contains(&pos.0) && (0..self.dims.height).contains(&pos.1) {
+            let offset = self.pos_to_offset(pos);
+            unsafe { &mut *(self.buffer.0.add(offset) as *mut P) }
+        } else {
+            panic!(
+                "FrameBuffer::Index out of bounds. {:?} is outside of {:?}",
+                pos,
+                self.dims
+            )
+        }
+    }
+}
+
+impl<P: FBPixel> OriginDimensions for BufferData<P> {
+    fn size(&self) -> Size {
+        self.dims
+    }
+}
+
+impl<P: FBPixel> DrawTarget for BufferData<P> {
+    type Color = P::Color;
+    // Since we just write to the framebuffer we have no failure points
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(coord, color) in pixels.into_iter() {
+            // Check if the pixel coordinates are out of bounds (negative or greater than
+            // (width, height)). `DrawTarget` implementation are required to discard any out of bounds
+            // pixels without returning an error or causing a panic.
+            if let Ok((x, y)) = coord.try_into() {
+                if (0..self.dims.width).contains(&x) && (0..self.dims.height).contains(&y) {
+                    self[ScreenPos(x, y)] = P::from_color(color, self.order);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+static FRAMEBUFFER: Once<IrqSafeMutex<FrameBuffer>> = Once::new();
+/// From `font=`, until `init` can load it
+static FONT_OPTION: Once<&'static str> = Once::new();
+static INPUT: IrqSafeMutex<ansi::KeyDecoder> =
+    IrqSafeMutex::ranked(rank::INPUT, ansi::KeyDecoder::new());
+
+/// Handle a character typed on the console.
+///
+/// Shift+Up/Down (or just Up/Down) scroll a line, PageUp/PageDown scroll a page and End jumps
+/// back to the newest output. Anything else is ignored.
+pub fn handle_input(c: char) {
+    use ansi::Key;
+    let cmd = match INPUT.lock().advance(c) {
+        Some(Key::Up) => ScrollCommand::LineUp,
+        Some(Key::Down) => ScrollCommand::LineDown,
+        Some(Key::PageUp) => ScrollCommand::PageUp,
+        Some(Key::PageDown) => ScrollCommand::PageDown,
+        Some(Key::End) => ScrollCommand::Bottom,
+        None => return,
+    };
+    if let Some(mut fb) = try_get() {
+        fb.scroll(cmd);
+    }
+}
+
+pub fn try_get() -> Option<IrqSafeMutexGuard<'static, FrameBuffer>> {
+    FRAMEBUFFER.get().and_then(|m| m.try_lock())
+}
+
+pub fn get() -> IrqSafeMutexGuard<'static, FrameBuffer> {
+    FRAMEBUFFER.get().unwrap().lock()
+}
+
+/// Whether `init` has finished
+pub fn is_init() -> bool {
+    FRAMEBUFFER.is_completed()
+}
+
+/// Breaks the lock on the framebuffer
+///
+/// # Safety
+///
+/// Whoever holds the lock must never touch the framebuffer again
+pub unsafe fn force_unlock() {
+    if let Some(fb) = FRAMEBUFFER.get() {
+        fb.force_unlock();
+    }
+}
+
+crate::cmdline::early_param!("font", set_font_option);
+
+fn set_font_option(value: &'static str) -> Result<(), &'static str> {
+    FONT_OPTION.call_once(|| value);
+    Ok(())
+}
+
+/// The font `font=` asks for: one of `Font::builtin`'s, or the path of a PSF2 font in the initrd.
+/// Needs `initrd::init`.
+fn requested_font() -> Result<Option<Font>, &'static str> {
+    let Some(&name) = FONT_OPTION.get() else {
+        return Ok(None);
+    };
+    if let Some(font) = Font::builtin(name) {
+        return Ok(Some(font));
+    }
+    let data = crate::initrd::read(name).ok_or("Not a built in font, or a file in the initrd")?;
+    Psf2Font::parse(data).map(|font| Some(Font::Psf2(font)))
+}
+
+/// Needs `mailbox::init`, and `initrd::init` for `font=`
+pub unsafe fn init() -> Result<(), &'static str> {
+    let mut mbox = mailbox::get();
+
+    let res = mbox
+        .send_and_poll_recieve_batch((
+            FBSetPhysicalSizeRequest {
+                width: PREFERRED_WIDTH as u32,
+                height: PREFERRED_HEIGHT as u32,
+            }
+            .into_tag(),
+            FBSetVirtualSizeRequest {
+                width: PREFERRED_WIDTH as u32,
+                height: PREFERRED_HEIGHT as u32,
+            }
+            .into_tag(),
+            FBSetBitsPerPixelRequest {
+                bpp: PREFERRED_BPP,
+            }
+            .into_tag(),
+            FBSetPixelOrderRequest {
+                state: PixelOrder::Rgb as u32,
+            }
+            .into_tag(),
+            FBAllocateBufferRequest { alignment: 16 }.into_tag(),
+            // Pitch is only known once the buffer has been allocated
+            FBGetPitchRequest {}.into_tag(),
+        ))
+        .map_err(|_| "Batch framebuffer init failed")?;
+
+    let virt_res = res
+        .1
+        .ok_or("Framebuffer virt size request did not get a response")?;
+    let dims = Size {
+        width: virt_res.width,
+        height: virt_res.height,
+    };
+
+    let bpp = res
+        .2
+        .ok_or("Framebuffer depth request did not get a response")?
+        .bpp;
+    let format = PixelFormat::from_bpp(bpp).ok_or("Framebuffer depth is not supported")?;
+
+    let order = res
+        .3
+        .ok_or("Framebuffer pixel order request did not get a response")?
+        .state;
+    let order = PixelOrder::try_from(order).map_err(|_| "Framebuffer pixel order is invalid")?;
+
+    let alloc_res = res
+        .4
+        .ok_or("FameBuffer buff allor request did not get a response")?;
+
+    let pitch = res
+        .5
+        .ok_or("Framebuffer pitch request did not get a response")?
+        .pitch as usize;
+
+    let ptr = alloc_res.base_address as usize as *mut u8;
+    let size = alloc_res.size as usize;
+    // Start with a black screen
+    core::ptr::write_bytes(ptr, 0, size);
+
+    // Safety: `init` is only called once, so these are the only references
+    let history = &mut *core::ptr::addr_of_mut!(HISTORY);
+    let glyphs = &mut *core::ptr::addr_of_mut!(GLYPHS);
+    let text_log = match format {
+        PixelFormat::Rgb565 => AnyTextLog::Rgb565(TextLogData::new(BufferData::new(ptr, size, pitch, order, dims), history, glyphs)),
+        PixelFormat::Rgb888 => AnyTextLog::Rgb888(TextLogData::new(BufferData::new(ptr, size, pitch, order, dims), history, glyphs)),
+        PixelFormat::Xrgb8888 => AnyTextLog::Xrgb8888(TextLogData::new(BufferData::new(ptr, size, pitch, order, dims), history, glyphs)),
+    };
+    let mut fb = FrameBuffer(DisplayMode::TextLog(text_log));
+    match requested_font() {
+        Ok(Some(font)) => fb.set_font(font),
+        Ok(None) => {}
+        Err(err) => log::warn!("Could not load font {}: {}", FONT_OPTION.get().unwrap(), err),
+    }
+    FRAMEBUFFER.call_once(|| IrqSafeMutex::ranked(rank::FRAMEBUFFER, fb));
+
+    Ok(())
+}
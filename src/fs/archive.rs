@@ -0,0 +1,102 @@
+//! A read-only filesystem over an archive in memory, like the initrd.
+//!
+//! Nothing is copied out of the archive. An entry's inode number is one past where its header
+//! is, so going from an inode back to its entry doesn't have to search, and 0 is left for the
+//! root, which archives don't always have an entry for.
+use super::{DirEntry, Error, FileSystem, Ino, Kind, Result, Stat};
+use crate::initrd::{self, Archive, Entry};
+use arrayvec::ArrayString;
+
+const ROOT: Ino = 0;
+
+pub struct ArchiveFs {
+    archive: &'static Archive,
+}
+
+impl ArchiveFs {
+    pub fn new(archive: &'static Archive) -> Self {
+        ArchiveFs { archive }
+    }
+
+    /// The entry for `ino`, or `None` for the root
+    fn entry(&self, ino: Ino) -> Result<Option<Entry>> {
+        if ino == ROOT {
+            return Ok(None);
+        }
+        self.archive.entry_at(ino as usize - 1).map(Some).ok_or(Error::NotFound)
+    }
+
+    /// The path of directory `dir` in the archive
+    fn dir_path(&self, dir: Ino) -> Result<ArrayString<{ initrd::MAX_PATH }>> {
+        match self.entry(dir)? {
+            None => Ok(ArrayString::new()),
+            Some(entry) if entry.kind() == initrd::Kind::Directory => {
+                Ok(ArrayString::from(entry.path()).unwrap())
+            }
+            Some(_) => Err(Error::NotDirectory),
+        }
+    }
+}
+
+fn ino(entry: &Entry) -> Ino {
+    entry.offset() as Ino + 1
+}
+
+fn kind(entry: &Entry) -> Kind {
+    match entry.kind() {
+        initrd::Kind::Directory => Kind::Directory,
+        initrd::Kind::Symlink => Kind::Symlink,
+        initrd::Kind::File | initrd::Kind::Other => Kind::File,
+    }
+}
+
+impl FileSystem for ArchiveFs {
+    fn name(&self) -> &'static str {
+        "archive"
+    }
+
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat> {
+        Ok(match self.entry(ino)? {
+            None => Stat { ino, kind: Kind::Directory, mode: 0o755, size: 0 },
+            Some(entry) => Stat {
+                ino,
+                kind: kind(&entry),
+                mode: entry.mode(),
+                size: entry.data().len(),
+            },
+        })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino> {
+        let mut path = self.dir_path(dir)?;
+        if !path.is_empty() {
+            path.try_push('/').map_err(|_| Error::NameTooLong)?;
+        }
+        path.try_push_str(name).map_err(|_| Error::NameTooLong)?;
+        self.archive.lookup(&path).map(|entry| ino(&entry)).ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>> {
+        let path = self.dir_path(dir)?;
+        let Some(entry) = self.archive.list(&path).nth(index) else {
+            return Ok(None);
+        };
+        let name = entry.path().rsplit('/').next().unwrap_or(entry.path());
+        DirEntry::new(ino(&entry), kind(&entry), name).map(Some)
+    }
+
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let entry = self.entry(ino)?.ok_or(Error::IsDirectory)?;
+        if entry.kind() == initrd::Kind::Directory {
+            return Err(Error::IsDirectory);
+        }
+        let data = entry.data().get(offset..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+}
@@ -0,0 +1,155 @@
+//! Devices as files.
+//!
+//! | File           | Device                                                  |
+//! |----------------|---------------------------------------------------------|
+//! | `/dev/console` | The mini UART. Reads wait for at least one character.   |
+//! | `/dev/fb0`     | The framebuffer's raw pixels                            |
+//! | `/dev/null`    | Reads nothing, and throws away writes                   |
+//! | `/dev/zero`    | Reads zeroes, and throws away writes                    |
+use super::{DirEntry, Error, FileSystem, Ino, Kind, Result, Stat};
+
+const ROOT: Ino = 0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Device {
+    Console = 1,
+    Framebuffer,
+    Null,
+    Zero,
+}
+
+const DEVICES: [(&str, Device, u32); 4] = [
+    ("console", Device::Console, 0o620),
+    ("fb0", Device::Framebuffer, 0o660),
+    ("null", Device::Null, 0o666),
+    ("zero", Device::Zero, 0o666),
+];
+
+pub struct DevFs;
+
+pub static DEVFS: DevFs = DevFs;
+
+fn device(ino: Ino) -> Result<(&'static str, Device, u32)> {
+    DEVICES
+        .iter()
+        .copied()
+        .find(|&(_, device, _)| device as Ino == ino)
+        .ok_or(Error::NotFound)
+}
+
+fn read_console(buf: &mut [u8]) -> Result<usize> {
+    if !crate::uart::is_init() {
+        return Err(Error::NoDevice);
+    }
+    Ok(crate::console::input::read(buf))
+}
+
+fn write_console(buf: &[u8]) -> Result<usize> {
+    use core::fmt::Write;
+    if !crate::uart::is_init() {
+        return Err(Error::NoDevice);
+    }
+    let mut uart = crate::uart::get();
+    for piece in buf.utf8_chunks() {
+        let _ = uart.write_str(piece.valid());
+        if !piece.invalid().is_empty() {
+            let _ = uart.write_char(char::REPLACEMENT_CHARACTER);
+        }
+    }
+    Ok(buf.len())
+}
+
+fn framebuffer_size() -> usize {
+    if crate::framebuffer::is_init() {
+        crate::framebuffer::get().bytes().map_or(0, |pixels| pixels.len())
+    } else {
+        0
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat> {
+        if ino == ROOT {
+            return Ok(Stat { ino, kind: Kind::Directory, mode: 0o755, size: 0 });
+        }
+        let (_, device, mode) = device(ino)?;
+        let size = if device == Device::Framebuffer { framebuffer_size() } else { 0 };
+        Ok(Stat { ino, kind: Kind::CharDevice, mode, size })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino> {
+        if dir != ROOT {
+            return Err(Error::NotDirectory);
+        }
+        DEVICES
+            .iter()
+            .find(|&&(device_name, _, _)| device_name == name)
+            .map(|&(_, device, _)| device as Ino)
+            .ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>> {
+        if dir != ROOT {
+            return Err(Error::NotDirectory);
+        }
+        match DEVICES.get(index) {
+            Some(&(name, device, _)) => {
+                DirEntry::new(device as Ino, Kind::CharDevice, name).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let (_, device, _) = device(ino).map_err(|_| Error::IsDirectory)?;
+        match device {
+            Device::Console => read_console(buf),
+            Device::Framebuffer => {
+                if !crate::framebuffer::is_init() {
+                    return Err(Error::NoDevice);
+                }
+                let mut fb = crate::framebuffer::get();
+                let pixels = fb.bytes().ok_or(Error::NoDevice)?;
+                let pixels = pixels.get(offset..).unwrap_or(&[]);
+                let len = pixels.len().min(buf.len());
+                buf[..len].copy_from_slice(&pixels[..len]);
+                Ok(len)
+            }
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn write(&self, ino: Ino, offset: usize, buf: &[u8]) -> Result<usize> {
+        let (_, device, _) = device(ino).map_err(|_| Error::IsDirectory)?;
+        match device {
+            Device::Console => write_console(buf),
+            Device::Framebuffer => {
+                if !crate::framebuffer::is_init() {
+                    return Err(Error::NoDevice);
+                }
+                let mut fb = crate::framebuffer::get();
+                let pixels = fb.bytes().ok_or(Error::NoDevice)?;
+                if offset >= pixels.len() && !buf.is_empty() {
+                    return Err(Error::NoSpace);
+                }
+                let pixels = &mut pixels[offset.min(pixels.len())..];
+                let len = pixels.len().min(buf.len());
+                pixels[..len].copy_from_slice(&buf[..len]);
+                Ok(len)
+            }
+            Device::Null | Device::Zero => Ok(buf.len()),
+        }
+    }
+}
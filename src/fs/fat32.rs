@@ -0,0 +1,1271 @@
+//! FAT32, like the Pi's boot partition.
+//!
+//! A file is a chain of clusters linked through the FAT, and a directory is a file of 32 byte
+//! entries. Every file has a short 8.3 entry, which may be preceded by long name (VFAT) entries
+//! holding its real name in UTF-16. Inode numbers are where a file's short entry is on disk, so
+//! finding a file from its inode is one sector read; the root directory has no entry, and is
+//! inode 0.
+//!
+//! Everything that touches the disk holds one lock, so two writers can't both grab the same free
+//! cluster or directory slot. Names are matched ignoring ASCII case, as Windows does. There's no
+//! clock, so everything is dated 1980-01-01, and the free count in FSInfo is marked unknown once
+//! anything is allocated.
+//! https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf
+use super::{DirEntry, Error, FileSystem, Ino, Kind, Result, Stat, MAX_NAME};
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::sync::Mutex;
+use arrayvec::{ArrayString, ArrayVec};
+use core::ops::ControlFlow;
+use log::warn;
+
+const ROOT: Ino = 0;
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = BLOCK_SIZE / ENTRY_SIZE;
+/// Only the low 28 bits of a FAT entry are used
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+/// FAT entries from here up end a chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const FREE: u32 = 0;
+/// Clusters are numbered from 2
+const FIRST_CLUSTER: u32 = 2;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Marks a long name entry
+const ATTR_LONG_NAME: u8 = 0x0F;
+/// In the reserved byte of a short entry: show the base or extension in lower case
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// First byte of an entry that was deleted
+const DELETED: u8 = 0xE5;
+/// First byte of the first free entry, past which there are no more
+const END_OF_DIR: u8 = 0x00;
+/// Set in the sequence number of a long name's first entry, which holds its end
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// UTF-16 units in each long name entry, and where they are in it
+const LONG_NAME_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Longest long name, in UTF-16 units
+const MAX_LONG_NAME: usize = 255;
+const MAX_LONG_ENTRIES: usize = MAX_LONG_NAME.div_ceil(LONG_NAME_OFFSETS.len());
+/// 1980-01-01
+const DATE: u16 = (1 << 5) | 1;
+/// Characters short names can have besides letters and digits
+const SHORT_NAME_EXTRA: &[u8] = b"!#$%&'()-@^_`{}~";
+/// Characters no name can have
+const INVALID_CHARS: &str = "\"*/:<>?\\|";
+/// FSInfo's free cluster count, and its value when nobody knows
+const FS_INFO_FREE_COUNT: usize = 488;
+const UNKNOWN: u32 = 0xFFFF_FFFF;
+
+fn io(err: &'static str) -> Error {
+    warn!("FAT: {}", err);
+    Error::Io
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Where a directory entry is
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    sector: u64,
+    index: usize,
+}
+
+impl Slot {
+    fn ino(self) -> Ino {
+        self.sector * ENTRIES_PER_SECTOR as u64 + self.index as u64 + 1
+    }
+
+    fn from_ino(ino: Ino) -> Slot {
+        let pos = ino - 1;
+        Slot {
+            sector: pos / ENTRIES_PER_SECTOR as u64,
+            index: (pos % ENTRIES_PER_SECTOR as u64) as usize,
+        }
+    }
+}
+
+/// The parts of a short entry we use
+#[derive(Clone, Copy, Debug)]
+struct ShortEntry {
+    /// Space padded base and extension, without the dot
+    name: [u8; 11],
+    attr: u8,
+    case: u8,
+    cluster: u32,
+    size: u32,
+}
+
+impl ShortEntry {
+    fn parse(raw: &[u8]) -> Self {
+        ShortEntry {
+            name: raw[..11].try_into().unwrap(),
+            attr: raw[11],
+            case: raw[12],
+            cluster: ((u16_at(raw, 20) as u32) << 16) | u16_at(raw, 26) as u32,
+            size: u32_at(raw, 28),
+        }
+    }
+
+    fn write(&self, raw: &mut [u8]) {
+        raw.fill(0);
+        raw[..11].copy_from_slice(&self.name);
+        raw[11] = self.attr;
+        raw[12] = self.case;
+        // Created, accessed and modified
+        for offset in [16, 18, 24] {
+            raw[offset..offset + 2].copy_from_slice(&DATE.to_le_bytes());
+        }
+        self.write_location(raw);
+    }
+
+    /// Just the first cluster and size, leaving the rest of `raw` as it was
+    fn write_location(&self, raw: &mut [u8]) {
+        raw[20..22].copy_from_slice(&((self.cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(self.cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&self.size.to_le_bytes());
+    }
+
+    fn kind(&self) -> Kind {
+        if self.attr & ATTR_DIRECTORY != 0 {
+            Kind::Directory
+        } else {
+            Kind::File
+        }
+    }
+
+    /// What long name entries for it are checked against
+    fn checksum(&self) -> u8 {
+        self.name.iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+    }
+
+    /// As `BASE.EXT`, with the case it asks for
+    fn display_name(&self) -> ArrayString<12> {
+        let mut name = ArrayString::new();
+        let part = |bytes: &[u8], lower: bool, name: &mut ArrayString<12>| {
+            for &b in bytes.iter().take_while(|&&b| b != b' ') {
+                let c = if lower { b.to_ascii_lowercase() } else { b };
+                // Other code pages aren't supported, so anything past ASCII shows up as `_`
+                name.push(if c.is_ascii() { c as char } else { '_' });
+            }
+        };
+        // 0x05 stands in for a real 0xE5 as the first byte
+        let mut base = [0; 8];
+        base.copy_from_slice(&self.name[..8]);
+        if base[0] == 0x05 {
+            base[0] = DELETED;
+        }
+        part(&base, self.case & CASE_LOWER_BASE != 0, &mut name);
+        if self.name[8] != b' ' {
+            name.push('.');
+            part(&self.name[8..], self.case & CASE_LOWER_EXT != 0, &mut name);
+        }
+        name
+    }
+}
+
+/// A long name, gathered from its entries as they go by
+struct LongName {
+    units: [u16; MAX_LONG_ENTRIES * 13],
+    checksum: u8,
+    /// Sequence number of the entry that should come next. Entries count down to 1.
+    next: u8,
+    valid: bool,
+}
+
+impl LongName {
+    const fn new() -> Self {
+        LongName { units: [0; MAX_LONG_ENTRIES * 13], checksum: 0, next: 0, valid: false }
+    }
+
+    fn add(&mut self, raw: &[u8]) {
+        let seq = raw[0] & !LAST_LONG_ENTRY;
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+            self.units.fill(0);
+            self.checksum = raw[13];
+            self.next = seq;
+            self.valid = seq != 0 && seq as usize <= MAX_LONG_ENTRIES;
+        }
+        if !self.valid || seq != self.next || raw[13] != self.checksum {
+            self.valid = false;
+            return;
+        }
+        let start = (seq as usize - 1) * LONG_NAME_OFFSETS.len();
+        for (idx, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            self.units[start + idx] = u16_at(raw, offset);
+        }
+        self.next -= 1;
+    }
+
+    /// The name, if it was complete and belongs to `short`
+    fn take(&mut self, short: &ShortEntry) -> Option<ArrayString<MAX_NAME>> {
+        let valid = core::mem::replace(&mut self.valid, false);
+        if !valid || self.next != 0 || self.checksum != short.checksum() {
+            return None;
+        }
+        let len =
+            self.units.iter().position(|&u| u == 0 || u == 0xFFFF).unwrap_or(self.units.len());
+        let mut name = ArrayString::new();
+        for c in char::decode_utf16(self.units[..len].iter().copied()) {
+            if name.try_push(c.unwrap_or(char::REPLACEMENT_CHARACTER)).is_err() {
+                break;
+            }
+        }
+        Some(name)
+    }
+}
+
+/// A file found in a directory
+struct Found {
+    slot: Slot,
+    entry: ShortEntry,
+    name: ArrayString<MAX_NAME>,
+}
+
+/// Whether `name` can be stored as a short name as it is, and if so, how
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let trailing_dot = name.contains('.') && ext.is_empty();
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || trailing_dot {
+        return None;
+    }
+    let valid = |part: &str| {
+        part.bytes().all(|b| b.is_ascii_alphanumeric() || SHORT_NAME_EXTRA.contains(&b))
+    };
+    // Each part has to be all one case, which the entry can record
+    let lower = |part: &str| -> Option<bool> {
+        let upper = part.bytes().any(|b| b.is_ascii_uppercase());
+        let lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        (!(upper && lower)).then_some(lower)
+    };
+    if !valid(base) || !valid(ext) {
+        return None;
+    }
+    let mut case = 0;
+    if lower(base)? {
+        case |= CASE_LOWER_BASE;
+    }
+    if lower(ext)? {
+        case |= CASE_LOWER_EXT;
+    }
+    let mut short = [b' '; 11];
+    for (dst, b) in short.iter_mut().zip(base.bytes()) {
+        *dst = b.to_ascii_uppercase();
+    }
+    for (dst, b) in short[8..].iter_mut().zip(ext.bytes()) {
+        *dst = b.to_ascii_uppercase();
+    }
+    Some((short, case))
+}
+
+/// A short name for `name` ending in `~n`, like Windows makes up
+fn numbered_short_name(name: &str, n: u32) -> [u8; 11] {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+    let convert = |c: char| -> Option<u8> {
+        match c {
+            ' ' | '.' => None,
+            c if c.is_ascii_alphanumeric() => Some(c.to_ascii_uppercase() as u8),
+            c if c.is_ascii() && SHORT_NAME_EXTRA.contains(&(c as u8)) => Some(c as u8),
+            _ => Some(b'_'),
+        }
+    };
+    let mut tail = ArrayString::<8>::new();
+    let _ = core::fmt::write(&mut tail, format_args!("~{}", n));
+    let mut short = [b' '; 11];
+    let base_len = 8 - tail.len();
+    let mut len = 0;
+    for b in base.chars().filter_map(convert).take(base_len) {
+        short[len] = b;
+        len += 1;
+    }
+    short[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+    for (dst, b) in short[8..].iter_mut().zip(ext.chars().filter_map(convert)) {
+        *dst = b;
+    }
+    short
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error::Invalid);
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || INVALID_CHARS.contains(c)) {
+        return Err(Error::Invalid);
+    }
+    if name.encode_utf16().count() > MAX_LONG_NAME {
+        return Err(Error::NameTooLong);
+    }
+    Ok(())
+}
+
+struct State {
+    /// Where to start looking for a free cluster
+    next_free: u32,
+    /// FSInfo's free count has been marked unknown
+    free_count_stale: bool,
+}
+
+pub struct Fat32 {
+    device: &'static dyn BlockDevice,
+    sectors_per_cluster: u64,
+    /// First sector of the first FAT
+    fat_start: u64,
+    fat_sectors: u64,
+    num_fats: u64,
+    /// First sector of cluster 2
+    data_start: u64,
+    /// One past the highest cluster number
+    cluster_end: u32,
+    root_cluster: u32,
+    fs_info: Option<u64>,
+    state: Mutex<State>,
+}
+
+impl Fat32 {
+    /// Read the boot sector of the filesystem on `device`
+    pub fn new(device: &'static dyn BlockDevice) -> core::result::Result<Self, &'static str> {
+        let mut boot = [0; BLOCK_SIZE];
+        device.read_blocks(0, &mut boot)?;
+        if boot[510..] != [0x55, 0xAA] {
+            return Err("No boot sector signature");
+        }
+        if u16_at(&boot, 11) as usize != BLOCK_SIZE {
+            return Err("Only 512 byte sectors are supported");
+        }
+        let sectors_per_cluster = boot[13] as u64;
+        if !sectors_per_cluster.is_power_of_two() {
+            return Err("Bad cluster size");
+        }
+        let reserved = u16_at(&boot, 14) as u64;
+        let num_fats = boot[16] as u64;
+        // FAT12 and 16 have a fixed root directory and a 16 bit FAT size here
+        if u16_at(&boot, 17) != 0 || u16_at(&boot, 22) != 0 || num_fats == 0 {
+            return Err("Not FAT32");
+        }
+        let total = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = u32_at(&boot, 36) as u64;
+        let data_start = reserved + num_fats * fat_sectors;
+        let clusters = total.checked_sub(data_start).ok_or("Bad FAT size")? / sectors_per_cluster;
+        // Whichever runs out first: the data area, or the FAT
+        let cluster_end = (clusters + FIRST_CLUSTER as u64)
+            .min(fat_sectors * (BLOCK_SIZE / 4) as u64)
+            .min(END_OF_CHAIN as u64) as u32;
+        let root_cluster = u32_at(&boot, 44);
+        if !(FIRST_CLUSTER..cluster_end).contains(&root_cluster) {
+            return Err("Bad root directory cluster");
+        }
+        let fs_info = match u16_at(&boot, 48) {
+            0 | 0xFFFF => None,
+            sector => Some(sector as u64),
+        };
+        Ok(Fat32 {
+            device,
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_sectors,
+            num_fats,
+            data_start,
+            cluster_end,
+            root_cluster,
+            fs_info,
+            state: Mutex::new(State { next_free: FIRST_CLUSTER, free_count_stale: false }),
+        })
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * BLOCK_SIZE
+    }
+
+    /// Whether `cluster` is in the data area
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..self.cluster_end).contains(&cluster)
+    }
+
+    /// Longest a chain can be without going through a cluster twice. Anything longer loops.
+    fn max_chain(&self) -> usize {
+        (self.cluster_end - FIRST_CLUSTER) as usize
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.device.read_blocks(sector, buf).map_err(io)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<()> {
+        self.device.write_blocks(sector, buf).map_err(io)
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32> {
+        let offset = cluster as usize * 4;
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(self.fat_start + (offset / BLOCK_SIZE) as u64, &mut sector)?;
+        Ok(u32_at(&sector, offset % BLOCK_SIZE) & CLUSTER_MASK)
+    }
+
+    /// Set `cluster`'s entry in every FAT
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<()> {
+        let offset = cluster as usize * 4;
+        let mut sector = [0; BLOCK_SIZE];
+        for fat in 0..self.num_fats {
+            let lba = self.fat_start + fat * self.fat_sectors + (offset / BLOCK_SIZE) as u64;
+            self.read_sector(lba, &mut sector)?;
+            let entry = &mut sector[offset % BLOCK_SIZE..][..4];
+            // The top four bits are reserved, and have to be kept
+            let old = u32::from_le_bytes((&*entry).try_into().unwrap());
+            entry.copy_from_slice(&((old & !CLUSTER_MASK) | value).to_le_bytes());
+            self.write_sector(lba, &sector)?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>> {
+        match self.fat_entry(cluster)? {
+            next if next >= END_OF_CHAIN => Ok(None),
+            next if self.is_cluster(next) => Ok(Some(next)),
+            _ => Err(io("Broken cluster chain")),
+        }
+    }
+
+    /// The `n`th cluster of the chain starting at `first`
+    fn nth_cluster(&self, first: u32, n: usize) -> Result<Option<u32>> {
+        let mut cluster = first;
+        for _ in 0..n {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(cluster))
+    }
+
+    /// Take a free cluster, zero it, and put it on the end of the chain ending at `prev`
+    fn alloc_cluster(&self, state: &mut State, prev: Option<u32>) -> Result<u32> {
+        let per_sector = (BLOCK_SIZE / 4) as u32;
+        let count = self.cluster_end - FIRST_CLUSTER;
+        let mut sector = [0; BLOCK_SIZE];
+        let mut loaded = None;
+        let cluster = (0..count)
+            .map(|offset| FIRST_CLUSTER + (state.next_free - FIRST_CLUSTER + offset) % count)
+            .find_map(|cluster| {
+                let lba = self.fat_start + (cluster / per_sector) as u64;
+                if loaded != Some(lba) {
+                    if let Err(err) = self.read_sector(lba, &mut sector) {
+                        return Some(Err(err));
+                    }
+                    loaded = Some(lba);
+                }
+                let idx = (cluster % per_sector) as usize * 4;
+                (u32_at(&sector, idx) & CLUSTER_MASK == FREE).then_some(Ok(cluster))
+            })
+            .ok_or(Error::NoSpace)??;
+
+        let first = self.cluster_sector(cluster);
+        let zeroes = [0; BLOCK_SIZE];
+        for lba in first..first + self.sectors_per_cluster {
+            self.write_sector(lba, &zeroes)?;
+        }
+        self.set_fat_entry(cluster, CLUSTER_MASK)?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+        state.next_free = if cluster + 1 < self.cluster_end { cluster + 1 } else { FIRST_CLUSTER };
+        self.mark_free_count_stale(state)?;
+        Ok(cluster)
+    }
+
+    /// We don't keep count of free clusters, so make sure nobody trusts the old count
+    fn mark_free_count_stale(&self, state: &mut State) -> Result<()> {
+        let Some(lba) = self.fs_info.filter(|_| !state.free_count_stale) else {
+            return Ok(());
+        };
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(lba, &mut sector)?;
+        sector[FS_INFO_FREE_COUNT..FS_INFO_FREE_COUNT + 4].copy_from_slice(&UNKNOWN.to_le_bytes());
+        self.write_sector(lba, &sector)?;
+        state.free_count_stale = true;
+        Ok(())
+    }
+
+    /// The last cluster of the chain starting at `first`, and how many clusters it has
+    fn chain_end(&self, first: u32) -> Result<(u32, usize)> {
+        let mut last = first;
+        let mut len = 1;
+        while let Some(next) = self.next_cluster(last)? {
+            if len == self.max_chain() {
+                return Err(io("Cluster chain loops"));
+            }
+            last = next;
+            len += 1;
+        }
+        Ok((last, len))
+    }
+
+    fn free_chain(&self, first: u32) -> Result<()> {
+        let mut cluster = Some(first);
+        for _ in 0..self.max_chain() {
+            let Some(current) = cluster else {
+                return Ok(());
+            };
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, FREE)?;
+        }
+        match cluster {
+            Some(_) => Err(io("Cluster chain loops")),
+            None => Ok(()),
+        }
+    }
+
+    /// Grow the chain starting at `first`, or make one if it's 0, until it holds `size` bytes.
+    /// Returns its first cluster.
+    fn reserve(&self, state: &mut State, first: u32, size: usize) -> Result<u32> {
+        let needed = size.div_ceil(self.cluster_bytes());
+        if needed == 0 {
+            return Ok(first);
+        }
+        let (first, mut last, mut have) = if first == 0 {
+            let cluster = self.alloc_cluster(state, None)?;
+            (cluster, cluster, 1)
+        } else {
+            let (last, have) = self.chain_end(first)?;
+            (first, last, have)
+        };
+        while have < needed {
+            last = self.alloc_cluster(state, Some(last))?;
+            have += 1;
+        }
+        Ok(first)
+    }
+
+    /// Call `f` with each sector that bytes `offset..offset + len` of the chain starting at
+    /// `first` are in, where in the sector they start, and how many of them there are
+    fn for_each_piece(
+        &self,
+        first: u32,
+        offset: usize,
+        len: usize,
+        mut f: impl FnMut(u64, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let cluster_bytes = self.cluster_bytes();
+        let mut cluster = self
+            .nth_cluster(first, offset / cluster_bytes)?
+            .ok_or_else(|| io("File is shorter than its size"))?;
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let in_cluster = pos % cluster_bytes;
+            let lba = self.cluster_sector(cluster) + (in_cluster / BLOCK_SIZE) as u64;
+            let start = in_cluster % BLOCK_SIZE;
+            let count = (end - pos).min(BLOCK_SIZE - start);
+            f(lba, start, count)?;
+            pos += count;
+            if pos % cluster_bytes == 0 && pos < end {
+                cluster = self
+                    .next_cluster(cluster)?
+                    .ok_or_else(|| io("File is shorter than its size"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_data(&self, first: u32, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let mut sector = [0; BLOCK_SIZE];
+        let mut done = 0;
+        self.for_each_piece(first, offset, buf.len(), |lba, start, count| {
+            let dst = &mut buf[done..done + count];
+            if count == BLOCK_SIZE {
+                self.read_sector(lba, dst)?;
+            } else {
+                self.read_sector(lba, &mut sector)?;
+                dst.copy_from_slice(&sector[start..start + count]);
+            }
+            done += count;
+            Ok(())
+        })
+    }
+
+    /// Write `buf` at `offset` in the chain starting at `first`, which has to be long enough.
+    /// With `buf` as `None`, writes that many zeroes.
+    fn write_data(&self, first: u32, offset: usize, len: usize, buf: Option<&[u8]>) -> Result<()> {
+        let mut sector = [0; BLOCK_SIZE];
+        let mut done = 0;
+        self.for_each_piece(first, offset, len, |lba, start, count| {
+            if count != BLOCK_SIZE {
+                self.read_sector(lba, &mut sector)?;
+            }
+            match buf {
+                Some(buf) => sector[start..start + count].copy_from_slice(&buf[done..done + count]),
+                None => sector[start..start + count].fill(0),
+            }
+            self.write_sector(lba, &sector)?;
+            done += count;
+            Ok(())
+        })
+    }
+
+    /// Change the short entry at `slot`
+    fn update_entry(&self, slot: Slot, f: impl FnOnce(&mut [u8])) -> Result<()> {
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(slot.sector, &mut sector)?;
+        f(&mut sector[slot.index * ENTRY_SIZE..][..ENTRY_SIZE]);
+        self.write_sector(slot.sector, &sector)
+    }
+
+    /// The short entry for inode `ino`, which can't be the root
+    fn entry(&self, ino: Ino) -> Result<ShortEntry> {
+        let slot = Slot::from_ino(ino);
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(slot.sector, &mut sector)?;
+        let raw = &sector[slot.index * ENTRY_SIZE..][..ENTRY_SIZE];
+        if raw[0] == END_OF_DIR || raw[0] == DELETED || raw[11] == ATTR_LONG_NAME {
+            return Err(Error::NotFound);
+        }
+        let entry = ShortEntry::parse(raw);
+        // Empty files have no clusters. Anything else has to start in the data area.
+        let empty = entry.cluster == 0 && entry.size == 0 && entry.kind() == Kind::File;
+        if !empty && !self.is_cluster(entry.cluster) {
+            return Err(io("Directory entry points outside the data area"));
+        }
+        Ok(entry)
+    }
+
+    /// The first cluster of directory `dir`
+    fn dir_cluster(&self, dir: Ino) -> Result<u32> {
+        if dir == ROOT {
+            return Ok(self.root_cluster);
+        }
+        let entry = self.entry(dir)?;
+        if entry.kind() != Kind::Directory {
+            return Err(Error::NotDirectory);
+        }
+        Ok(entry.cluster)
+    }
+
+    /// Call `f` with every slot in the directory starting at `cluster`, until it breaks
+    fn for_each_slot<T>(
+        &self,
+        cluster: u32,
+        mut f: impl FnMut(Slot, &[u8]) -> ControlFlow<T>,
+    ) -> Result<Option<T>> {
+        let mut cluster = Some(cluster);
+        let mut sector = [0; BLOCK_SIZE];
+        for _ in 0..self.max_chain() {
+            let Some(current) = cluster else {
+                return Ok(None);
+            };
+            let first = self.cluster_sector(current);
+            for lba in first..first + self.sectors_per_cluster {
+                self.read_sector(lba, &mut sector)?;
+                for (index, raw) in sector.chunks_exact(ENTRY_SIZE).enumerate() {
+                    if let ControlFlow::Break(res) = f(Slot { sector: lba, index }, raw) {
+                        return Ok(Some(res));
+                    }
+                }
+            }
+            cluster = self.next_cluster(current)?;
+        }
+        match cluster {
+            Some(_) => Err(io("Cluster chain loops")),
+            None => Ok(None),
+        }
+    }
+
+    /// Call `f` with every file in the directory starting at `cluster`, until it breaks
+    fn for_each_file<T>(
+        &self,
+        cluster: u32,
+        mut f: impl FnMut(Found) -> ControlFlow<T>,
+    ) -> Result<Option<T>> {
+        let mut long = LongName::new();
+        let res = self.for_each_slot(cluster, |slot, raw| {
+            if raw[0] == END_OF_DIR {
+                return ControlFlow::Break(None);
+            }
+            if raw[0] == DELETED {
+                long.valid = false;
+                return ControlFlow::Continue(());
+            }
+            if raw[11] == ATTR_LONG_NAME {
+                long.add(raw);
+                return ControlFlow::Continue(());
+            }
+            let entry = ShortEntry::parse(raw);
+            let name = long.take(&entry);
+            if entry.attr & ATTR_VOLUME_ID != 0 || entry.name[0] == b'.' {
+                return ControlFlow::Continue(());
+            }
+            let name = name.unwrap_or_else(|| {
+                // 12 bytes always fit
+                ArrayString::from(entry.display_name().as_str()).unwrap()
+            });
+            match f(Found { slot, entry, name }) {
+                ControlFlow::Break(res) => ControlFlow::Break(Some(res)),
+                ControlFlow::Continue(()) => ControlFlow::Continue(()),
+            }
+        })?;
+        Ok(res.flatten())
+    }
+
+    fn find(&self, cluster: u32, name: &str) -> Result<Option<Found>> {
+        self.for_each_file(cluster, |found| {
+            if found.name.eq_ignore_ascii_case(name) {
+                ControlFlow::Break(found)
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+    }
+
+    /// Whether a file in the directory starting at `cluster` has short name `short`
+    fn short_name_taken(&self, cluster: u32, short: &[u8; 11]) -> Result<bool> {
+        let taken = self.for_each_file(cluster, |found| {
+            if &found.entry.name == short {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
+        Ok(taken.is_some())
+    }
+
+    /// `count` free slots in a row in the directory starting at `cluster`, which is grown if
+    /// there aren't enough
+    fn free_slots(
+        &self,
+        state: &mut State,
+        cluster: u32,
+        count: usize,
+    ) -> Result<ArrayVec<Slot, { MAX_LONG_ENTRIES + 1 }>> {
+        loop {
+            let mut run = ArrayVec::new();
+            let found = self.for_each_slot(cluster, |slot, raw| {
+                if raw[0] == END_OF_DIR || raw[0] == DELETED {
+                    run.push(slot);
+                    if run.len() == count {
+                        return ControlFlow::Break(());
+                    }
+                } else {
+                    run.clear();
+                }
+                ControlFlow::Continue(())
+            })?;
+            if found.is_some() {
+                return Ok(run);
+            }
+            // Out of room. A new cluster is zeroed, so it's all free.
+            let (last, _) = self.chain_end(cluster)?;
+            self.alloc_cluster(state, Some(last))?;
+        }
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat> {
+        if ino == ROOT {
+            return Ok(Stat { ino, kind: Kind::Directory, mode: 0o755, size: 0 });
+        }
+        let _state = self.state.lock();
+        let entry = self.entry(ino)?;
+        let mode = match entry.kind() {
+            Kind::Directory => 0o755,
+            _ if entry.attr & ATTR_READ_ONLY != 0 => 0o444,
+            _ => 0o644,
+        };
+        Ok(Stat { ino, kind: entry.kind(), mode, size: entry.size as usize })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino> {
+        let _state = self.state.lock();
+        let cluster = self.dir_cluster(dir)?;
+        let found = self.find(cluster, name)?.ok_or(Error::NotFound)?;
+        Ok(found.slot.ino())
+    }
+
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>> {
+        let _state = self.state.lock();
+        let cluster = self.dir_cluster(dir)?;
+        let mut seen = 0;
+        self.for_each_file(cluster, |found| {
+            if seen == index {
+                return ControlFlow::Break(DirEntry {
+                    ino: found.slot.ino(),
+                    kind: found.entry.kind(),
+                    name: found.name,
+                });
+            }
+            seen += 1;
+            ControlFlow::Continue(())
+        })
+    }
+
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if ino == ROOT {
+            return Err(Error::IsDirectory);
+        }
+        let _state = self.state.lock();
+        let entry = self.entry(ino)?;
+        if entry.kind() == Kind::Directory {
+            return Err(Error::IsDirectory);
+        }
+        let len = (entry.size as usize).saturating_sub(offset).min(buf.len());
+        self.read_data(entry.cluster, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write(&self, ino: Ino, offset: usize, buf: &[u8]) -> Result<usize> {
+        if ino == ROOT {
+            return Err(Error::IsDirectory);
+        }
+        let mut state = self.state.lock();
+        let mut entry = self.entry(ino)?;
+        if entry.kind() == Kind::Directory {
+            return Err(Error::IsDirectory);
+        }
+        let end = offset.checked_add(buf.len()).ok_or(Error::NoSpace)?;
+        let new_size = u32::try_from(end.max(entry.size as usize)).map_err(|_| Error::NoSpace)?;
+        let first = self.reserve(&mut state, entry.cluster, end)?;
+        // Whatever was on disk past the old end has to read back as zeroes
+        let old_size = entry.size as usize;
+        if offset > old_size {
+            self.write_data(first, old_size, offset - old_size, None)?;
+        }
+        self.write_data(first, offset, buf.len(), Some(buf))?;
+
+        entry.cluster = first;
+        entry.size = new_size;
+        self.update_entry(Slot::from_ino(ino), |raw| entry.write_location(raw))?;
+        Ok(buf.len())
+    }
+
+    fn create(&self, dir: Ino, name: &str, kind: Kind, _mode: u32) -> Result<Ino> {
+        check_name(name)?;
+        if kind != Kind::File && kind != Kind::Directory {
+            return Err(Error::NotSupported);
+        }
+        let mut state = self.state.lock();
+        let parent = self.dir_cluster(dir)?;
+        if self.find(parent, name)?.is_some() {
+            return Err(Error::Exists);
+        }
+
+        let exact = exact_short_name(name).filter(|(short, _)| {
+            !self.short_name_taken(parent, short).unwrap_or(true)
+        });
+        let (short, case, long_entries) = match exact {
+            Some((short, case)) => (short, case, 0),
+            None => {
+                let mut short = None;
+                for n in 1..1000 {
+                    let candidate = numbered_short_name(name, n);
+                    if !self.short_name_taken(parent, &candidate)? {
+                        short = Some(candidate);
+                        break;
+                    }
+                }
+                let short = short.ok_or(Error::Exists)?;
+                (short, 0, name.encode_utf16().count().div_ceil(LONG_NAME_OFFSETS.len()))
+            }
+        };
+
+        let mut entry = ShortEntry {
+            name: short,
+            attr: if kind == Kind::Directory { ATTR_DIRECTORY } else { ATTR_ARCHIVE },
+            case,
+            cluster: 0,
+            size: 0,
+        };
+        if kind == Kind::Directory {
+            entry.cluster = self.alloc_cluster(&mut state, None)?;
+            let mut sector = [0; BLOCK_SIZE];
+            let dot = ShortEntry { name: *b".          ", ..entry };
+            // The root is cluster 0 to `..`
+            let parent_cluster = if dir == ROOT { 0 } else { parent };
+            let dot_dot = ShortEntry { name: *b"..         ", cluster: parent_cluster, ..entry };
+            dot.write(&mut sector[..ENTRY_SIZE]);
+            dot_dot.write(&mut sector[ENTRY_SIZE..2 * ENTRY_SIZE]);
+            self.write_sector(self.cluster_sector(entry.cluster), &sector)?;
+        }
+
+        let slots = self.free_slots(&mut state, parent, long_entries + 1)?;
+        let units: ArrayVec<u16, { MAX_LONG_ENTRIES * 13 }> = name.encode_utf16().collect();
+        let checksum = entry.checksum();
+        let mut sector = [0; BLOCK_SIZE];
+        let mut loaded = None;
+        for (idx, &slot) in slots.iter().enumerate() {
+            if loaded != Some(slot.sector) {
+                if let Some(lba) = loaded {
+                    self.write_sector(lba, &sector)?;
+                }
+                self.read_sector(slot.sector, &mut sector)?;
+                loaded = Some(slot.sector);
+            }
+            let raw = &mut sector[slot.index * ENTRY_SIZE..][..ENTRY_SIZE];
+            if idx == long_entries {
+                entry.write(raw);
+                continue;
+            }
+            // The end of the name comes first
+            let seq = long_entries - idx;
+            raw.fill(0);
+            raw[0] = seq as u8 | if idx == 0 { LAST_LONG_ENTRY } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+            for (n, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                // Padded with a NUL, then 0xFFFF
+                let pos = (seq - 1) * LONG_NAME_OFFSETS.len() + n;
+                let unit = match pos.cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[pos],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        if let Some(lba) = loaded {
+            self.write_sector(lba, &sector)?;
+        }
+        Ok(slots.last().unwrap().ino())
+    }
+
+    fn sync(&self) -> Result<()> {
+        let _state = self.state.lock();
+        self.device.flush().map_err(io)
+    }
+
+    fn truncate(&self, ino: Ino, size: usize) -> Result<()> {
+        if ino == ROOT {
+            return Err(Error::IsDirectory);
+        }
+        let mut state = self.state.lock();
+        let mut entry = self.entry(ino)?;
+        if entry.kind() == Kind::Directory {
+            return Err(Error::IsDirectory);
+        }
+        let old_size = entry.size as usize;
+        let new_size = u32::try_from(size).map_err(|_| Error::NoSpace)?;
+        if size > old_size {
+            entry.cluster = self.reserve(&mut state, entry.cluster, size)?;
+            self.write_data(entry.cluster, old_size, size - old_size, None)?;
+        } else if entry.cluster != 0 {
+            let keep = size.div_ceil(self.cluster_bytes());
+            if keep == 0 {
+                self.free_chain(entry.cluster)?;
+                entry.cluster = 0;
+            } else if let Some(last) = self.nth_cluster(entry.cluster, keep - 1)? {
+                if let Some(rest) = self.next_cluster(last)? {
+                    self.set_fat_entry(last, CLUSTER_MASK)?;
+                    self.free_chain(rest)?;
+                }
+            }
+        }
+        entry.size = new_size;
+        self.update_entry(Slot::from_ino(ino), |raw| entry.write_location(raw))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ramdisk::MemDisk;
+
+    const BLOCKS: usize = 64;
+    static DISK: MemDisk<BLOCKS> = MemDisk::new();
+
+    const RESERVED: u16 = 2;
+    const HELLO: &[u8] = b"Hello, world\n";
+
+    /// A filesystem with two FATs of a sector each, the root directory in cluster 2, and
+    /// `hello.txt` in cluster 3
+    fn format(blocks: &mut [[u8; BLOCK_SIZE]; BLOCKS], sectors_per_cluster: u8, total: u32) {
+        let boot = &mut blocks[0];
+        boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        boot[3..11].copy_from_slice(b"MSWIN4.1");
+        boot[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        boot[13] = sectors_per_cluster;
+        boot[14..16].copy_from_slice(&RESERVED.to_le_bytes());
+        boot[16] = 2;
+        boot[21] = 0xF8;
+        boot[32..36].copy_from_slice(&total.to_le_bytes());
+        boot[36..40].copy_from_slice(&1u32.to_le_bytes());
+        boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+        boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+        boot[82..90].copy_from_slice(b"FAT32   ");
+        boot[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        let fs_info = &mut blocks[1];
+        fs_info[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fs_info[FS_INFO_FREE_COUNT..][..4].copy_from_slice(&10u32.to_le_bytes());
+        fs_info[508..].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+        for fat in 2..4 {
+            let entries = [0x0FFF_FFF8u32, CLUSTER_MASK, CLUSTER_MASK, CLUSTER_MASK];
+            for (idx, entry) in entries.iter().enumerate() {
+                blocks[fat][idx * 4..][..4].copy_from_slice(&entry.to_le_bytes());
+            }
+        }
+
+        let data_start = RESERVED as usize + 2;
+        let hello = ShortEntry {
+            name: *b"HELLO   TXT",
+            attr: ATTR_ARCHIVE,
+            case: CASE_LOWER_BASE | CASE_LOWER_EXT,
+            cluster: 3,
+            size: HELLO.len() as u32,
+        };
+        hello.write(&mut blocks[data_start][..ENTRY_SIZE]);
+        blocks[data_start + sectors_per_cluster as usize][..HELLO.len()].copy_from_slice(HELLO);
+    }
+
+    fn mount(sectors_per_cluster: u8) -> Fat32 {
+        DISK.load(|blocks| format(blocks, sectors_per_cluster, BLOCKS as u32));
+        Fat32::new(&DISK).unwrap()
+    }
+
+    fn bad_image(f: impl FnOnce(&mut [[u8; BLOCK_SIZE]; BLOCKS])) -> Option<&'static str> {
+        DISK.load(|blocks| {
+            format(blocks, 1, BLOCKS as u32);
+            f(blocks);
+        });
+        Fat32::new(&DISK).err()
+    }
+
+    #[test_case]
+    fn rejects_bad_boot_sectors() {
+        assert_eq!(bad_image(|blocks| blocks[0][510] = 0), Some("No boot sector signature"));
+        assert_eq!(
+            bad_image(|blocks| blocks[0][11..13].copy_from_slice(&1024u16.to_le_bytes())),
+            Some("Only 512 byte sectors are supported")
+        );
+        for sectors_per_cluster in [0, 3, 6, 255] {
+            let err = bad_image(|blocks| blocks[0][13] = sectors_per_cluster);
+            assert_eq!(err, Some("Bad cluster size"));
+        }
+        // A FAT16 root directory
+        assert_eq!(
+            bad_image(|blocks| blocks[0][17..19].copy_from_slice(&512u16.to_le_bytes())),
+            Some("Not FAT32")
+        );
+        assert_eq!(bad_image(|blocks| blocks[0][16] = 0), Some("Not FAT32"));
+        // Fewer sectors than the reserved ones and the FATs take
+        assert_eq!(
+            bad_image(|blocks| blocks[0][32..36].copy_from_slice(&3u32.to_le_bytes())),
+            Some("Bad FAT size")
+        );
+        for root in [0u32, 1, 62, u32::MAX] {
+            assert_eq!(
+                bad_image(|blocks| blocks[0][44..48].copy_from_slice(&root.to_le_bytes())),
+                Some("Bad root directory cluster")
+            );
+        }
+    }
+
+    #[test_case]
+    fn reads_existing_file() {
+        for sectors_per_cluster in [1, 2] {
+            let fs = mount(sectors_per_cluster);
+            let ino = fs.lookup(ROOT, "hello.txt").unwrap();
+            // Names are matched ignoring case
+            assert_eq!(fs.lookup(ROOT, "HELLO.TXT"), Ok(ino));
+            assert_eq!(fs.lookup(ROOT, "nope.txt"), Err(Error::NotFound));
+            let stat = fs.stat(ino).unwrap();
+            assert_eq!((stat.kind, stat.size), (Kind::File, HELLO.len()));
+
+            let mut buf = [0; 64];
+            assert_eq!(fs.read(ino, 0, &mut buf), Ok(HELLO.len()));
+            assert_eq!(&buf[..HELLO.len()], HELLO);
+            assert_eq!(fs.read(ino, 7, &mut buf), Ok(6));
+            assert_eq!(&buf[..6], b"world\n");
+            assert_eq!(fs.read(ino, 100, &mut buf), Ok(0));
+
+            let entry = fs.read_dir(ROOT, 0).unwrap().unwrap();
+            assert_eq!((entry.ino, entry.name.as_str()), (ino, "hello.txt"));
+            assert!(fs.read_dir(ROOT, 1).unwrap().is_none());
+        }
+    }
+
+    #[test_case]
+    fn writes_long_names_across_clusters() {
+        let fs = mount(1);
+        let name = "A long file name.text";
+        let ino = fs.create(ROOT, name, Kind::File, 0o644).unwrap();
+        assert_eq!(fs.create(ROOT, name, Kind::File, 0o644), Err(Error::Exists));
+
+        let mut data = [0; 3 * BLOCK_SIZE - 100];
+        data.iter_mut().enumerate().for_each(|(idx, b)| *b = idx as u8);
+        assert_eq!(fs.write(ino, 0, &data), Ok(data.len()));
+        assert_eq!(fs.stat(ino).unwrap().size, data.len());
+
+        // A new mount has to find it all on the disk
+        let fs = Fat32::new(&DISK).unwrap();
+        assert_eq!(fs.lookup(ROOT, "a LONG file NAME.TEXT"), Ok(ino));
+        let mut buf = [0; 3 * BLOCK_SIZE];
+        assert_eq!(fs.read(ino, 0, &mut buf), Ok(data.len()));
+        assert_eq!(&buf[..data.len()], &data[..]);
+        let entry = fs.read_dir(ROOT, 1).unwrap().unwrap();
+        assert_eq!(entry.name.as_str(), name);
+
+        // Three clusters in a chain
+        let first = fs.entry(ino).unwrap().cluster;
+        assert_eq!(fs.nth_cluster(first, 2).unwrap().map(|c| fs.next_cluster(c)), Some(Ok(None)));
+        // Both FATs were changed
+        DISK.inspect(|blocks| assert_eq!(blocks[2], blocks[3]));
+    }
+
+    #[test_case]
+    fn writes_past_the_end_leave_zeroes() {
+        let fs = mount(1);
+        let ino = fs.lookup(ROOT, "hello.txt").unwrap();
+        assert_eq!(fs.write(ino, 1000, b"!"), Ok(1));
+        let mut buf = [0xFF; 1001];
+        assert_eq!(fs.read(ino, 0, &mut buf), Ok(1001));
+        assert_eq!(&buf[..HELLO.len()], HELLO);
+        assert!(buf[HELLO.len()..1000].iter().all(|&b| b == 0));
+        assert_eq!(buf[1000], b'!');
+    }
+
+    #[test_case]
+    fn makes_directories() {
+        let fs = mount(1);
+        let dir = fs.create(ROOT, "sub", Kind::Directory, 0o755).unwrap();
+        assert_eq!(fs.stat(dir).unwrap().kind, Kind::Directory);
+        let file = fs.create(dir, "inner.txt", Kind::File, 0o644).unwrap();
+        fs.write(file, 0, b"inside").unwrap();
+        assert_eq!(fs.lookup(dir, "inner.txt"), Ok(file));
+        assert_eq!(fs.lookup(ROOT, "inner.txt"), Err(Error::NotFound));
+        assert_eq!(fs.read(dir, 0, &mut [0; 4]), Err(Error::IsDirectory));
+
+        // `.` and `..` come first, but aren't listed
+        let cluster = fs.entry(dir).unwrap().cluster;
+        let mut sector = [0; BLOCK_SIZE];
+        fs.read_sector(fs.cluster_sector(cluster), &mut sector).unwrap();
+        assert_eq!(&sector[..11], b".          ");
+        assert_eq!(&sector[ENTRY_SIZE..ENTRY_SIZE + 11], b"..         ");
+        assert_eq!(fs.read_dir(dir, 0).unwrap().unwrap().name.as_str(), "inner.txt");
+    }
+
+    #[test_case]
+    fn truncates() {
+        let fs = mount(1);
+        let ino = fs.create(ROOT, "big", Kind::File, 0o644).unwrap();
+        fs.write(ino, 0, &[7; 3 * BLOCK_SIZE]).unwrap();
+        let first = fs.entry(ino).unwrap().cluster;
+        let third = fs.nth_cluster(first, 2).unwrap().unwrap();
+
+        fs.truncate(ino, 600).unwrap();
+        assert_eq!(fs.stat(ino).unwrap().size, 600);
+        assert_eq!(fs.fat_entry(third), Ok(FREE));
+        fs.truncate(ino, 1000).unwrap();
+        let mut buf = [0; 1000];
+        assert_eq!(fs.read(ino, 0, &mut buf), Ok(1000));
+        assert!(buf[..600].iter().all(|&b| b == 7) && buf[600..].iter().all(|&b| b == 0));
+
+        fs.truncate(ino, 0).unwrap();
+        assert_eq!(fs.entry(ino).unwrap().cluster, 0);
+        assert_eq!(fs.fat_entry(first), Ok(FREE));
+    }
+
+    #[test_case]
+    fn runs_out_of_space() {
+        let fs = mount(1);
+        let ino = fs.create(ROOT, "huge", Kind::File, 0o644).unwrap();
+        // Clusters 2 to 61, less the root and hello.txt
+        let free = BLOCKS - RESERVED as usize - 2 - 2;
+        assert_eq!(fs.write(ino, 0, &[1; BLOCK_SIZE]), Ok(BLOCK_SIZE));
+        assert_eq!(fs.write(ino, free * BLOCK_SIZE, &[1]), Err(Error::NoSpace));
+        assert_eq!(fs.write(ino, (free - 1) * BLOCK_SIZE, &[1]), Ok(1));
+        // The free count can't be trusted any more
+        DISK.inspect(|blocks| {
+            assert_eq!(u32_at(&blocks[1], FS_INFO_FREE_COUNT), UNKNOWN);
+        });
+    }
+
+    /// Point the entry for `ino` at `cluster`
+    fn move_entry(fs: &Fat32, ino: Ino, cluster: u32) {
+        fs.update_entry(Slot::from_ino(ino), |raw| {
+            ShortEntry { cluster, ..ShortEntry::parse(raw) }.write_location(raw)
+        })
+        .unwrap();
+    }
+
+    #[test_case]
+    fn rejects_entries_outside_the_data_area() {
+        // Clusters 2 to 61
+        for cluster in [0, 1, 62, CLUSTER_MASK] {
+            let fs = mount(1);
+            let ino = fs.lookup(ROOT, "hello.txt").unwrap();
+            move_entry(&fs, ino, cluster);
+            assert_eq!(fs.stat(ino).err(), Some(Error::Io));
+            assert_eq!(fs.read(ino, 0, &mut [0; 4]), Err(Error::Io));
+            assert_eq!(fs.write(ino, 0, b"x"), Err(Error::Io));
+            assert_eq!(fs.truncate(ino, 0), Err(Error::Io));
+        }
+
+        let fs = mount(1);
+        let dir = fs.create(ROOT, "sub", Kind::Directory, 0o755).unwrap();
+        move_entry(&fs, dir, 0);
+        assert_eq!(fs.lookup(dir, "x"), Err(Error::Io));
+        assert_eq!(fs.create(dir, "x", Kind::File, 0o644), Err(Error::Io));
+        // Empty files don't need a cluster
+        let ino = fs.create(ROOT, "empty", Kind::File, 0o644).unwrap();
+        assert_eq!(fs.read(ino, 0, &mut [0; 4]), Ok(0));
+    }
+
+    #[test_case]
+    fn stops_at_looping_chains() {
+        let fs = mount(1);
+        let ino = fs.lookup(ROOT, "hello.txt").unwrap();
+        // Nothing in the root directory ends the listing, and its chain runs back into itself
+        DISK.load(|blocks| {
+            format(blocks, 1, BLOCKS as u32);
+            let root = &mut blocks[RESERVED as usize + 2];
+            root[ENTRY_SIZE..].chunks_exact_mut(ENTRY_SIZE).for_each(|raw| raw[0] = DELETED);
+        });
+        fs.set_fat_entry(2, 2).unwrap();
+        assert_eq!(fs.lookup(ROOT, "nope"), Err(Error::Io));
+
+        fs.set_fat_entry(3, 3).unwrap();
+        assert_eq!(fs.write(ino, 5000, b"x"), Err(Error::Io));
+        assert_eq!(fs.truncate(ino, 0), Err(Error::Io));
+    }
+
+    #[test_case]
+    fn rejects_bad_names() {
+        let fs = mount(1);
+        for name in ["", ".", "..", "a/b", "what?", "tab\there"] {
+            assert_eq!(fs.create(ROOT, name, Kind::File, 0o644), Err(Error::Invalid));
+        }
+        let mut long = ArrayString::<{ MAX_LONG_NAME + 1 }>::new();
+        (0..=MAX_LONG_NAME).for_each(|_| long.push('x'));
+        assert_eq!(fs.create(ROOT, &long, Kind::File, 0o644), Err(Error::NameTooLong));
+    }
+}
@@ -0,0 +1,240 @@
+//! Open files, and the descriptor tables processes reach them through.
+use super::{resolve, resolve_parent, DirEntry, Error, Inode, Kind, Result, Stat};
+use crate::sync::Mutex;
+
+/// Files open across the whole system
+const MAX_OPEN_FILES: usize = 128;
+/// File descriptors each process can have
+pub const MAX_FDS: usize = 32;
+
+/// How to open a file. Reading only, by default.
+#[derive(Clone, Copy, Debug)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    /// Every write goes at the end
+    pub append: bool,
+    /// Make it if it isn't there
+    pub create: bool,
+    /// With `create`, fail if it's there
+    pub exclusive: bool,
+    /// Empty it first
+    pub truncate: bool,
+    /// Fail unless it's a directory
+    pub directory: bool,
+    /// Permissions it's made with
+    pub mode: u32,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            read: true,
+            write: false,
+            append: false,
+            create: false,
+            exclusive: false,
+            truncate: false,
+            directory: false,
+            mode: 0o644,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+struct OpenFile {
+    inode: Inode,
+    /// For directories, which entry is next
+    offset: usize,
+    options: OpenOptions,
+    /// How many `FileId`s there are to it
+    refs: usize,
+}
+
+/// An open file. Each one has to be given back with `close`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileId(usize);
+
+static FILES: Mutex<[Option<OpenFile>; MAX_OPEN_FILES]> =
+    Mutex::new([const { None }; MAX_OPEN_FILES]);
+
+/// Open the file at `path`
+pub fn open(path: &str, options: OpenOptions) -> Result<FileId> {
+    let inode = match resolve(path) {
+        Ok(_) if options.create && options.exclusive => return Err(Error::Exists),
+        Ok(inode) => inode,
+        Err(Error::NotFound) if options.create => {
+            let (dir, name) = resolve_parent(path)?;
+            dir.create(name, Kind::File, options.mode)?
+        }
+        Err(err) => return Err(err),
+    };
+    let kind = inode.stat()?.kind;
+    if options.directory && kind != Kind::Directory {
+        return Err(Error::NotDirectory);
+    }
+    if kind == Kind::Directory && options.write {
+        return Err(Error::IsDirectory);
+    }
+    if options.truncate && options.write && kind == Kind::File {
+        inode.truncate(0)?;
+    }
+
+    let mut files = FILES.lock();
+    let (idx, slot) = files
+        .iter_mut()
+        .enumerate()
+        .find(|(_, slot)| slot.is_none())
+        .ok_or(Error::TooManyFiles)?;
+    *slot = Some(OpenFile { inode, offset: 0, options, refs: 1 });
+    Ok(FileId(idx))
+}
+
+/// Run `f` on open file `id`
+fn with_file<R>(id: FileId, f: impl FnOnce(&mut OpenFile) -> Result<R>) -> Result<R> {
+    let mut files = FILES.lock();
+    files.get_mut(id.0).and_then(Option::as_mut).ok_or(Error::BadFile).and_then(f)
+}
+
+/// Another `FileId` for the same open file, sharing its offset
+pub fn dup(id: FileId) -> Result<FileId> {
+    with_file(id, |file| {
+        file.refs += 1;
+        Ok(id)
+    })
+}
+
+/// Give back `id`. The file is closed once every `FileId` to it is.
+pub fn close(id: FileId) -> Result<()> {
+    let mut files = FILES.lock();
+    let slot = files.get_mut(id.0).ok_or(Error::BadFile)?;
+    let file = slot.as_mut().ok_or(Error::BadFile)?;
+    file.refs -= 1;
+    if file.refs == 0 {
+        *slot = None;
+    }
+    Ok(())
+}
+
+/// Read from where the last read or write left off
+pub fn read(id: FileId, buf: &mut [u8]) -> Result<usize> {
+    // The filesystem can take a while, or sleep, so it's called without holding the table
+    let (inode, offset) = with_file(id, |file| {
+        if !file.options.read {
+            return Err(Error::BadFile);
+        }
+        Ok((file.inode, file.offset))
+    })?;
+    if inode.stat()?.kind == Kind::Directory {
+        return Err(Error::IsDirectory);
+    }
+    let len = inode.read(offset, buf)?;
+    with_file(id, |file| {
+        file.offset = offset + len;
+        Ok(len)
+    })
+}
+
+/// Write where the last read or write left off, or at the end if it was opened to append
+pub fn write(id: FileId, buf: &[u8]) -> Result<usize> {
+    let (inode, offset, append) = with_file(id, |file| {
+        if !file.options.write {
+            return Err(Error::BadFile);
+        }
+        Ok((file.inode, file.offset, file.options.append))
+    })?;
+    let offset = if append { inode.stat()?.size } else { offset };
+    let len = inode.write(offset, buf)?;
+    with_file(id, |file| {
+        file.offset = offset + len;
+        Ok(len)
+    })
+}
+
+/// Move where the next read or write happens. Returns the new offset.
+pub fn seek(id: FileId, pos: SeekFrom) -> Result<usize> {
+    let (inode, offset) = with_file(id, |file| Ok((file.inode, file.offset)))?;
+    let new = match pos {
+        SeekFrom::Start(pos) => usize::try_from(pos).ok(),
+        SeekFrom::Current(delta) => offset.checked_add_signed(delta as isize),
+        SeekFrom::End(delta) => inode.stat()?.size.checked_add_signed(delta as isize),
+    }
+    .ok_or(Error::Invalid)?;
+    with_file(id, |file| {
+        file.offset = new;
+        Ok(new)
+    })
+}
+
+pub fn stat(id: FileId) -> Result<Stat> {
+    let inode = with_file(id, |file| Ok(file.inode))?;
+    inode.stat()
+}
+
+/// Sync the filesystem `id` is on
+pub fn fsync(id: FileId) -> Result<()> {
+    let inode = with_file(id, |file| Ok(file.inode))?;
+    inode.fs().sync()
+}
+
+/// The next entry of a directory, without moving past it. `seek(id, SeekFrom::Current(1))`
+/// moves on to the one after.
+pub fn read_dir(id: FileId) -> Result<Option<DirEntry>> {
+    let (inode, offset) = with_file(id, |file| Ok((file.inode, file.offset)))?;
+    if inode.stat()?.kind != Kind::Directory {
+        return Err(Error::NotDirectory);
+    }
+    inode.read_dir(offset)
+}
+
+/// A process's file descriptors
+pub struct FdTable {
+    fds: [Option<FileId>; MAX_FDS],
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        FdTable { fds: [None; MAX_FDS] }
+    }
+
+    /// Standard input, output and error all on `/dev/console`. Empty if that can't be opened.
+    pub fn with_console() -> Self {
+        let mut table = FdTable::new();
+        let options = OpenOptions { write: true, ..OpenOptions::default() };
+        if let Ok(console) = open("/dev/console", options) {
+            table.fds[0] = Some(console);
+            table.fds[1] = dup(console).ok();
+            table.fds[2] = dup(console).ok();
+        }
+        table
+    }
+
+    pub fn get(&self, fd: usize) -> Result<FileId> {
+        self.fds.get(fd).copied().flatten().ok_or(Error::BadFile)
+    }
+
+    /// Give `file` the lowest free descriptor
+    pub fn insert(&mut self, file: FileId) -> Result<usize> {
+        let fd = self.fds.iter().position(Option::is_none).ok_or(Error::TooManyFiles)?;
+        self.fds[fd] = Some(file);
+        Ok(fd)
+    }
+
+    /// Free `fd`. The file it was for still has to be `close`d.
+    pub fn remove(&mut self, fd: usize) -> Result<FileId> {
+        self.fds.get_mut(fd).and_then(Option::take).ok_or(Error::BadFile)
+    }
+
+    /// Close every file. Can sleep, so not while holding a spinlock.
+    pub fn close_all(&mut self) {
+        for file in self.fds.iter_mut().filter_map(Option::take) {
+            let _ = close(file);
+        }
+    }
+}
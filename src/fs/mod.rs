@@ -0,0 +1,380 @@
+//! The virtual filesystem.
+//!
+//! Every filesystem implements `FileSystem`, which works in inode numbers that only mean
+//! something to it. An `Inode` pairs one of those with its filesystem, so the rest of the kernel
+//! can pass files around without caring where they live. Filesystems are mounted over paths, and
+//! a path is looked up by finding the longest mount that covers it, then walking down from that
+//! filesystem's root a name at a time. Names already walked are kept in a small dentry cache, so
+//! opening files in the same directory doesn't go back to the filesystem for every step.
+//!
+//! Files that are open are kept in a table shared by everyone, with their offset. Processes hold
+//! `FileId`s to them through an `FdTable`, which is what their file descriptors index.
+//!
+//! | Mount   | Filesystem                                    |
+//! |---------|-----------------------------------------------|
+//! | `/`     | The initrd, read-only. A `tmpfs` without one. |
+//! | `/tmp`  | `tmpfs`                                       |
+//! | `/dev`  | `devfs`                                       |
+//! | `/boot` | `fat32`, on the disk's first FAT partition    |
+//!
+//! There's no current directory, so relative paths start at `/`. `..` is handled by dropping the
+//! name before it, before anything is looked up.
+use crate::sync::Mutex;
+use arrayvec::{ArrayString, ArrayVec};
+use log::{info, warn};
+use spin::Once;
+
+pub mod archive;
+pub mod devfs;
+pub mod fat32;
+mod file;
+pub mod tmpfs;
+
+pub use file::{
+    close, dup, fsync, open, read, read_dir, seek, stat, write, FdTable, FileId, OpenOptions,
+    SeekFrom, MAX_FDS,
+};
+
+/// Longest name a file can have
+pub const MAX_NAME: usize = 255;
+/// Longest path that can be looked up
+pub const MAX_PATH: usize = 256;
+const MAX_MOUNTS: usize = 8;
+/// Most names deep a path can be
+const MAX_DEPTH: usize = 32;
+const MAX_DENTRIES: usize = 64;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    Exists,
+    ReadOnly,
+    NoSpace,
+    /// Not an open file, or not open for that
+    BadFile,
+    Invalid,
+    TooManyFiles,
+    NameTooLong,
+    /// The device behind it isn't there
+    NoDevice,
+    /// The device behind it failed
+    Io,
+    NotSupported,
+}
+
+impl Error {
+    /// The Linux errno it's reported to processes as
+    pub fn errno(self) -> i64 {
+        match self {
+            Error::NotFound => 2,
+            Error::Io => 5,
+            Error::BadFile => 9,
+            Error::Exists => 17,
+            Error::NoDevice => 19,
+            Error::NotDirectory => 20,
+            Error::IsDirectory => 21,
+            Error::Invalid => 22,
+            Error::TooManyFiles => 24,
+            Error::NoSpace => 28,
+            Error::ReadOnly => 30,
+            Error::NameTooLong => 36,
+            Error::NotSupported => 95,
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Which inode in its filesystem
+pub type Ino = u64;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    File,
+    Directory,
+    CharDevice,
+    /// Reading it gives the path it points to. They aren't followed.
+    Symlink,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub ino: Ino,
+    pub kind: Kind,
+    /// Permission bits
+    pub mode: u32,
+    pub size: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub ino: Ino,
+    pub kind: Kind,
+    pub name: ArrayString<MAX_NAME>,
+}
+
+impl DirEntry {
+    pub fn new(ino: Ino, kind: Kind, name: &str) -> Result<Self> {
+        let name = ArrayString::from(name).map_err(|_| Error::NameTooLong)?;
+        Ok(DirEntry { ino, kind, name })
+    }
+}
+
+/// A filesystem. Only the reading half has to be implemented; anything that changes it fails
+/// with `ReadOnly` unless overridden.
+pub trait FileSystem: Sync {
+    /// Shown in the mount table
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Ino;
+    fn stat(&self, ino: Ino) -> Result<Stat>;
+    /// The inode called `name` in directory `dir`
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino>;
+    /// The `index`th entry in directory `dir`, or `None` past the last
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>>;
+    /// Read from `offset` into `buf`. Returns how much was read, which is 0 at the end.
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize>;
+
+    /// Write `buf` at `offset`, growing the file if needed. Returns how much was written.
+    fn write(&self, _ino: Ino, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Make a new, empty, `kind` called `name` in directory `dir`
+    fn create(&self, _dir: Ino, _name: &str, _kind: Kind, _mode: u32) -> Result<Ino> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Change the size of file `ino`. Anything added reads as zeroes.
+    fn truncate(&self, _ino: Ino, _size: usize) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Get everything written so far onto the device behind it
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A file or directory somewhere in the tree
+#[derive(Clone, Copy)]
+pub struct Inode {
+    fs: &'static dyn FileSystem,
+    ino: Ino,
+}
+
+impl Inode {
+    pub fn root_of(fs: &'static dyn FileSystem) -> Self {
+        Inode { fs, ino: fs.root() }
+    }
+
+    pub fn ino(&self) -> Ino {
+        self.ino
+    }
+
+    pub fn fs(&self) -> &'static dyn FileSystem {
+        self.fs
+    }
+
+    /// Whether both are the same file
+    pub fn same(&self, other: &Inode) -> bool {
+        core::ptr::addr_eq(self.fs, other.fs) && self.ino == other.ino
+    }
+
+    pub fn stat(&self) -> Result<Stat> {
+        self.fs.stat(self.ino)
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<Inode> {
+        if let Some(inode) = dentries::get(self, name) {
+            return Ok(inode);
+        }
+        let inode = Inode { fs: self.fs, ino: self.fs.lookup(self.ino, name)? };
+        dentries::insert(self, name, inode);
+        Ok(inode)
+    }
+
+    pub fn read_dir(&self, index: usize) -> Result<Option<DirEntry>> {
+        self.fs.read_dir(self.ino, index)
+    }
+
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.fs.read(self.ino, offset, buf)
+    }
+
+    pub fn write(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.fs.write(self.ino, offset, buf)
+    }
+
+    pub fn create(&self, name: &str, kind: Kind, mode: u32) -> Result<Inode> {
+        if name.len() > MAX_NAME {
+            return Err(Error::NameTooLong);
+        }
+        let inode = Inode { fs: self.fs, ino: self.fs.create(self.ino, name, kind, mode)? };
+        dentries::insert(self, name, inode);
+        Ok(inode)
+    }
+
+    pub fn truncate(&self, size: usize) -> Result<()> {
+        self.fs.truncate(self.ino, size)
+    }
+}
+
+/// Names that have been looked up, most recently used last. Only names that were found are
+/// kept, and nothing can be removed yet, so entries never go stale.
+mod dentries {
+    use super::{Inode, MAX_DENTRIES, MAX_NAME};
+    use crate::sync::Mutex;
+    use arrayvec::{ArrayString, ArrayVec};
+
+    struct Dentry {
+        parent: Inode,
+        name: ArrayString<MAX_NAME>,
+        inode: Inode,
+    }
+
+    static DENTRIES: Mutex<ArrayVec<Dentry, MAX_DENTRIES>> = Mutex::new(ArrayVec::new_const());
+
+    pub fn get(parent: &Inode, name: &str) -> Option<Inode> {
+        let mut dentries = DENTRIES.lock();
+        let idx = dentries
+            .iter()
+            .position(|d| d.parent.same(parent) && d.name.as_str() == name)?;
+        let dentry = dentries.remove(idx);
+        let inode = dentry.inode;
+        dentries.push(dentry);
+        Some(inode)
+    }
+
+    pub fn insert(parent: &Inode, name: &str, inode: Inode) {
+        let Ok(name) = ArrayString::from(name) else {
+            return;
+        };
+        let mut dentries = DENTRIES.lock();
+        if dentries.is_full() {
+            dentries.remove(0);
+        }
+        dentries.push(Dentry { parent: *parent, name, inode });
+    }
+}
+
+struct Mount {
+    /// Without the leading `/`, so the root is `""`
+    path: &'static str,
+    fs: &'static dyn FileSystem,
+}
+
+static MOUNTS: Mutex<ArrayVec<Mount, MAX_MOUNTS>> = Mutex::new(ArrayVec::new_const());
+
+/// Put `fs` at `path`, over whatever was there
+pub fn mount(path: &'static str, fs: &'static dyn FileSystem) -> Result<()> {
+    let path = path.trim_matches('/');
+    let mut mounts = MOUNTS.lock();
+    mounts.retain(|m| m.path != path);
+    mounts.try_push(Mount { path, fs }).map_err(|_| Error::NoSpace)
+}
+
+/// Sync every mounted filesystem. Keeps going past failures, and returns the first.
+pub fn sync() -> Result<()> {
+    let filesystems: ArrayVec<&'static dyn FileSystem, MAX_MOUNTS> =
+        MOUNTS.lock().iter().map(|m| m.fs).collect();
+    let mut res = Ok(());
+    for fs in filesystems {
+        if let Err(err) = fs.sync() {
+            res = res.and(Err(err));
+        }
+    }
+    res
+}
+
+/// The names in `path`, with `.` and `..` taken care of
+fn components(path: &str) -> Result<ArrayVec<&str, MAX_DEPTH>> {
+    if path.len() > MAX_PATH {
+        return Err(Error::NameTooLong);
+    }
+    let mut names = ArrayVec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                names.pop();
+            }
+            name => names.try_push(name).map_err(|_| Error::NameTooLong)?,
+        }
+    }
+    Ok(names)
+}
+
+/// The filesystem mounted over the longest part of `names`, and how many names that took
+fn mount_for(names: &[&str]) -> Result<(&'static dyn FileSystem, usize)> {
+    let mounts = MOUNTS.lock();
+    mounts
+        .iter()
+        .filter_map(|m| {
+            let mut depth = 0;
+            for part in m.path.split('/').filter(|p| !p.is_empty()) {
+                if names.get(depth) != Some(&part) {
+                    return None;
+                }
+                depth += 1;
+            }
+            Some((m.fs, depth))
+        })
+        .max_by_key(|&(_, depth)| depth)
+        .ok_or(Error::NotFound)
+}
+
+fn walk(names: &[&str]) -> Result<Inode> {
+    let (fs, depth) = mount_for(names)?;
+    let mut inode = Inode::root_of(fs);
+    for name in &names[depth..] {
+        if inode.stat()?.kind != Kind::Directory {
+            return Err(Error::NotDirectory);
+        }
+        inode = inode.lookup(name)?;
+    }
+    Ok(inode)
+}
+
+/// The file at `path`
+pub fn resolve(path: &str) -> Result<Inode> {
+    walk(&components(path)?)
+}
+
+/// The directory `path` would be in, and its last name. Fails for `/`, which has neither.
+pub fn resolve_parent(path: &str) -> Result<(Inode, &str)> {
+    let names = components(path)?;
+    let (&name, parent) = names.split_last().ok_or(Error::Exists)?;
+    let dir = walk(parent)?;
+    if dir.stat()?.kind != Kind::Directory {
+        return Err(Error::NotDirectory);
+    }
+    Ok((dir, name))
+}
+
+static ROOT_ARCHIVE: Once<archive::ArchiveFs> = Once::new();
+static ROOT_TMPFS: tmpfs::TmpFs = tmpfs::TmpFs::new();
+static TMP: tmpfs::TmpFs = tmpfs::TmpFs::new();
+static BOOT: Once<fat32::Fat32> = Once::new();
+
+/// Mount everything. Needs `initrd::init`, and the disk's driver if `/boot` is wanted.
+pub fn init() -> Result<(), &'static str> {
+    let root: &'static dyn FileSystem = match crate::initrd::archive() {
+        Some(initrd) => ROOT_ARCHIVE.call_once(|| archive::ArchiveFs::new(initrd)),
+        None => &ROOT_TMPFS,
+    };
+    mount("/", root).map_err(|_| "Could not mount /")?;
+    mount("/tmp", &TMP).map_err(|_| "Could not mount /tmp")?;
+    mount("/dev", &devfs::DEVFS).map_err(|_| "Could not mount /dev")?;
+    if let Some(part) = crate::block::partitions().iter().find(|part| part.kind().is_fat()) {
+        match fat32::Fat32::new(part) {
+            Ok(fat) => mount("/boot", BOOT.call_once(|| fat)).map_err(|_| "Could not mount /boot")?,
+            Err(err) => warn!("Could not mount /boot: {}", err),
+        }
+    }
+    for m in MOUNTS.lock().iter() {
+        info!("Mounted {} on /{}", m.fs.name(), m.path);
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! A filesystem that only lives in memory.
+//!
+//! Inodes are slots in a fixed table, counted from 1 since the root isn't kept in it. A file's
+//! data is kept in whole pages from the frame allocator, which go back when it shrinks.
+use super::{DirEntry, Error, FileSystem, Ino, Kind, Result, Stat, MAX_NAME};
+use crate::mmu::{frames, PAGE_SIZE};
+use crate::sync::Mutex;
+use arrayvec::{ArrayString, ArrayVec};
+
+const MAX_NODES: usize = 128;
+/// So a file can be up to 256KiB
+const MAX_FILE_PAGES: usize = 64;
+const ROOT: Ino = 0;
+
+struct Node {
+    kind: Kind,
+    parent: Ino,
+    name: ArrayString<MAX_NAME>,
+    mode: u32,
+    size: usize,
+    pages: ArrayVec<usize, MAX_FILE_PAGES>,
+}
+
+impl Node {
+    const fn new(kind: Kind, parent: Ino, name: ArrayString<MAX_NAME>, mode: u32) -> Self {
+        Node { kind, parent, name, mode, size: 0, pages: ArrayVec::new_const() }
+    }
+
+    /// Make room for `size` bytes
+    fn grow(&mut self, size: usize) -> Result<()> {
+        let pages = size.div_ceil(PAGE_SIZE);
+        if pages > MAX_FILE_PAGES {
+            return Err(Error::NoSpace);
+        }
+        while self.pages.len() < pages {
+            self.pages.push(frames::alloc().ok_or(Error::NoSpace)?);
+        }
+        Ok(())
+    }
+
+    /// The bytes of page `idx`
+    fn page(&mut self, idx: usize) -> &mut [u8] {
+        // Safety: The page is from `frames::alloc`, identity mapped, and only this node has it
+        unsafe { core::slice::from_raw_parts_mut(self.pages[idx] as *mut u8, PAGE_SIZE) }
+    }
+}
+
+pub struct TmpFs {
+    nodes: Mutex<[Option<Node>; MAX_NODES]>,
+}
+
+impl TmpFs {
+    pub const fn new() -> Self {
+        TmpFs { nodes: Mutex::new([const { None }; MAX_NODES]) }
+    }
+}
+
+/// The node `ino` in `nodes`. Not the root, which has none.
+fn node(nodes: &mut [Option<Node>; MAX_NODES], ino: Ino) -> Result<&mut Node> {
+    let idx = (ino as usize).checked_sub(1).ok_or(Error::IsDirectory)?;
+    nodes.get_mut(idx).and_then(Option::as_mut).ok_or(Error::NotFound)
+}
+
+fn is_dir(nodes: &mut [Option<Node>; MAX_NODES], ino: Ino) -> Result<bool> {
+    Ok(ino == ROOT || node(nodes, ino)?.kind == Kind::Directory)
+}
+
+/// What's in directory `dir`, as inode numbers and nodes
+fn children(nodes: &[Option<Node>; MAX_NODES], dir: Ino) -> impl Iterator<Item = (Ino, &Node)> {
+    nodes
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, node)| Some((idx as Ino + 1, node.as_ref()?)))
+        .filter(move |&(_, node)| node.parent == dir)
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat> {
+        if ino == ROOT {
+            return Ok(Stat { ino, kind: Kind::Directory, mode: 0o777, size: 0 });
+        }
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, ino)?;
+        Ok(Stat { ino, kind: node.kind, mode: node.mode, size: node.size })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino> {
+        let nodes = self.nodes.lock();
+        children(&nodes, dir)
+            .find(|(_, node)| node.name.as_str() == name)
+            .map(|(ino, _)| ino)
+            .ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>> {
+        let nodes = self.nodes.lock();
+        match children(&nodes, dir).nth(index) {
+            Some((ino, node)) => Ok(Some(DirEntry { ino, kind: node.kind, name: node.name })),
+            None => Ok(None),
+        }
+    }
+
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, ino)?;
+        if node.kind == Kind::Directory {
+            return Err(Error::IsDirectory);
+        }
+        let end = node.size.min(offset.saturating_add(buf.len()));
+        let mut pos = offset;
+        while pos < end {
+            let len = (end - pos).min(PAGE_SIZE - pos % PAGE_SIZE);
+            let page = node.page(pos / PAGE_SIZE);
+            buf[pos - offset..][..len].copy_from_slice(&page[pos % PAGE_SIZE..][..len]);
+            pos += len;
+        }
+        Ok(pos.saturating_sub(offset))
+    }
+
+    fn write(&self, ino: Ino, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, ino)?;
+        if node.kind == Kind::Directory {
+            return Err(Error::IsDirectory);
+        }
+        let end = offset.checked_add(buf.len()).ok_or(Error::NoSpace)?;
+        node.grow(end)?;
+        let mut pos = offset;
+        while pos < end {
+            let len = (end - pos).min(PAGE_SIZE - pos % PAGE_SIZE);
+            let page = node.page(pos / PAGE_SIZE);
+            page[pos % PAGE_SIZE..][..len].copy_from_slice(&buf[pos - offset..][..len]);
+            pos += len;
+        }
+        node.size = node.size.max(end);
+        Ok(buf.len())
+    }
+
+    fn create(&self, dir: Ino, name: &str, kind: Kind, mode: u32) -> Result<Ino> {
+        let name = ArrayString::from(name).map_err(|_| Error::NameTooLong)?;
+        let mut nodes = self.nodes.lock();
+        if !is_dir(&mut nodes, dir)? {
+            return Err(Error::NotDirectory);
+        }
+        if children(&nodes, dir).any(|(_, node)| node.name == name) {
+            return Err(Error::Exists);
+        }
+        let idx = nodes.iter().position(Option::is_none).ok_or(Error::NoSpace)?;
+        nodes[idx] = Some(Node::new(kind, dir, name, mode));
+        Ok(idx as Ino + 1)
+    }
+
+    fn truncate(&self, ino: Ino, size: usize) -> Result<()> {
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, ino)?;
+        if node.kind == Kind::Directory {
+            return Err(Error::IsDirectory);
+        }
+        if size > node.size {
+            node.grow(size)?;
+        } else {
+            while node.pages.len() > size.div_ceil(PAGE_SIZE) {
+                frames::free(node.pages.pop().unwrap());
+            }
+            // So growing it again reads back zeroes
+            if size % PAGE_SIZE != 0 {
+                node.page(size / PAGE_SIZE)[size % PAGE_SIZE..].fill(0);
+            }
+        }
+        node.size = size;
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! The `newc` cpio format, which is what `cpio -H newc` and the Linux initramfs tools write.
+//!
+//! Each file is a 110 byte header of ASCII hex fields, then its name, then its data. Both the
+//! header plus name and the data are padded to 4 bytes. A file called `TRAILER!!!` ends it.
+use super::{Entry, Kind};
+
+pub const MAGIC: &[u8] = b"070701";
+/// Same layout, with a checksum we don't check
+pub const MAGIC_CRC: &[u8] = b"070702";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// The 8 digit hex field at `offset` in the header
+fn field(header: &[u8], offset: usize) -> Option<u32> {
+    let digits = core::str::from_utf8(&header[offset..offset + 8]).ok()?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+pub struct Entries {
+    archive: &'static [u8],
+    offset: usize,
+}
+
+impl Entries {
+    /// Starting with the entry whose header is at `offset`
+    pub fn new(archive: &'static [u8], offset: usize) -> Self {
+        Entries { archive, offset }
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            let start = self.offset;
+            let header = self.archive.get(self.offset..self.offset + HEADER_SIZE)?;
+            if &header[..6] != MAGIC && &header[..6] != MAGIC_CRC {
+                return None;
+            }
+            let mode = field(header, 14)?;
+            let size = field(header, 54)? as usize;
+            let name_size = field(header, 94)? as usize;
+
+            let name_start = self.offset + HEADER_SIZE;
+            // The name size counts its NUL
+            let name = self.archive.get(name_start..name_start + name_size.checked_sub(1)?)?;
+            let name = core::str::from_utf8(name).ok()?;
+            if name == TRAILER {
+                return None;
+            }
+            let data_start = (name_start + name_size).next_multiple_of(4);
+            let data = self.archive.get(data_start..data_start + size)?;
+            self.offset = (data_start + size).next_multiple_of(4);
+
+            let kind = match mode & S_IFMT {
+                S_IFREG => Kind::File,
+                S_IFDIR => Kind::Directory,
+                S_IFLNK => Kind::Symlink,
+                _ => Kind::Other,
+            };
+            // A path too long to keep only loses that entry
+            if let Some(entry) = Entry::new(&[name], kind, mode & !S_IFMT, data) {
+                return Some(entry.at(start));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+
+    /// See `testdata/make_archives.py` for what's in it
+    const TREE: &[u8] = include_bytes!("testdata/tree.cpio");
+
+    /// Where `entry`'s data ends in `TREE`
+    fn data_end(entry: &Entry) -> usize {
+        entry.data().as_ptr() as usize - TREE.as_ptr() as usize + entry.data().len()
+    }
+
+    #[test_case]
+    fn reads_entries() {
+        let entries: ArrayVec<Entry, 8> = Entries::new(TREE, 0).collect();
+        let paths: ArrayVec<&str, 8> = entries.iter().map(Entry::path).collect();
+        // The 300 character name is skipped rather than ending the list
+        assert_eq!(paths.as_slice(), ["", "etc", "etc/motd", "bin", "bin/sh", "last"]);
+
+        assert_eq!((entries[1].kind(), entries[1].mode()), (Kind::Directory, 0o755));
+        let motd = &entries[2];
+        assert_eq!((motd.kind(), motd.mode(), motd.data()), (Kind::File, 0o644, &b"hello\n"[..]));
+        let sh = &entries[4];
+        assert_eq!((sh.kind(), sh.data()), (Kind::Symlink, &b"/bin/busybox"[..]));
+        assert_eq!(entries[5].data(), b"after the long one");
+
+        for entry in &entries {
+            assert_eq!(Entries::new(TREE, entry.offset()).next().unwrap().path(), entry.path());
+        }
+    }
+
+    #[test_case]
+    fn stops_at_truncated_entries() {
+        let entries: ArrayVec<Entry, 8> = Entries::new(TREE, 0).collect();
+        let motd = &entries[2];
+        // In its header, its name and its data
+        for end in [motd.offset() + 50, motd.offset() + HEADER_SIZE + 4, data_end(motd) - 1] {
+            assert_eq!(Entries::new(&TREE[..end], 0).count(), 2);
+        }
+        // Without the trailer
+        let end = data_end(&entries[5]).next_multiple_of(4);
+        assert_eq!(Entries::new(&TREE[..end], 0).count(), 6);
+        assert_eq!(Entries::new(TREE, 4).count(), 0);
+    }
+}
@@ -0,0 +1,261 @@
+//! The initial ramdisk.
+//!
+//! QEMU's `-initrd` (or the firmware's `initramfs` line in config.txt) puts an archive in RAM and
+//! says where in the device tree's `/chosen` node, or in an `ATAG_INITRD2`. We keep that memory
+//! out of the page allocator and read files straight out of it, so fonts, config and test data
+//! can ship without a storage driver. Both `newc` cpio and ustar archives work.
+use arrayvec::ArrayString;
+use core::ops::Range;
+use log::{info, warn};
+use spin::Once;
+
+mod cpio;
+mod tar;
+
+/// Longest path an entry can have
+pub const MAX_PATH: usize = 256;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    File,
+    Directory,
+    /// Its data is the path it points to
+    Symlink,
+    Other,
+}
+
+/// A file in the archive
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Relative to the root of the archive, without a leading `/` or `./`
+    path: ArrayString<MAX_PATH>,
+    kind: Kind,
+    /// Permission bits
+    mode: u32,
+    data: &'static [u8],
+    /// Where its header is in the archive. See `Archive::entry_at`.
+    offset: usize,
+}
+
+impl Entry {
+    /// Joins `parts` into the path. Empty parts are skipped.
+    fn new(parts: &[&str], kind: Kind, mode: u32, data: &'static [u8]) -> Option<Entry> {
+        let mut path = ArrayString::new();
+        for part in parts.iter().map(|part| normalize(part)).filter(|part| !part.is_empty()) {
+            if !path.is_empty() {
+                path.try_push('/').ok()?;
+            }
+            if path.try_push_str(part).is_err() {
+                warn!("initrd: Path starting {} is too long", path);
+                return None;
+            }
+        }
+        Some(Entry { path, kind, mode, data, offset: 0 })
+    }
+
+    fn at(mut self, offset: usize) -> Entry {
+        self.offset = offset;
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// Archives name the root `.`, `./` or `/` depending on how they were made
+fn normalize(path: &str) -> &str {
+    let path = path.trim_start_matches("./").trim_start_matches('/').trim_end_matches('/');
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Format {
+    Cpio,
+    Tar,
+}
+
+pub struct Archive {
+    data: &'static [u8],
+    format: Format,
+}
+
+impl Archive {
+    /// Every entry, in the order they were stored
+    pub fn entries(&self) -> impl Iterator<Item = Entry> {
+        self.entries_from(0)
+    }
+
+    fn entries_from(&self, offset: usize) -> impl Iterator<Item = Entry> {
+        let (cpio, tar) = match self.format {
+            Format::Cpio => (Some(cpio::Entries::new(self.data, offset)), None),
+            Format::Tar => (None, Some(tar::Entries::new(self.data, offset))),
+        };
+        cpio.into_iter().flatten().chain(tar.into_iter().flatten())
+    }
+
+    /// The entry whose header is at `offset`, from `Entry::offset`
+    pub fn entry_at(&self, offset: usize) -> Option<Entry> {
+        self.entries_from(offset).next()
+    }
+
+    /// The entry at `path`. A leading `/` or `./` is optional.
+    pub fn lookup(&self, path: &str) -> Option<Entry> {
+        let path = normalize(path);
+        self.entries().find(|entry| entry.path() == path)
+    }
+
+    /// What's directly inside the directory at `path`. The root is `""` or `/`.
+    pub fn list<'a>(&self, path: &'a str) -> impl Iterator<Item = Entry> + 'a {
+        let dir = normalize(path);
+        self.entries().filter(move |entry| {
+            !entry.path().is_empty() && parent(entry.path()) == dir
+        })
+    }
+}
+
+/// The directory `path` is in, with the root as `""`
+pub fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+static ARCHIVE: Once<Archive> = Once::new();
+
+/// Where the boot loader put the initrd, going by what it left in x0
+fn locate(boot_arg: usize) -> Option<Range<usize>> {
+    if crate::fdt::get().is_some() {
+        return crate::fdt::initrd();
+    }
+    let addr = if boot_arg == 0 { crate::atags::DEFAULT_ADDR } else { boot_arg };
+    // Safety: Whatever the firmware points x0 at is in RAM, and nothing has been put over it
+    unsafe { crate::atags::Atags::from_addr(addr) }?.initrd()
+}
+
+/// Find the initrd and keep its memory from being handed out. Having none is fine: every lookup
+/// just fails. Must run before anything allocates pages.
+pub fn init(boot_arg: usize) -> Result<(), &'static str> {
+    let Some(range) = locate(boot_arg) else {
+        info!("No initrd");
+        return Ok(());
+    };
+    if range.is_empty() || range.end > crate::mmu::frames::RAM_END {
+        return Err("initrd is outside of RAM");
+    }
+    crate::mmu::frames::reserve(range.clone());
+    // Safety: Reserved just now, so it stays put for good
+    let data = unsafe { core::slice::from_raw_parts(range.start as *const u8, range.len()) };
+
+    let format = if data.starts_with(cpio::MAGIC) || data.starts_with(cpio::MAGIC_CRC) {
+        Format::Cpio
+    } else if data.get(tar::MAGIC_OFFSET..).is_some_and(|magic| magic.starts_with(tar::MAGIC)) {
+        Format::Tar
+    } else {
+        return Err("initrd is not a cpio or tar archive");
+    };
+    ARCHIVE.call_once(|| Archive { data, format });
+    info!(
+        "initrd: {:?} archive of {} bytes at {:#x}, {} entries",
+        format,
+        range.len(),
+        range.start,
+        entries().count()
+    );
+    Ok(())
+}
+
+/// Whether there's an initrd
+pub fn is_present() -> bool {
+    ARCHIVE.is_completed()
+}
+
+/// The initrd, if there is one
+pub fn archive() -> Option<&'static Archive> {
+    ARCHIVE.get()
+}
+
+/// Every entry in the archive, in the order they were stored
+pub fn entries() -> impl Iterator<Item = Entry> {
+    ARCHIVE.get().into_iter().flat_map(Archive::entries)
+}
+
+/// The entry at `path`. A leading `/` or `./` is optional.
+pub fn lookup(path: &str) -> Option<Entry> {
+    ARCHIVE.get()?.lookup(path)
+}
+
+/// The contents of the file at `path`
+pub fn read(path: &str) -> Option<&'static [u8]> {
+    lookup(path).filter(|entry| entry.kind == Kind::File).map(|entry| entry.data)
+}
+
+/// What's directly inside the directory at `path`. The root is `""` or `/`.
+pub fn list(path: &str) -> impl Iterator<Item = Entry> + '_ {
+    ARCHIVE.get().into_iter().flat_map(move |archive| archive.list(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+
+    /// See `testdata/make_archives.py` for what's in them
+    const CPIO: Archive =
+        Archive { data: include_bytes!("testdata/tree.cpio"), format: Format::Cpio };
+    const TAR: Archive = Archive { data: include_bytes!("testdata/tree.tar"), format: Format::Tar };
+
+    #[test_case]
+    fn looks_up_paths() {
+        for archive in [CPIO, TAR] {
+            for path in ["etc/motd", "/etc/motd", "./etc/motd"] {
+                assert_eq!(archive.lookup(path).unwrap().data(), b"hello\n");
+            }
+            assert_eq!(archive.lookup("/").unwrap().kind(), Kind::Directory);
+            assert_eq!(archive.lookup("bin/sh").unwrap().kind(), Kind::Symlink);
+            assert!(archive.lookup("etc/mot").is_none());
+            assert!(archive.lookup("motd").is_none());
+
+            let motd = archive.lookup("etc/motd").unwrap();
+            assert_eq!(archive.entry_at(motd.offset()).unwrap().path(), "etc/motd");
+        }
+    }
+
+    #[test_case]
+    fn lists_directories() {
+        let entries: ArrayVec<Entry, 8> = CPIO.list("/").collect();
+        let paths: ArrayVec<&str, 8> = entries.iter().map(Entry::path).collect();
+        assert_eq!(paths.as_slice(), ["etc", "bin", "last"]);
+        for path in ["etc", "./etc/"] {
+            let entries: ArrayVec<Entry, 8> = CPIO.list(path).collect();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].path(), "etc/motd");
+        }
+        assert_eq!(CPIO.list("etc/motd").count(), 0);
+        assert_eq!(CPIO.list("nope").count(), 0);
+
+        // Tar has no entries for the directories the font is in, but it's still listed
+        let entries: ArrayVec<Entry, 8> = TAR.list("usr/share/consolefonts").collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path(), "usr/share/consolefonts/Lat15-Terminus16.psf");
+        assert_eq!(TAR.list("").count(), 1);
+    }
+}
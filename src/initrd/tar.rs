@@ -0,0 +1,129 @@
+//! POSIX ustar archives.
+//!
+//! Each file is a 512 byte header with octal ASCII fields, then its data padded to 512 bytes.
+//! Long paths are split between the `prefix` and `name` fields. An all-zero header ends it.
+use super::{Entry, Kind};
+
+pub const MAGIC_OFFSET: usize = 257;
+/// `ustar\0` from POSIX, or `ustar ` from GNU tar
+pub const MAGIC: &[u8] = b"ustar";
+const BLOCK_SIZE: usize = 512;
+
+const TYPE_FILE: u8 = b'0';
+/// Very old tars mark regular files with a NUL
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIRECTORY: u8 = b'5';
+
+/// A NUL padded string field
+fn string(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).ok()
+}
+
+/// An octal number field, which may be padded with spaces or NULs
+fn number(field: &[u8]) -> Option<usize> {
+    let digits = string(field)?.trim_matches(' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(digits, 8).ok()
+}
+
+pub struct Entries {
+    archive: &'static [u8],
+    offset: usize,
+}
+
+impl Entries {
+    /// Starting with the entry whose header is at `offset`
+    pub fn new(archive: &'static [u8], offset: usize) -> Self {
+        Entries { archive, offset }
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            let start = self.offset;
+            let header = self.archive.get(self.offset..self.offset + BLOCK_SIZE)?;
+            if header[0] == 0 || !header[MAGIC_OFFSET..].starts_with(MAGIC) {
+                return None;
+            }
+            let name = string(&header[0..100])?;
+            let mode = number(&header[100..108])? as u32;
+            let size = number(&header[124..136])?;
+            let prefix = string(&header[345..500])?;
+
+            let data_start = self.offset + BLOCK_SIZE;
+            let data = self.archive.get(data_start..data_start + size)?;
+            self.offset = data_start + size.next_multiple_of(BLOCK_SIZE);
+
+            let kind = match header[156] {
+                TYPE_FILE | TYPE_FILE_OLD => Kind::File,
+                TYPE_DIRECTORY => Kind::Directory,
+                TYPE_SYMLINK => Kind::Symlink,
+                _ => Kind::Other,
+            };
+            // Symlinks keep their target in `linkname` rather than in the data
+            let data = if kind == Kind::Symlink {
+                let target = &header[157..257];
+                &target[..target.iter().position(|&b| b == 0).unwrap_or(target.len())]
+            } else {
+                data
+            };
+            // A path too long to keep only loses that entry
+            if let Some(entry) = Entry::new(&[prefix, name], kind, mode, data) {
+                return Some(entry.at(start));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayVec;
+
+    /// See `testdata/make_archives.py` for what's in it
+    const TREE: &[u8] = include_bytes!("testdata/tree.tar");
+
+    #[test_case]
+    fn reads_entries() {
+        let entries: ArrayVec<Entry, 8> = Entries::new(TREE, 0).collect();
+        let paths: ArrayVec<&str, 8> = entries.iter().map(Entry::path).collect();
+        assert_eq!(
+            paths.as_slice(),
+            ["", "etc", "etc/motd", "bin/sh", "usr/share/consolefonts/Lat15-Terminus16.psf"]
+        );
+
+        assert_eq!((entries[1].kind(), entries[1].mode()), (Kind::Directory, 0o755));
+        let motd = &entries[2];
+        assert_eq!((motd.kind(), motd.mode(), motd.data()), (Kind::File, 0o644, &b"hello\n"[..]));
+        // The target is in `linkname`, and the entry has no data of its own
+        let sh = &entries[3];
+        assert_eq!((sh.kind(), sh.mode(), sh.data()), (Kind::Symlink, 0o777, &b"busybox"[..]));
+        // Joined from `prefix` and `name`, with data over two blocks
+        let font = &entries[4];
+        assert_eq!(font.data().len(), 600);
+        assert_eq!(font.data()[599], 199);
+
+        for entry in &entries {
+            assert_eq!(Entries::new(TREE, entry.offset()).next().unwrap().path(), entry.path());
+        }
+    }
+
+    #[test_case]
+    fn stops_at_truncated_entries() {
+        let font = Entries::new(TREE, 0).last().unwrap().offset();
+        // In its header, and in its second block of data
+        for end in [font + 100, font + BLOCK_SIZE + 550] {
+            assert_eq!(Entries::new(&TREE[..end], 0).count(), 4);
+        }
+        // Without the zero blocks at the end
+        assert_eq!(Entries::new(&TREE[..TREE.len() - 2 * BLOCK_SIZE], 0).count(), 5);
+        assert_eq!(Entries::new(TREE, 1).count(), 0);
+    }
+}
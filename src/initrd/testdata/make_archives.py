@@ -0,0 +1,105 @@
+#!/usr/bin/env python3
+"""Writes the archives the initrd parser's tests use. Run it from this directory.
+
+They're made by hand rather than with cpio and tar, so that the order of entries, the ustar
+prefix split and the over-long name are exactly what the tests expect.
+
+tree.cpio (newc) holds, in order:
+
+    .                     directory
+    etc                   directory
+    etc/motd              file, "hello\\n"
+    bin                   directory
+    bin/sh                symlink to /bin/busybox
+    aaa...a               file, with a 300 character name
+    last                  file, "after the long one"
+
+tree.tar (ustar) holds, in order:
+
+    ./                    directory
+    ./etc/                directory
+    ./etc/motd            file, "hello\\n"
+    ./bin/sh              symlink to busybox
+    usr/share/consolefonts + Lat15-Terminus16.psf
+                          file split between `prefix` and `name`, 600 bytes
+"""
+import struct
+
+CPIO_MAGIC = b"070701"
+S_IFDIR, S_IFREG, S_IFLNK = 0o040000, 0o100000, 0o120000
+
+
+def pad(data, align):
+    return data + b"\0" * (-len(data) % align)
+
+
+def cpio_entry(ino, name, mode, data=b""):
+    name = name.encode() + b"\0"
+    fields = [ino, mode, 0, 0, 1, 0, len(data), 0, 0, 0, 0, len(name), 0]
+    header = CPIO_MAGIC + b"".join(b"%08X" % field for field in fields)
+    return pad(header + name, 4) + pad(data, 4)
+
+
+def cpio():
+    return b"".join([
+        cpio_entry(1, ".", S_IFDIR | 0o755),
+        cpio_entry(2, "etc", S_IFDIR | 0o755),
+        cpio_entry(3, "etc/motd", S_IFREG | 0o644, b"hello\n"),
+        cpio_entry(4, "bin", S_IFDIR | 0o755),
+        cpio_entry(5, "bin/sh", S_IFLNK | 0o777, b"/bin/busybox"),
+        cpio_entry(6, "a" * 300, S_IFREG | 0o644, b"too long"),
+        cpio_entry(7, "last", S_IFREG | 0o644, b"after the long one"),
+        cpio_entry(0, "TRAILER!!!", 0),
+    ])
+
+
+def field(value, size):
+    return value.encode().ljust(size, b"\0")
+
+
+def octal(value, size):
+    return b"%0*o\0" % (size - 1, value)
+
+
+def tar_entry(name, typeflag, mode, data=b"", linkname="", prefix=""):
+    header = b"".join([
+        field(name, 100),
+        octal(mode, 8),
+        octal(0, 8),
+        octal(0, 8),
+        octal(len(data), 12),
+        octal(0, 12),
+        b" " * 8,
+        typeflag,
+        field(linkname, 100),
+        b"ustar\x0000",
+        field("root", 32),
+        field("root", 32),
+        octal(0, 8),
+        octal(0, 8),
+        field(prefix, 155),
+    ])
+    checksum = b"%06o\0 " % sum(header)
+    header = pad(header[:148] + checksum + header[156:], 512)
+    return header + pad(data, 512)
+
+
+def tar():
+    return b"".join([
+        tar_entry("./", b"5", 0o755),
+        tar_entry("./etc/", b"5", 0o755),
+        tar_entry("./etc/motd", b"0", 0o644, b"hello\n"),
+        tar_entry("./bin/sh", b"2", 0o777, linkname="busybox"),
+        tar_entry(
+            "Lat15-Terminus16.psf", b"0", 0o644, bytes(range(200)) * 3,
+            prefix="usr/share/consolefonts",
+        ),
+        b"\0" * 1024,
+    ])
+
+
+if __name__ == "__main__":
+    with open("tree.cpio", "wb") as f:
+        f.write(cpio())
+    with open("tree.tar", "wb") as f:
+        f.write(tar())
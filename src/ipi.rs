@@ -0,0 +1,225 @@
+//! Inter-processor interrupts.
+//!
+//! Every core has four mailboxes in the ARM-local peripherals. Setting a bit in one raises an IRQ
+//! on its core, so we give each kind of IPI its own bit in mailbox 0. Several IPIs can be pending
+//! at once and are handled together.
+use crate::arm_local;
+use crate::smp::{cpu_id, cpus_online, MAX_CPUS};
+use crate::sync::PerCpu;
+use aarch64_cpu::asm::barrier;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// The mailbox IPIs are sent through
+pub const MAILBOX: usize = 0;
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ipi {
+    /// Run the function waiting in `run_on`
+    Call = 1 << 0,
+    /// Drop cached translations. See `tlb_shootdown`.
+    TlbShootdown = 1 << 1,
+    /// Stop for good. Sent when panicking.
+    Stop = 1 << 2,
+    /// A thread on this core became ready. See `thread`.
+    Reschedule = 1 << 3,
+}
+
+/// Start taking IPIs on this core
+pub fn init_core() {
+    arm_local::enable_mailbox_irq(cpu_id(), MAILBOX);
+}
+
+/// Interrupt `cpu`
+pub fn send(cpu: usize, ipi: Ipi) {
+    // Anything we wrote for the other core to look at has to land before it gets interrupted
+    barrier::dsb(barrier::ISHST);
+    arm_local::mailbox_set(cpu, MAILBOX, ipi as u32);
+}
+
+/// Interrupt every other core that is running
+pub fn broadcast(ipi: Ipi) {
+    for cpu in other_cpus() {
+        send(cpu, ipi);
+    }
+}
+
+fn other_cpus() -> impl Iterator<Item = usize> {
+    let me = cpu_id();
+    // Cores are started in order, so these are the ones that are up
+    (0..cpus_online()).filter(move |&cpu| cpu != me)
+}
+
+/// Handle every IPI waiting for this core. Called from the IRQ handler, and by cores spinning
+/// on another core so two cores waiting on each other don't deadlock.
+pub fn handle_pending() {
+    let pending =
+        crate::exceptions::without_interrupts(|| arm_local::mailbox_take(cpu_id(), MAILBOX));
+    handle(pending);
+}
+
+/// Handle the IPIs that are safe to take anywhere, which the sender spins waiting for: stops and
+/// shootdowns. For spinlocks, whose holder may be the one waiting on us.
+pub fn handle_urgent() {
+    let mask = Ipi::Stop as u32 | Ipi::TlbShootdown as u32;
+    let pending = crate::exceptions::without_interrupts(|| {
+        arm_local::mailbox_take_bits(cpu_id(), MAILBOX, mask)
+    });
+    handle(pending);
+}
+
+fn handle(pending: u32) {
+    if pending & Ipi::Stop as u32 != 0 {
+        crate::panic::halt();
+    }
+    if pending & Ipi::TlbShootdown as u32 != 0 {
+        flush_local_tlb();
+        SHOOTDOWN_ACKS.fetch_sub(1, Ordering::AcqRel);
+    }
+    if pending & Ipi::Call as u32 != 0 {
+        run_call();
+    }
+    if pending & Ipi::Reschedule as u32 != 0 {
+        crate::thread::request_resched();
+    }
+}
+
+/// Wait for `done`, handling IPIs sent to us in the meantime
+fn wait_until(mut done: impl FnMut() -> bool) {
+    while !done() {
+        handle_pending();
+        core::hint::spin_loop();
+    }
+}
+
+struct CallRequest<'a> {
+    func: &'a (dyn Fn() + Sync),
+    done: AtomicBool,
+}
+
+/// Call waiting for each core. Points into the stack of the core waiting in `run_on`.
+static CALLS: PerCpu<AtomicPtr<CallRequest<'static>>> =
+    PerCpu::new([const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS]);
+
+/// Run `func` on `cpu` and wait for it to finish. It runs in interrupt context, so it must not
+/// block.
+pub fn run_on<F: Fn() + Sync>(cpu: usize, func: F) -> Result<(), &'static str> {
+    if cpu == cpu_id() {
+        crate::exceptions::without_interrupts(&func);
+        return Ok(());
+    }
+    if cpu >= cpus_online() {
+        return Err("CPU is not online");
+    }
+
+    let request = CallRequest {
+        func: &func,
+        done: AtomicBool::new(false),
+    };
+    // Safety of the lifetime: We don't return until the other core is done with it
+    let ptr = &request as *const CallRequest as *mut CallRequest<'static>;
+
+    // One call at a time per core
+    let slot = CALLS.for_cpu(cpu);
+    wait_until(|| {
+        slot.compare_exchange(core::ptr::null_mut(), ptr, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    });
+    send(cpu, Ipi::Call);
+    wait_until(|| request.done.load(Ordering::Acquire));
+    Ok(())
+}
+
+fn run_call() {
+    let slot = CALLS.get();
+    let ptr = slot.load(Ordering::Acquire);
+    // Safety: The caller is waiting in `run_on` until we set `done`
+    let Some(request) = (unsafe { ptr.as_ref() }) else {
+        return;
+    };
+    (request.func)();
+    // Free the slot for the next caller before letting this one go. After `done` is set the
+    // request may be gone.
+    slot.store(core::ptr::null_mut(), Ordering::Release);
+    request.done.store(true, Ordering::Release);
+}
+
+/// Only one shootdown is in flight at a time
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+/// Cores yet to flush for the current shootdown
+static SHOOTDOWN_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+fn flush_local_tlb() {
+    // Safety: Only drops cached translations
+    unsafe { core::arch::asm!("tlbi vmalle1") };
+    barrier::dsb(barrier::NSH);
+    barrier::isb(barrier::SY);
+}
+
+/// Which cached translations a shootdown drops
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TlbFlush {
+    /// An address space's translations for the page holding `addr`
+    AsidPage { asid: u16, addr: usize },
+    /// Everything tagged with an ASID
+    Asid(u16),
+}
+
+/// Make every core forget the translations in `flush`. Call after changing a mapping. Returns
+/// once no core can use the old one.
+pub fn tlb_shootdown(flush: TlbFlush) {
+    // The inner shareable TLBIs get the other cores' TLBs too, but they may still be partway
+    // through an access that used the old mapping. Interrupting them makes sure they're done.
+    barrier::dsb(barrier::ISHST);
+    let asid = |asid: u16| (asid as u64) << 48;
+    // Safety: Only drops cached translations
+    unsafe {
+        match flush {
+            TlbFlush::AsidPage { asid: id, addr } => {
+                core::arch::asm!("tlbi vae1is, {}", in(reg) asid(id) | (addr >> 12) as u64)
+            }
+            TlbFlush::Asid(id) => core::arch::asm!("tlbi aside1is, {}", in(reg) asid(id)),
+        }
+    }
+    barrier::dsb(barrier::ISH);
+    barrier::isb(barrier::SY);
+
+    let others = other_cpus().count();
+    if others == 0 {
+        return;
+    }
+
+    let mut guard = None;
+    wait_until(|| {
+        guard = SHOOTDOWN.try_lock();
+        guard.is_some()
+    });
+    SHOOTDOWN_ACKS.store(others, Ordering::Release);
+    broadcast(Ipi::TlbShootdown);
+    wait_until(|| SHOOTDOWN_ACKS.load(Ordering::Acquire) == 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn runs_on_every_core() {
+        for cpu in 0..cpus_online() {
+            let ran_on = AtomicUsize::new(usize::MAX);
+            run_on(cpu, || ran_on.store(cpu_id(), Ordering::Relaxed)).unwrap();
+            assert_eq!(ran_on.load(Ordering::Relaxed), cpu);
+        }
+        assert!(run_on(MAX_CPUS, || {}).is_err());
+    }
+
+    #[test_case]
+    fn shootdowns_wait_for_every_core() {
+        // Holding a spinlock keeps IRQs masked the whole time, like the page table code does
+        static LOCK: crate::sync::IrqSafeMutex<()> = crate::sync::IrqSafeMutex::new(());
+        let _guard = LOCK.lock();
+        tlb_shootdown(TlbFlush::Asid(0));
+        assert_eq!(SHOOTDOWN_ACKS.load(Ordering::Acquire), 0);
+    }
+}
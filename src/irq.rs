@@ -0,0 +1,118 @@
+//! The BCM2835 interrupt controller, which gathers the peripherals' ("GPU") interrupts.
+//!
+//! All of them arrive at the ARM-local controller as one `GPU` source, routed to core 0. Drivers
+//! `register` a handler for their interrupt number, and `handle_pending` runs the handlers for
+//! whatever is pending. Each handler has to quiet its device, since the lines are level
+//! triggered.
+//! https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf section 7
+use crate::{bus_to_phys, MMIODerefWrapper};
+use log::warn;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_structs,
+    registers::{ReadOnly, ReadWrite},
+};
+
+/// Interrupts 0-31 are in the first bank of registers and 32-63 in the second
+pub const NUM_IRQS: usize = 64;
+/// The first DMA channel's interrupt. The rest follow it.
+pub const DMA_BASE: usize = 16;
+/// The Arasan SD host controller
+pub const EMMC: usize = 62;
+
+const BUS_ADDR: usize = 0x7E00_B200;
+
+type Controller = MMIODerefWrapper<Registers>;
+
+register_structs! {
+    Registers {
+        (0x00 => basic_pending: ReadOnly<u32>),
+        (0x04 => pending: [ReadOnly<u32>; 2]),
+        (0x0C => fiq_control: ReadWrite<u32>),
+        // Writing sets bits
+        (0x10 => enable: [ReadWrite<u32>; 2]),
+        (0x18 => enable_basic: ReadWrite<u32>),
+        // Writing clears bits in `enable`
+        (0x1C => disable: [ReadWrite<u32>; 2]),
+        (0x24 => disable_basic: ReadWrite<u32>),
+        (0x28 => @END),
+    }
+}
+
+/// Where the registers are, once `init` has found them
+static ADDR: AtomicUsize = AtomicUsize::new(0);
+static HANDLERS: RwLock<[Option<fn()>; NUM_IRQS]> = RwLock::new([None; NUM_IRQS]);
+
+/// Find the controller and mask every interrupt, until someone registers for it
+pub unsafe fn init() {
+    let addr = crate::fdt::device("brcm,bcm2836-armctrl-ic")
+        .map_or(bus_to_phys(BUS_ADDR), |dev| dev.regs.start);
+    let ctrl = Controller::new(addr);
+    ctrl.disable[0].set(u32::MAX);
+    ctrl.disable[1].set(u32::MAX);
+    ctrl.disable_basic.set(u32::MAX);
+    ADDR.store(addr, Ordering::Release);
+}
+
+fn regs() -> Option<Controller> {
+    let addr = ADDR.load(Ordering::Acquire);
+    // Safety: `init` found the controller there, and every access is a single register read or
+    // write
+    (addr != 0).then(|| unsafe { Controller::new(addr) })
+}
+
+/// Run `handler` in the IRQ handler whenever interrupt `irq` is pending, and unmask it
+pub fn register(irq: usize, handler: fn()) -> Result<(), &'static str> {
+    let ctrl = regs().ok_or("Interrupt controller is not initialized")?;
+    if irq >= NUM_IRQS {
+        return Err("No such interrupt");
+    }
+    // Masked, so the IRQ handler can't come in on this core and wait on us forever
+    crate::exceptions::without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        if handlers[irq].is_some() {
+            return Err("Interrupt already has a handler");
+        }
+        handlers[irq] = Some(handler);
+        ctrl.enable[irq / 32].set(1 << (irq % 32));
+        Ok(())
+    })
+}
+
+/// Mask interrupt `irq` and forget its handler
+pub fn unregister(irq: usize) {
+    if let Some(ctrl) = regs() {
+        ctrl.disable[irq / 32].set(1 << (irq % 32));
+    }
+    crate::exceptions::without_interrupts(|| HANDLERS.write()[irq] = None);
+}
+
+/// Handle every pending interrupt. Called when the ARM-local controller says the GPU has one.
+/// Returns `false` if none were pending.
+pub fn handle_pending() -> bool {
+    let Some(ctrl) = regs() else {
+        return false;
+    };
+    let handlers = HANDLERS.read();
+    let mut any = false;
+    for bank in 0..2 {
+        let mut pending = ctrl.pending[bank].get();
+        while pending != 0 {
+            let bit = pending.trailing_zeros() as usize;
+            pending &= !(1 << bit);
+            let irq = bank * 32 + bit;
+            match handlers[irq] {
+                Some(handler) => handler(),
+                None => {
+                    // Keep it from firing forever
+                    warn!("Unexpected interrupt {}. Masking it.", irq);
+                    ctrl.disable[bank].set(1 << bit);
+                }
+            }
+            any = true;
+        }
+    }
+    any
+}
@@ -0,0 +1,137 @@
+//! Backend for the `log` crate. Records go out through the console.
+//!
+//! Every line is prefixed with the uptime, the core it came from and its level:
+//! `[    1.234567] cpu0 DEBUG mmu: Enabling mmu`
+//!
+//! Which records get through is set with a filter spec like `info,mmu=trace,exceptions=off`: a
+//! default level followed by per-module overrides. Modules are named by their path without the
+//! crate name, and an override also covers the module's children. The spec is taken from the
+//! `KERNEL_LOG` environment variable at compile time and can be replaced from the command line.
+use arrayvec::ArrayVec;
+use core::str::FromStr;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::RwLock;
+
+/// Used when `KERNEL_LOG` isn't set
+const DEFAULT_SPEC: &str = "info";
+const MAX_DIRECTIVES: usize = 16;
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+struct Filter {
+    default: LevelFilter,
+    /// Module path and the most verbose level allowed for it
+    directives: ArrayVec<(&'static str, LevelFilter), MAX_DIRECTIVES>,
+}
+
+impl Filter {
+    const fn new() -> Self {
+        Filter {
+            default: LevelFilter::Info,
+            directives: ArrayVec::new_const(),
+        }
+    }
+
+    fn parse(spec: &'static str) -> Result<Self, &'static str> {
+        let mut filter = Filter::new();
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let level = LevelFilter::from_str(level).map_err(|_| "Invalid log level")?;
+                    filter
+                        .directives
+                        .try_push((strip_crate(module.trim()), level))
+                        .map_err(|_| "Too many log filter directives")?;
+                }
+                None => {
+                    filter.default = LevelFilter::from_str(directive).map_err(|_| "Invalid log level")?;
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Most verbose level allowed for records from `target`.
+    /// The most specific directive that covers it wins.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let target = strip_crate(target);
+        self.directives
+            .iter()
+            .filter(|(module, _)| covers(module, target))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// The most verbose level any module is allowed
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+fn strip_crate(path: &str) -> &str {
+    path.strip_prefix(CRATE_PREFIX).unwrap_or(path)
+}
+
+/// Whether `module` is `target` or one of its parents
+fn covers(module: &str, target: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter::new());
+
+crate::cmdline::early_param!("loglevel", set_filter);
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Don't risk deadlocking if we interrupted someone changing the filter
+        match FILTER.try_read() {
+            Some(filter) => metadata.level() <= filter.level_for(metadata.target()),
+            None => metadata.level() <= Level::Info,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let micros = crate::time::uptime_microsec();
+        crate::console::write(
+            record.level(),
+            format_args!(
+                "[{:5}.{:06}] cpu{} {:5} {}: {}\n",
+                micros / 1_000_000,
+                micros % 1_000_000,
+                crate::smp::cpu_id(),
+                record.level(),
+                strip_crate(record.target()),
+                record.args()
+            ),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Replace the filter. `spec` is in the same format as `KERNEL_LOG`.
+pub fn set_filter(spec: &'static str) -> Result<(), &'static str> {
+    let filter = Filter::parse(spec)?;
+    log::set_max_level(filter.max_level());
+    *FILTER.write() = filter;
+    Ok(())
+}
+
+/// Start handling `log` records, filtered by the spec given at compile time
+pub fn init() -> Result<(), &'static str> {
+    set_filter(option_env!("KERNEL_LOG").unwrap_or(DEFAULT_SPEC))?;
+    log::set_logger(&LOGGER).map_err(|_| "Logger was already set")
+}
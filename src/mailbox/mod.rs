@@ -9,7 +9,8 @@ use tock_registers::{
 };
 
 
-use spin::{Mutex, Once};
+use crate::sync::{rank, IrqSafeMutex, IrqSafeMutexGuard};
+use spin::Once;
 
 pub mod tags;
 use tags::*;
@@ -156,7 +157,7 @@ impl Mailbox {
     }
 }
 
-static MAILBOX: Once<Mutex<Mailbox>> = Once::new();
+static MAILBOX: Once<IrqSafeMutex<Mailbox>> = Once::new();
 
 // https://github.com/raspberrypi/firmware/wiki/Mailboxes
 register_structs! {
@@ -177,11 +178,47 @@ register_structs! {
     }
 }
 
-pub fn get() -> spin::MutexGuard<'static, Mailbox> {
+pub fn get() -> IrqSafeMutexGuard<'static, Mailbox> {
     MAILBOX.get().unwrap().lock()
 }
 
+/// Clocks `GetClockRate` knows about
+#[repr(u32)]
+#[derive(Clone, Copy, Debug)]
+pub enum ClockId {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    /// The VPU clock, which also drives the mini UART
+    Core = 4,
+}
+
+/// How fast `clock` runs, in Hz
+pub fn clock_rate(clock: ClockId) -> Option<u32> {
+    let res = get()
+        .send_and_poll_recieve_one(GetClockRateRequest { clock_id: clock as u32 })
+        .ok()?;
+    (res.rate != 0).then_some(res.rate)
+}
+
+/// The command line the firmware would give Linux, if it has one
+pub fn command_line() -> Option<arrayvec::ArrayString<COMMAND_LINE_LEN>> {
+    let res = get()
+        .send_and_poll_recieve_one(GetCommandLineRequest { zeroes: [0; COMMAND_LINE_LEN] })
+        .ok()?;
+    let len = res.cmdline.iter().position(|&b| b == 0).unwrap_or(res.cmdline.len());
+    let cmdline = core::str::from_utf8(&res.cmdline[..len]).ok()?;
+    (!cmdline.is_empty()).then(|| arrayvec::ArrayString::from(cmdline).unwrap())
+}
+
+/// The DMA channels the firmware isn't using, one bit each
+pub fn dma_channels() -> Option<u32> {
+    let res = get().send_and_poll_recieve_one(GetDmaChannelsRequest {}).ok()?;
+    Some(res.mask)
+}
+
 pub unsafe fn init() {
-    let mbox = MBox::new(phys_to_bus(0xB880));
-    MAILBOX.call_once(|| Mutex::new(Mailbox { mbox }));
+    let addr = crate::fdt::device("brcm,bcm2835-mbox").map_or(phys_to_bus(0xB880), |dev| dev.regs.start);
+    let mbox = MBox::new(addr);
+    MAILBOX.call_once(|| IrqSafeMutex::ranked(rank::MAILBOX, Mailbox { mbox }));
 }
@@ -67,6 +67,9 @@ pub union TagData<Req: Copy, Res: Copy> {
 pub enum TagValue {
     FirmwareRevision = 0x0_0001,
     BoardModel = 0x1_0001,
+    GetClockRate = 0x3_0002,
+    GetCommandLine = 0x5_0001,
+    GetDmaChannels = 0x6_0001,
     FBAllocateBuffer = 0x4_0001,
     FBReleaseBuffer = 0x4_8001,
     FBGetPhysicalSize = 0x4_0003,
@@ -74,6 +77,10 @@ pub enum TagValue {
     FBSetVirtualSize = 0x4_8004,
     // AKA Depth
     FBSetBitsPerPixel = 0x4_8005,
+    FBGetPixelOrder = 0x4_0006,
+    FBSetPixelOrder = 0x4_8006,
+    // AKA Bytes per line
+    FBGetPitch = 0x4_0008,
 }
 
 pub trait TagInterface: fmt::Debug {
@@ -152,6 +159,35 @@ impl<T1: TagInterface, T2: TagInterface, T3: TagInterface, T4: TagInterface, T5:
     }
 }
 
+impl<
+        T1: TagInterface,
+        T2: TagInterface,
+        T3: TagInterface,
+        T4: TagInterface,
+        T5: TagInterface,
+        T6: TagInterface,
+    > TagBatch for (T1, T2, T3, T4, T5, T6)
+{
+    type Res = (
+        Option<T1::Res>,
+        Option<T2::Res>,
+        Option<T3::Res>,
+        Option<T4::Res>,
+        Option<T5::Res>,
+        Option<T6::Res>,
+    );
+    fn responses(&self) -> Self::Res {
+        (
+            self.0.response(),
+            self.1.response(),
+            self.2.response(),
+            self.3.response(),
+            self.4.response(),
+            self.5.response(),
+        )
+    }
+}
+
 macro_rules! define_tags {
     ($({
         $name:ident, $enum_value:expr, {$($req_field_name:ident:$req_field_type:ty),*}, {$($res_field_name:ident:$res_field_type:ty),*}
@@ -201,7 +237,8 @@ define_tag!([<$name Tag>], $enum_value, [<$name Request>], [<$name Response>], {
                 fn from_request(req: $req_name) -> $tag_name {
                     Tag {
                         id: $enum_value,
-                        size: core::mem::size_of::<$req_name>() as u32,
+                        // The value buffer has to be big enough for the response too
+                        size: core::mem::size_of::<TagData<$req_name, $res_name>>() as u32,
                         req_res_code: TagReqResCode::new(),
                         data: TagData { req, },
                     }
@@ -225,6 +262,9 @@ define_tag!([<$name Tag>], $enum_value, [<$name Request>], [<$name Response>], {
     };
 }
 
+/// Room for the command line in `GetCommandLine`
+pub const COMMAND_LINE_LEN: usize = crate::cmdline::MAX_LEN;
+
 // https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface
 define_tags! {
     {
@@ -243,6 +283,39 @@ define_tags! {
             model: u32
         }
     },
+    {
+        GetClockRate,
+        TagValue::GetClockRate,
+        {
+            clock_id: u32
+        },
+        {
+            clock_id: u32,
+            rate: u32
+        }
+    },
+
+    {
+        GetCommandLine,
+        TagValue::GetCommandLine,
+        {
+            // Only here so the whole buffer starts zeroed. The response may not be terminated.
+            zeroes: [u8; COMMAND_LINE_LEN]
+        },
+        {
+            cmdline: [u8; COMMAND_LINE_LEN]
+        }
+    },
+
+    {
+        GetDmaChannels,
+        TagValue::GetDmaChannels,
+        {},
+        {
+            // Bit n is set if the ARM may use channel n
+            mask: u32
+        }
+    },
 
     // Frame buffer stuff
     {
@@ -304,5 +377,31 @@ define_tags! {
         {
             bpp: u32
         }
+    },
+    {
+        FBGetPixelOrder,
+        TagValue::FBGetPixelOrder,
+        {},
+        {
+            state: u32
+        }
+    },
+    {
+        FBSetPixelOrder,
+        TagValue::FBSetPixelOrder,
+        {
+            state: u32
+        },
+        {
+            state: u32
+        }
+    },
+    {
+        FBGetPitch,
+        TagValue::FBGetPitch,
+        {},
+        {
+            pitch: u32
+        }
     }
 }
@@ -3,86 +3,81 @@
 
 #![allow(dead_code)]
 
-// #![feature(custom_test_frameworks)]
-// #![test_runner(crate::test_runner::test_runner)]
-// #![reexport_test_harness_main = "test_main"]
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::test_runner::test_runner))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
 
 use core::arch::global_asm;
 use core::convert::Infallible;
-use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use tock_registers::interfaces::Writeable;
 use aarch64_cpu::{asm, registers::*};
 
 
+// Output goes to every console sink. See `console`.
 #[allow(unused_macros)]
 macro_rules! print {
     ($($arg:tt)*) => {{
-        use core::fmt::Write;
-        write!(crate::uart::get(), $($arg)*).unwrap();
+        crate::console::write(log::Level::Info, format_args!($($arg)*));
     }};
 }
 macro_rules! println {
     () => {{
-        use core::fmt::Write;
-        write!(crate::uart::get(), "\n").unwrap();
+        print!("\n");
     }};
     ($($arg:tt)*) => {{
-        use core::fmt::Write;
-        write!(crate::uart::get(), $($arg)*).unwrap();
-        println!();
+        crate::console::write(log::Level::Info, format_args!("{}\n", format_args!($($arg)*)));
     }};
 }
 
-macro_rules! try_println {
-    () => {{
-        use core::fmt::Write;
-        crate::uart::try_get()
-            .ok_or(())
-            .map(|mut w| write!(w, "\n"))
-    }};
+// Errors. Shown on every sink that isn't turned off.
+#[allow(unused_macros)]
+macro_rules! eprint {
     ($($arg:tt)*) => {{
-        use core::fmt::Write;
-        crate::uart::try_get()
-            .ok_or(())
-            .map(|mut w| {
-                write!(w, $($arg)*)
-                    .and(write!(w, "\n"))
-            })
+        crate::console::write(log::Level::Error, format_args!($($arg)*));
     }};
 }
-
-// Try to report an error in various ways
 macro_rules! eprintln {
     () => {{
-        try_println!("\n")
-            .unwrap()
+        eprint!("\n");
     }};
     ($($arg:tt)*) => {{
-        try_println!($($arg)*)
-            .unwrap()
-            .unwrap()
+        crate::console::write(log::Level::Error, format_args!("{}\n", format_args!($($arg)*)));
     }};
 }
 
 mod units;
+mod console;
+mod logger;
 mod framebuffer;
 mod mailbox;
 mod time;
 mod uart;
+mod pl011;
 mod mmu;
 mod exceptions;
+mod smp;
+mod sync;
+mod thread;
+mod process;
+mod arm_local;
+mod cmdline;
+mod atags;
+mod fdt;
+mod initrd;
+mod fs;
+mod irq;
+mod block;
+mod emmc;
+mod dma;
+mod debug;
+mod ipi;
+mod panic;
+mod backtrace;
+#[cfg(test)]
+mod test_runner;
 
 
-// our existing panic handler
-// #[cfg(not(test))] // new attribute
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    use core::fmt::Write;
-    eprintln!("Panic Occured: {}", info);
-    write!(uart::get(), "{}", info).ok();
-    loop {}
-}
-
 
 #[no_mangle]
 pub static HELLO: &[u8] = b"Hello World!";
@@ -92,17 +87,22 @@ extern "C" {
     static __kernel_stack_start: usize;
 }
 
+/// What the firmware left in x0: the address of the device tree, or of the ATAGs. Saved by `boot.s`.
+#[no_mangle]
+static BOOT_ARG: AtomicUsize = AtomicUsize::new(0);
+
 
 
 global_asm!(include_str!("boot.s"));
 
 
-/// Transition from hypervisor to OS
+/// Transition from hypervisor to OS. The `eret` afterwards lands in `entry`, running on the
+/// stack that ends at `stack_top`.
 ///
 /// # Safety
 ///
 /// `bss` hasn't been initialized, so do not touch it
-unsafe fn prep_transition_el2_to_el1() {
+pub(crate) unsafe fn prep_transition_el2_to_el1(entry: extern "C" fn() -> !, stack_top: u64) {
     // Enable timer counter registers for EL1
     CNTHCTL_EL2.write(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
 
@@ -125,16 +125,16 @@ unsafe fn prep_transition_el2_to_el1() {
             + SPSR_EL2::M::EL1h,
     );
 
-    // Then let link register point to `kernel_main`
-    ELR_EL2.set(kernel_init as *const() as u64);
+    // Then let link register point to the entry point
+    ELR_EL2.set(entry as *const() as u64);
 
-    // Set up stack pointer. Just set it to the same stack we are using now.
-    SP_EL1.set(&__kernel_stack_start as *const usize as usize as u64);
+    SP_EL1.set(stack_top);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn __start_kernel() -> ! {
-    prep_transition_el2_to_el1();
+    // Just keep using the same stack we are using now
+    prep_transition_el2_to_el1(kernel_init, &__kernel_stack_start as *const usize as usize as u64);
     asm::eret()
 }
 
@@ -148,10 +148,30 @@ pub extern "C" fn kernel_init() -> ! {
     loop {}
 }
 fn main() -> Result<Infallible, &'static str> {
+    // Sinks skip output until their device is up, so they can all be registered now
+    console::init()?;
+    logger::init()?;
+    // First, since it says where everything else is
+    fdt::init(BOOT_ARG.load(Ordering::Relaxed));
+    atags::init(BOOT_ARG.load(Ordering::Relaxed));
+    if let Some((bus, phys)) = fdt::peripherals() {
+        set_peripherals(bus, phys);
+    }
+    unsafe {
+        // Early, in case the command line has to come from the firmware
+        mailbox::init();
+    }
+    cmdline::init(BOOT_ARG.load(Ordering::Relaxed));
+    cmdline::parse_early();
     unsafe {
         uart::init();
+        pl011::init();
         exceptions::init();
+        irq::init();
+        ipi::init_core();
     println!("uart initialized");
+    fdt::report();
+    cmdline::report();
 
 // {
 //     uart::spin_until_enter();
@@ -161,48 +181,90 @@ fn main() -> Result<Infallible, &'static str> {
 // }
         mmu::init()?;
         println!("vm initialized");
-        mailbox::init();
+        initrd::init(BOOT_ARG.load(Ordering::Relaxed))?;
+        block::ramdisk::init()?;
+        uart::update_clock();
+        if let Err(err) = dma::init() {
+            log::warn!("No DMA: {}", err);
+        }
         framebuffer::init()?;
-    }
-
-    println!("Hello from println!!!!");
-
-    {
-        uart::spin_until_enter();
-        let x = 0x4FF1_5040usize as *mut usize;
-        println!("about to write to addr {:#x} -> {:x}", x as u64, mmu::translate_virt_to_phys(x as u64));
-        unsafe {
-            *x = 5;
+        if let Err(err) = emmc::init() {
+            log::warn!("No SD card: {}", err);
         }
+        fs::init()?;
+        smp::start_secondary_cores();
+    }
+    thread::init_core()?;
+    #[cfg(test)]
+    test_main();
+    // Its handle is dropped, so it runs on its own
+    if let Err(err) = process::start_init() {
+        log::warn!("Could not start {}: {}", process::init_path(), err);
     }
 
+    println!("Hello from println!!!!");
 
     // framebuffer::draw_text("HELLOOOOOOO");
 
+    let mut last_tick = time::uptime_microsec();
     loop {
-        time::wait_microsec(1_000_000);
-        println!("Its been a second");
+        let input = uart::get().try_read_char();
+        if let Some(c) = input {
+            if !debug::handle_key(c) {
+                framebuffer::handle_input(c);
+                console::input::push(c);
+            }
+        }
+
+        if time::uptime_microsec() - last_tick >= 1_000_000 {
+            last_tick += 1_000_000;
+            println!("Its been a second");
+        }
     }
 }
 
+/// Where the peripherals' bus addresses start
+const PERIPHERAL_BUS_BASE: usize = 0x7E00_0000;
+/// Where the peripherals are in physical memory, and how much room they take. A Pi 3's, unless
+/// the device tree says otherwise.
+static PERIPHERAL_BASE: AtomicUsize = AtomicUsize::new(0x3F00_0000);
+static PERIPHERAL_SIZE: AtomicUsize = AtomicUsize::new(0x0100_0000);
+
+/// Record that bus address `bus` shows up at the start of `phys`
+fn set_peripherals(bus: usize, phys: core::ops::Range<usize>) {
+    if bus != PERIPHERAL_BUS_BASE {
+        log::warn!("Peripherals start at unexpected bus address {:#x}. Ignoring it.", bus);
+        return;
+    }
+    PERIPHERAL_BASE.store(phys.start, Ordering::Relaxed);
+    PERIPHERAL_SIZE.store(phys.len(), Ordering::Relaxed);
+}
+
+/// Where the peripherals are in physical memory
+pub fn peripheral_range() -> core::ops::Range<usize> {
+    let base = PERIPHERAL_BASE.load(Ordering::Relaxed);
+    base..base + PERIPHERAL_SIZE.load(Ordering::Relaxed)
+}
+
 /// Convert a bus address into a physical address.
 ///
 ///
 /// The documentation for the BCM2837 gives peripheral bus addresses, which are
-/// not directly mapped to physical addresses. Physical addresses 0x3f000000 to
+/// not directly mapped to physical addresses. On a Pi 3, physical addresses 0x3f000000 to
 /// 0x3fffffff, used for peripheral MMIO, are mapped starting at the peripheral
-/// bus addresses range starting at 0x7e000000 (and ending at 0x7effffff).
+/// bus addresses range starting at 0x7e000000 (and ending at 0x7effffff). The device tree's
+/// `/soc` node says where they really are.
 ///
 /// Example: bus address 0x7e00beef corresponds to physical address 0x3f00beef.
-pub const fn bus_to_phys(addr: usize) -> usize {
-    addr - 0x3f000000
+pub fn bus_to_phys(addr: usize) -> usize {
+    addr - PERIPHERAL_BUS_BASE + PERIPHERAL_BASE.load(Ordering::Relaxed)
 }
 
-// Get the full address for a mmio peripheral
+// Get the full address for a mmio peripheral, from its offset into the peripherals
 // https://jsandler18.github.io/extra/peripheral.html
 // NOTE: This is a different address for the Pi 1
-pub const fn phys_to_bus(base: usize) -> usize {
-    base + 0x3f000000
+pub fn phys_to_bus(base: usize) -> usize {
+    base + PERIPHERAL_BASE.load(Ordering::Relaxed)
 }
 
 #[derive(Debug)]
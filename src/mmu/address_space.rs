@@ -0,0 +1,275 @@
+//! Address spaces for user processes.
+//!
+//! Each one has its own translation tables for TTBR0. The first `NUM_LEVEL_1` GiB point at the
+//! kernel's own level 2 tables, so the kernel is mapped the same way whichever process is running,
+//! and their table descriptors keep EL0 out. User pages go in `USER_SPACE`, tagged with the
+//! process's ASID so switching processes doesn't need a TLB flush.
+use super::{frames, page_size, PageEntry, TableDescriptor, NUM_LEVEL_1};
+use crate::ipi::{tlb_shootdown, TlbFlush};
+use crate::sync::{rank, IrqSafeMutex};
+use core::ops::Range;
+use tock_registers::{
+    interfaces::{ReadWriteable, Readable, Writeable},
+    registers::InMemoryRegister,
+};
+
+/// Where user pages can be mapped. One level 1 entry's worth, right after the kernel's.
+pub const USER_SPACE: Range<usize> = 0x1_0000_0000..0x1_4000_0000;
+const USER_LEVEL1_IDX: usize = USER_SPACE.start / page_size::LEVEL1_TABLE_COVERAGE;
+const _: () = assert!(USER_LEVEL1_IDX >= NUM_LEVEL_1);
+const _: () = assert!(USER_SPACE.end - USER_SPACE.start == page_size::LEVEL1_TABLE_COVERAGE);
+
+const ENTRIES_PER_TABLE: usize = page_size::SIZE / 8;
+
+/// 8 bit ASIDs. 0 belongs to the kernel.
+const NUM_ASIDS: usize = 256;
+static ASIDS: IrqSafeMutex<[u64; NUM_ASIDS / 64]> = IrqSafeMutex::ranked(rank::ASIDS, [1, 0, 0, 0]);
+
+fn alloc_asid() -> Option<u16> {
+    let mut asids = ASIDS.lock();
+    let asid = (0..NUM_ASIDS).find(|&asid| asids[asid / 64] & (1 << (asid % 64)) == 0)?;
+    asids[asid / 64] |= 1 << (asid % 64);
+    Some(asid as u16)
+}
+
+fn free_asid(asid: u16) {
+    let asid = asid as usize;
+    ASIDS.lock()[asid / 64] &= !(1 << (asid % 64));
+}
+
+/// What a process may do with a page. Writable or executable pages are readable too.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UserPerms {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl UserPerms {
+    /// Mapped, but any access from the process faults
+    pub const NONE: UserPerms = UserPerms { read: false, write: false, execute: false };
+    pub const READ: UserPerms = UserPerms { read: true, write: false, execute: false };
+    pub const READ_WRITE: UserPerms = UserPerms { read: true, write: true, execute: false };
+    pub const READ_EXECUTE: UserPerms = UserPerms { read: true, write: false, execute: true };
+}
+
+type Table = [u64; ENTRIES_PER_TABLE];
+
+/// The table at physical address `addr`. Tables come from `frames`, which are identity mapped.
+fn table<'a>(addr: usize) -> &'a mut Table {
+    // Safety: Only the address space that allocated it has its address
+    unsafe { &mut *(addr as *mut Table) }
+}
+
+fn table_entry(addr: usize) -> u64 {
+    let desc = InMemoryRegister::<u64, TableDescriptor::Register>::new(0);
+    desc.write(
+        TableDescriptor::VALID::SET
+            + TableDescriptor::TYPE::Table
+            + TableDescriptor::OUTPUT_ADDR.val((addr / page_size::SIZE) as u64)
+    );
+    desc.get()
+}
+
+fn table_addr(entry: u64) -> Option<usize> {
+    let desc = InMemoryRegister::<u64, TableDescriptor::Register>::new(entry);
+    desc.is_set(TableDescriptor::VALID)
+        .then(|| desc.read(TableDescriptor::OUTPUT_ADDR) as usize * page_size::SIZE)
+}
+
+pub struct AddressSpace {
+    asid: u16,
+    level0: usize,
+    level1: usize,
+    /// Covers `USER_SPACE`
+    level2: usize,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let asid = alloc_asid().ok_or("Out of ASIDs")?;
+        // Dropping it gives back whatever was allocated so far
+        let mut space = AddressSpace {
+            asid,
+            level0: 0,
+            level1: 0,
+            level2: 0,
+        };
+        space.level0 = frames::alloc().ok_or("Out of memory")?;
+        space.level1 = frames::alloc().ok_or("Out of memory")?;
+        space.level2 = frames::alloc().ok_or("Out of memory")?;
+
+        table(space.level0)[0] = table_entry(space.level1);
+        let level1 = table(space.level1);
+        level1[..NUM_LEVEL_1].copy_from_slice(&super::kernel_level1_entries());
+        level1[USER_LEVEL1_IDX] = table_entry(space.level2);
+        Ok(space)
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// What goes in `TTBR0_EL1` while this address space is in use
+    pub fn ttbr0(&self) -> u64 {
+        (self.asid as u64) << 48 | self.level0 as u64
+    }
+
+    /// The level 3 entry for `virt_addr`, making its table if `create` is set
+    fn page_entry(&self, virt_addr: usize, create: bool) -> Result<Option<*mut u64>, &'static str> {
+        if !USER_SPACE.contains(&virt_addr) {
+            return Err("Address is outside of user space");
+        }
+        let offset = virt_addr - USER_SPACE.start;
+        let l2_idx = offset / page_size::LEVEL2_TABLE_COVERAGE;
+        let l3_idx = (offset / page_size::LEVEL3_TABLE_COVERAGE) % ENTRIES_PER_TABLE;
+
+        let level2 = table(self.level2);
+        let level3 = match table_addr(level2[l2_idx]) {
+            Some(addr) => addr,
+            None if create => {
+                let addr = frames::alloc().ok_or("Out of memory")?;
+                level2[l2_idx] = table_entry(addr);
+                addr
+            }
+            None => return Ok(None),
+        };
+        Ok(Some(&mut table(level3)[l3_idx] as *mut u64))
+    }
+
+    /// Map the page holding `virt_addr` to the page at `phys_addr`. The page becomes the address
+    /// space's, and is freed with it.
+    pub fn map(&mut self, virt_addr: usize, phys_addr: usize, perms: UserPerms) -> Result<(), &'static str> {
+        let entry = self.page_entry(virt_addr, true)?.expect("Level 3 table was just made");
+        // Safety: Points into one of our tables, and we have `&mut self`
+        let entry = unsafe { &mut *entry };
+        let old = *entry;
+
+        let page = InMemoryRegister::<u64, PageEntry::Register>::new(0);
+        page.write(
+            PageEntry::VALID::SET
+                + PageEntry::TYPE::Page
+                + PageEntry::ATTRIB_INDEX.val(0)
+                + PageEntry::SHAREABILITY::InnerShareable
+                + PageEntry::ACCESS_FLAG::SET
+                + PageEntry::NON_GLOBAL::SET
+                // The kernel never runs user code
+                + PageEntry::PRIVILEGED_EXECUTE_NEVER::SET
+                + PageEntry::OUTPUT_ADDR.val((phys_addr / page_size::SIZE) as u64)
+        );
+        if perms.write {
+            page.modify(PageEntry::ACCESS_PERMISSION::UrwPrw);
+        } else if perms.read || perms.execute {
+            page.modify(PageEntry::ACCESS_PERMISSION::UrPr);
+        } else {
+            page.modify(PageEntry::ACCESS_PERMISSION::UnPrw);
+        }
+        if !perms.execute {
+            page.modify(PageEntry::UNPRIVILEGED_EXECUTE_NEVER::SET);
+        }
+        *entry = page.get();
+
+        if let Some(old_phys) = page_addr(old) {
+            self.flush(virt_addr);
+            if old_phys != phys_addr & !(page_size::SIZE - 1) {
+                frames::free(old_phys);
+            }
+        }
+        Ok(())
+    }
+
+    /// Map a fresh zeroed page at `virt_addr`. Returns its physical address.
+    pub fn map_new(&mut self, virt_addr: usize, perms: UserPerms) -> Result<usize, &'static str> {
+        let phys_addr = frames::alloc().ok_or("Out of memory")?;
+        if let Err(err) = self.map(virt_addr, phys_addr, perms) {
+            frames::free(phys_addr);
+            return Err(err);
+        }
+        Ok(phys_addr)
+    }
+
+    /// Remove the page holding `virt_addr` and free it. Returns whether there was one.
+    pub fn unmap(&mut self, virt_addr: usize) -> Result<bool, &'static str> {
+        let Some(entry) = self.page_entry(virt_addr, false)? else {
+            return Ok(false);
+        };
+        // Safety: Points into one of our tables, and we have `&mut self`
+        let entry = unsafe { &mut *entry };
+        let Some(phys_addr) = page_addr(*entry) else {
+            return Ok(false);
+        };
+        *entry = 0;
+        self.flush(virt_addr);
+        frames::free(phys_addr);
+        Ok(true)
+    }
+
+    /// Where `virt_addr` is in physical memory, if it's mapped
+    pub fn translate(&self, virt_addr: usize) -> Option<usize> {
+        let entry = self.page_entry(virt_addr, false).ok()??;
+        // Safety: Points into one of our tables
+        page_addr(unsafe { *entry }).map(|page| page + virt_addr % page_size::SIZE)
+    }
+
+    /// Copy `bytes` into the address space at `virt_addr`, ignoring page permissions.
+    /// Fails if any of it isn't mapped.
+    pub fn write_bytes(&self, mut virt_addr: usize, mut bytes: &[u8]) -> Result<(), &'static str> {
+        while !bytes.is_empty() {
+            let phys_addr = self.translate(virt_addr).ok_or("Address is not mapped")?;
+            let len = bytes.len().min(page_size::SIZE - virt_addr % page_size::SIZE);
+            // Safety: The page belongs to this address space and is identity mapped for us
+            unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), phys_addr as *mut u8, len) };
+            virt_addr += len;
+            bytes = &bytes[len..];
+        }
+        Ok(())
+    }
+
+    /// Copy out of the address space at `virt_addr`. Fails if any of it isn't mapped.
+    pub fn read_bytes(&self, mut virt_addr: usize, mut buf: &mut [u8]) -> Result<(), &'static str> {
+        while !buf.is_empty() {
+            let phys_addr = self.translate(virt_addr).ok_or("Address is not mapped")?;
+            let len = buf.len().min(page_size::SIZE - virt_addr % page_size::SIZE);
+            // Safety: The page belongs to this address space and is identity mapped for us
+            unsafe { core::ptr::copy_nonoverlapping(phys_addr as *const u8, buf.as_mut_ptr(), len) };
+            virt_addr += len;
+            buf = &mut buf[len..];
+        }
+        Ok(())
+    }
+
+    /// Drop any cached translation for the page holding `virt_addr`, on every core
+    fn flush(&self, virt_addr: usize) {
+        tlb_shootdown(TlbFlush::AsidPage { asid: self.asid, addr: virt_addr });
+    }
+}
+
+/// The page a level 3 entry maps, if it's valid
+fn page_addr(entry: u64) -> Option<usize> {
+    let page = InMemoryRegister::<u64, PageEntry::Register>::new(entry);
+    page.is_set(PageEntry::VALID)
+        .then(|| page.read(PageEntry::OUTPUT_ADDR) as usize * page_size::SIZE)
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // The ASID may be handed out again and the pages reused, so nothing tagged with it can
+        // stay cached
+        tlb_shootdown(TlbFlush::Asid(self.asid));
+        if self.level2 != 0 {
+            for &l2_entry in table(self.level2).iter() {
+                let Some(level3) = table_addr(l2_entry) else {
+                    continue;
+                };
+                table(level3).iter().filter_map(|&entry| page_addr(entry)).for_each(frames::free);
+                frames::free(level3);
+            }
+        }
+        for addr in [self.level2, self.level1, self.level0] {
+            if addr != 0 {
+                frames::free(addr);
+            }
+        }
+        free_asid(self.asid);
+    }
+}
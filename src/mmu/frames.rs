@@ -0,0 +1,119 @@
+//! Physical page allocator.
+//!
+//! One bit per page of RAM, set while the page is in use. The kernel image (and the boot stack
+//! below it) is marked used by `init`, and anything else that's already spoken for can be held
+//! back with `reserve`.
+use super::page_size;
+use crate::sync::{rank, IrqSafeMutex};
+use core::ops::Range;
+
+/// Where the VideoCore's share of RAM starts on a 1GiB Pi 3 with the default `gpu_mem`.
+/// Everything above it belongs to the GPU. Pages past the end of the memory the device tree (or
+/// the ATAGs) list are held back too, so this is only an upper bound.
+pub const RAM_END: usize = 0x3C00_0000;
+const NUM_FRAMES: usize = RAM_END / page_size::SIZE;
+
+extern "C" {
+    static __end: u8;
+}
+
+struct Frames {
+    used: [u64; NUM_FRAMES / 64],
+    /// Where to start looking for a free page
+    next: usize,
+    free: usize,
+    ready: bool,
+}
+
+static FRAMES: IrqSafeMutex<Frames> = IrqSafeMutex::ranked(
+    rank::FRAMES,
+    Frames {
+        used: [0; NUM_FRAMES / 64],
+        next: 0,
+        free: NUM_FRAMES,
+        ready: false,
+    },
+);
+
+impl Frames {
+    fn is_used(&self, frame: usize) -> bool {
+        self.used[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        if self.is_used(frame) == used {
+            return;
+        }
+        if used {
+            self.used[frame / 64] |= 1 << (frame % 64);
+            self.free -= 1;
+        } else {
+            self.used[frame / 64] &= !(1 << (frame % 64));
+            self.free += 1;
+        }
+    }
+
+    fn reserve(&mut self, range: Range<usize>) {
+        let first = range.start / page_size::SIZE;
+        let end = range.end.div_ceil(page_size::SIZE).min(NUM_FRAMES);
+        for frame in first..end {
+            self.set_used(frame, true);
+        }
+    }
+}
+
+/// Start handing out pages. Until then `alloc` always fails.
+pub fn init() {
+    // Safety: Only the address is used
+    let kernel_end = unsafe { core::ptr::addr_of!(__end) as usize };
+    let mut frames = FRAMES.lock();
+    // The boot stack and spin table sit below the kernel, so hold back all of it
+    frames.reserve(0..kernel_end);
+    if let Some(memory) = crate::fdt::memory().or_else(crate::atags::memory) {
+        for frame in 0..NUM_FRAMES {
+            let addr = frame * page_size::SIZE;
+            if !memory.iter().any(|bank| bank.contains(&addr) && bank.contains(&(addr + page_size::SIZE - 1))) {
+                frames.set_used(frame, true);
+            }
+        }
+    }
+    frames.ready = true;
+}
+
+/// Keep `range` from being handed out
+pub fn reserve(range: Range<usize>) {
+    FRAMES.lock().reserve(range);
+}
+
+/// A zeroed page. Returns its physical address, which is also where the kernel can reach it.
+pub fn alloc() -> Option<usize> {
+    let addr = {
+        let mut frames = FRAMES.lock();
+        if !frames.ready || frames.free == 0 {
+            return None;
+        }
+        let start = frames.next;
+        let frame = (0..NUM_FRAMES)
+            .map(|offset| (start + offset) % NUM_FRAMES)
+            .find(|&frame| !frames.is_used(frame))?;
+        frames.set_used(frame, true);
+        frames.next = (frame + 1) % NUM_FRAMES;
+        frame * page_size::SIZE
+    };
+    // Safety: The page is ours and identity mapped
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0, page_size::SIZE) };
+    Some(addr)
+}
+
+/// Give back a page from `alloc`
+pub fn free(addr: usize) {
+    let frame = addr / page_size::SIZE;
+    let mut frames = FRAMES.lock();
+    assert!(frames.is_used(frame), "Freed page {:#x} that wasn't allocated", addr);
+    frames.set_used(frame, false);
+}
+
+/// How many pages are left
+pub fn free_count() -> usize {
+    FRAMES.lock().free
+}
@@ -1,4 +1,6 @@
-use spin::Mutex;
+use log::debug;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::sync::{rank, IrqSafeMutex};
 use elain::Align;
 use tock_registers::{
     register_bitfields,
@@ -7,6 +9,12 @@ use tock_registers::{
 };
 use static_assertions::{assert_eq_size, assert_eq_align, const_assert_eq};
 
+mod address_space;
+pub mod frames;
+pub use address_space::{AddressSpace, UserPerms, USER_SPACE};
+
+pub const PAGE_SIZE: usize = page_size::SIZE;
+
 // https://stackoverflow.com/a/53646925
 const fn max(a: usize, b: usize) -> usize {
     [a, b][(a < b) as usize]
@@ -22,6 +30,8 @@ pub mod mmap {
     pub const END_RAM_ADDR: usize = (4 * GIB - 1) as usize;
     static_assertions::const_assert_eq!(END_RAM_ADDR, 0xFFFF_FFFF);
     pub const MMIO_ADDR: RangeInclusive<usize> = 0xFE00_0000..=0xFF84_FFFF;
+    /// Per-core interrupt routing, mailboxes and timers. See `arm_local`.
+    pub const LOCAL_PERIPHERALS: RangeInclusive<usize> = 0x4000_0000..=0x4003_FFFF;
 }
 
 
@@ -83,7 +93,23 @@ mod page_size {
 }
 
 
-static TRANLSATION_TABLES: Mutex<TranslationTable> = Mutex::new(TranslationTable::new());
+/// Cleared by `mmu=off` on the command line
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+crate::cmdline::early_param!("mmu", set_enabled);
+
+fn set_enabled(value: &'static str) -> Result<(), &'static str> {
+    ENABLED.store(crate::cmdline::parse_bool(value)?, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether the MMU is in use. Processes need it.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+static TRANLSATION_TABLES: IrqSafeMutex<TranslationTable> =
+    IrqSafeMutex::ranked(rank::MMU, TranslationTable::new());
 
 
 const NUM_LEVEL_0: usize = page_size::LEVEL0_TABLE_SIZE;
@@ -202,6 +228,8 @@ impl TranslationTable {
     }
 
     fn populate_table_entries(&mut self) {
+        // Where the device tree says, or 0x3F00_0000 on a Pi 3
+        let peripherals = crate::peripheral_range();
         let l0_shift = page_size::LEVEL0_TABLE_COVERAGE.trailing_zeros();
         let l1_shift = page_size::LEVEL1_TABLE_COVERAGE.trailing_zeros();
         let l2_shift = page_size::LEVEL2_TABLE_COVERAGE.trailing_zeros();
@@ -213,7 +241,7 @@ impl TranslationTable {
             l0_entry.modify(
                 TableDescriptor::VALID::SET +
                 TableDescriptor::TYPE::Table +
-                TableDescriptor::TABLE_ACCESS_PERMISSION::UrwPrw
+                TableDescriptor::TABLE_ACCESS_PERMISSION::NoEl0
             );
 
             for (l1_idx, l1_entry) in self.level1[l0_idx].0.iter_mut().enumerate() {
@@ -223,7 +251,7 @@ impl TranslationTable {
                 l1_entry.modify(
                     TableDescriptor::VALID::SET +
                     TableDescriptor::TYPE::Table +
-                    TableDescriptor::TABLE_ACCESS_PERMISSION::UrwPrw
+                    TableDescriptor::TABLE_ACCESS_PERMISSION::NoEl0
                 );
 
                 for (l2_idx, l2_entry) in self.level2[l0_idx][l1_idx].0.iter_mut().enumerate() {
@@ -233,7 +261,7 @@ impl TranslationTable {
                     l2_entry.modify(
                         TableDescriptor::VALID::SET +
                         TableDescriptor::TYPE::Table +
-                        TableDescriptor::TABLE_ACCESS_PERMISSION::UrwPrw
+                        TableDescriptor::TABLE_ACCESS_PERMISSION::NoEl0
                     );
 
                     for (l3_idx, l3_entry) in self.level3[l0_idx][l1_idx][l2_idx].0.iter_mut().enumerate() {
@@ -246,7 +274,10 @@ impl TranslationTable {
                             PageEntry::ATTRIB_INDEX.val(0) +
                             PageEntry::ACCESS_FLAG::SET
                         );
-                        if mmap::MMIO_ADDR.contains(&phys_addr) {
+                        if mmap::MMIO_ADDR.contains(&phys_addr)
+                            || mmap::LOCAL_PERIPHERALS.contains(&phys_addr)
+                            || peripherals.contains(&phys_addr)
+                        {
                             l3_entry.modify(PageEntry::ATTRIB_INDEX.val(1));
                         }
 
@@ -338,10 +369,7 @@ impl TranslationTable {
 }
 
 pub fn init() -> Result<(), &'static str> {
-    use aarch64_cpu::{
-        registers::*,
-        asm::barrier,
-    };
+    use aarch64_cpu::registers::*;
         use tock_registers::interfaces::*;
         // Fail early if translation granule is not supported.
         if !ID_AA64MMFR0_EL1.matches_all(ID_AA64MMFR0_EL1::TGran64::Supported) {
@@ -361,6 +389,54 @@ pub fn init() -> Result<(), &'static str> {
         // println!("Tables map address space to itself.");
     }
 
+    if !is_enabled() {
+        log::warn!("Leaving the mmu off, as asked");
+        frames::init();
+        return Ok(());
+    }
+    debug!("Populated tables and set mmu args. Enabling mmu");
+    enable();
+    frames::init();
+    Ok(())
+}
+
+/// What goes in `TTBR0_EL1` for threads that aren't in a process. Uses ASID 0.
+pub fn kernel_ttbr0() -> u64 {
+    // Safety: Only the address is used
+    unsafe { core::ptr::addr_of!((*TRANLSATION_TABLES.as_mut_ptr()).level0.0) as usize as u64 }
+}
+
+/// Level 1 entries mapping the kernel, for address spaces to share
+fn kernel_level1_entries() -> [u64; NUM_LEVEL_1] {
+    use tock_registers::interfaces::Readable;
+    let table = TRANLSATION_TABLES.lock();
+    core::array::from_fn(|idx| table.level1[0].0[idx].get())
+}
+
+/// Start using the translation tables at `ttbr0`, either `kernel_ttbr0` or an
+/// `AddressSpace::ttbr0`. The kernel is mapped the same way in all of them, so this is safe to do
+/// from anywhere in the kernel.
+pub fn switch_ttbr0(ttbr0: u64) {
+    use aarch64_cpu::{asm::barrier, registers::TTBR0_EL1};
+    TTBR0_EL1.set(ttbr0);
+    barrier::isb(barrier::SY);
+}
+
+/// Turn on the MMU for a secondary core, using the tables the boot core set up in `init`
+pub fn init_secondary() {
+    if is_enabled() {
+        enable();
+    }
+}
+
+/// Point this core at the translation tables and turn on the MMU
+fn enable() {
+    use aarch64_cpu::{
+        registers::*,
+        asm::barrier,
+    };
+    use tock_registers::interfaces::*;
+
       // Define the memory types being mapped.
     MAIR_EL1.write(
         // Attribute 0 - Cacheable normal DRAM.
@@ -372,7 +448,9 @@ pub fn init() -> Result<(), &'static str> {
     );
 
     // let table_base_addr = &TRANLSATION_TABLES.lock().level1[0].0 as *const [TableDescriptionR; NUM_LEVEL_1] as usize as u64;
-    let table_base_addr = &TRANLSATION_TABLES.lock().level0.0 as *const [TableDescriptionR; NUM_LEVEL_0] as usize as u64;
+    // Not locked, since secondary cores get here with the MMU off, and atomics might not work
+    // without it. The tables never move, so the address is safe to read.
+    let table_base_addr = kernel_ttbr0();
     // Set the address of the translation tables for lower half of virt address space
     TTBR0_EL1.set_baddr(table_base_addr);
     // Set the address of the translation tables for upper half of virt address space
@@ -391,7 +469,8 @@ pub fn init() -> Result<(), &'static str> {
         TCR_EL1::TG0::KiB_4 +
         // On TLB miss, walk translation table instead of faulting
         TCR_EL1::EPD0::EnableTTBR0Walks +
-        TCR_EL1::EPD1::EnableTTBR1Walks +
+        // Nothing is mapped in the upper half, so don't let anyone look there
+        TCR_EL1::EPD1::DisableTTBR1Walks +
         // TODO: check if this has an off-by-one error
         // TCR_EL1::T0SZ.val(mmap::END_RAM_ADDR.trailing_ones() as u64) +
         TCR_EL1::T0SZ.val(64-48) +
@@ -418,7 +497,6 @@ pub fn init() -> Result<(), &'static str> {
     //     TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
     //  );
 
-    println!("Populated tables and set mmu args. Enabling mmu");
     // Not sure what this argument does
     barrier::isb(barrier::SY);
 
@@ -427,8 +505,6 @@ pub fn init() -> Result<(), &'static str> {
 
     // Again, not sure what this argument does
     barrier::isb(barrier::SY);
-
-    Ok(())
 }
 
 pub fn translate_virt_to_phys(addr: u64) -> u64 {
@@ -496,15 +572,16 @@ register_bitfields! {
 
         TABLE_PRIVILEGED_EXECUTE_NEVER      OFFSET(59)  NUMBITS(1) [],
         TABLE_UNPRIVILEGED_EXECUTE_NEVER    OFFSET(60)  NUMBITS(1) [],
+        // Limits on everything the table maps, on top of the page entries' own permissions
         TABLE_ACCESS_PERMISSION             OFFSET(61)  NUMBITS(2) [
-            /// Unprivelaged = nothing, Privelaged = read/write
-            UnPrw = 0b00,
-            /// Unprivelaged = read/write, Privelaged = read/write
-            UrwPrw = 0b01,
+            /// No limits
+            NoEffect = 0b00,
+            /// Unprivelaged = nothing
+            NoEl0 = 0b01,
+            /// Read only for everyone
+            NoWrite = 0b10,
             /// Unprivelaged = nothing, Privelaged = read
-            UnPr = 0b10,
-            /// Unprivelaged = read, Privelaged = read
-            UrPr = 0b11,
+            NoWriteNoEl0 = 0b11,
         ],
         TABLE_NON_SECURE_ACCESS             OFFSET(63)  NUMBITS(1) [],
 
@@ -0,0 +1,81 @@
+//! The panic handler.
+//!
+//! A panic can happen anywhere: halfway through a `println!` with the UART locked, in an
+//! exception handler, or before the UART is even set up. So nothing here waits on a lock, and the
+//! report is only ever printed once, however many cores (or nested panics) get here.
+use crate::backtrace::{self, Backtrace, Symbolized};
+use aarch64_cpu::asm;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::Level;
+
+/// Set by the first panic. Everyone else that panics just stops.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+/// Tells cores still running kernel code to stop
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a panic has asked every core to stop. Cores waiting around should check this.
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::Acquire)
+}
+
+/// Parks the current core for good
+pub fn halt() -> ! {
+    crate::exceptions::disable_interrupts();
+    loop {
+        asm::wfe();
+    }
+}
+
+fn stop_other_cores() {
+    STOP_REQUESTED.store(true, Ordering::Release);
+    // Wake anyone waiting for an event so they see the request
+    asm::sev();
+    // And interrupt anyone that's busy
+    crate::ipi::broadcast(crate::ipi::Ipi::Stop);
+}
+
+/// Writes a line to every console sink, and straight to the UART if it was never set up
+fn emit(args: fmt::Arguments) {
+    use core::fmt::Write;
+    crate::console::write(Level::Error, format_args!("{}\n", args));
+    if !crate::uart::is_init() {
+        // Safety: Nothing else is printing. Every other core has been told to stop.
+        let mut uart = unsafe { crate::uart::emergency_writer() };
+        write!(uart, "{}\n", args).ok();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crate::exceptions::disable_interrupts();
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        // Either we panicked while reporting a panic, or another core got here first.
+        // Both ways the report is being taken care of.
+        halt();
+    }
+    stop_other_cores();
+
+    // Safety: We never return, so whoever held the locks won't use them again
+    unsafe { crate::console::force_unlock_all() };
+
+    emit(format_args!("\n!!! KERNEL PANIC !!!"));
+    match info.location() {
+        Some(loc) => emit(format_args!("Panic Occured at {}:{}:{}", loc.file(), loc.line(), loc.column())),
+        None => emit(format_args!("Panic Occured at an unknown location")),
+    }
+    emit(format_args!("{}", info.message()));
+    backtrace::print(None, Backtrace::here(), emit);
+
+    if let Some(frame) = crate::exceptions::current_frame() {
+        emit(format_args!("While handling an exception at {}", Symbolized(frame.elr())));
+        emit(format_args!("{:#x?}", frame));
+        emit(format_args!("lr = {}", Symbolized(frame.lr())));
+        backtrace::print(Some(frame.elr()), Backtrace::from_fp(frame.fp()), emit);
+    }
+
+    #[cfg(test)]
+    crate::test_runner::exit_qemu(crate::test_runner::QemuExitCode::Failed);
+    halt()
+}
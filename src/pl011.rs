@@ -0,0 +1,152 @@
+//! The PL011 UART (UART0). QEMU connects it to the first `-serial`.
+use crate::{MMIODerefWrapper, bus_to_phys};
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+type Uart = MMIODerefWrapper<Registers>;
+
+use core::fmt;
+use crate::sync::{rank, IrqSafeMutex, IrqSafeMutexGuard};
+use spin::Once;
+
+pub struct Controller {
+    uart: Uart,
+}
+
+impl Controller {
+    pub fn read_char(&mut self) -> char {
+        while self.uart.fr.is_set(FR::RX_FIFO_EMPTY) {}
+        self.uart.dr.read(DR::DATA) as u8 as char
+    }
+
+    /// Read a character if one has been received
+    pub fn try_read_char(&mut self) -> Option<char> {
+        if self.uart.fr.is_set(FR::RX_FIFO_EMPTY) {
+            None
+        } else {
+            Some(self.uart.dr.read(DR::DATA) as u8 as char)
+        }
+    }
+}
+
+impl fmt::Write for Controller {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            while self.uart.fr.is_set(FR::TX_FIFO_FULL) {}
+            self.uart.dr.write(DR::DATA.val(byte as u32));
+        }
+        Ok(())
+    }
+}
+
+static UART: Once<IrqSafeMutex<Controller>> = Once::new();
+
+pub fn try_get() -> Option<IrqSafeMutexGuard<'static, Controller>> {
+    UART.get().and_then(|m| m.try_lock())
+}
+
+pub fn get() -> IrqSafeMutexGuard<'static, Controller> {
+    UART.get().unwrap().lock()
+}
+
+/// Whether `init` has finished
+pub fn is_init() -> bool {
+    UART.is_completed()
+}
+
+/// Breaks the lock on the UART
+///
+/// # Safety
+///
+/// Whoever holds the lock must never touch the UART again
+pub unsafe fn force_unlock() {
+    if let Some(uart) = UART.get() {
+        uart.force_unlock();
+    }
+}
+
+// Section 13.4
+// https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf
+register_structs! {
+    Registers {
+        (0x00 => dr: ReadWrite<u32, DR::Register>),
+        (0x04 => _reserved_rsrecr),
+        (0x18 => fr: ReadOnly<u32, FR::Register>),
+        (0x1C => _reserved_ilpr),
+        (0x24 => ibrd: WriteOnly<u32, IBRD::Register>),
+        (0x28 => fbrd: WriteOnly<u32, FBRD::Register>),
+        (0x2C => lcrh: WriteOnly<u32, LCRH::Register>),
+        (0x30 => cr: WriteOnly<u32, CR::Register>),
+        (0x34 => _reserved_ifls),
+        (0x44 => icr: WriteOnly<u32, ICR::Register>),
+        (0x48 => @END),
+    }
+}
+
+register_bitfields! {
+    // 32 bit registers
+    u32,
+
+    DR [
+        DATA    OFFSET(0)   NUMBITS(8),
+    ],
+    FR [
+        BUSY            OFFSET(3)   NUMBITS(1) [],
+        RX_FIFO_EMPTY   OFFSET(4)   NUMBITS(1) [],
+        TX_FIFO_FULL    OFFSET(5)   NUMBITS(1) [],
+    ],
+    IBRD [
+        DIVISOR     OFFSET(0)   NUMBITS(16) [],
+    ],
+    FBRD [
+        DIVISOR     OFFSET(0)   NUMBITS(6) [],
+    ],
+    LCRH [
+        FIFO_ENABLE     OFFSET(4)   NUMBITS(1) [],
+        WORD_LENGTH     OFFSET(5)   NUMBITS(2) [
+            EightBit = 0b11,
+        ],
+    ],
+    CR [
+        UART_ENABLE         OFFSET(0)   NUMBITS(1) [],
+        TRANSMITTER_ENABLE  OFFSET(8)   NUMBITS(1) [],
+        RECEIVER_ENABLE     OFFSET(9)   NUMBITS(1) [],
+    ],
+    ICR [
+        ALL     OFFSET(0)   NUMBITS(11) [],
+    ],
+}
+
+const BAUD_RATE: usize = 115200;
+// The firmware's default `init_uart_clock`, for when the device tree doesn't say
+const DEFAULT_UART_CLOCK_FREQ: usize = 48_000_000;
+const UART_BUS_ADDR: usize = 0x7E20_1000;
+pub unsafe fn init() {
+    // NOTE TO SELF: On real board will have to set GPIO first
+
+    let device = crate::fdt::device("arm,pl011");
+    let uart = Uart::new(device.as_ref().map_or(bus_to_phys(UART_BUS_ADDR), |dev| dev.regs.start));
+    let clock_freq = device
+        .and_then(|dev| dev.clock_frequency)
+        .map_or(DEFAULT_UART_CLOCK_FREQ, |freq| freq as usize);
+
+    // Disable while we configure it
+    uart.cr.set(0);
+    while uart.fr.is_set(FR::BUSY) {}
+    uart.icr.write(ICR::ALL::SET);
+
+    // Divisor is a fixed point number with 6 fractional bits
+    let divisor_64ths = (clock_freq * 4 + BAUD_RATE / 2) / BAUD_RATE;
+    uart.ibrd.write(IBRD::DIVISOR.val((divisor_64ths >> 6) as u32));
+    uart.fbrd.write(FBRD::DIVISOR.val((divisor_64ths & 0x3F) as u32));
+
+    // 8N1 with FIFOs
+    uart.lcrh.write(LCRH::WORD_LENGTH::EightBit + LCRH::FIFO_ENABLE::SET);
+
+    uart.cr.write(CR::UART_ENABLE::SET + CR::TRANSMITTER_ENABLE::SET + CR::RECEIVER_ENABLE::SET);
+
+    UART.call_once(|| IrqSafeMutex::ranked(rank::PL011, Controller { uart }));
+}
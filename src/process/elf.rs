@@ -0,0 +1,464 @@
+//! Loading static ELF64 programs into new processes.
+//!
+//! Only what a statically linked aarch64 binary needs: the `PT_LOAD` segments get mapped with
+//! their own permissions, and the stack is laid out the way the System V ABI (and so musl's and
+//! newlib's `_start`) expects, with argc, argv, envp and the auxiliary vector. There's no dynamic
+//! linker, so position independent (`ET_DYN`) programs must not need relocating by one.
+//!
+//! Segments have to be in `USER_SPACE`, since the kernel's identity mapping covers the low 4GiB.
+//! Toolchains put static `ET_EXEC` programs at 0x400000, so a default static link is rejected and
+//! has to be redone with `user/user.ld`, which moves it up. Static PIE programs (`-static-pie`)
+//! work as they are: they're put at `DYN_BASE`, and relocate themselves. Loading the default
+//! layout would take moving the kernel to TTBR1 first.
+//!
+//! ```text
+//!   USER_STACK_TOP ->  argument and environment strings
+//!                      AT_RANDOM bytes
+//!                      (padding to 16 bytes)
+//!                      auxv pairs, ending with AT_NULL
+//!                      envp pointers, then NULL
+//!                      argv pointers, then NULL
+//!   sp ->              argc
+//! ```
+use super::{with_process, USER_STACK_SIZE, USER_STACK_TOP};
+use crate::mmu::{UserPerms, PAGE_SIZE, USER_SPACE};
+use crate::thread::JoinHandle;
+use log::debug;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+const MACHINE_AARCH64: u16 = 183;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+const NUM_AUX: usize = 8;
+
+/// Where `ET_DYN` programs are put. Leaves the first 64KiB unmapped, to catch null pointers.
+const DYN_BASE: usize = USER_SPACE.start + 0x1_0000;
+/// Arguments and environment may use at most this much of the stack
+const MAX_ARGS_SIZE: usize = USER_STACK_SIZE / 4;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: usize,
+    pub vaddr: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+}
+
+impl ProgramHeader {
+    fn parse(bytes: &[u8]) -> Self {
+        ProgramHeader {
+            kind: read_u32(bytes, 0),
+            flags: read_u32(bytes, 4),
+            offset: read_u64(bytes, 8) as usize,
+            vaddr: read_u64(bytes, 16) as usize,
+            file_size: read_u64(bytes, 32) as usize,
+            mem_size: read_u64(bytes, 40) as usize,
+        }
+    }
+
+    fn perms(&self) -> UserPerms {
+        UserPerms {
+            read: true,
+            write: self.flags & PF_W != 0,
+            execute: self.flags & PF_X != 0,
+        }
+    }
+
+    /// The pages the segment covers in memory
+    fn pages(&self, base: usize) -> core::ops::Range<usize> {
+        let start = (base + self.vaddr) / PAGE_SIZE * PAGE_SIZE;
+        let end = (base + self.vaddr + self.mem_size).next_multiple_of(PAGE_SIZE);
+        start..end
+    }
+}
+
+/// A checked ELF image
+pub struct Elf<'a> {
+    image: &'a [u8],
+    kind: u16,
+    entry: usize,
+    ph_offset: usize,
+    ph_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Check that `image` is an ELF64 program we can run
+    pub fn parse(image: &'a [u8]) -> Result<Self, &'static str> {
+        if image.len() < HEADER_SIZE || image[..4] != MAGIC {
+            return Err("Not an ELF file");
+        }
+        if image[4] != CLASS_64 || image[5] != DATA_LITTLE_ENDIAN || image[6] != VERSION_CURRENT {
+            return Err("Not a little endian ELF64 file");
+        }
+        let kind = read_u16(image, 16);
+        if kind != TYPE_EXEC && kind != TYPE_DYN {
+            return Err("ELF file is not an executable");
+        }
+        if read_u16(image, 18) != MACHINE_AARCH64 {
+            return Err("ELF file is not for aarch64");
+        }
+        let ph_offset = read_u64(image, 32) as usize;
+        let ph_entry_size = read_u16(image, 54) as usize;
+        let ph_count = read_u16(image, 56) as usize;
+        if ph_entry_size != PROGRAM_HEADER_SIZE {
+            return Err("ELF program headers are the wrong size");
+        }
+        let ph_end = ph_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(ph_offset));
+        if ph_end.map_or(true, |end| end > image.len()) {
+            return Err("ELF program headers are past the end of the file");
+        }
+
+        let elf = Elf {
+            image,
+            kind,
+            entry: read_u64(image, 24) as usize,
+            ph_offset,
+            ph_count,
+        };
+        for ph in elf.program_headers().filter(|ph| ph.kind == PT_LOAD) {
+            if ph.file_size > ph.mem_size {
+                return Err("ELF segment is bigger in the file than in memory");
+            }
+            if ph.offset.checked_add(ph.file_size).map_or(true, |end| end > image.len()) {
+                return Err("ELF segment is past the end of the file");
+            }
+            let end = ph.vaddr.checked_add(ph.mem_size).and_then(|end| end.checked_add(elf.base()));
+            if end.is_none() || !elf.fits(ph) {
+                return Err("ELF segment is outside of user space. Link it with user/user.ld.");
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.ph_count).map(|idx| {
+            let offset = self.ph_offset + idx * PROGRAM_HEADER_SIZE;
+            ProgramHeader::parse(&self.image[offset..offset + PROGRAM_HEADER_SIZE])
+        })
+    }
+
+    /// How far the program is moved from the addresses it was linked at
+    fn base(&self) -> usize {
+        if self.kind == TYPE_DYN {
+            DYN_BASE
+        } else {
+            0
+        }
+    }
+
+    /// Whether the segment lands below the area saved for the stack and `mmap`s
+    fn fits(&self, ph: ProgramHeader) -> bool {
+        let pages = ph.pages(self.base());
+        pages.start >= USER_SPACE.start && pages.end <= super::MMAP_TOP
+    }
+
+    /// Where the program starts running
+    pub fn entry(&self) -> usize {
+        self.base() + self.entry
+    }
+
+    /// Where the program headers end up in memory, for `AT_PHDR`
+    fn phdr_addr(&self) -> Option<usize> {
+        if let Some(ph) = self.program_headers().find(|ph| ph.kind == PT_PHDR) {
+            return Some(self.base() + ph.vaddr);
+        }
+        self.program_headers()
+            .filter(|ph| ph.kind == PT_LOAD)
+            .find(|ph| (ph.offset..ph.offset + ph.file_size).contains(&self.ph_offset))
+            .map(|ph| self.base() + ph.vaddr + (self.ph_offset - ph.offset))
+    }
+
+    /// Permissions for a page: everything any segment touching it needs
+    fn page_perms(&self, page: usize) -> UserPerms {
+        self.program_headers()
+            .filter(|ph| ph.kind == PT_LOAD && ph.pages(self.base()).contains(&page))
+            .map(|ph| ph.perms())
+            .fold(UserPerms::READ, |acc, perms| UserPerms {
+                read: true,
+                write: acc.write || perms.write,
+                execute: acc.execute || perms.execute,
+            })
+    }
+}
+
+/// Make a process running `image`, with `argv` and `envp` on its stack
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<JoinHandle, &'static str> {
+    let elf = Elf::parse(image)?;
+    let pid = super::create()?;
+    let sp = match with_process(pid, |p| load(&elf, p, argv, envp)).ok_or("No such process")? {
+        Ok(sp) => sp,
+        Err(err) => {
+            super::destroy(pid);
+            return Err(err);
+        }
+    };
+    debug!("Loaded process {} with entry {:#x}, stack {:#x}", pid, elf.entry(), sp);
+    super::start(pid, elf.entry(), sp).inspect_err(|_| super::destroy(pid))
+}
+
+/// Map the program into `process` and set up its stack. Returns the stack pointer.
+fn load(elf: &Elf, process: &mut super::Process, argv: &[&str], envp: &[&str]) -> Result<usize, &'static str> {
+    let base = elf.base();
+    let mut image_end = USER_SPACE.start;
+    for ph in elf.program_headers().filter(|ph| ph.kind == PT_LOAD) {
+        let pages = ph.pages(base);
+        for page in pages.clone().step_by(PAGE_SIZE) {
+            // Segments can share a page at their edges, in which case the first one maps it
+            if process.space().translate(page).is_none() {
+                process.space().map_new(page, elf.page_perms(page))?;
+            }
+        }
+        let vaddr = base + ph.vaddr;
+        process.space().write_bytes(vaddr, &elf.image[ph.offset..ph.offset + ph.file_size])?;
+        // New pages are already zero, but the rest of the page the file data ends in may not be
+        let bss = vaddr + ph.file_size;
+        let bss_end = (vaddr + ph.mem_size).min(bss.next_multiple_of(PAGE_SIZE));
+        process.space().write_bytes(bss, &[0; PAGE_SIZE][..bss_end - bss])?;
+        image_end = image_end.max(pages.end);
+    }
+    process.set_brk_start(image_end);
+
+    let auxv: [(u64, u64); NUM_AUX] = [
+        (AT_PHDR, elf.phdr_addr().unwrap_or(0) as u64),
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, elf.ph_count as u64),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_BASE, 0),
+        (AT_ENTRY, elf.entry() as u64),
+        // Filled in with the address of the random bytes
+        (AT_RANDOM, 0),
+        (AT_NULL, 0),
+    ];
+    build_stack(process, argv, envp, auxv)
+}
+
+fn build_stack(
+    process: &mut super::Process,
+    argv: &[&str],
+    envp: &[&str],
+    mut auxv: [(u64, u64); NUM_AUX],
+) -> Result<usize, &'static str> {
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let table_size = (1 + argv.len() + 1 + envp.len() + 1 + 2 * NUM_AUX) * 8;
+    if strings_size + 16 + table_size + 16 > MAX_ARGS_SIZE {
+        return Err("Arguments don't fit on the stack");
+    }
+    let space = process.space();
+
+    let strings = USER_STACK_TOP - strings_size;
+    let random = (strings - 16) & !0xF;
+    space.write_bytes(random, &random_bytes())?;
+    for (kind, value) in auxv.iter_mut() {
+        if *kind == AT_RANDOM {
+            *value = random as u64;
+        }
+    }
+    // argc has to be 16 byte aligned
+    let sp = (random - table_size) & !0xF;
+
+    let mut table = sp;
+    let mut push = |value: u64| -> Result<(), &'static str> {
+        space.write_bytes(table, &value.to_le_bytes())?;
+        table += 8;
+        Ok(())
+    };
+    push(argv.len() as u64)?;
+    let mut string = strings;
+    for list in [argv, envp] {
+        for s in list {
+            // Each string is followed by its NUL
+            space.write_bytes(string, s.as_bytes())?;
+            space.write_bytes(string + s.len(), &[0])?;
+            push(string as u64)?;
+            string += s.len() + 1;
+        }
+        push(0)?;
+    }
+    for (kind, value) in auxv {
+        push(kind)?;
+        push(value)?;
+    }
+    Ok(sp)
+}
+
+/// Bytes for `AT_RANDOM`, which libcs use for stack canaries and pointer guards. There's no
+/// hardware RNG driver, so this is the system counter run through splitmix64. Unpredictable enough
+/// to vary between runs, but nothing to rely on.
+fn random_bytes() -> [u8; 16] {
+    use aarch64_cpu::registers::CNTPCT_EL0;
+    use tock_registers::interfaces::Readable;
+    let mut state = CNTPCT_EL0.get();
+    let mut next = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&next().to_le_bytes());
+    bytes[8..].copy_from_slice(&next().to_le_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::{create, destroy, Pid};
+
+    // Made by testdata/make_elfs.py, which says what's in each
+    const EXEC: &[u8] = include_bytes!("testdata/exec.elf");
+    const DYN: &[u8] = include_bytes!("testdata/dyn.elf");
+    const LOW_EXEC: &[u8] = include_bytes!("testdata/low_exec.elf");
+    const BAD_MAGIC: &[u8] = include_bytes!("testdata/bad_magic.elf");
+    const WRONG_MACHINE: &[u8] = include_bytes!("testdata/wrong_machine.elf");
+    const PHDRS_PAST_EOF: &[u8] = include_bytes!("testdata/phdrs_past_eof.elf");
+    const FILE_BIGGER_THAN_MEM: &[u8] = include_bytes!("testdata/file_bigger_than_mem.elf");
+
+    const EXEC_BASE: usize = 0x1_0001_0000;
+
+    #[test_case]
+    fn parses_exec() {
+        let elf = Elf::parse(EXEC).unwrap();
+        assert_eq!(elf.kind, TYPE_EXEC);
+        assert_eq!(elf.ph_count, 3);
+        assert_eq!(elf.entry(), EXEC_BASE + HEADER_SIZE + 3 * PROGRAM_HEADER_SIZE);
+        assert_eq!(elf.program_headers().filter(|ph| ph.kind == PT_LOAD).count(), 2);
+    }
+
+    #[test_case]
+    fn parses_dyn_at_dyn_base() {
+        let elf = Elf::parse(DYN).unwrap();
+        assert_eq!(elf.kind, TYPE_DYN);
+        assert_eq!(elf.entry(), DYN_BASE + HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE);
+    }
+
+    #[test_case]
+    fn rejects_malformed() {
+        let cases = [
+            (BAD_MAGIC, "Not an ELF file"),
+            (&EXEC[..HEADER_SIZE - 1], "Not an ELF file"),
+            (WRONG_MACHINE, "ELF file is not for aarch64"),
+            (PHDRS_PAST_EOF, "ELF program headers are past the end of the file"),
+            (FILE_BIGGER_THAN_MEM, "ELF segment is bigger in the file than in memory"),
+            (LOW_EXEC, "ELF segment is outside of user space. Link it with user/user.ld."),
+        ];
+        for (image, err) in cases {
+            assert_eq!(Elf::parse(image).err(), Some(err));
+        }
+    }
+
+    #[test_case]
+    fn phdr_addr_from_pt_phdr() {
+        let elf = Elf::parse(EXEC).unwrap();
+        assert_eq!(elf.phdr_addr(), Some(EXEC_BASE + HEADER_SIZE));
+    }
+
+    #[test_case]
+    fn phdr_addr_from_load_segment() {
+        // No PT_PHDR, so it's found in the first segment, which starts at the file's start
+        let elf = Elf::parse(DYN).unwrap();
+        assert_eq!(elf.phdr_addr(), Some(DYN_BASE + HEADER_SIZE));
+    }
+
+    #[test_case]
+    fn page_perms() {
+        let elf = Elf::parse(EXEC).unwrap();
+        assert_eq!(elf.page_perms(EXEC_BASE), UserPerms::READ_EXECUTE);
+        assert_eq!(elf.page_perms(EXEC_BASE + PAGE_SIZE), UserPerms::READ_WRITE);
+        assert_eq!(elf.page_perms(EXEC_BASE + 2 * PAGE_SIZE), UserPerms::READ_WRITE);
+
+        // The data shares the code's page, which needs both
+        let elf = Elf::parse(DYN).unwrap();
+        let all = UserPerms { read: true, write: true, execute: true };
+        assert_eq!(elf.page_perms(DYN_BASE), all);
+        assert_eq!(elf.page_perms(DYN_BASE + PAGE_SIZE), UserPerms::READ_WRITE);
+    }
+
+    fn read_u64_at(pid: Pid, addr: usize) -> u64 {
+        let mut bytes = [0; 8];
+        with_process(pid, |p| p.space().read_bytes(addr, &mut bytes)).unwrap().unwrap();
+        u64::from_le_bytes(bytes)
+    }
+
+    fn read_str_at(pid: Pid, addr: usize, expected: &str) -> bool {
+        let mut bytes = [0; 32];
+        let len = expected.len() + 1;
+        with_process(pid, |p| p.space().read_bytes(addr, &mut bytes[..len])).unwrap().unwrap();
+        &bytes[..len - 1] == expected.as_bytes() && bytes[len - 1] == 0
+    }
+
+    #[test_case]
+    fn build_stack_layout() {
+        let argv = ["/bin/prog", "-v"];
+        let envp = ["HOME=/"];
+        let mut auxv = [(AT_NULL, 0); NUM_AUX];
+        auxv[0] = (AT_PAGESZ, PAGE_SIZE as u64);
+        auxv[1] = (AT_RANDOM, 0);
+        let pid = create().unwrap();
+        let sp = with_process(pid, |p| build_stack(p, &argv, &envp, auxv)).unwrap().unwrap();
+        assert_eq!(sp % 16, 0);
+
+        let word = |idx: usize| read_u64_at(pid, sp + idx * 8);
+        assert_eq!(word(0), argv.len() as u64);
+        for (idx, arg) in argv.iter().enumerate() {
+            assert!(read_str_at(pid, word(1 + idx) as usize, arg));
+        }
+        assert_eq!(word(1 + argv.len()), 0);
+        let envp_at = 2 + argv.len();
+        assert!(read_str_at(pid, word(envp_at) as usize, envp[0]));
+        assert_eq!(word(envp_at + 1), 0);
+
+        let auxv_at = envp_at + 2;
+        assert_eq!((word(auxv_at), word(auxv_at + 1)), (AT_PAGESZ, PAGE_SIZE as u64));
+        assert_eq!(word(auxv_at + 2), AT_RANDOM);
+        let random = word(auxv_at + 3) as usize;
+        assert_eq!(random % 16, 0);
+        // Between the table and the strings, which end right at the top of the stack
+        assert!(random >= sp + (auxv_at + 2 * NUM_AUX) * 8);
+        let strings_size: usize = argv.iter().chain(&envp).map(|s| s.len() + 1).sum();
+        assert!(random + 16 <= USER_STACK_TOP - strings_size);
+        assert_eq!(word(auxv_at + 2 * NUM_AUX - 2), AT_NULL);
+        assert!(read_str_at(pid, USER_STACK_TOP - strings_size, argv[0]));
+
+        destroy(pid);
+    }
+}
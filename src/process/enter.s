@@ -0,0 +1,48 @@
+.section ".text"
+
+// Drop to EL0 at x0 with the stack pointer at x1. Doesn't return. The process comes back through
+// the exception vectors, on whatever is left of the kernel stack we were called on.
+//
+// void __enter_user(usize entry, usize sp) -> !
+.global __enter_user
+__enter_user:
+    // An interrupt here would overwrite ELR_EL1 and SPSR_EL1 before the eret
+    msr DAIFSet, #2
+    msr SP_EL0, x1
+    msr ELR_EL1, x0
+    // EL0t, with nothing masked
+    msr SPSR_EL1, xzr
+
+    // Don't leave anything from the kernel lying around in registers
+    mov x0, xzr
+    mov x1, xzr
+    mov x2, xzr
+    mov x3, xzr
+    mov x4, xzr
+    mov x5, xzr
+    mov x6, xzr
+    mov x7, xzr
+    mov x8, xzr
+    mov x9, xzr
+    mov x10, xzr
+    mov x11, xzr
+    mov x12, xzr
+    mov x13, xzr
+    mov x14, xzr
+    mov x15, xzr
+    mov x16, xzr
+    mov x17, xzr
+    mov x18, xzr
+    mov x19, xzr
+    mov x20, xzr
+    mov x21, xzr
+    mov x22, xzr
+    mov x23, xzr
+    mov x24, xzr
+    mov x25, xzr
+    mov x26, xzr
+    mov x27, xzr
+    mov x28, xzr
+    mov x29, xzr
+    mov x30, xzr
+    eret
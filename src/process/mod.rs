@@ -0,0 +1,239 @@
+//! User processes.
+//!
+//! A process is an `AddressSpace` and the one thread that runs in it at EL0. The thread drops to
+//! EL0 with `eret` and comes back through the exception vectors on its kernel stack, so the rest
+//! of the kernel sees it as an ordinary thread that happens to spend most of its time elsewhere.
+//!
+//! Anything a process does wrong only takes the process down: the exception handler marks it
+//! killed, and it exits instead of returning to EL0.
+use crate::fs::FdTable;
+use crate::mmu::{AddressSpace, UserPerms, USER_SPACE};
+use crate::sync::{rank, IrqSafeMutex};
+use crate::thread::{self, JoinHandle, ThreadId};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+use log::info;
+use spin::Once;
+
+pub mod elf;
+pub mod syscall;
+
+pub const MAX_PROCESSES: usize = 16;
+/// The user stack sits at the top of user space and grows down
+pub const USER_STACK_TOP: usize = USER_SPACE.end;
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+/// Exit status of a process that was killed
+pub const KILLED_STATUS: i32 = -1;
+/// Anonymous `mmap`s are handed out downwards from here, leaving a guard page under the stack
+const MMAP_TOP: usize = USER_STACK_TOP - USER_STACK_SIZE - crate::mmu::PAGE_SIZE;
+
+global_asm!(include_str!("enter.s"));
+
+extern "C" {
+    fn __enter_user(entry: usize, sp: usize) -> !;
+}
+
+pub type Pid = u32;
+
+pub struct Process {
+    pid: Pid,
+    space: AddressSpace,
+    /// The thread running it, once it has been started
+    thread: Option<ThreadId>,
+    entry: usize,
+    sp: usize,
+    /// Exit instead of going back to EL0
+    killed: bool,
+    /// Where the heap `brk` grows starts. Right after the program's data.
+    brk_start: usize,
+    /// The current end of the heap
+    brk: usize,
+    /// Bottom of the anonymous `mmap`s so far
+    mmap_next: usize,
+    /// From `set_tid_address`
+    clear_child_tid: usize,
+    files: FdTable,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn space(&mut self) -> &mut AddressSpace {
+        &mut self.space
+    }
+
+    pub fn files(&mut self) -> &mut FdTable {
+        &mut self.files
+    }
+
+    /// Put the start of the heap at `addr`. Call after mapping the program, with the end of its
+    /// highest segment.
+    pub fn set_brk_start(&mut self, addr: usize) {
+        self.brk_start = addr;
+        self.brk = addr;
+    }
+}
+
+static PROCESSES: IrqSafeMutex<[Option<Process>; MAX_PROCESSES]> =
+    IrqSafeMutex::ranked(rank::PROCESSES, [const { None }; MAX_PROCESSES]);
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+
+/// Run when `init=` isn't given
+const DEFAULT_INIT_PATH: &str = "/init";
+static INIT_PATH: Once<&'static str> = Once::new();
+
+crate::cmdline::early_param!("init", set_init_path);
+
+fn set_init_path(path: &'static str) -> Result<(), &'static str> {
+    if !path.starts_with('/') {
+        return Err("Path must be absolute");
+    }
+    INIT_PATH.call_once(|| path);
+    Ok(())
+}
+
+/// The first program to run
+pub fn init_path() -> &'static str {
+    INIT_PATH.get().copied().unwrap_or(DEFAULT_INIT_PATH)
+}
+
+/// Start the program at `init_path()` in the initrd. Needs `fs::init` and `thread::init_core`.
+pub fn start_init() -> Result<JoinHandle, &'static str> {
+    let path = init_path();
+    let image = crate::initrd::read(path).ok_or("Not in the initrd")?;
+    elf::spawn(image, &[path], &[])
+}
+
+/// Make a process with an empty address space and a user stack, and `/dev/console` open as its
+/// standard input and output. Map its program with `with_process`, then `start` it.
+pub fn create() -> Result<Pid, &'static str> {
+    if !crate::mmu::is_enabled() {
+        return Err("Processes need the mmu");
+    }
+    let mut space = AddressSpace::new()?;
+    for page in (USER_STACK_TOP - USER_STACK_SIZE..USER_STACK_TOP).step_by(crate::mmu::PAGE_SIZE) {
+        space.map_new(page, UserPerms::READ_WRITE)?;
+    }
+
+    // Opening files can sleep, so it's done before taking the lock
+    let mut files = FdTable::with_console();
+
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let mut processes = PROCESSES.lock();
+    let Some(slot) = processes.iter_mut().find(|slot| slot.is_none()) else {
+        drop(processes);
+        files.close_all();
+        return Err("Too many processes");
+    };
+    *slot = Some(Process {
+        pid,
+        space,
+        thread: None,
+        entry: 0,
+        sp: USER_STACK_TOP,
+        killed: false,
+        brk_start: USER_SPACE.start,
+        brk: USER_SPACE.start,
+        mmap_next: MMAP_TOP,
+        clear_child_tid: 0,
+        files,
+    });
+    Ok(pid)
+}
+
+/// Throw away a process that was never started
+pub fn destroy(pid: Pid) {
+    let process = PROCESSES
+        .lock()
+        .iter_mut()
+        .find(|slot| slot.as_ref().is_some_and(|p| p.pid == pid && p.thread.is_none()))
+        .and_then(Option::take);
+    // Its files and memory are freed outside the lock
+    if let Some(mut process) = process {
+        process.files.close_all();
+    }
+}
+
+/// Run `f` on process `pid`, if there is one
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let mut processes = PROCESSES.lock();
+    processes.iter_mut().flatten().find(|p| p.pid == pid).map(f)
+}
+
+/// Run `f` on the process the current thread is running, if it is running one
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let me = thread::try_current()?;
+    let mut processes = PROCESSES.lock();
+    processes.iter_mut().flatten().find(|p| p.thread == Some(me)).map(f)
+}
+
+pub fn current_pid() -> Option<Pid> {
+    with_current(|p| p.pid)
+}
+
+/// Start running process `pid` at `entry`, with its stack pointer at `sp`
+pub fn start(pid: Pid, entry: usize, sp: usize) -> Result<JoinHandle, &'static str> {
+    if !USER_SPACE.contains(&entry) || !(USER_SPACE.start..=USER_SPACE.end).contains(&sp) {
+        return Err("Entry point or stack is outside of user space");
+    }
+    let mut processes = PROCESSES.lock();
+    let process = processes
+        .iter_mut()
+        .flatten()
+        .find(|p| p.pid == pid)
+        .ok_or("No such process")?;
+    if process.thread.is_some() {
+        return Err("Process is already running");
+    }
+    process.entry = entry;
+    process.sp = sp;
+    // The thread can't look itself up until we let go of the lock, by which point it's recorded
+    let handle = thread::spawn_with_ttbr0(user_main, process.space.ttbr0())?;
+    process.thread = Some(handle.thread());
+    Ok(handle)
+}
+
+fn user_main() {
+    let (pid, entry, sp) =
+        with_current(|p| (p.pid, p.entry, p.sp)).expect("Process thread without a process");
+    info!("Starting process {} at {:#x}", pid, entry);
+    // Safety: Both are inside the process's address space, which this thread is using
+    unsafe { __enter_user(entry, sp) }
+}
+
+/// Kill the current process. It exits the next time it would return to EL0.
+pub fn kill_current() {
+    with_current(|p| p.killed = true);
+}
+
+/// Called by exception handlers just before they return to EL0
+pub fn return_to_user() {
+    if with_current(|p| p.killed) == Some(true) {
+        exit_current(KILLED_STATUS);
+    }
+}
+
+/// End the current process, freeing all of its memory, and its thread
+pub fn exit_current(status: i32) -> ! {
+    let process = thread::try_current().and_then(|me| {
+        PROCESSES
+            .lock()
+            .iter_mut()
+            .find(|slot| slot.as_ref().is_some_and(|p| p.thread == Some(me)))
+            .and_then(Option::take)
+    });
+    // Get off of its translation tables before they're freed
+    thread::set_ttbr0(0);
+    if let Some(mut process) = process {
+        process.files.close_all();
+        if process.clear_child_tid != 0 {
+            // Nobody can be waiting on it yet, but this is what Linux promises
+            let _ = process.space.write_bytes(process.clear_child_tid, &0u32.to_le_bytes());
+        }
+        info!("Process {} exited with status {}", process.pid, status);
+        drop(process);
+    }
+    thread::exit()
+}
@@ -0,0 +1,453 @@
+//! System calls, made with `svc #0` following the Linux aarch64 ABI.
+//!
+//! The number goes in x8 and up to six arguments in x0-x5. The result comes back in x0, with
+//! failures as a negative errno. Only enough of Linux is here for a static binary to start up,
+//! use files, allocate and exit; anything else fails with `ENOSYS`.
+use super::{exit_current, with_current, Process, MMAP_TOP};
+use crate::exceptions::{self, InterruptFrame};
+use crate::fs::{self, FileId, Kind, OpenOptions, SeekFrom};
+use crate::mmu::{UserPerms, PAGE_SIZE, USER_SPACE};
+use log::warn;
+
+mod nr {
+    pub const OPENAT: u64 = 56;
+    pub const CLOSE: u64 = 57;
+    pub const GETDENTS64: u64 = 61;
+    pub const LSEEK: u64 = 62;
+    pub const READ: u64 = 63;
+    pub const WRITE: u64 = 64;
+    pub const WRITEV: u64 = 66;
+    pub const FSTAT: u64 = 80;
+    pub const SYNC: u64 = 81;
+    pub const FSYNC: u64 = 82;
+    pub const EXIT: u64 = 93;
+    pub const EXIT_GROUP: u64 = 94;
+    pub const SET_TID_ADDRESS: u64 = 96;
+    pub const CLOCK_GETTIME: u64 = 113;
+    pub const UNAME: u64 = 160;
+    pub const GETPID: u64 = 172;
+    pub const BRK: u64 = 214;
+    pub const MUNMAP: u64 = 215;
+    pub const MMAP: u64 = 222;
+}
+
+mod errno {
+    pub const ENOMEM: i64 = 12;
+    pub const EFAULT: i64 = 14;
+    pub const EINVAL: i64 = 22;
+    pub const ENAMETOOLONG: i64 = 36;
+    pub const ENOSYS: i64 = 38;
+}
+
+/// `openat` flags. aarch64 uses the same values as 32-bit Arm.
+mod open {
+    pub const ACCESS_MODE: u64 = 3;
+    pub const RDONLY: u64 = 0;
+    pub const WRONLY: u64 = 1;
+    pub const CREAT: u64 = 0o100;
+    pub const EXCL: u64 = 0o200;
+    pub const TRUNC: u64 = 0o1000;
+    pub const APPEND: u64 = 0o2000;
+    pub const DIRECTORY: u64 = 0o40000;
+}
+
+const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Paths passed to `openat` are relative to this, rather than a directory's descriptor
+const AT_FDCWD: i64 = -100;
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+/// How much of a `read` or `write` goes through the kernel at a time
+const IO_CHUNK: usize = 256;
+/// Most entries `writev` takes, like Linux's `UIO_MAXIOV`
+const IOV_MAX: u64 = 1024;
+/// Length of each field of `struct utsname`
+const UTS_LEN: usize = 65;
+
+type SyscallResult = Result<u64, i64>;
+
+/// Handle the `svc` that `frame` was taken for, leaving the result in its x0
+pub fn dispatch(frame: &mut InterruptFrame) {
+    // A system call can take a while, and may sleep, so let interrupts in while it runs. They go
+    // back off before the frame is restored, since an IRQ then would clobber ELR_EL1.
+    exceptions::enable_interrupts();
+    let [a0, a1, a2, a3, a4, a5] = frame.syscall_args();
+    let res = match frame.syscall_number() {
+        nr::OPENAT => openat(a0 as i64, a1 as usize, a2, a3),
+        nr::CLOSE => close(a0),
+        nr::GETDENTS64 => getdents64(a0, a1 as usize, a2 as usize),
+        nr::LSEEK => lseek(a0, a1 as i64, a2),
+        nr::READ => read(a0, a1 as usize, a2 as usize),
+        nr::WRITE => write(a0, a1 as usize, a2 as usize),
+        nr::WRITEV => writev(a0, a1 as usize, a2),
+        nr::FSTAT => fstat(a0, a1 as usize),
+        nr::SYNC => sync(),
+        nr::FSYNC => fsync(a0),
+        nr::EXIT | nr::EXIT_GROUP => exit_current(a0 as i32),
+        nr::SET_TID_ADDRESS => set_tid_address(a0 as usize),
+        nr::CLOCK_GETTIME => clock_gettime(a1 as usize),
+        nr::UNAME => uname(a0 as usize),
+        nr::GETPID => current(|p| Ok(p.pid as u64)),
+        nr::BRK => brk(a0 as usize),
+        nr::MUNMAP => munmap(a0 as usize, a1 as usize),
+        nr::MMAP => mmap(a0 as usize, a1 as usize, a2, a3, a4 as i64, a5),
+        number => {
+            warn!(
+                "Process {} made unknown system call {}",
+                super::current_pid().unwrap_or(0),
+                number
+            );
+            Err(errno::ENOSYS)
+        }
+    };
+    frame.set_syscall_return(match res {
+        Ok(value) => value,
+        Err(errno) => (-errno) as u64,
+    });
+    exceptions::disable_interrupts();
+}
+
+/// Run `f` on the calling process
+fn current(f: impl FnOnce(&mut Process) -> SyscallResult) -> SyscallResult {
+    // Only processes make system calls, so this is always there
+    with_current(f).unwrap_or(Err(errno::ENOSYS))
+}
+
+fn read_user(addr: usize, buf: &mut [u8]) -> Result<(), i64> {
+    current(|p| p.space.read_bytes(addr, buf).map(|_| 0).map_err(|_| errno::EFAULT)).map(|_| ())
+}
+
+fn write_user(addr: usize, bytes: &[u8]) -> Result<(), i64> {
+    current(|p| p.space.write_bytes(addr, bytes).map(|_| 0).map_err(|_| errno::EFAULT)).map(|_| ())
+}
+
+/// Copy the NUL terminated string at `addr` into `buf`
+fn read_user_str(mut addr: usize, buf: &mut [u8]) -> Result<&str, i64> {
+    let mut len = 0;
+    while len < buf.len() {
+        // Up to the end of the page, so we don't fault past a string that ends before it
+        let chunk = (PAGE_SIZE - addr % PAGE_SIZE).min(buf.len() - len);
+        read_user(addr, &mut buf[len..len + chunk])?;
+        if let Some(end) = buf[len..len + chunk].iter().position(|&b| b == 0) {
+            return core::str::from_utf8(&buf[..len + end]).map_err(|_| errno::EINVAL);
+        }
+        len += chunk;
+        addr += chunk;
+    }
+    Err(errno::ENAMETOOLONG)
+}
+
+/// The open file behind the calling process's descriptor `fd`
+fn file(fd: u64) -> Result<FileId, i64> {
+    with_current(|p| p.files.get(fd as usize))
+        .ok_or(errno::ENOSYS)?
+        .map_err(fs::Error::errno)
+}
+
+/// There's no current directory, so paths are all relative to `/`
+fn openat(dirfd: i64, path: usize, flags: u64, mode: u64) -> SyscallResult {
+    let mut buf = [0; fs::MAX_PATH + 1];
+    let path = read_user_str(path, &mut buf)?;
+    if dirfd != AT_FDCWD && !path.starts_with('/') {
+        return Err(errno::ENOSYS);
+    }
+    let access = flags & open::ACCESS_MODE;
+    let options = OpenOptions {
+        read: access != open::WRONLY,
+        write: access != open::RDONLY,
+        append: flags & open::APPEND != 0,
+        create: flags & open::CREAT != 0,
+        exclusive: flags & open::EXCL != 0,
+        truncate: flags & open::TRUNC != 0,
+        directory: flags & open::DIRECTORY != 0,
+        mode: mode as u32 & 0o7777,
+    };
+    let file = fs::open(path, options).map_err(fs::Error::errno)?;
+    let fd = with_current(|p| p.files.insert(file)).unwrap_or(Err(fs::Error::BadFile));
+    fd.map(|fd| fd as u64).map_err(|err| {
+        let _ = fs::close(file);
+        err.errno()
+    })
+}
+
+fn close(fd: u64) -> SyscallResult {
+    let file = with_current(|p| p.files.remove(fd as usize))
+        .ok_or(errno::ENOSYS)?
+        .map_err(fs::Error::errno)?;
+    fs::close(file).map_err(fs::Error::errno)?;
+    Ok(0)
+}
+
+fn read(fd: u64, addr: usize, len: usize) -> SyscallResult {
+    let file = file(fd)?;
+    let mut buf = [0; IO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut buf[..(len - done).min(IO_CHUNK)];
+        let read = match fs::read(file, chunk) {
+            Ok(read) => read,
+            Err(err) if done == 0 => return Err(err.errno()),
+            Err(_) => break,
+        };
+        write_user(addr + done, &chunk[..read])?;
+        done += read;
+        // Stop at the end of the file, or when a device has nothing more for now
+        if read < chunk.len() {
+            break;
+        }
+    }
+    Ok(done as u64)
+}
+
+fn write(fd: u64, addr: usize, len: usize) -> SyscallResult {
+    let file = file(fd)?;
+    let mut buf = [0; IO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut buf[..(len - done).min(IO_CHUNK)];
+        read_user(addr + done, chunk)?;
+        // Written outside the process lock, since devices can take a while
+        let written = match fs::write(file, chunk) {
+            Ok(written) => written,
+            Err(err) if done == 0 => return Err(err.errno()),
+            Err(_) => break,
+        };
+        done += written;
+        if written < chunk.len() {
+            break;
+        }
+    }
+    Ok(done as u64)
+}
+
+fn writev(fd: u64, iov: usize, count: u64) -> SyscallResult {
+    if count > IOV_MAX {
+        return Err(errno::EINVAL);
+    }
+    let mut total = 0;
+    for idx in 0..count as usize {
+        // struct iovec { void *iov_base; size_t iov_len; }
+        let mut entry = [0; 16];
+        let written = read_user(iov + idx * entry.len(), &mut entry).and_then(|()| {
+            let base = u64::from_le_bytes(entry[..8].try_into().unwrap()) as usize;
+            let len = u64::from_le_bytes(entry[8..].try_into().unwrap()) as usize;
+            write(fd, base, len).map(|written| (written, len))
+        });
+        // Once some of it is written, that's what we report, like a short write
+        let (written, len) = match written {
+            Ok(written) => written,
+            Err(err) if total == 0 => return Err(err),
+            Err(_) => break,
+        };
+        total += written;
+        if (written as usize) < len {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+fn lseek(fd: u64, offset: i64, whence: u64) -> SyscallResult {
+    let pos = match whence {
+        SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| errno::EINVAL)?),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(errno::EINVAL),
+    };
+    fs::seek(file(fd)?, pos).map(|pos| pos as u64).map_err(fs::Error::errno)
+}
+
+fn fstat(fd: u64, addr: usize) -> SyscallResult {
+    let stat = fs::stat(file(fd)?).map_err(fs::Error::errno)?;
+    let format: u32 = match stat.kind {
+        Kind::File => 0o100000,
+        Kind::Directory => 0o040000,
+        Kind::CharDevice => 0o020000,
+        Kind::Symlink => 0o120000,
+    };
+    // The generic `struct stat` that aarch64 uses. Everything we don't track is left as 0.
+    let mut bytes = [0; 128];
+    bytes[8..16].copy_from_slice(&stat.ino.to_le_bytes());
+    bytes[16..20].copy_from_slice(&(format | stat.mode).to_le_bytes());
+    bytes[20..24].copy_from_slice(&1u32.to_le_bytes());
+    bytes[48..56].copy_from_slice(&(stat.size as u64).to_le_bytes());
+    bytes[56..60].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+    bytes[64..72].copy_from_slice(&(stat.size.div_ceil(512) as u64).to_le_bytes());
+    write_user(addr, &bytes)?;
+    Ok(0)
+}
+
+fn sync() -> SyscallResult {
+    fs::sync().map_err(fs::Error::errno)?;
+    Ok(0)
+}
+
+fn fsync(fd: u64) -> SyscallResult {
+    fs::fsync(file(fd)?).map_err(fs::Error::errno)?;
+    Ok(0)
+}
+
+/// Fill `addr` with as many `struct linux_dirent64`s as fit in `len` bytes
+fn getdents64(fd: u64, addr: usize, len: usize) -> SyscallResult {
+    // d_ino, d_off, d_reclen and d_type come before the name
+    const HEADER: usize = 19;
+    let file = file(fd)?;
+    let mut done = 0;
+    while let Some(entry) = fs::read_dir(file).map_err(fs::Error::errno)? {
+        let name = entry.name.as_bytes();
+        let size = (HEADER + name.len() + 1).next_multiple_of(8);
+        if done + size > len {
+            if done == 0 {
+                return Err(errno::EINVAL);
+            }
+            break;
+        }
+        let next = fs::seek(file, SeekFrom::Current(0)).map_err(fs::Error::errno)? + 1;
+        let kind: u8 = match entry.kind {
+            Kind::File => 8,
+            Kind::Directory => 4,
+            Kind::CharDevice => 2,
+            Kind::Symlink => 10,
+        };
+        let mut record = [0; (HEADER + fs::MAX_NAME + 1).next_multiple_of(8)];
+        record[..8].copy_from_slice(&entry.ino.to_le_bytes());
+        record[8..16].copy_from_slice(&(next as u64).to_le_bytes());
+        record[16..18].copy_from_slice(&(size as u16).to_le_bytes());
+        record[18] = kind;
+        record[HEADER..HEADER + name.len()].copy_from_slice(name);
+        write_user(addr + done, &record[..size])?;
+        fs::seek(file, SeekFrom::Start(next as u64)).map_err(fs::Error::errno)?;
+        done += size;
+    }
+    Ok(done as u64)
+}
+
+fn set_tid_address(addr: usize) -> SyscallResult {
+    current(|p| {
+        p.clear_child_tid = addr;
+        Ok(p.pid as u64)
+    })
+}
+
+fn clock_gettime(timespec: usize) -> SyscallResult {
+    // Every clock is time since boot
+    let micros = crate::time::uptime_microsec();
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&(micros / 1_000_000).to_le_bytes());
+    bytes[8..].copy_from_slice(&(micros % 1_000_000 * 1000).to_le_bytes());
+    write_user(timespec, &bytes)?;
+    Ok(0)
+}
+
+fn uname(utsname: usize) -> SyscallResult {
+    let fields: [&str; 6] = [
+        "os_experiments",
+        "raspberrypi",
+        env!("CARGO_PKG_VERSION"),
+        "",
+        "aarch64",
+        "",
+    ];
+    let mut bytes = [0; UTS_LEN * 6];
+    for (field, value) in bytes.chunks_mut(UTS_LEN).zip(fields) {
+        let len = value.len().min(UTS_LEN - 1);
+        field[..len].copy_from_slice(&value.as_bytes()[..len]);
+    }
+    write_user(utsname, &bytes)?;
+    Ok(0)
+}
+
+/// Move the end of the heap to `addr`. Returns where it ends up, which is where it was if it
+/// can't move. `brk(0)` asks where that is.
+fn brk(addr: usize) -> SyscallResult {
+    current(|p| {
+        if addr < p.brk_start || addr >= p.mmap_next {
+            return Ok(p.brk as u64);
+        }
+        let old_end = p.brk.next_multiple_of(PAGE_SIZE);
+        let new_end = addr.next_multiple_of(PAGE_SIZE);
+        if new_end > old_end {
+            for page in (old_end..new_end).step_by(PAGE_SIZE) {
+                if p.space.map_new(page, UserPerms::READ_WRITE).is_err() {
+                    for page in (old_end..page).step_by(PAGE_SIZE) {
+                        let _ = p.space.unmap(page);
+                    }
+                    return Ok(p.brk as u64);
+                }
+            }
+        } else {
+            for page in (new_end..old_end).step_by(PAGE_SIZE) {
+                let _ = p.space.unmap(page);
+            }
+        }
+        p.brk = addr;
+        Ok(addr as u64)
+    })
+}
+
+/// Only anonymous private mappings. Without `MAP_FIXED` they're stacked downwards below the user
+/// stack, and never reused.
+fn mmap(addr: usize, len: usize, prot: u64, flags: u64, fd: i64, offset: u64) -> SyscallResult {
+    if flags & MAP_ANONYMOUS == 0 || (fd != -1 && fd != 0) || offset != 0 {
+        return Err(errno::ENOSYS);
+    }
+    if len == 0 || addr % PAGE_SIZE != 0 {
+        return Err(errno::EINVAL);
+    }
+    let len = len.checked_next_multiple_of(PAGE_SIZE).ok_or(errno::ENOMEM)?;
+    let perms = UserPerms {
+        read: prot & PROT_READ != 0,
+        write: prot & PROT_WRITE != 0,
+        execute: prot & PROT_EXEC != 0,
+    };
+    current(|p| {
+        let old_next = p.mmap_next;
+        let start = if flags & MAP_FIXED != 0 {
+            let end = addr.checked_add(len).ok_or(errno::ENOMEM)?;
+            if addr < USER_SPACE.start || end > MMAP_TOP {
+                return Err(errno::ENOMEM);
+            }
+            addr
+        } else {
+            let start = p.mmap_next.checked_sub(len).ok_or(errno::ENOMEM)?;
+            if start < p.brk.next_multiple_of(PAGE_SIZE) {
+                return Err(errno::ENOMEM);
+            }
+            p.mmap_next = start;
+            start
+        };
+        for page in (start..start + len).step_by(PAGE_SIZE) {
+            // Fresh pages, so the mapping comes out zeroed even over an old one
+            if p.space.map_new(page, perms).is_err() {
+                // Don't leave half a mapping behind
+                for mapped in (start..page).step_by(PAGE_SIZE) {
+                    let _ = p.space.unmap(mapped);
+                }
+                p.mmap_next = old_next;
+                return Err(errno::ENOMEM);
+            }
+        }
+        Ok(start as u64)
+    })
+}
+
+fn munmap(addr: usize, len: usize) -> SyscallResult {
+    if addr % PAGE_SIZE != 0 || len == 0 {
+        return Err(errno::EINVAL);
+    }
+    let end = addr.checked_add(len).ok_or(errno::EINVAL)?.next_multiple_of(PAGE_SIZE);
+    if addr < USER_SPACE.start || end > USER_SPACE.end {
+        return Err(errno::EINVAL);
+    }
+    current(|p| {
+        for page in (addr..end).step_by(PAGE_SIZE) {
+            let _ = p.space.unmap(page);
+        }
+        Ok(0)
+    })
+}
@@ -0,0 +1,170 @@
+#!/usr/bin/env python3
+"""Writes the device tree the FDT parser's tests use. Run it from this directory.
+
+It's made by hand rather than with dtc, so that it stays small and every value the tests look at
+is known. In dts, it's:
+
+    /memreserve/ 0x1000 0x2000;
+    / {
+        #address-cells = <1>;
+        #size-cells = <1>;
+        model = "Test Pi";
+
+        memory@0 { device_type = "memory"; reg = <0x0 0x3c000000>; };
+        memory@40000000 { device_type = "memory"; reg = <0x40000000 0x10000000>; };
+
+        chosen {
+            bootargs = "console=serial0 init=/bin/sh";
+            linux,initrd-start = <0x2000000>;
+            linux,initrd-end = <0x2100000>;
+        };
+
+        soc {
+            compatible = "simple-bus";
+            #address-cells = <1>;
+            #size-cells = <1>;
+            ranges = <0x7e000000 0x3f000000 0x1000000>;
+
+            serial@7e215040 {
+                compatible = "brcm,bcm2835-aux-uart";
+                reg = <0x7e215040 0x40>;
+                interrupts = <1 29>;
+                clocks = <&clk 1>;
+            };
+            serial@7e201000 {
+                compatible = "arm,pl011", "arm,primecell";
+                reg = <0x7e201000 0x200>;
+                status = "disabled";
+            };
+            clk: clock { phandle = <1>; clock-frequency = <250000000>; };
+            /* Outside of the soc's ranges */
+            unmapped@1000 { reg = <0x1000 0x10>; };
+        };
+
+        /* No ranges, so its children aren't on the CPU's bus */
+        gpu {
+            #address-cells = <1>;
+            #size-cells = <1>;
+            dev@10 { reg = <0x10 0x10>; };
+        };
+    };
+"""
+import struct
+
+MAGIC = 0xD00DFEED
+FDT_BEGIN_NODE, FDT_END_NODE, FDT_PROP, FDT_END = 1, 2, 3, 9
+
+strings = bytearray()
+structs = bytearray()
+
+
+def pad():
+    while len(structs) % 4:
+        structs.append(0)
+
+
+def string(name):
+    key = name.encode() + b"\0"
+    offset = strings.find(key)
+    if offset < 0 or (offset > 0 and strings[offset - 1] != 0):
+        offset = len(strings)
+        strings.extend(key)
+    return offset
+
+
+def cells(*values):
+    return struct.pack(">%dI" % len(values), *values)
+
+
+def strs(*values):
+    return b"".join(v.encode() + b"\0" for v in values)
+
+
+def prop(name, value):
+    structs.extend(struct.pack(">III", FDT_PROP, len(value), string(name)))
+    structs.extend(value)
+    pad()
+
+
+def node(name, props=(), children=()):
+    structs.extend(struct.pack(">I", FDT_BEGIN_NODE))
+    structs.extend(name.encode() + b"\0")
+    pad()
+    for name, value in props:
+        prop(name, value)
+    for child in children:
+        child()
+    structs.extend(struct.pack(">I", FDT_END_NODE))
+
+
+def tree():
+    node("", [
+        ("#address-cells", cells(1)),
+        ("#size-cells", cells(1)),
+        ("model", strs("Test Pi")),
+    ], [
+        lambda: node("memory@0", [
+            ("device_type", strs("memory")),
+            ("reg", cells(0, 0x3C000000)),
+        ]),
+        lambda: node("memory@40000000", [
+            ("device_type", strs("memory")),
+            ("reg", cells(0x40000000, 0x10000000)),
+        ]),
+        lambda: node("chosen", [
+            ("bootargs", strs("console=serial0 init=/bin/sh")),
+            ("linux,initrd-start", cells(0x2000000)),
+            ("linux,initrd-end", cells(0x2100000)),
+        ]),
+        lambda: node("soc", [
+            ("compatible", strs("simple-bus")),
+            ("#address-cells", cells(1)),
+            ("#size-cells", cells(1)),
+            ("ranges", cells(0x7E000000, 0x3F000000, 0x1000000)),
+        ], [
+            lambda: node("serial@7e215040", [
+                ("compatible", strs("brcm,bcm2835-aux-uart")),
+                ("reg", cells(0x7E215040, 0x40)),
+                ("interrupts", cells(1, 29)),
+                ("clocks", cells(1, 1)),
+            ]),
+            lambda: node("serial@7e201000", [
+                ("compatible", strs("arm,pl011", "arm,primecell")),
+                ("reg", cells(0x7E201000, 0x200)),
+                ("status", strs("disabled")),
+            ]),
+            lambda: node("clock", [
+                ("phandle", cells(1)),
+                ("clock-frequency", cells(250000000)),
+            ]),
+            lambda: node("unmapped@1000", [("reg", cells(0x1000, 0x10))]),
+        ]),
+        lambda: node("gpu", [
+            ("#address-cells", cells(1)),
+            ("#size-cells", cells(1)),
+        ], [
+            lambda: node("dev@10", [("reg", cells(0x10, 0x10))]),
+        ]),
+    ])
+    structs.extend(struct.pack(">I", FDT_END))
+
+
+def blob():
+    tree()
+    header_size = 40
+    reservations = struct.pack(">QQQQ", 0x1000, 0x2000, 0, 0)
+    off_rsvmap = header_size
+    off_struct = off_rsvmap + len(reservations)
+    off_strings = off_struct + len(structs)
+    total = off_strings + len(strings)
+    header = struct.pack(
+        ">10I",
+        MAGIC, total, off_struct, off_strings, off_rsvmap,
+        17, 16, 0, len(strings), len(structs),
+    )
+    return header + reservations + bytes(structs) + bytes(strings)
+
+
+if __name__ == "__main__":
+    with open("tree.dtb", "wb") as f:
+        f.write(blob())
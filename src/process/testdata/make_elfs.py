@@ -0,0 +1,88 @@
+#!/usr/bin/env python3
+"""Writes the ELF files the loader's tests use. Run it from this directory.
+
+Each program just calls exit(0). They're made by hand rather than by a toolchain so that every
+field the tests look at is known, and so that they're tiny.
+"""
+import struct
+
+# mov x0, #0; mov x8, #93 (exit); svc #0
+CODE = struct.pack("<3I", 0xD2800000, 0xD2800BA8, 0xD4000001)
+
+ET_EXEC, ET_DYN = 2, 3
+EM_AARCH64, EM_X86_64 = 183, 62
+PT_LOAD, PT_PHDR = 1, 6
+PF_X, PF_W, PF_R = 1, 2, 4
+HEADER_SIZE, PHDR_SIZE = 64, 56
+
+
+def header(kind, entry, phnum, machine=EM_AARCH64):
+    ident = b"\x7fELF" + bytes([2, 1, 1]) + bytes(9)
+    return ident + struct.pack(
+        "<HHIQQQIHHHHHH",
+        kind, machine, 1, entry, HEADER_SIZE, 0, 0,
+        HEADER_SIZE, PHDR_SIZE, phnum, 0, 0, 0,
+    )
+
+
+def phdr(kind, flags, offset, vaddr, file_size, mem_size):
+    return struct.pack("<IIQQQQQQ", kind, flags, offset, vaddr, vaddr, file_size, mem_size, 0x1000)
+
+
+def program(kind, base, phdrs_for):
+    """`phdrs_for(code_offset)` gives the program headers, once we know where the code goes"""
+    count = len(phdrs_for(0))
+    code_offset = HEADER_SIZE + count * PHDR_SIZE
+    phdrs = phdrs_for(code_offset)
+    image = header(kind, base + code_offset, count) + b"".join(phdrs) + CODE
+    # Some initialised data right after the code
+    return image + struct.pack("<Q", 0x1122334455667788)
+
+
+EXEC_BASE = 0x1_0001_0000
+
+
+def exec_phdrs(code_offset):
+    end = code_offset + len(CODE)
+    return [
+        phdr(PT_PHDR, PF_R, HEADER_SIZE, EXEC_BASE + HEADER_SIZE, 3 * PHDR_SIZE, 3 * PHDR_SIZE),
+        phdr(PT_LOAD, PF_R | PF_X, 0, EXEC_BASE, end, end),
+        # The data and a page and a bit of bss, on the pages after the code
+        phdr(PT_LOAD, PF_R | PF_W, end, EXEC_BASE + 0x1000 + end, 8, 0x1800),
+    ]
+
+
+def dyn_phdrs(code_offset):
+    end = code_offset + len(CODE)
+    return [
+        phdr(PT_LOAD, PF_R | PF_X, 0, 0, end, end),
+        # Shares the code's page, and runs onto the next one
+        phdr(PT_LOAD, PF_R | PF_W, end, 0xF80, 8, 0x100),
+    ]
+
+
+def low_exec_phdrs(code_offset):
+    end = code_offset + len(CODE)
+    return [phdr(PT_LOAD, PF_R | PF_X, 0, 0x40_0000, end, end)]
+
+
+def write(name, data):
+    with open(name, "wb") as f:
+        f.write(data)
+
+
+exec_elf = program(ET_EXEC, EXEC_BASE, exec_phdrs)
+write("exec.elf", exec_elf)
+write("dyn.elf", program(ET_DYN, 0, dyn_phdrs))
+# Linked at the usual 0x400000, without user.ld
+write("low_exec.elf", program(ET_EXEC, 0x40_0000, low_exec_phdrs))
+
+write("bad_magic.elf", b"\x7fELG" + exec_elf[4:])
+write("wrong_machine.elf", exec_elf[:18] + struct.pack("<H", EM_X86_64) + exec_elf[20:])
+# Says it has 3 program headers, but the file ends after the first
+write("phdrs_past_eof.elf", exec_elf[:HEADER_SIZE + PHDR_SIZE])
+# The data segment claims more of the file than it has room for in memory
+bigger = bytearray(exec_elf)
+data_phdr = HEADER_SIZE + 2 * PHDR_SIZE
+struct.pack_into("<QQ", bigger, data_phdr + 32, 0x10, 0x8)
+write("file_bigger_than_mem.elf", bytes(bigger))
@@ -0,0 +1,117 @@
+//! Bringing up the other cores.
+//!
+//! The firmware (and QEMU) parks cores 1-3 in a loop that waits for an address to show up in
+//! their slot of the spin table, then jumps to it. We hand each one a stack, write
+//! `_start_secondary` into its slot and wake it with `sev`. From there it drops to EL1, installs
+//! the exception vectors and turns on the MMU the same way the boot core did.
+use aarch64_cpu::{asm, registers::MPIDR_EL1};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{info, warn};
+use tock_registers::interfaces::Readable;
+
+pub const MAX_CPUS: usize = 4;
+const STACK_SIZE: usize = 64 * 1024;
+/// Where core N (for N = 1..=3) looks for its entry point
+/// https://github.com/raspberrypi/tools/blob/master/armstubs/armstub8.S
+const SPIN_TABLE: [usize; MAX_CPUS - 1] = [0xE0, 0xE8, 0xF0];
+/// How long a core gets to check in before we give up on it
+const START_TIMEOUT_MICROSEC: u64 = 1_000_000;
+
+/// Which core we are running on
+pub fn cpu_id() -> usize {
+    (MPIDR_EL1.get() & 0xFF) as usize
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+// The boot core uses the stack below the kernel image, so it doesn't need one
+static mut STACKS: [Stack; MAX_CPUS - 1] = [const { Stack([0; STACK_SIZE]) }; MAX_CPUS - 1];
+
+/// Stack for the core being started. `_start_secondary` picks it up.
+/// Cores are started one at a time, so they never see each other's.
+#[no_mangle]
+static SECONDARY_STACK_TOP: AtomicUsize = AtomicUsize::new(0);
+
+/// Cores that are up and running kernel code, including the boot core
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+pub fn cpus_online() -> usize {
+    CPUS_ONLINE.load(Ordering::Acquire)
+}
+
+extern "C" {
+    fn _start_secondary();
+}
+
+/// Write a cache line out to RAM, so a core with its MMU (and caches) off can see it
+fn clean_dcache_line(addr: usize) {
+    // Safety: Cleaning the cache doesn't change memory
+    unsafe { core::arch::asm!("dc civac, {}", in(reg) addr) };
+}
+
+/// Start cores 1-3. Returns once they are all running (or have timed out).
+pub fn start_secondary_cores() {
+    for (idx, &slot) in SPIN_TABLE.iter().enumerate() {
+        let cpu = idx + 1;
+        let online = cpus_online();
+
+        // Safety: This core is still parked, so nobody else is using its stack
+        let stack_top = unsafe { core::ptr::addr_of!(STACKS[idx]) as usize + STACK_SIZE };
+        SECONDARY_STACK_TOP.store(stack_top, Ordering::Release);
+        clean_dcache_line(SECONDARY_STACK_TOP.as_ptr() as usize);
+
+        // Safety: The spin table is reserved for exactly this
+        unsafe { (slot as *mut u64).write_volatile(_start_secondary as usize as u64) };
+        clean_dcache_line(slot);
+        asm::barrier::dsb(asm::barrier::SY);
+        asm::sev();
+
+        let start = crate::time::uptime_microsec();
+        while cpus_online() == online {
+            if crate::time::uptime_microsec() - start > START_TIMEOUT_MICROSEC {
+                warn!("cpu{} did not start", cpu);
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    info!("{} cpus online", cpus_online());
+}
+
+/// Where `_start_secondary` jumps, still at EL2
+#[no_mangle]
+unsafe extern "C" fn __start_secondary_kernel() -> ! {
+    // The MMU is off, so read this like the core that wrote it didn't have a cache either.
+    let stack_top = (SECONDARY_STACK_TOP.as_ptr() as *const usize).read_volatile();
+    crate::prep_transition_el2_to_el1(secondary_kernel_init, stack_top as u64);
+    asm::eret()
+}
+
+extern "C" fn secondary_kernel_init() -> ! {
+    unsafe {
+        crate::exceptions::init();
+    }
+    crate::mmu::init_secondary();
+    crate::ipi::init_core();
+    CPUS_ONLINE.fetch_add(1, Ordering::AcqRel);
+    info!("cpu{} online", cpu_id());
+
+    if let Err(err) = crate::thread::init_core() {
+        warn!("cpu{} can't run threads: {}", cpu_id(), err);
+        idle()
+    }
+    // Nothing left to do on the boot stack. The core's idle thread takes over until it's handed
+    // some threads.
+    crate::thread::exit()
+}
+
+/// What secondary cores do when they have nothing to do
+fn idle() -> ! {
+    loop {
+        if crate::panic::stop_requested() {
+            crate::panic::halt();
+        }
+        asm::wfe();
+    }
+}
@@ -0,0 +1,140 @@
+//! A bounded channel with any number of senders and one receiver.
+use super::{IrqSafeMutex, WaitQueue};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Holds up to `N` values. Senders park while it's full and the receiver parks while it's empty.
+/// Interrupt handlers can send with `try_send`.
+///
+/// There's no heap, so the channel lives somewhere else (usually a `static`) and the ends
+/// borrow it.
+pub struct Channel<T, const N: usize> {
+    queue: IrqSafeMutex<Ring<T, N>>,
+    not_empty: WaitQueue,
+    not_full: WaitQueue,
+    receiver_taken: AtomicBool,
+}
+
+struct Ring<T, const N: usize> {
+    slots: [Option<T>; N],
+    /// The oldest value
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> Ring<T, N> {
+    fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == N {
+            return Err(value);
+        }
+        self.slots[(self.head + self.len) % N] = Some(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let value = self.slots[self.head].take()?;
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Self {
+        assert!(N > 0, "A channel needs room for at least one value");
+        Channel {
+            queue: IrqSafeMutex::new(Ring {
+                slots: [const { None }; N],
+                head: 0,
+                len: 0,
+            }),
+            not_empty: WaitQueue::new(),
+            not_full: WaitQueue::new(),
+            receiver_taken: AtomicBool::new(false),
+        }
+    }
+
+    pub fn sender(&self) -> Sender<'_, T, N> {
+        Sender { channel: self }
+    }
+
+    /// The receiving end. There's only one, so this is `None` after the first call.
+    pub fn receiver(&self) -> Option<Receiver<'_, T, N>> {
+        (!self.receiver_taken.swap(true, Ordering::AcqRel)).then(|| Receiver { channel: self })
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Sender<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+impl<T, const N: usize> Clone for Sender<'_, T, N> {
+    fn clone(&self) -> Self {
+        Sender { channel: self.channel }
+    }
+}
+
+impl<T, const N: usize> Sender<'_, T, N> {
+    /// Send `value`, parking while the channel is full
+    pub fn send(&self, value: T) {
+        let mut value = Some(value);
+        self.channel.not_full.wait_until(|| {
+            let Some(v) = value.take() else {
+                return true;
+            };
+            match self.channel.queue.lock().push(v) {
+                Ok(()) => true,
+                Err(v) => {
+                    value = Some(v);
+                    false
+                }
+            }
+        });
+        self.channel.not_empty.wake_one();
+    }
+
+    /// Send `value` if there's room, or hand it back. Safe to call from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        self.channel.queue.lock().push(value)?;
+        self.channel.not_empty.wake_one();
+        Ok(())
+    }
+}
+
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+impl<T, const N: usize> Receiver<'_, T, N> {
+    /// The oldest value, parking until there is one
+    pub fn recv(&mut self) -> T {
+        let mut value = None;
+        self.channel.not_empty.wait_until(|| {
+            value = self.channel.queue.lock().pop();
+            value.is_some()
+        });
+        self.channel.not_full.wake_one();
+        value.expect("Woke without a value")
+    }
+
+    /// The oldest value, if there is one
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.channel.queue.lock().pop()?;
+        self.channel.not_full.wake_one();
+        Some(value)
+    }
+}
@@ -0,0 +1,61 @@
+//! Condition variables.
+use super::{MutexGuard, WaitQueue};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Lets threads holding a `Mutex` sleep until another thread says something changed.
+/// Waits can end for no reason, so always check the condition again, or use `wait_while`.
+pub struct Condvar {
+    /// Bumped by every notify, so a waiter can tell one happened after it let go of the lock
+    generation: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock `guard`, wait for a notify, and lock it again
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        // Read while still holding the lock, so a notify after we let go isn't missed
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters
+            .wait_until(|| self.generation.load(Ordering::Acquire) != generation);
+        mutex.lock()
+    }
+
+    /// Wait until `condition` returns false
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake one waiting thread. Safe to call from interrupt handlers.
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wake every waiting thread. Safe to call from interrupt handlers.
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
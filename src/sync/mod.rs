@@ -0,0 +1,314 @@
+//! Locks and per-core data.
+//!
+//! `spin::Mutex` leaves IRQs unmasked, so an interrupt handler that wants a lock the code it
+//! interrupted is holding spins forever. `IrqSafeMutex` masks IRQs for as long as it's held.
+//! Underneath it is a `TicketLock`, which hands the lock out in the order cores asked for it so
+//! none of them gets starved.
+//!
+//! Those all spin. Threads that may have to wait a while should use the ones that park instead:
+//! `Mutex`, `Semaphore`, `Condvar` and `Channel`, all built on `WaitQueue`.
+use crate::smp::{cpu_id, MAX_CPUS};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+mod channel;
+mod condvar;
+mod mutex;
+mod semaphore;
+mod wait_queue;
+pub use channel::{Channel, Receiver, Sender};
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
+
+/// One `T` for each core, indexed by the core ID in `MPIDR_EL1`. Each core only ever gets its
+/// own, so `T` doesn't need to be `Sync`.
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+// Safety: A core can only reach its own slot, unless `T` is `Sync`
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        PerCpu { slots }
+    }
+
+    /// The current core's value
+    pub fn get(&self) -> &T {
+        &self.slots[cpu_id()]
+    }
+
+    /// Another core's value
+    pub fn for_cpu(&self, cpu: usize) -> &T
+    where
+        T: Sync,
+    {
+        &self.slots[cpu]
+    }
+
+    /// Every core's value, in order of core ID
+    pub fn iter(&self) -> impl Iterator<Item = &T>
+    where
+        T: Sync,
+    {
+        self.slots.iter()
+    }
+}
+
+/// A spinlock that's handed out first come, first served
+pub struct TicketLock<T: ?Sized> {
+    /// The ticket the next core to ask gets
+    next: AtomicU32,
+    /// The ticket that holds the lock
+    serving: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+// Safety: The lock makes sure only one core has the data at a time
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+
+pub struct TicketLockGuard<'a, T: ?Sized> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(data: T) -> Self {
+        TicketLock {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// The data, without taking the lock
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            // IRQs are usually masked here, and the holder may be waiting for us to ack a TLB
+            // shootdown
+            crate::ipi::handle_urgent();
+            core::hint::spin_loop();
+        }
+        TicketLockGuard { lock: self }
+    }
+
+    /// Takes the lock if nobody has it or is waiting for it
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| TicketLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+
+    /// Frees the lock, skipping everyone that was waiting for it
+    ///
+    /// # Safety
+    ///
+    /// The holder and anyone waiting must never run again
+    pub unsafe fn force_unlock(&self) {
+        self.serving.store(self.next.load(Ordering::Relaxed), Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: We hold the lock
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: We hold the lock
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TicketLockGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Where each `IrqSafeMutex` sits in the lock order. A core holding a lock may only take locks
+/// of a higher rank. Unranked (0) locks aren't checked.
+pub mod rank {
+    pub const PROCESSES: u8 = 1;
+    pub const MMU: u8 = 2;
+    pub const FRAMES: u8 = 3;
+    pub const ASIDS: u8 = 4;
+    pub const MAILBOX: u8 = 5;
+    pub const INPUT: u8 = 6;
+    // The console devices. Printing while holding any of the above is fine.
+    pub const FRAMEBUFFER: u8 = 7;
+    pub const PL011: u8 = 8;
+    pub const UART: u8 = 9;
+    pub const RING: u8 = 10;
+    // Taken by anything that blocks or wakes a thread, so it goes last
+    pub const SCHEDULER: u8 = 11;
+}
+
+/// A lock that masks IRQs on the core holding it, so interrupt handlers can take it too.
+/// Guards put back the IRQ mask they found, so drop them in the reverse order they were taken.
+pub struct IrqSafeMutex<T: ?Sized> {
+    rank: u8,
+    lock: TicketLock<T>,
+}
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<TicketLockGuard<'a, T>>,
+    /// `DAIF` from before we took the lock
+    saved_daif: u64,
+    rank: u8,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self::ranked(0, data)
+    }
+
+    /// A lock with a place in the lock order. See `rank`.
+    pub const fn ranked(rank: u8, data: T) -> Self {
+        assert!(rank < u32::BITS as u8);
+        IrqSafeMutex {
+            rank,
+            lock: TicketLock::new(data),
+        }
+    }
+
+    /// The data, without taking the lock
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.lock.as_mut_ptr()
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let saved_daif = crate::exceptions::mask_interrupts();
+        order::acquire(self.rank);
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.lock.lock()),
+            saved_daif,
+            rank: self.rank,
+        }
+    }
+
+    /// Takes the lock if it's free. Can't deadlock, so it's left out of the lock order.
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let saved_daif = crate::exceptions::mask_interrupts();
+        match self.lock.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                saved_daif,
+                rank: 0,
+            }),
+            None => {
+                crate::exceptions::restore_interrupts(saved_daif);
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+
+    /// See `TicketLock::force_unlock`
+    ///
+    /// # Safety
+    ///
+    /// The holder and anyone waiting must never run again
+    pub unsafe fn force_unlock(&self) {
+        self.lock.force_unlock();
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: Never used again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        order::release(self.rank);
+        crate::exceptions::restore_interrupts(self.saved_daif);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSafeMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Lock order checking. Debug builds only.
+#[cfg(debug_assertions)]
+mod order {
+    use super::PerCpu;
+    use crate::smp::MAX_CPUS;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    /// One bit per rank held by each core
+    static HELD: PerCpu<AtomicU32> = PerCpu::new([const { AtomicU32::new(0) }; MAX_CPUS]);
+
+    pub fn acquire(rank: u8) {
+        if rank == 0 {
+            return;
+        }
+        let held = HELD.get().load(Ordering::Relaxed);
+        if held >> rank != 0 {
+            let highest = u32::BITS - 1 - held.leading_zeros();
+            panic!("Took a lock of rank {} while holding one of rank {}", rank, highest);
+        }
+        HELD.get().fetch_or(1 << rank, Ordering::Relaxed);
+    }
+
+    pub fn release(rank: u8) {
+        if rank != 0 {
+            HELD.get().fetch_and(!(1 << rank), Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod order {
+    #[inline(always)]
+    pub fn acquire(_rank: u8) {}
+    #[inline(always)]
+    pub fn release(_rank: u8) {}
+}
@@ -0,0 +1,40 @@
+//! A counting semaphore.
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Hands out up to `permits` at once. Threads asking for more park until one is released.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take a permit, parking until one is free
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Take a permit if one is free
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| permits.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Give back a permit. Safe to call from interrupt handlers, so an IRQ can signal a thread.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,116 @@
+//! Threads waiting for something to happen.
+use super::IrqSafeMutex;
+use crate::thread::{self, ThreadId, MAX_THREADS};
+use arrayvec::ArrayVec;
+use core::time::Duration;
+
+/// A line of parked threads. Whoever makes the condition they're waiting on true wakes them.
+pub struct WaitQueue {
+    /// Oldest first
+    waiters: IrqSafeMutex<ArrayVec<ThreadId, MAX_THREADS>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSafeMutex::new(ArrayVec::new_const()),
+        }
+    }
+
+    /// Park until `cond` returns true. `cond` is checked again every time we're woken.
+    /// Before the scheduler is running this spins instead.
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        if cond() {
+            return;
+        }
+        let Some(me) = thread::try_current() else {
+            while !cond() {
+                core::hint::spin_loop();
+            }
+            return;
+        };
+        loop {
+            self.waiters.lock().push(me);
+            // A wake between here and `park` leaves the thread unparked, so `park` won't sleep
+            if cond() {
+                self.remove(me);
+                return;
+            }
+            thread::park();
+            self.remove(me);
+            if cond() {
+                return;
+            }
+        }
+    }
+
+    /// Like `wait_until`, but give up after `timeout`. Returns whether `cond` became true.
+    /// Without a wake, the deadline is only noticed on the next scheduler tick.
+    pub fn wait_until_timeout(&self, mut cond: impl FnMut() -> bool, timeout: Duration) -> bool {
+        let until = crate::time::uptime_microsec().saturating_add(timeout.as_micros() as u64);
+        let expired = || crate::time::uptime_microsec() >= until;
+        if cond() {
+            return true;
+        }
+        let Some(me) = thread::try_current() else {
+            while !cond() {
+                if expired() {
+                    return false;
+                }
+                core::hint::spin_loop();
+            }
+            return true;
+        };
+        loop {
+            self.waiters.lock().push(me);
+            if cond() {
+                self.remove(me);
+                return true;
+            }
+            thread::park_until(until);
+            self.remove(me);
+            if cond() {
+                return true;
+            }
+            if expired() {
+                return false;
+            }
+        }
+    }
+
+    fn remove(&self, thread: ThreadId) {
+        self.waiters.lock().retain(|&mut waiter| waiter != thread);
+    }
+
+    /// Wake the thread that has waited longest. Returns `false` if nobody was waiting.
+    /// Safe to call from interrupt handlers.
+    pub fn wake_one(&self) -> bool {
+        let waiter = {
+            let mut waiters = self.waiters.lock();
+            (!waiters.is_empty()).then(|| waiters.remove(0))
+        };
+        match waiter {
+            Some(waiter) => {
+                thread::unpark(waiter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake every waiting thread. Returns how many there were.
+    /// Safe to call from interrupt handlers.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for &waiter in &waiters {
+            thread::unpark(waiter);
+        }
+        waiters.len()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,43 +1,65 @@
-use crate::{ serial_print, serial_println };
+//! Runs the `#[test_case]`s, for `cargo test` (or `make test`).
+//!
+//! The tests run inside the kernel under QEMU, once everything has been brought up, so they can
+//! use the frame allocator, processes and block devices like the rest of the kernel does.
+//! `test=name` on the command line only runs the ones whose names contain `name`, which
+//! `qemu_runner.sh` sets from whatever comes after `cargo test --`.
+//!
+//! QEMU is exited through semihosting, so its exit status is the result. A test fails by
+//! panicking, which the panic handler turns into a failed exit after reporting it.
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
+    Success = 0,
+    Failed = 1,
 }
 
+/// Semihosting `SYS_EXIT`, and the reason that makes QEMU exit with a status
+const SYS_EXIT: u64 = 0x18;
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x2_0026;
 
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
+/// Stop QEMU, which exits with `exit_code`. Needs `-semihosting`; without it, this is a debug
+/// exception.
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    let block = [ADP_STOPPED_APPLICATION_EXIT, exit_code as u64];
+    // Safety: Only reads `block`
     unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
+        core::arch::asm!(
+            "hlt #0xf000",
+            in("x0") SYS_EXIT,
+            in("x1") block.as_ptr(),
+        );
     }
+    crate::panic::halt()
 }
 
 pub trait Testable {
-    fn run(&self) -> ();
+    fn name(&self) -> &'static str;
+    fn run(&self);
 }
 
 impl<T> Testable for T
 where
     T: Fn(),
 {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        print!("{}...\t", self.name());
         self();
-        serial_println!("[ok]");
+        println!("[ok]");
     }
 }
 
-#[cfg(test)]
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
+    let filter = crate::cmdline::test_filter().unwrap_or("");
+    let selected = || tests.iter().filter(|test| test.name().contains(filter));
+    println!("Running {} of {} tests", selected().count(), tests.len());
+    for test in selected() {
         test.run();
     }
     exit_qemu(QemuExitCode::Success);
 }
-
@@ -0,0 +1,505 @@
+//! Kernel threads and the scheduler.
+//!
+//! Every core has its own round-robin run queue, and threads stay on the core they were spawned
+//! on. The generic timer ticks `time::TICK_HZ` times a second and each tick moves the running thread to
+//! the back of its queue. A core with nothing to run switches to its idle thread, which waits for
+//! an interrupt.
+//!
+//! There is no heap, so threads live in a fixed table and each slot has its own stack.
+use crate::smp::{cpu_id, cpus_online, MAX_CPUS};
+use crate::sync::{rank, IrqSafeMutex, PerCpu};
+use aarch64_cpu::asm;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+pub const MAX_THREADS: usize = 32;
+const STACK_SIZE: usize = 32 * 1024;
+/// Bytes `__switch_context` keeps on a stack: x19-x30, `TPIDR_EL0` and padding, then q0-q31,
+/// `FPCR` and `FPSR`
+const CONTEXT_SIZE: usize = 14 * 8 + 32 * 16 + 2 * 8;
+
+global_asm!(include_str!("switch.s"));
+
+extern "C" {
+    fn __switch_context(prev_sp: *mut usize, next_sp: usize);
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+/// Stack for the thread in each slot. Slots for threads that were already running when the core
+/// started scheduling (see `init_core`) leave theirs unused.
+static mut STACKS: [Stack; MAX_THREADS] = [const { Stack([0; STACK_SIZE]) }; MAX_THREADS];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ThreadId(usize);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    /// Slot is unused
+    Free,
+    /// Waiting in a run queue
+    Ready,
+    Running,
+    /// Waiting for the uptime to reach `until`, in microseconds
+    Sleeping { until: u64 },
+    /// Waiting for someone to wake it
+    Blocked,
+    /// Finished, waiting to be joined
+    Exited,
+    /// Finished, and nobody will join it (any more). Only its own core frees it, from
+    /// `switch_from_current`, which it can't get to before it has switched off the thread's stack.
+    Dead,
+}
+
+#[derive(Clone, Copy)]
+struct Thread {
+    state: State,
+    /// Saved stack pointer while not running
+    sp: usize,
+    entry: Option<fn()>,
+    /// The core whose queue it runs from
+    cpu: usize,
+    /// Thread waiting in `join`
+    joiner: Option<usize>,
+    /// Nobody holds a `JoinHandle`
+    detached: bool,
+    /// `unpark` was called while it wasn't parked. The next `park` returns right away.
+    unparked: bool,
+    /// Translation tables it runs with. 0 for the kernel's.
+    ttbr0: u64,
+}
+
+impl Thread {
+    const FREE: Thread = Thread {
+        state: State::Free,
+        sp: 0,
+        entry: None,
+        cpu: 0,
+        joiner: None,
+        detached: false,
+        unparked: false,
+        ttbr0: 0,
+    };
+}
+
+/// Ring of thread IDs
+struct RunQueue {
+    ids: [usize; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        RunQueue {
+            ids: [0; MAX_THREADS],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, id: usize) {
+        // Every thread is in at most one queue, so this never fills up
+        assert!(self.len < MAX_THREADS, "Run queue overflowed");
+        self.ids[(self.head + self.len) % MAX_THREADS] = id;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[self.head];
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(id)
+    }
+}
+
+struct Scheduler {
+    threads: [Thread; MAX_THREADS],
+    queues: [RunQueue; MAX_CPUS],
+    /// What each core is running. `None` until `init_core`.
+    current: [Option<usize>; MAX_CPUS],
+    idle: [Option<usize>; MAX_CPUS],
+}
+
+static SCHEDULER: IrqSafeMutex<Scheduler> = IrqSafeMutex::ranked(
+    rank::SCHEDULER,
+    Scheduler {
+        threads: [Thread::FREE; MAX_THREADS],
+        queues: [const { RunQueue::new() }; MAX_CPUS],
+        current: [None; MAX_CPUS],
+        idle: [None; MAX_CPUS],
+    },
+);
+
+/// Set by the timer tick (or another core) when this core should switch threads
+static NEED_RESCHED: PerCpu<AtomicBool> = PerCpu::new([const { AtomicBool::new(false) }; MAX_CPUS]);
+
+/// Where `__switch_context` should go
+struct Switch {
+    prev_sp: *mut usize,
+    next_sp: usize,
+    next_ttbr0: u64,
+}
+
+impl Scheduler {
+    fn current(&self) -> usize {
+        self.current[cpu_id()].expect("Scheduling on a core without threads")
+    }
+
+    fn alloc(&mut self, cpu: usize) -> Result<usize, &'static str> {
+        let id = self
+            .threads
+            .iter()
+            .position(|t| t.state == State::Free)
+            .ok_or("Too many threads")?;
+        self.threads[id] = Thread {
+            cpu,
+            ..Thread::FREE
+        };
+        Ok(id)
+    }
+
+    /// Make `id` runnable, and get its core to look at it
+    fn wake(&mut self, id: usize) {
+        let thread = &mut self.threads[id];
+        if !matches!(thread.state, State::Blocked | State::Sleeping { .. }) {
+            return;
+        }
+        thread.state = State::Ready;
+        let cpu = thread.cpu;
+        self.queues[cpu].push(id);
+        if cpu != cpu_id() {
+            crate::ipi::send(cpu, crate::ipi::Ipi::Reschedule);
+        } else {
+            // If an interrupt handler woke it, switch on the way out rather than on the next tick
+            request_resched();
+        }
+    }
+
+    /// The core with the least to do
+    fn least_loaded_cpu(&self) -> usize {
+        (0..cpus_online())
+            .filter(|&cpu| self.idle[cpu].is_some())
+            .min_by_key(|&cpu| {
+                self.threads
+                    .iter()
+                    .filter(|t| t.cpu == cpu && !matches!(t.state, State::Free | State::Dead))
+                    .count()
+            })
+            .unwrap_or(cpu_id())
+    }
+
+    /// Put the current thread in `state` and pick what runs next.
+    /// Returns `None` if the current thread keeps running.
+    fn switch_from_current(&mut self, state: State) -> Option<Switch> {
+        let cpu = cpu_id();
+        let cur = self.current();
+        let idle = self.idle[cpu];
+        let now = crate::time::uptime_microsec();
+
+        for (id, thread) in self.threads.iter_mut().enumerate() {
+            if thread.cpu != cpu || id == cur {
+                continue;
+            }
+            match thread.state {
+                State::Sleeping { until } if until <= now => {
+                    thread.state = State::Ready;
+                    self.queues[cpu].push(id);
+                }
+                // It's not running, so nothing is on its stack any more
+                State::Dead => *thread = Thread::FREE,
+                _ => {}
+            }
+        }
+
+        self.threads[cur].state = state;
+        if state == State::Running {
+            self.threads[cur].state = State::Ready;
+            // The idle thread only runs when nothing else can, so it never waits in the queue
+            if Some(cur) != idle {
+                self.queues[cpu].push(cur);
+            }
+        }
+
+        let next = self.queues[cpu].pop().or(idle).unwrap_or(cur);
+        self.threads[next].state = State::Running;
+        if next == cur {
+            return None;
+        }
+        self.current[cpu] = Some(next);
+        Some(Switch {
+            prev_sp: &mut self.threads[cur].sp,
+            next_sp: self.threads[next].sp,
+            next_ttbr0: self.threads[next].ttbr0,
+        })
+    }
+}
+
+/// Put the current thread in `state` and run something else.
+/// Returns once the thread gets picked again.
+fn reschedule(state: State) {
+    // IRQs stay masked until we're back, so nothing can preempt us halfway through the switch
+    let saved = crate::exceptions::mask_interrupts();
+    let switch = SCHEDULER.lock().switch_from_current(state);
+    do_switch(switch);
+    crate::exceptions::restore_interrupts(saved);
+}
+
+/// Call with IRQs masked and the scheduler unlocked
+fn do_switch(switch: Option<Switch>) {
+    if let Some(Switch { prev_sp, next_sp, next_ttbr0 }) = switch {
+        load_ttbr0(next_ttbr0);
+        // Safety: The lock is dropped, but nobody else touches this core's threads' stack pointers.
+        // Threads never move between cores.
+        unsafe { __switch_context(prev_sp, next_sp) };
+    }
+}
+
+fn load_ttbr0(ttbr0: u64) {
+    crate::mmu::switch_ttbr0(match ttbr0 {
+        0 => crate::mmu::kernel_ttbr0(),
+        ttbr0 => ttbr0,
+    });
+}
+
+/// A fresh stack that `__switch_context` will start `thread_start` from
+fn initial_sp(id: usize) -> usize {
+    // Safety: The slot is free, so nobody is using its stack
+    let top = unsafe { core::ptr::addr_of!(STACKS[id]) as usize } + STACK_SIZE;
+    let sp = top - CONTEXT_SIZE;
+    let mut context = [0usize; CONTEXT_SIZE / 8];
+    // x29 stays 0, so backtraces stop here
+    context[11] = thread_start as usize;
+    // TPIDR_EL0, the padding and the FP/SIMD registers stay 0, which is also the default FPCR
+    // Safety: Inside the stack
+    unsafe { (sp as *mut [usize; CONTEXT_SIZE / 8]).write(context) };
+    sp
+}
+
+extern "C" fn thread_start() -> ! {
+    // We got here from `reschedule`, which had IRQs masked
+    crate::exceptions::enable_interrupts();
+    let entry = {
+        let sched = SCHEDULER.lock();
+        sched.threads[sched.current()].entry
+    };
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+/// Owns a thread that was spawned. Dropping it lets the thread run on its own.
+pub struct JoinHandle {
+    id: usize,
+}
+
+impl JoinHandle {
+    pub fn thread(&self) -> ThreadId {
+        ThreadId(self.id)
+    }
+
+    /// Wait for the thread to finish
+    pub fn join(self) {
+        let id = self.id;
+        core::mem::forget(self);
+        let saved = crate::exceptions::mask_interrupts();
+        loop {
+            let mut sched = SCHEDULER.lock();
+            if sched.threads[id].state == State::Exited {
+                // Its core may still be on its way off the stack, so leave freeing it to that core
+                sched.threads[id].state = State::Dead;
+                break;
+            }
+            let cur = sched.current();
+            sched.threads[id].joiner = Some(cur);
+            // Block without letting go of the lock in between, so `exit` can't miss us
+            let switch = sched.switch_from_current(State::Blocked);
+            drop(sched);
+            do_switch(switch);
+        }
+        crate::exceptions::restore_interrupts(saved);
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let mut sched = SCHEDULER.lock();
+        let thread = &mut sched.threads[self.id];
+        match thread.state {
+            // It finished before anyone joined. Its core frees it once it's surely off the stack.
+            State::Exited => thread.state = State::Dead,
+            _ => thread.detached = true,
+        }
+    }
+}
+
+/// Start a thread running `entry` on whichever core has the least to do
+pub fn spawn(entry: fn()) -> Result<JoinHandle, &'static str> {
+    spawn_with_ttbr0(entry, 0)
+}
+
+/// Start a thread that runs with the translation tables at `ttbr0`, for a process's address space.
+/// See `mmu::switch_ttbr0`.
+pub fn spawn_with_ttbr0(entry: fn(), ttbr0: u64) -> Result<JoinHandle, &'static str> {
+    let mut sched = SCHEDULER.lock();
+    let cpu = sched.least_loaded_cpu();
+    let id = sched.alloc(cpu)?;
+    let thread = &mut sched.threads[id];
+    thread.entry = Some(entry);
+    thread.sp = initial_sp(id);
+    thread.ttbr0 = ttbr0;
+    thread.state = State::Blocked;
+    sched.wake(id);
+    Ok(JoinHandle { id })
+}
+
+/// The thread we're running in
+pub fn current() -> ThreadId {
+    ThreadId(SCHEDULER.lock().current())
+}
+
+/// Switch the current thread to the translation tables at `ttbr0`. 0 goes back to the kernel's.
+pub fn set_ttbr0(ttbr0: u64) {
+    let mut sched = SCHEDULER.lock();
+    let cur = sched.current();
+    sched.threads[cur].ttbr0 = ttbr0;
+    load_ttbr0(ttbr0);
+}
+
+/// The thread we're running in, or `None` if this core isn't running threads yet
+pub fn try_current() -> Option<ThreadId> {
+    SCHEDULER.lock().current[cpu_id()].map(ThreadId)
+}
+
+/// Block until another thread (or an interrupt handler) calls `unpark`. May also return for no
+/// reason, so check what you were waiting for and park again if needed.
+///
+/// On a core that isn't running threads yet this just returns, so waiting turns into spinning.
+pub fn park() {
+    park_as(State::Blocked);
+}
+
+/// Like `park`, but also returns once the uptime reaches `until` microseconds, on the first
+/// scheduler tick after it.
+pub fn park_until(until: u64) {
+    park_as(State::Sleeping { until });
+}
+
+fn park_as(state: State) {
+    let saved = crate::exceptions::mask_interrupts();
+    let mut sched = SCHEDULER.lock();
+    let Some(cur) = sched.current[cpu_id()] else {
+        drop(sched);
+        crate::exceptions::restore_interrupts(saved);
+        core::hint::spin_loop();
+        return;
+    };
+    if core::mem::take(&mut sched.threads[cur].unparked) {
+        drop(sched);
+        crate::exceptions::restore_interrupts(saved);
+        return;
+    }
+    let switch = sched.switch_from_current(state);
+    drop(sched);
+    do_switch(switch);
+    crate::exceptions::restore_interrupts(saved);
+}
+
+/// Wake `thread` if it's parked, or make its next `park` return right away if not.
+/// Safe to call from interrupt handlers.
+pub fn unpark(thread: ThreadId) {
+    let mut sched = SCHEDULER.lock();
+    match sched.threads[thread.0].state {
+        // `sleep` goes back to sleep if it's woken early, so this only cuts `park_until` short
+        State::Blocked | State::Sleeping { .. } => sched.wake(thread.0),
+        State::Free | State::Exited | State::Dead => {}
+        _ => sched.threads[thread.0].unparked = true,
+    }
+}
+
+/// Let the other threads on this core run
+pub fn yield_now() {
+    reschedule(State::Running);
+}
+
+/// Sleep for at least `duration`. We only wake on a scheduler tick, so it may be up to a tick
+/// longer.
+pub fn sleep(duration: Duration) {
+    let until = crate::time::uptime_microsec().saturating_add(duration.as_micros() as u64);
+    while crate::time::uptime_microsec() < until {
+        reschedule(State::Sleeping { until });
+    }
+}
+
+/// Stop the current thread
+pub fn exit() -> ! {
+    crate::exceptions::mask_interrupts();
+    let mut sched = SCHEDULER.lock();
+    let cur = sched.current();
+    let thread = &mut sched.threads[cur];
+    let state = if thread.detached { State::Dead } else { State::Exited };
+    if let Some(joiner) = thread.joiner.take() {
+        sched.wake(joiner);
+    }
+    let switch = sched.switch_from_current(state);
+    drop(sched);
+    do_switch(switch);
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// Called from the timer tick and reschedule IPIs. Switches threads once the interrupt handler
+/// is done with this one, see `preempt`.
+pub fn request_resched() {
+    NEED_RESCHED.get().store(true, Ordering::Relaxed);
+}
+
+/// Switch threads if a tick or another core asked us to. Called at the end of the IRQ handler.
+pub fn preempt() {
+    if !NEED_RESCHED.get().swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let ready = SCHEDULER.lock().current[cpu_id()].is_some();
+    if ready {
+        yield_now();
+    }
+}
+
+/// Turn whatever this core is running into a thread, give the core an idle thread, and start
+/// the scheduler tick
+pub fn init_core() -> Result<(), &'static str> {
+    let cpu = cpu_id();
+    {
+        let mut sched = SCHEDULER.lock();
+        let cur = sched.alloc(cpu)?;
+        // Nobody has a handle to it
+        sched.threads[cur].detached = true;
+        sched.threads[cur].state = State::Running;
+        sched.current[cpu] = Some(cur);
+
+        let idle = sched.alloc(cpu)?;
+        sched.threads[idle].entry = Some(idle_loop);
+        sched.threads[idle].sp = initial_sp(idle);
+        sched.threads[idle].state = State::Ready;
+        sched.idle[cpu] = Some(idle);
+    }
+    crate::time::start_tick();
+    Ok(())
+}
+
+/// What a core runs when no other thread is ready
+fn idle_loop() {
+    loop {
+        if crate::panic::stop_requested() {
+            crate::panic::halt();
+        }
+        yield_now();
+        // Woken by the tick, or another core handing us a thread
+        asm::wfi();
+    }
+}
@@ -0,0 +1,79 @@
+.section ".text"
+// The kernel target leaves these off, but we have to save them for processes
+.arch_extension fp
+.arch_extension simd
+
+// Save the callee-saved registers, the user thread pointer and the FP/SIMD registers on the
+// current stack, store the stack pointer to [x0], then load the stack pointer from x1 and restore
+// what was saved on that stack.
+// The kernel is built soft-float, so the FP/SIMD registers only ever hold a process's values, and
+// nothing but this saves them. Every thread gets its own.
+// Returns to wherever the other thread last called this, or `thread_start` for a new thread.
+//
+// void __switch_context(usize *prev_sp, usize next_sp)
+.global __switch_context
+__switch_context:
+    sub sp, sp, #640
+    stp x19, x20, [sp, #0]
+    stp x21, x22, [sp, #16]
+    stp x23, x24, [sp, #32]
+    stp x25, x26, [sp, #48]
+    stp x27, x28, [sp, #64]
+    stp x29, x30, [sp, #80]
+    mrs x9, TPIDR_EL0
+    str x9, [sp, #96]
+    add x9, sp, #112
+    stp q0, q1, [x9, #0]
+    stp q2, q3, [x9, #32]
+    stp q4, q5, [x9, #64]
+    stp q6, q7, [x9, #96]
+    stp q8, q9, [x9, #128]
+    stp q10, q11, [x9, #160]
+    stp q12, q13, [x9, #192]
+    stp q14, q15, [x9, #224]
+    stp q16, q17, [x9, #256]
+    stp q18, q19, [x9, #288]
+    stp q20, q21, [x9, #320]
+    stp q22, q23, [x9, #352]
+    stp q24, q25, [x9, #384]
+    stp q26, q27, [x9, #416]
+    stp q28, q29, [x9, #448]
+    stp q30, q31, [x9, #480]
+    mrs x10, FPCR
+    mrs x11, FPSR
+    stp x10, x11, [x9, #512]
+
+    mov x9, sp
+    str x9, [x0]
+    mov sp, x1
+
+    ldp x19, x20, [sp, #0]
+    ldp x21, x22, [sp, #16]
+    ldp x23, x24, [sp, #32]
+    ldp x25, x26, [sp, #48]
+    ldp x27, x28, [sp, #64]
+    ldp x29, x30, [sp, #80]
+    ldr x9, [sp, #96]
+    msr TPIDR_EL0, x9
+    add x9, sp, #112
+    ldp q0, q1, [x9, #0]
+    ldp q2, q3, [x9, #32]
+    ldp q4, q5, [x9, #64]
+    ldp q6, q7, [x9, #96]
+    ldp q8, q9, [x9, #128]
+    ldp q10, q11, [x9, #160]
+    ldp q12, q13, [x9, #192]
+    ldp q14, q15, [x9, #224]
+    ldp q16, q17, [x9, #256]
+    ldp q18, q19, [x9, #288]
+    ldp q20, q21, [x9, #320]
+    ldp q22, q23, [x9, #352]
+    ldp q24, q25, [x9, #384]
+    ldp q26, q27, [x9, #416]
+    ldp q28, q29, [x9, #448]
+    ldp q30, q31, [x9, #480]
+    ldp x10, x11, [x9, #512]
+    msr FPCR, x10
+    msr FPSR, x11
+    add sp, sp, #640
+    ret
@@ -1,5 +1,9 @@
 use aarch64_cpu::{asm, registers::*};
-use tock_registers::interfaces::Readable;
+use log::trace;
+use tock_registers::interfaces::{Readable, Writeable};
+
+/// Scheduler ticks per second
+pub const TICK_HZ: u64 = 100;
 
 fn timer_frequency() -> u64 {
     CNTFRQ_EL0.get()
@@ -9,6 +13,23 @@ fn timer_count() -> u64 {
     CNTPCT_EL0.get()
 }
 
+/// Microseconds since the system counter started
+pub fn uptime_microsec() -> u64 {
+    (timer_count() as u128 * 1_000_000 / timer_frequency() as u128) as u64
+}
+
+/// Start ticking on this core. Each tick is an IRQ from the non-secure physical timer.
+pub fn start_tick() {
+    crate::arm_local::enable_physical_timer_irq(crate::smp::cpu_id());
+    rearm_tick();
+}
+
+/// Schedule the next tick. Also acknowledges the current one.
+pub fn rearm_tick() {
+    CNTP_TVAL_EL0.set(timer_frequency() / TICK_HZ);
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+}
+
 // TODO: convert to macro with ASM so that it is exact # of cycles
 pub fn wait_cycle(mut num: usize) {
     while num > 0 {
@@ -20,7 +41,7 @@ pub fn wait_microsec(msec: u64) {
     let freq = timer_frequency();
     let dt = ((freq as u64 / 1000) * msec) / 1000;
     let then = timer_count();
-    println!(
+    trace!(
         "timer freq = {}, dt = {}, then = {}, target = {}",
         freq,
         dt,
@@ -29,6 +50,6 @@ pub fn wait_microsec(msec: u64) {
     );
     let target = then.saturating_add(dt) as u64;
     while timer_count() < target {}
-    println!("done waiting");
-    println!("now = {}", timer_count());
+    trace!("done waiting");
+    trace!("now = {}", timer_count());
 }
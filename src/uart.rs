@@ -9,7 +9,9 @@ type Uart = MMIODerefWrapper<Registers>;
 type Aux = MMIODerefWrapper<aux::Registers>;
 
 use core::fmt;
-use spin::{Mutex, Once};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::{rank, IrqSafeMutex, IrqSafeMutexGuard};
+use spin::Once;
 
 pub struct Controller {
     uart: Uart,
@@ -20,6 +22,15 @@ impl Controller {
         while !self.uart.lsr.is_set(LSR::DATA_READY) {}
         self.uart.io.read(IO::DATA) as u8 as char
     }
+
+    /// Read a character if one has been received
+    pub fn try_read_char(&mut self) -> Option<char> {
+        if self.uart.lsr.is_set(LSR::DATA_READY) {
+            Some(self.uart.io.read(IO::DATA) as u8 as char)
+        } else {
+            None
+        }
+    }
 }
 
 impl fmt::Write for Controller {
@@ -31,26 +42,54 @@ impl fmt::Write for Controller {
     }
 
     fn write_char(&mut self, c: char) -> fmt::Result {
-        let c = if c as usize > u8::MAX.into() {
-            '~' as u8
-        } else {
-            c as u8
-        };
-        while !self.uart.lsr.is_set(LSR::TRANSMITTER_EMPTY) {}
-        self.uart.io.write(IO::DATA.val(c as u32));
+        // Send it as UTF-8 and let the terminal on the other end sort it out
+        let mut buf = [0u8; 4];
+        for &byte in c.encode_utf8(&mut buf).as_bytes() {
+            while !self.uart.lsr.is_set(LSR::TRANSMITTER_EMPTY) {}
+            self.uart.io.write(IO::DATA.val(byte as u32));
+        }
         Ok(())
     }
 }
 
-static UART: Once<Mutex<Controller>> = Once::new();
+static UART: Once<IrqSafeMutex<Controller>> = Once::new();
 
-pub fn try_get() -> Option<spin::MutexGuard<'static, Controller>> {
+pub fn try_get() -> Option<IrqSafeMutexGuard<'static, Controller>> {
     UART.get().and_then(|m| m.try_lock())
 }
 
-pub fn get() -> spin::MutexGuard<'static, Controller> {
+pub fn get() -> IrqSafeMutexGuard<'static, Controller> {
     UART.get().unwrap().lock()
 }
+
+/// Whether `init` has finished
+pub fn is_init() -> bool {
+    UART.is_completed()
+}
+
+/// Breaks the lock on the UART
+///
+/// # Safety
+///
+/// Whoever holds the lock must never touch the UART again
+pub unsafe fn force_unlock() {
+    if let Some(uart) = UART.get() {
+        uart.force_unlock();
+    }
+}
+
+/// A UART writer that skips the lock entirely, for when there is no other way to get output
+/// out. Sets the UART up first if `init` never ran.
+///
+/// # Safety
+///
+/// Output will get mixed with whatever anyone else is writing
+pub unsafe fn emergency_writer() -> Controller {
+    if !is_init() {
+        configure();
+    }
+    Controller { uart: Uart::new(addresses().1) }
+}
 // Section 2.2
 // https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf
 register_structs! {
@@ -136,12 +175,55 @@ mod aux {
 }
 
 const BAUD_RATE: usize = 115200;
-const ASSUMED_CPU_CLOCK_FREQ: usize = 250_000_000;
+/// The mini UART runs off the core clock. This is its usual speed, for until the mailbox can tell
+/// us the real one.
+const DEFAULT_CORE_CLOCK_FREQ: usize = 250_000_000;
+const AUX_BUS_ADDR: usize = 0x7E21_5000;
+const UART_BUS_ADDR: usize = 0x7E21_5040;
+
+static CLOCK_FREQ: AtomicUsize = AtomicUsize::new(DEFAULT_CORE_CLOCK_FREQ);
+
+/// Where the aux block and the mini UART's registers are
+fn addresses() -> (usize, usize) {
+    let aux = crate::fdt::device("brcm,bcm2835-aux").map_or(bus_to_phys(AUX_BUS_ADDR), |dev| dev.regs.start);
+    let uart = crate::fdt::device("brcm,bcm2835-aux-uart").map_or(bus_to_phys(UART_BUS_ADDR), |dev| dev.regs.start);
+    (aux, uart)
+}
+
+fn baud_divisor() -> u32 {
+    (CLOCK_FREQ.load(Ordering::Relaxed) / BAUD_RATE / 8 - 1) as u32
+}
+
 pub unsafe fn init() {
+    if let Some(freq) = crate::fdt::device("brcm,bcm2835-aux-uart").and_then(|dev| dev.clock_frequency) {
+        CLOCK_FREQ.store(freq as usize, Ordering::Relaxed);
+    }
+    let uart = configure();
+    UART.call_once(|| IrqSafeMutex::ranked(rank::UART, Controller { uart }));
+}
+
+/// Ask the firmware how fast the core clock really runs, and fix the baud rate if we guessed
+/// wrong. Needs the mailbox.
+pub fn update_clock() {
+    let Some(freq) = crate::mailbox::clock_rate(crate::mailbox::ClockId::Core) else {
+        return;
+    };
+    if CLOCK_FREQ.swap(freq as usize, Ordering::Relaxed) == freq as usize {
+        return;
+    }
+    if let Some(uart) = UART.get() {
+        let uart = uart.lock();
+        while !uart.uart.lsr.is_set(LSR::TRANSMITTER_IDLE) {}
+        uart.uart.baud.write(BAUD::BAUDRATE.val(baud_divisor()));
+    }
+}
+
+unsafe fn configure() -> Uart {
     // NOTE TO SELF: On real board will have to set GPIO first
 
-    let aux = Aux::new(bus_to_phys(0x7E21_5000));
-    let uart = Uart::new(bus_to_phys(0x7E21_5040));
+    let (aux, uart) = addresses();
+    let aux = Aux::new(aux);
+    let uart = Uart::new(uart);
 
     // Disable interrupts
     uart.ier.modify(IER::INTERRUPTS_ENABLED::BothOff);
@@ -150,8 +232,7 @@ pub unsafe fn init() {
     uart.lcr.modify(LCR::DATA_SIZE::EightBit + LCR::DLAB_ACCESS::CLEAR);
 
     // Calculate and set baud rate
-    let reg_baud = (ASSUMED_CPU_CLOCK_FREQ / BAUD_RATE / 8) - 1;
-    uart.baud.write(BAUD::BAUDRATE.val(reg_baud as u32));
+    uart.baud.write(BAUD::BAUDRATE.val(baud_divisor()));
 
 
     // Enable reading and writing
@@ -160,7 +241,7 @@ pub unsafe fn init() {
     // Enable use of UART
     aux.enable.modify(aux::ENABLES::MINI_UART_ENABLE::SET);
 
-    UART.call_once(|| Mutex::new(Controller { uart }));
+    uart
 }
 
 pub fn spin_until_enter() {